@@ -0,0 +1,368 @@
+//! Everything deet needs to know about the CPU it is debugging: how to read and write the program
+//! counter and frame pointer, what a breakpoint instruction looks like, how stack frames are laid
+//! out, and how to set hardware watchpoints. The rest of the debugger goes through `Native`, the
+//! implementation for the architecture deet was compiled for (deet only debugs programs of its
+//! own architecture).
+
+use nix::sys::ptrace;
+use nix::unistd::Pid;
+use std::mem::size_of;
+
+pub trait Arch {
+    /// The instruction that traps into the debugger, as it is laid out in memory
+    const BREAKPOINT: &'static [u8];
+
+    /// How far past the start of a breakpoint instruction the program counter is when the trap is
+    /// reported. x86 reports the address after the int3; ARM reports the brk itself.
+    const PC_OFFSET_AFTER_TRAP: usize;
+
+    /// Where, relative to the frame pointer, a frame keeps its return address. (The caller's frame
+    /// pointer is saved right at the frame pointer on every architecture we support.)
+    const RETURN_ADDRESS_OFFSET: usize = size_of::<usize>();
+
+    fn get_pc(pid: Pid) -> Result<usize, nix::Error>;
+
+    fn set_pc(pid: Pid, pc: usize) -> Result<(), nix::Error>;
+
+    fn get_frame_pointer(pid: Pid) -> Result<usize, nix::Error>;
+
+    fn get_stack_pointer(pid: Pid) -> Result<usize, nix::Error>;
+
+    /// Called after single-stepping the instruction at `pc` with the stack pointer at `sp`: if the
+    /// instruction was a call, returns the address the callee will return to. This default is for
+    /// architectures where a call pushes the return address, which is then the word on top of the
+    /// stack and points just past the instruction; x86 instructions are at most 15 bytes long.
+    fn return_address_of_call(pid: Pid, pc: usize, sp: usize) -> Result<Option<usize>, nix::Error> {
+        let new_sp = Self::get_stack_pointer(pid)?;
+        if new_sp != sp.wrapping_sub(size_of::<usize>()) {
+            return Ok(None);
+        }
+        let ret = ptrace::read(pid, new_sp as ptrace::AddressType)? as usize;
+        if ret > pc && ret <= pc + 15 && Self::get_pc(pid)? != ret {
+            Ok(Some(ret))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// The general-purpose registers (plus the program counter and flags), by name, in the order
+    /// they should be shown in
+    fn get_registers(pid: Pid) -> Result<Vec<(&'static str, usize)>, nix::Error>;
+
+    /// How many hardware watchpoints there are (none where deet doesn't support them)
+    const WATCHPOINTS: usize = 0;
+
+    /// Makes hardware watchpoint `slot` stop the inferior after it writes to any of the `len`
+    /// bytes at `addr`. `len` must be one the hardware supports, and `addr` aligned to it.
+    fn set_watchpoint(
+        _pid: Pid,
+        _slot: usize,
+        _addr: usize,
+        _len: usize,
+    ) -> Result<(), nix::Error> {
+        Err(nix::Error::UnsupportedOperation)
+    }
+
+    fn clear_watchpoint(_pid: Pid, _slot: usize) -> Result<(), nix::Error> {
+        Err(nix::Error::UnsupportedOperation)
+    }
+
+    /// Returns the watchpoint that stopped the inferior, if it was one, and resets the record of
+    /// it so that the next stop isn't mistaken for it too.
+    fn triggered_watchpoint(_pid: Pid) -> Result<Option<usize>, nix::Error> {
+        Ok(None)
+    }
+}
+
+/// The x86 debug registers, DR0-DR7, which ptrace gets at through the `u_debugreg` field of the
+/// tracee's `struct user`. DR0-DR3 hold the watched addresses, DR6 says which of them fired, and
+/// DR7 enables each one and says what kind of access, to how many bytes, it watches.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+mod debug_regs {
+    use nix::errno::Errno;
+    use nix::unistd::Pid;
+    use std::mem::{offset_of, size_of};
+
+    const DR6: usize = 6;
+    const DR7: usize = 7;
+
+    fn offset(reg: usize) -> *mut libc::c_void {
+        (offset_of!(libc::user, u_debugreg) + reg * size_of::<usize>()) as *mut libc::c_void
+    }
+
+    fn peek(pid: Pid, reg: usize) -> Result<usize, nix::Error> {
+        let value = unsafe {
+            Errno::clear();
+            let data = std::ptr::null_mut::<libc::c_void>();
+            libc::ptrace(libc::PTRACE_PEEKUSER, pid.as_raw(), offset(reg), data)
+        };
+        // The register's value is the return value, so -1 is only an error if errno says so
+        match Errno::result(value) {
+            Ok(_) | Err(nix::Error::Sys(Errno::UnknownErrno)) => Ok(value as usize),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn poke(pid: Pid, reg: usize, value: usize) -> Result<(), nix::Error> {
+        let data = value as *mut libc::c_void;
+        let res = unsafe { libc::ptrace(libc::PTRACE_POKEUSER, pid.as_raw(), offset(reg), data) };
+        Errno::result(res).map(drop)
+    }
+
+    /// The DR7 bits that belong to `slot`: its local and global enable bits, and its access type
+    /// and length
+    fn dr7_bits(slot: usize) -> usize {
+        (0b11 << (2 * slot)) | (0b1111 << (16 + 4 * slot))
+    }
+
+    pub fn set(pid: Pid, slot: usize, addr: usize, len: usize) -> Result<(), nix::Error> {
+        let len_bits = match len {
+            1 => 0b00,
+            2 => 0b01,
+            4 => 0b11,
+            8 if size_of::<usize>() == 8 => 0b10,
+            _ => return Err(nix::Error::Sys(Errno::EINVAL)),
+        };
+        poke(pid, slot, addr)?;
+        // Locally enabled, breaking on writes (access type 01)
+        let dr7 = (peek(pid, DR7)? & !dr7_bits(slot))
+            | (1 << (2 * slot))
+            | ((0b01 | (len_bits << 2)) << (16 + 4 * slot));
+        poke(pid, DR7, dr7)
+    }
+
+    pub fn clear(pid: Pid, slot: usize) -> Result<(), nix::Error> {
+        let dr7 = peek(pid, DR7)? & !dr7_bits(slot);
+        poke(pid, DR7, dr7)
+    }
+
+    pub fn triggered(pid: Pid) -> Result<Option<usize>, nix::Error> {
+        let dr6 = peek(pid, DR6)?;
+        // The CPU never clears DR6 itself
+        poke(pid, DR6, 0)?;
+        Ok((0..4).find(|slot| dr6 & (1 << slot) != 0))
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+pub type Native = X86_64;
+#[cfg(target_arch = "x86")]
+pub type Native = X86;
+#[cfg(target_arch = "aarch64")]
+pub type Native = Aarch64;
+
+#[cfg(target_arch = "x86_64")]
+pub struct X86_64;
+
+#[cfg(target_arch = "x86_64")]
+impl Arch for X86_64 {
+    /// int3
+    const BREAKPOINT: &'static [u8] = &[0xcc];
+    const PC_OFFSET_AFTER_TRAP: usize = 1;
+
+    fn get_pc(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(pid)?.rip as usize)
+    }
+
+    fn set_pc(pid: Pid, pc: usize) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(pid)?;
+        regs.rip = pc as u64;
+        ptrace::setregs(pid, regs)
+    }
+
+    fn get_frame_pointer(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(pid)?.rbp as usize)
+    }
+
+    fn get_stack_pointer(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(pid)?.rsp as usize)
+    }
+
+    fn get_registers(pid: Pid) -> Result<Vec<(&'static str, usize)>, nix::Error> {
+        let regs = ptrace::getregs(pid)?;
+        Ok(vec![
+            ("rax", regs.rax as usize),
+            ("rbx", regs.rbx as usize),
+            ("rcx", regs.rcx as usize),
+            ("rdx", regs.rdx as usize),
+            ("rsi", regs.rsi as usize),
+            ("rdi", regs.rdi as usize),
+            ("rbp", regs.rbp as usize),
+            ("rsp", regs.rsp as usize),
+            ("r8", regs.r8 as usize),
+            ("r9", regs.r9 as usize),
+            ("r10", regs.r10 as usize),
+            ("r11", regs.r11 as usize),
+            ("r12", regs.r12 as usize),
+            ("r13", regs.r13 as usize),
+            ("r14", regs.r14 as usize),
+            ("r15", regs.r15 as usize),
+            ("rip", regs.rip as usize),
+            ("eflags", regs.eflags as usize),
+        ])
+    }
+
+    const WATCHPOINTS: usize = 4;
+
+    fn set_watchpoint(pid: Pid, slot: usize, addr: usize, len: usize) -> Result<(), nix::Error> {
+        debug_regs::set(pid, slot, addr, len)
+    }
+
+    fn clear_watchpoint(pid: Pid, slot: usize) -> Result<(), nix::Error> {
+        debug_regs::clear(pid, slot)
+    }
+
+    fn triggered_watchpoint(pid: Pid) -> Result<Option<usize>, nix::Error> {
+        debug_regs::triggered(pid)
+    }
+}
+
+#[cfg(target_arch = "x86")]
+pub struct X86;
+
+#[cfg(target_arch = "x86")]
+impl Arch for X86 {
+    /// int3
+    const BREAKPOINT: &'static [u8] = &[0xcc];
+    const PC_OFFSET_AFTER_TRAP: usize = 1;
+
+    fn get_pc(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(pid)?.eip as usize)
+    }
+
+    fn set_pc(pid: Pid, pc: usize) -> Result<(), nix::Error> {
+        let mut regs = ptrace::getregs(pid)?;
+        regs.eip = pc as libc::c_long;
+        ptrace::setregs(pid, regs)
+    }
+
+    fn get_frame_pointer(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(pid)?.ebp as usize)
+    }
+
+    fn get_stack_pointer(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(ptrace::getregs(pid)?.esp as usize)
+    }
+
+    fn get_registers(pid: Pid) -> Result<Vec<(&'static str, usize)>, nix::Error> {
+        let regs = ptrace::getregs(pid)?;
+        Ok(vec![
+            ("eax", regs.eax as usize),
+            ("ebx", regs.ebx as usize),
+            ("ecx", regs.ecx as usize),
+            ("edx", regs.edx as usize),
+            ("esi", regs.esi as usize),
+            ("edi", regs.edi as usize),
+            ("ebp", regs.ebp as usize),
+            ("esp", regs.esp as usize),
+            ("eip", regs.eip as usize),
+            ("eflags", regs.eflags as usize),
+        ])
+    }
+
+    const WATCHPOINTS: usize = 4;
+
+    fn set_watchpoint(pid: Pid, slot: usize, addr: usize, len: usize) -> Result<(), nix::Error> {
+        debug_regs::set(pid, slot, addr, len)
+    }
+
+    fn clear_watchpoint(pid: Pid, slot: usize) -> Result<(), nix::Error> {
+        debug_regs::clear(pid, slot)
+    }
+
+    fn triggered_watchpoint(pid: Pid) -> Result<Option<usize>, nix::Error> {
+        debug_regs::triggered(pid)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+pub struct Aarch64;
+
+/// nix doesn't wrap register access on aarch64, where PTRACE_GETREGS doesn't exist; the registers
+/// have to be read and written as the NT_PRSTATUS register set instead.
+#[cfg(target_arch = "aarch64")]
+fn regset(
+    request: libc::c_uint,
+    pid: Pid,
+    regs: &mut libc::user_regs_struct,
+) -> Result<(), nix::Error> {
+    let mut iov = libc::iovec {
+        iov_base: regs as *mut libc::user_regs_struct as *mut libc::c_void,
+        iov_len: size_of::<libc::user_regs_struct>(),
+    };
+    let res = unsafe {
+        libc::ptrace(
+            request,
+            pid.as_raw(),
+            libc::NT_PRSTATUS as *mut libc::c_void,
+            &mut iov as *mut libc::iovec as *mut libc::c_void,
+        )
+    };
+    nix::errno::Errno::result(res).map(drop)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn getregs(pid: Pid) -> Result<libc::user_regs_struct, nix::Error> {
+    let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+    regset(libc::PTRACE_GETREGSET, pid, &mut regs)?;
+    Ok(regs)
+}
+
+#[cfg(target_arch = "aarch64")]
+impl Arch for Aarch64 {
+    /// brk #0
+    const BREAKPOINT: &'static [u8] = &[0x00, 0x00, 0x20, 0xd4];
+    const PC_OFFSET_AFTER_TRAP: usize = 0;
+
+    fn get_pc(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(getregs(pid)?.pc as usize)
+    }
+
+    fn set_pc(pid: Pid, pc: usize) -> Result<(), nix::Error> {
+        let mut regs = getregs(pid)?;
+        regs.pc = pc as u64;
+        regset(libc::PTRACE_SETREGSET, pid, &mut regs)
+    }
+
+    /// The frame pointer lives in x29
+    fn get_frame_pointer(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(getregs(pid)?.regs[29] as usize)
+    }
+
+    fn get_stack_pointer(pid: Pid) -> Result<usize, nix::Error> {
+        Ok(getregs(pid)?.sp as usize)
+    }
+
+    /// bl and blr leave the return address, the next instruction, in the link register (x30)
+    fn return_address_of_call(
+        pid: Pid,
+        pc: usize,
+        _sp: usize,
+    ) -> Result<Option<usize>, nix::Error> {
+        let regs = getregs(pid)?;
+        let ret = pc + 4;
+        if regs.regs[30] as usize == ret && regs.pc as usize != ret {
+            Ok(Some(ret))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn get_registers(pid: Pid) -> Result<Vec<(&'static str, usize)>, nix::Error> {
+        const NAMES: [&str; 31] = [
+            "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11", "x12", "x13",
+            "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23", "x24", "x25",
+            "x26", "x27", "x28", "x29", "x30",
+        ];
+        let regs = getregs(pid)?;
+        let mut named: Vec<(&'static str, usize)> = NAMES
+            .iter()
+            .zip(regs.regs.iter())
+            .map(|(name, value)| (*name, *value as usize))
+            .collect();
+        named.push(("sp", regs.sp as usize));
+        named.push(("pc", regs.pc as usize));
+        named.push(("pstate", regs.pstate as usize));
+        Ok(named)
+    }
+}
@@ -0,0 +1,196 @@
+//! Generates and compiles C test programs for exercising deet: recursion N frames deep, optionally
+//! segfaulting at the bottom, optionally running the recursion on several threads at once.
+//!
+//! ```text
+//! cargo run --bin deet-fixtures -- --depth 1000 --segfault
+//! cargo run --bin deet -- samples/fixtures/recurse_1000_segfault
+//! ```
+//!
+//! The programs are compiled with the same flags as the Makefile uses for samples/, so that deet
+//! can read their debug info. tests/fixtures.rs generates its programs with this too.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const DEFAULT_DEPTH: usize = 10;
+const DEFAULT_DIR: &str = "samples/fixtures";
+/// Keep in sync with the Makefile
+const CFLAGS: &[&str] = &[
+    "-O0",
+    "-g",
+    "-gdwarf-4",
+    "-no-pie",
+    "-fno-omit-frame-pointer",
+];
+
+struct Options {
+    depth: usize,
+    segfault: bool,
+    threads: usize,
+    output: Option<PathBuf>,
+}
+
+fn usage(program: &str) -> ! {
+    println!(
+        "Usage: {} [--depth N] [--segfault] [--threads N] [-o OUTPUT]\n\
+         \n\
+         \x20 --depth N     recurse N frames deep (default {})\n\
+         \x20 --segfault    dereference NULL at the bottom of the recursion\n\
+         \x20 --threads N   run the recursion on N threads instead of main (default 0)\n\
+         \x20 -o OUTPUT     where to put the program (default {}/recurse_N[_segfault][_tN]);\n\
+         \x20               the source goes next to it as OUTPUT.c",
+        program, DEFAULT_DEPTH, DEFAULT_DIR
+    );
+    std::process::exit(1);
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut options = Options {
+        depth: DEFAULT_DEPTH,
+        segfault: false,
+        threads: 0,
+        output: None,
+    };
+    let mut args_iter = args[1..].iter();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--depth" => {
+                options.depth = match args_iter.next().and_then(|n| n.parse().ok()) {
+                    Some(depth) if depth > 0 => depth,
+                    _ => usage(&args[0]),
+                }
+            }
+            "--segfault" => options.segfault = true,
+            "--threads" => {
+                options.threads = match args_iter.next().and_then(|n| n.parse().ok()) {
+                    Some(threads) => threads,
+                    None => usage(&args[0]),
+                }
+            }
+            "-o" => match args_iter.next() {
+                Some(output) => options.output = Some(PathBuf::from(output)),
+                None => usage(&args[0]),
+            },
+            _ => usage(&args[0]),
+        }
+    }
+    options
+}
+
+/// The default output path, named after the parameters so that fixtures don't overwrite each other
+fn default_output(options: &Options) -> PathBuf {
+    let mut name = format!("recurse_{}", options.depth);
+    if options.segfault {
+        name.push_str("_segfault");
+    }
+    if options.threads > 0 {
+        name.push_str(&format!("_t{}", options.threads));
+    }
+    Path::new(DEFAULT_DIR).join(name)
+}
+
+fn generate_source(options: &Options) -> String {
+    let mut source = String::from("#include <stdio.h>\n");
+    if options.threads > 0 {
+        source.push_str("#include <pthread.h>\n");
+    }
+    source.push_str(&format!(
+        "\n\
+         #define DEPTH {}\n\
+         \n\
+         int recurse(int depth) {{\n\
+         \x20   if (depth == DEPTH) {{\n\
+         \x20       printf(\"Reached depth %d\\n\", depth);\n",
+        options.depth
+    ));
+    if options.segfault {
+        source.push_str("        *(volatile int*)0 = depth;\n");
+    }
+    // Adding to the result keeps the call from being a tail call, so every frame stays on the stack
+    source.push_str(
+        "        return depth;\n\
+         \x20   }\n\
+         \x20   return recurse(depth + 1) + 1;\n\
+         }\n",
+    );
+    if options.threads > 0 {
+        source.push_str(&format!(
+            "\n\
+             #define THREADS {}\n\
+             \n\
+             void *worker(void *arg) {{\n\
+             \x20   long id = (long)arg;\n\
+             \x20   printf(\"Thread %ld starting\\n\", id);\n\
+             \x20   recurse(1);\n\
+             \x20   return NULL;\n\
+             }}\n\
+             \n\
+             int main() {{\n\
+             \x20   pthread_t threads[THREADS];\n\
+             \x20   for (long i = 0; i < THREADS; i++) {{\n\
+             \x20       pthread_create(&threads[i], NULL, worker, (void *)i);\n\
+             \x20   }}\n\
+             \x20   for (int i = 0; i < THREADS; i++) {{\n\
+             \x20       pthread_join(threads[i], NULL);\n\
+             \x20   }}\n\
+             \x20   return 0;\n\
+             }}\n",
+            options.threads
+        ));
+    } else {
+        source.push_str(
+            "\n\
+             int main() {\n\
+             \x20   recurse(1);\n\
+             \x20   return 0;\n\
+             }\n",
+        );
+    }
+    source
+}
+
+/// Compiles the program the same way the Makefile compiles samples/
+fn compile(source_path: &Path, output: &Path, threads: bool) -> Result<(), String> {
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let mut cmd = Command::new(&cc);
+    cmd.args(CFLAGS);
+    if threads {
+        cmd.arg("-pthread");
+    }
+    cmd.arg("-o").arg(output).arg(source_path);
+    let status = cmd
+        .status()
+        .map_err(|err| format!("could not run {}: {}", cc, err))?;
+    if !status.success() {
+        return Err(format!("{} failed ({})", cc, status));
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = parse_args(&args);
+    let output = options
+        .output
+        .clone()
+        .unwrap_or_else(|| default_output(&options));
+    let source_path = output.with_extension("c");
+
+    if let Some(dir) = output.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        if let Err(err) = fs::create_dir_all(dir) {
+            println!("Could not create {}: {}", dir.display(), err);
+            std::process::exit(1);
+        }
+    }
+    if let Err(err) = fs::write(&source_path, generate_source(&options)) {
+        println!("Could not write {}: {}", source_path.display(), err);
+        std::process::exit(1);
+    }
+    if let Err(err) = compile(&source_path, &output, options.threads > 0) {
+        println!("Could not compile {}: {}", source_path.display(), err);
+        std::process::exit(1);
+    }
+    println!("Wrote {} and {}", source_path.display(), output.display());
+}
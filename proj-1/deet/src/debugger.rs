@@ -1,8 +1,20 @@
 use crate::{debugger_command::DebuggerCommand, inferior};
+use crate::arch::{Arch, Native};
+use crate::display::Display;
 use crate::inferior::Inferior;
+use crate::memwatch::{self, MemoryUsage};
+use crate::pretty;
+use crate::source::SourceCache;
+use crate::watchdog;
+use crate::watchpoint;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
-use crate::dwarf_data::{DwarfData, Error as DwarfError};
+use crate::dwarf_data::{
+    DwarfData, Error as DwarfError, Function, Line, Location, TypeDef, Variable,
+};
+use std::mem::size_of;
+use std::process::Command;
+use std::time::{Duration, Instant};
 
 pub struct Debugger {
     target: String,
@@ -11,12 +23,54 @@ pub struct Debugger {
     inferior: Option<Inferior>,
     debug_data: DwarfData,
     break_points: Vec<Breakpoint>,
+    /// Hit statistics, indexed the same way as break_points
+    bp_stats: Vec<BreakpointStats>,
+    next_breakpoint_number: usize,
+    /// The breakpoint the inferior is currently stopped at, and when it stopped there
+    stopped_at: Option<(usize, Instant)>,
+    session_start: Instant,
+    /// Expressions shown at every stop, with the numbers `undisplay` refers to them by
+    displays: Vec<(usize, Display)>,
+    next_display_number: usize,
+    /// The watchpoints, with their numbers, which they keep when they are set again in a new
+    /// process
+    watchpoints: Vec<(usize, watchpoint::Spec)>,
+    /// RSS growth (in kB) between stops that gets a warning, or None if memory isn't reported
+    memory_threshold_kb: Option<u64>,
+    /// The inferior's memory usage at the previous stop
+    last_memory: Option<MemoryUsage>,
+    /// How long the inferior may run without stopping before the watchdog speaks up, or None if
+    /// it shouldn't
+    watchdog: Option<Duration>,
+    /// Source files read for the listings printed at each stop
+    sources: SourceCache,
 }
 
 #[derive(Clone)]
 pub struct Breakpoint {
+    /// The number the user refers to this breakpoint by. Numbers aren't reused after a delete.
+    pub number: usize,
     pub addr: usize,
-    pub orig_byte: u8,
+    /// What the user typed to set this breakpoint (e.g. `main`, `12`). Setting another breakpoint
+    /// that resolves to the same address adds to this instead of creating a second breakpoint.
+    pub labels: Vec<String>,
+    /// The instruction bytes the breakpoint instruction replaced
+    pub orig_bytes: Vec<u8>,
+    /// Disabled breakpoints keep their number and hit counts, but aren't installed
+    pub enabled: bool,
+}
+
+/// How often (and for how long) the inferior stopped at a breakpoint. Reported when the session
+/// ends.
+#[derive(Default)]
+struct BreakpointStats {
+    hits: usize,
+    /// Hits since the program was last started
+    run_hits: usize,
+    time_stopped: Duration,
+    /// Offsets from the start of the session
+    first_hit: Option<Duration>,
+    last_hit: Option<Duration>,
 }
 
 impl Debugger {
@@ -34,7 +88,15 @@ impl Debugger {
                 std::process::exit(1);
             }
         };
-        debug_data.print();
+        if debug_data.has_debug_info() {
+            debug_data.print();
+        } else {
+            println!("{} has no debugging information, so deet is in assembly-level mode:", target);
+            println!("  * break at an address (break *0x401136) or a function in the symbol table");
+            println!("  * inspect registers (regs) and memory (x <address> [words])");
+            println!("  * backtraces show function+offset instead of source lines");
+            println!("Rebuild the program with -g for source lines, next, print and display.");
+        }
 
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
         let mut readline = Editor::<()>::new();
@@ -48,6 +110,17 @@ impl Debugger {
             inferior: None,
             debug_data,
             break_points: Vec::new(),
+            bp_stats: Vec::new(),
+            next_breakpoint_number: 0,
+            stopped_at: None,
+            session_start: Instant::now(),
+            displays: Vec::new(),
+            next_display_number: 1,
+            watchpoints: Vec::new(),
+            memory_threshold_kb: Some(memwatch::DEFAULT_THRESHOLD_KB),
+            last_memory: None,
+            watchdog: Some(watchdog::DEFAULT_THRESHOLD),
+            sources: SourceCache::new(),
         }
     }
 
@@ -60,35 +133,464 @@ impl Debugger {
         usize::from_str_radix(addr_without_0x, 16).ok()
     }
 
+    /// Returns true if source-level debugging information is available for `command`, or
+    /// explains why it can't be used if not.
+    fn require_debug_info(&self, command: &str) -> bool {
+        if self.debug_data.has_debug_info() {
+            return true;
+        }
+        println!(
+            "Error: {} needs debugging information. Rebuild {} with -g to use it.",
+            command, self.target
+        );
+        false
+    }
+
+    /// Opens `line` in the user's $EDITOR. Most editors (vi, emacs, nano, ...) accept `+LINE FILE`;
+    /// VS Code wants `--goto FILE:LINE` instead. If $EDITOR isn't set, prints commands the user can
+    /// paste instead.
+    fn open_in_editor(line: &Line) {
+        let editor = match std::env::var("EDITOR") {
+            Ok(editor) if !editor.trim().is_empty() => editor,
+            _ => {
+                println!("$EDITOR is not set. To open {}, try one of:", line);
+                println!("  emacsclient +{} {}", line.number, line.file);
+                println!("  vscode://file{}:{}", line.file, line.number);
+                return;
+            }
+        };
+        // $EDITOR may contain arguments (e.g. "code --wait"), so split it on whitespace
+        let mut words = editor.split_whitespace();
+        let program = words.next().unwrap();
+        let mut cmd = Command::new(program);
+        cmd.args(words);
+        if program.ends_with("code") {
+            cmd.arg("--goto").arg(format!("{}:{}", line.file, line.number));
+        } else {
+            cmd.arg(format!("+{}", line.number)).arg(&line.file);
+        }
+        if let Err(err) = cmd.status() {
+            println!("Error launching {}: {}", editor, err);
+        }
+    }
+
+    /// Called whenever the inferior is about to resume (or be killed), to account for the time it
+    /// spent stopped at a breakpoint.
+    fn leave_stop(&mut self) {
+        if let Some((idx, since)) = self.stopped_at.take() {
+            self.bp_stats[idx].time_stopped += since.elapsed();
+        }
+    }
+
+    /// Called after the inferior stops, to count a hit if it stopped at one of our breakpoints.
+    fn enter_stop(&mut self) {
+        let addr = match self.inferior.as_ref().and_then(|inferior| inferior.breakpoint_addr()) {
+            Some(addr) => addr,
+            None => return,
+        };
+        if let Some(idx) = self.break_points.iter().position(|bp| bp.addr == addr) {
+            let now = Instant::now();
+            let offset = now - self.session_start;
+            let stats = &mut self.bp_stats[idx];
+            stats.hits += 1;
+            stats.run_hits += 1;
+            stats.first_hit.get_or_insert(offset);
+            stats.last_hit = Some(offset);
+            self.stopped_at = Some((idx, now));
+        }
+    }
+
+    /// Prints the display expressions, if the inferior is stopped (rather than exited) so that
+    /// there is memory to read.
+    fn show_displays(&self) {
+        let inferior = match &self.inferior {
+            Some(inferior) if Native::get_pc(inferior.pid()).is_ok() => inferior,
+            _ => return,
+        };
+        for (num, display) in &self.displays {
+            let value = display.render(&self.debug_data, inferior);
+            println!("{}: {} = {}", num, display.text(), value);
+        }
+    }
+
+    /// Reads `var` out of the stopped inferior and renders it by its type. `function` is the
+    /// function the inferior is stopped in, which locals are found relative to.
+    fn format_variable(
+        &self,
+        inferior: &Inferior,
+        var: &Variable,
+        function: Option<&Function>,
+    ) -> String {
+        let addr = match inferior.variable_addr(var, function) {
+            Ok(addr) => addr,
+            Err(err) => return format!("<cannot find the stack frame: {}>", err),
+        };
+        let size = match var.type_offset.and_then(|offset| self.debug_data.get_type_size(offset)) {
+            Some(size) => size,
+            None => return "<type of unknown size>".to_string(),
+        };
+        let bytes = match inferior.read_bytes(addr, size) {
+            Ok(bytes) => bytes,
+            Err(err) => return format!("<cannot access memory at {:#x}: {}>", addr, err),
+        };
+        let value = pretty::format_value(&self.debug_data, var.type_offset, &bytes);
+        // Like gdb, say what a pointer points to, since the address alone doesn't
+        match var.type_offset.and_then(|offset| self.debug_data.get_type(offset)) {
+            Some(TypeDef::Pointer { .. }) => {
+                format!("({}) {}", pretty::type_name(&self.debug_data, var.type_offset), value)
+            }
+            _ => value,
+        }
+    }
+
+    /// Prints the variable `name`, or with no name, the locals of the function the inferior is
+    /// stopped in and then the globals.
+    fn print_variables(&self, name: Option<&str>) {
+        let (inferior, pc) = match &self.inferior {
+            Some(inferior) => match Native::get_pc(inferior.pid()) {
+                Ok(pc) => (inferior, inferior.debug_addr(pc)),
+                Err(_) => {
+                    println!("Error: the program is not stopped, so it has no variables to show.");
+                    return;
+                }
+            },
+            None => {
+                println!("Error: no inferior process running. Use 'run' to start a process.");
+                return;
+            }
+        };
+        if let Some(name) = name {
+            match self.debug_data.find_variable(pc, name) {
+                Some((var, function)) => {
+                    println!("{} = {}", name, self.format_variable(inferior, var, function))
+                }
+                None => println!("Error: no variable named {} in the current scope", name),
+            }
+            return;
+        }
+        if let Some(function) = self.debug_data.get_function_containing(pc) {
+            println!("Locals of {}:", function.name);
+            for var in &function.variables {
+                let value = self.format_variable(inferior, var, Some(function));
+                println!("  {} = {}", var.name, value);
+            }
+        }
+        println!("Globals:");
+        for var in self.debug_data.global_variables() {
+            println!("  {} = {}", var.name, self.format_variable(inferior, var, None));
+        }
+    }
+
+    /// Sets a watchpoint on `target`: a variable in scope where the inferior is stopped, or an
+    /// address (`0x...` or `*0x...`), which is watched a word at a time.
+    fn watch(&mut self, target: &str) {
+        let debug_data = &self.debug_data;
+        let inferior = match &mut self.inferior {
+            Some(inferior) => inferior,
+            None => {
+                println!("Error: no inferior process running. Use 'run' to start a process.");
+                return;
+            }
+        };
+        let pc = match Native::get_pc(inferior.pid()) {
+            Ok(pc) => inferior.debug_addr(pc),
+            Err(_) => {
+                println!("Error: the program is not stopped, so it can't be watched.");
+                return;
+            }
+        };
+        let address = target.strip_prefix('*').unwrap_or(target);
+        let (addr, len, type_offset, local) = if address.to_lowercase().starts_with("0x") {
+            match Debugger::parse_address(address) {
+                Some(addr) => (addr, size_of::<usize>(), None, false),
+                None => {
+                    println!("Error: {} is not an address", target);
+                    return;
+                }
+            }
+        } else {
+            let (var, function) = match debug_data.find_variable(pc, target) {
+                Some(found) => found,
+                None => {
+                    println!("Error: no variable named {} in the current scope", target);
+                    return;
+                }
+            };
+            let addr = match inferior.variable_addr(var, function) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    println!("Error: cannot find the stack frame: {}", err);
+                    return;
+                }
+            };
+            let size = var.type_offset.and_then(|offset| debug_data.get_type_size(offset));
+            let local = matches!(var.location, Location::FramePointerOffset(_));
+            match size {
+                Some(size) => (addr, size, var.type_offset, local),
+                None => {
+                    println!("Error: {} has a type of unknown size", target);
+                    return;
+                }
+            }
+        };
+        match inferior.add_watchpoint(target, addr, len, type_offset) {
+            Ok(num) => {
+                println!("Set watchpoint {} on {} ({} bytes at {:#x})", num, target, len, addr);
+                let expr = target.to_string();
+                let spec = watchpoint::Spec { expr, addr, len, type_offset, local };
+                self.watchpoints.push((num, spec));
+            }
+            Err(err) => println!("Error: {}", err),
+        }
+    }
+
+    /// Sets the watchpoints and display expressions up in the process the program has just been
+    /// started as. `previous` is the process it last ran as, if any. Whatever was in the program's
+    /// image there (code and globals) is looked for where it is in the new process. Other
+    /// addresses are kept as they were, though they may not hold the same thing anymore, except
+    /// for locals: their frames don't exist yet, so watchpoints on them are dropped.
+    fn carry_over(&mut self, previous: Option<&Inferior>) {
+        let inferior = match &mut self.inferior {
+            Some(inferior) => inferior,
+            None => return,
+        };
+        // Where `addr` is in the new process, and whether it's known to hold the same thing
+        let rebase = |inferior: &Inferior, addr: usize| match previous {
+            Some(previous) => match previous.image_addr(addr) {
+                Some(addr) => (inferior.runtime_addr(addr), true),
+                None => (addr, false),
+            },
+            // Set before the program first ran, so there's nothing to move it from
+            None => (addr, true),
+        };
+        let mut kept = Vec::new();
+        for (num, mut spec) in std::mem::take(&mut self.watchpoints) {
+            if spec.local {
+                println!("Watchpoint {} on {} dropped: it is a local variable.", num, spec.expr);
+                continue;
+            }
+            let (addr, same) = rebase(inferior, spec.addr);
+            match inferior.set_watchpoint(num, &spec.expr, addr, spec.len, spec.type_offset) {
+                Ok(()) => {
+                    if !same {
+                        println!(
+                            "Note: watchpoint {} is on {:#x}, outside the program, which may not \
+                             hold the same thing in the new process.",
+                            num, addr
+                        );
+                    }
+                    spec.addr = addr;
+                    kept.push((num, spec));
+                }
+                Err(err) => println!("Watchpoint {} on {} dropped: {}", num, spec.expr, err),
+            }
+        }
+        self.watchpoints = kept;
+        for (num, display) in &mut self.displays {
+            let (addr, same) = rebase(inferior, display.addr());
+            display.move_to(addr);
+            if !same {
+                println!(
+                    "Note: display {} reads {:#x}, outside the program, which may not hold the \
+                     same thing in the new process.",
+                    num, addr
+                );
+            }
+        }
+    }
+
+    /// Prints the inferior's memory usage and how it changed since the last stop, if memory
+    /// reports are on and the inferior is still around.
+    fn report_memory(&mut self) {
+        let threshold_kb = match self.memory_threshold_kb {
+            Some(threshold_kb) => threshold_kb,
+            None => return,
+        };
+        let usage = match self.inferior.as_ref().and_then(|inferior| MemoryUsage::of(inferior.pid())) {
+            Some(usage) => usage,
+            None => return,
+        };
+        memwatch::report(usage, self.last_memory, threshold_kb);
+        self.last_memory = Some(usage);
+    }
+
+    /// Asks the user whether to kill the inferior and start it again, if it is still around.
+    /// Returns false if the user would rather keep it.
+    fn confirm_restart(&mut self) -> bool {
+        let pid = match &self.inferior {
+            Some(inferior) if Native::get_pc(inferior.pid()).is_ok() => inferior.pid(),
+            _ => return true,
+        };
+        let prompt = format!(
+            "The program being debugged (pid {}) is still running. Start it from the beginning? \
+             (y or n) ",
+            pid
+        );
+        loop {
+            match self.readline.readline(&prompt) {
+                Ok(answer) => match answer.trim() {
+                    "y" | "yes" => return true,
+                    "n" | "no" => return false,
+                    _ => println!("Please answer y or n."),
+                },
+                // Ctrl+C or Ctrl+D (or a closed stdin): don't kill anything
+                Err(_) => return false,
+            }
+        }
+    }
+
+    /// Returns the index in break_points of breakpoint number `num`, or explains that there's no
+    /// such breakpoint.
+    fn find_breakpoint(&self, num: usize) -> Option<usize> {
+        let idx = self.break_points.iter().position(|bp| bp.number == num);
+        if idx.is_none() {
+            println!("No breakpoint number {}.", num);
+        }
+        idx
+    }
+
+    /// Enables or disables breakpoint number `num`, in the running inferior too if there is one.
+    /// Disabling a breakpoint puts back the instruction it replaced.
+    fn set_breakpoint_enabled(&mut self, num: usize, enable: bool) {
+        let idx = match self.find_breakpoint(num) {
+            Some(idx) => idx,
+            None => return,
+        };
+        let bp = &mut self.break_points[idx];
+        if bp.enabled != enable {
+            bp.enabled = enable;
+            if let Some(inferior) = &mut self.inferior {
+                if enable {
+                    inferior.add_breakpoint(bp);
+                } else {
+                    inferior.remove_breakpoint(bp.addr);
+                }
+            }
+        }
+        println!(
+            "Breakpoint {} at {:#x} is {}",
+            num,
+            bp.addr,
+            if enable { "enabled" } else { "disabled" }
+        );
+    }
+
+    /// Lists the breakpoints, with where they are and how often they were hit.
+    fn print_breakpoints(&self) {
+        if self.break_points.is_empty() {
+            println!("No breakpoints. Add one with break <function|line|*address>.");
+            return;
+        }
+        println!("{:>4}  {:<3}  {:<18}  {:>6}  {}", "#", "enb", "address", "hits", "what");
+        for (bp, stats) in self.break_points.iter().zip(&self.bp_stats) {
+            let location = match self.debug_data.get_line_from_addr(bp.addr) {
+                Some(line) => line.to_string(),
+                None => self.debug_data.describe_addr(bp.addr),
+            };
+            println!(
+                "{:>4}  {:<3}  {:<18}  {:>6}  {} at {}",
+                bp.number,
+                if bp.enabled { "y" } else { "n" },
+                format!("{:#x}", bp.addr),
+                stats.hits,
+                bp.labels.join(", "),
+                location
+            );
+            let failure = self
+                .inferior
+                .as_ref()
+                .and_then(|inferior| inferior.breakpoint_failure(bp.addr));
+            if let Some(reason) = failure {
+                println!("{:>4}  not set in the running program: {}", "", reason);
+            }
+        }
+    }
+
+    fn print_breakpoint_summary(&self) {
+        if self.break_points.is_empty() {
+            return;
+        }
+        let fmt_offset = |offset: Option<Duration>| match offset {
+            Some(offset) => format!("+{:.3}s", offset.as_secs_f64()),
+            None => "-".to_string(),
+        };
+        println!("Breakpoint summary:");
+        println!(
+            "{:>4}  {:<18}  {:>6}  {:>8}  {:>12}  {:>10}  {:>10}  {}",
+            "#", "address", "hits", "this run", "time stopped", "first hit", "last hit", "set as"
+        );
+        for (bp, stats) in self.break_points.iter().zip(&self.bp_stats) {
+            println!(
+                "{:>4}  {:<18}  {:>6}  {:>8}  {:>12}  {:>10}  {:>10}  {}",
+                bp.number,
+                format!("{:#x}", bp.addr),
+                stats.hits,
+                stats.run_hits,
+                format!("{:.3}s", stats.time_stopped.as_secs_f64()),
+                fmt_offset(stats.first_hit),
+                fmt_offset(stats.last_hit),
+                bp.labels.join(", ")
+            );
+        }
+    }
+
     pub fn run(&mut self) {
         loop {
             match self.get_next_command() {
                 DebuggerCommand::Run(args) => {
-                    if let Some(inferior) = &mut self.inferior {
-                        inferior.kill();
+                    if !self.confirm_restart() {
+                        println!("Program not restarted.");
+                        continue;
+                    }
+                    self.leave_stop();
+                    let mut previous = self.inferior.take();
+                    if let Some(inferior) = &mut previous {
+                        if Native::get_pc(inferior.pid()).is_ok() {
+                            inferior.kill();
+                        }
+                    }
+                    // Breakpoints, watchpoints and display expressions carry over to the new
+                    // process, but the hits counted for this run start from zero
+                    for stats in &mut self.bp_stats {
+                        stats.run_hits = 0;
                     }
                     if let Some(inferior) = Inferior::new(&self.target, &args, &self.break_points) {
                         // Create the inferior
                         self.inferior = Some(inferior);
+                        self.carry_over(previous.as_ref());
+                        self.last_memory = None;
                         // Make the inferior run
                         // You may use self.inferior.as_mut().unwrap() to get a mutable reference
                         // to the Inferior object
-                        self.inferior.as_mut().unwrap().continue_proc(&self.debug_data);
+                        self.inferior.as_mut().unwrap().continue_proc(
+                            &self.debug_data,
+                            self.watchdog,
+                            &mut self.sources,
+                        );
+                        self.enter_stop();
+                        self.show_displays();
+                        self.report_memory();
                     } else {
                         println!("Error starting subprocess");
                     }
                 }
                 DebuggerCommand::Continue => {
+                    self.leave_stop();
                     if let Some(inferior) = &mut self.inferior {
-                        inferior.continue_proc(&self.debug_data);
+                        inferior.continue_proc(&self.debug_data, self.watchdog, &mut self.sources);
+                        self.enter_stop();
+                        self.show_displays();
+                        self.report_memory();
                     } else {
                         println!("Error: no inferior process running. Use 'run' to start a process.");
                     }
                 }
                 DebuggerCommand::Quit => {
+                    self.leave_stop();
                     if let Some(inferior) = &mut self.inferior {
                         inferior.kill();
                     }
+                    self.print_breakpoint_summary();
                     return;
                 }
                 DebuggerCommand::Backtrace => {
@@ -97,32 +599,254 @@ impl Debugger {
                     }
                 }
                 DebuggerCommand::Break(bp_target) => {
-                    let idx;
                     let address;
                     if bp_target.starts_with("*") {
                         address = Debugger::parse_address(&bp_target[1..]);
                     } else if bp_target.parse::<usize>().is_ok() {
+                        if !self.require_debug_info("break <line>") {
+                            continue;
+                        }
                         address = self.debug_data.get_addr_for_line(None, bp_target.parse::<usize>().unwrap());
                     } else {
                         address = self.debug_data.get_addr_for_function(None, &bp_target);
                     }
-                    idx = self.break_points.len();
-                    self.break_points.push(Breakpoint { 
-                        addr: address.unwrap(),
-                        orig_byte: 0xcc,
-                    });
-                    println!("Set breakpoint {} at {:#x}", idx, self.break_points[idx].addr);
+                    let address = match address {
+                        Some(address) => address,
+                        None => {
+                            println!("Error: could not find where to break for {}", bp_target);
+                            continue;
+                        }
+                    };
+                    // Two breakpoints at one address would each save the other's breakpoint
+                    // instruction as the original bytes, so merge them into one
+                    if let Some(bp) = self.break_points.iter_mut().find(|bp| bp.addr == address) {
+                        if !bp.labels.contains(&bp_target) {
+                            bp.labels.push(bp_target);
+                        }
+                        println!(
+                            "Breakpoint {} is already set at {:#x} ({}){}",
+                            bp.number,
+                            address,
+                            bp.labels.join(", "),
+                            if bp.enabled { "" } else { ", but disabled" }
+                        );
+                        continue;
+                    }
+                    let bp = Breakpoint {
+                        number: self.next_breakpoint_number,
+                        addr: address,
+                        labels: vec![bp_target],
+                        orig_bytes: Vec::new(),
+                        enabled: true,
+                    };
+                    self.next_breakpoint_number += 1;
+                    // A running inferior gets it too, not just the next one
+                    if let Some(inferior) = &mut self.inferior {
+                        inferior.add_breakpoint(&bp);
+                    }
+                    println!("Set breakpoint {} at {:#x}", bp.number, bp.addr);
+                    self.break_points.push(bp);
+                    self.bp_stats.push(BreakpointStats::default());
+                }
+                DebuggerCommand::InfoBreakpoints => self.print_breakpoints(),
+                DebuggerCommand::Delete(num) => {
+                    let idx = match self.find_breakpoint(num) {
+                        Some(idx) => idx,
+                        None => continue,
+                    };
+                    // The stop being timed is found by index, which shifts with the removal
+                    match self.stopped_at {
+                        Some((stopped, _)) if stopped == idx => self.stopped_at = None,
+                        Some((ref mut stopped, _)) if *stopped > idx => *stopped -= 1,
+                        _ => {}
+                    }
+                    let bp = self.break_points.remove(idx);
+                    self.bp_stats.remove(idx);
+                    if let Some(inferior) = &mut self.inferior {
+                        inferior.remove_breakpoint(bp.addr);
+                    }
+                    println!("Deleted breakpoint {} at {:#x}", num, bp.addr);
                 }
-                DebuggerCommand::Print => {
-                    self.debug_data.print_var(self.inferior.as_ref().unwrap().pid());
+                DebuggerCommand::Enable(num) => self.set_breakpoint_enabled(num, true),
+                DebuggerCommand::Disable(num) => self.set_breakpoint_enabled(num, false),
+                DebuggerCommand::Print(name) => {
+                    if !self.require_debug_info("print") {
+                        continue;
+                    }
+                    self.print_variables(name.as_deref());
                 }
                 DebuggerCommand::Next => {
+                    if !self.require_debug_info("next") {
+                        continue;
+                    }
+                    self.leave_stop();
+                    if let Some(inferior) = &mut self.inferior {
+                        let stepped = inferior.step_to_next_line(
+                            &self.debug_data,
+                            self.watchdog,
+                            &mut self.sources,
+                        );
+                        if let Err(err) = stepped {
+                            println!("Error stepping: {}", err);
+                        }
+                        self.enter_stop();
+                        self.show_displays();
+                        self.report_memory();
+                    } else {
+                        println!("Error: no inferior process running. Use 'run' to start a process.");
+                    }
+                }
+                DebuggerCommand::StepInstruction(count) => {
+                    self.leave_stop();
                     if let Some(inferior) = &mut self.inferior {
-                        inferior.step_to_next_line(&self.debug_data).unwrap();
+                        if let Err(err) =
+                            inferior.step_instructions(count, &self.debug_data, &mut self.sources)
+                        {
+                            println!("Error stepping: {}", err);
+                        }
+                        self.enter_stop();
+                        self.show_displays();
+                        self.report_memory();
                     } else {
                         println!("Error: no inferior process running. Use 'run' to start a process.");
                     }
                 }
+                DebuggerCommand::Display(None) => {
+                    if self.displays.is_empty() {
+                        println!("No display expressions. Add one with display <expression>.");
+                    }
+                    self.show_displays();
+                }
+                DebuggerCommand::Display(Some(expr)) => {
+                    if !self.require_debug_info("display") {
+                        continue;
+                    }
+                    match Display::parse(&expr, &self.debug_data) {
+                        Ok(display) => {
+                            let num = self.next_display_number;
+                            self.next_display_number += 1;
+                            self.displays.push((num, display));
+                            self.show_displays();
+                        }
+                        Err(err) => println!("Error: {}", err),
+                    }
+                }
+                DebuggerCommand::Undisplay(num) => {
+                    let before = self.displays.len();
+                    self.displays.retain(|(n, _)| *n != num);
+                    if self.displays.len() == before {
+                        println!("No display number {}.", num);
+                    }
+                }
+                DebuggerCommand::Edit => {
+                    if !self.require_debug_info("edit") {
+                        continue;
+                    }
+                    let line = self
+                        .inferior
+                        .as_ref()
+                        .and_then(|inferior| inferior.current_line(&self.debug_data));
+                    match line {
+                        Some(line) => Debugger::open_in_editor(&line),
+                        None => println!("Error: the inferior is not stopped at a known source line."),
+                    }
+                }
+                DebuggerCommand::Registers => match &self.inferior {
+                    Some(inferior) => {
+                        if let Err(err) = inferior.print_registers() {
+                            println!("Error reading registers: {}", err);
+                        }
+                    }
+                    None => println!("Error: no inferior process running. Use 'run' to start a process."),
+                },
+                DebuggerCommand::Examine(target, count) => {
+                    let inferior = match &self.inferior {
+                        Some(inferior) => inferior,
+                        None => {
+                            println!("Error: no inferior process running. Use 'run' to start a process.");
+                            continue;
+                        }
+                    };
+                    let address = Debugger::parse_address(&target).or_else(|| {
+                        let address = self.debug_data.get_addr_for_function(None, &target)?;
+                        Some(inferior.runtime_addr(address))
+                    });
+                    match address {
+                        Some(address) => {
+                            if let Err(err) = inferior.print_memory(address, count) {
+                                println!("Error reading memory at {:#x}: {}", address, err);
+                            }
+                        }
+                        None => println!("Error: {} is not an address or function", target),
+                    }
+                }
+                DebuggerCommand::Watch(None) => {
+                    if self.watchpoints.is_empty() {
+                        println!("No watchpoints.");
+                    }
+                    for (num, wp) in &self.watchpoints {
+                        println!("{}: {} ({} bytes at {:#x})", num, wp.expr, wp.len, wp.addr);
+                    }
+                }
+                DebuggerCommand::Watch(Some(target)) => self.watch(&target),
+                DebuggerCommand::Unwatch(num) => {
+                    let known = self.watchpoints.iter().any(|(n, _)| *n == num);
+                    self.watchpoints.retain(|(n, _)| *n != num);
+                    let removed = match &mut self.inferior {
+                        Some(inferior) => inferior.remove_watchpoint(num),
+                        None => Err(format!("no watchpoint number {}", num)),
+                    };
+                    match removed {
+                        Err(err) if !known => println!("Error: {}", err),
+                        // Once the process has exited, its debug registers are gone anyway
+                        _ => println!("Removed watchpoint {}", num),
+                    }
+                }
+                DebuggerCommand::MemWatch(None) => match self.memory_threshold_kb {
+                    Some(threshold_kb) => println!(
+                        "Reporting memory at each stop, warning when RSS grows by more than {} kB.",
+                        threshold_kb
+                    ),
+                    None => println!("Memory reports are off."),
+                },
+                DebuggerCommand::MemWatch(Some(setting)) => {
+                    if setting == "off" {
+                        self.memory_threshold_kb = None;
+                        self.last_memory = None;
+                        println!("Memory reports are off.");
+                    } else if let Ok(threshold_kb) = setting.parse() {
+                        self.memory_threshold_kb = Some(threshold_kb);
+                        println!("Warning when RSS grows by more than {} kB between stops.", threshold_kb);
+                    } else {
+                        println!("Usage: memwatch [<threshold in kB> | off]");
+                    }
+                }
+                DebuggerCommand::Watchdog(None) => match self.watchdog {
+                    Some(threshold) => println!(
+                        "The watchdog speaks up when the program runs for {}s without stopping.",
+                        threshold.as_secs_f64()
+                    ),
+                    None => println!("The watchdog is off."),
+                },
+                DebuggerCommand::Watchdog(Some(setting)) => {
+                    if setting == "off" {
+                        self.watchdog = None;
+                        println!("The watchdog is off.");
+                    } else if let Ok(secs) = setting.parse::<f64>() {
+                        if secs > 0.0 && secs.is_finite() {
+                            self.watchdog = Some(Duration::from_secs_f64(secs));
+                            println!(
+                                "The watchdog speaks up when the program runs for {}s without \
+                                 stopping.",
+                                secs
+                            );
+                        } else {
+                            println!("Usage: watchdog [<seconds> | off]");
+                        }
+                    } else {
+                        println!("Usage: watchdog [<seconds> | off]");
+                    }
+                }
             }
         }
     }
@@ -4,8 +4,31 @@ pub enum DebuggerCommand {
     Continue,
     Backtrace,
     Break(String),
-    Print,
+    /// List the breakpoints, with their hit counts
+    InfoBreakpoints,
+    Delete(usize),
+    Enable(usize),
+    Disable(usize),
+    /// Show a variable, or with no variable, the current function's locals and the globals
+    Print(Option<String>),
     Next,
+    /// Execute a number of machine instructions
+    StepInstruction(usize),
+    Edit,
+    /// Show an expression at every stop, or with no expression, show all of them now
+    Display(Option<String>),
+    Undisplay(usize),
+    Registers,
+    /// Show memory: an address (or symbol) and a number of words
+    Examine(String, usize),
+    /// Set the RSS growth warning threshold (in kB) or `off`, or with no argument, show it
+    MemWatch(Option<String>),
+    /// Set how long the inferior may run without stopping before the watchdog speaks up (in
+    /// seconds) or `off`, or with no argument, show it
+    Watchdog(Option<String>),
+    /// Stop when a variable or address is written to, or with no argument, list the watchpoints
+    Watch(Option<String>),
+    Unwatch(usize),
 }
 
 impl DebuggerCommand {
@@ -29,8 +52,79 @@ impl DebuggerCommand {
                     None
                 }
             },
-            "p" | "print" => Some(DebuggerCommand::Print),
+            "i" | "info" => match tokens.get(1) {
+                Some(&"b") | Some(&"break") | Some(&"breakpoints") => {
+                    Some(DebuggerCommand::InfoBreakpoints)
+                }
+                _ => {
+                    eprintln!("Usage: info breakpoints");
+                    None
+                }
+            },
+            "d" | "delete" | "enable" | "disable" => {
+                match tokens.get(1).and_then(|n| n.parse().ok()) {
+                    Some(num) => Some(match tokens[0] {
+                        "enable" => DebuggerCommand::Enable(num),
+                        "disable" => DebuggerCommand::Disable(num),
+                        _ => DebuggerCommand::Delete(num),
+                    }),
+                    None => {
+                        eprintln!("Usage: {} <breakpoint number>", tokens[0]);
+                        None
+                    }
+                }
+            },
+            "p" | "print" => Some(DebuggerCommand::Print(tokens.get(1).map(|s| s.to_string()))),
             "n" | "next" => Some(DebuggerCommand::Next),
+            "si" | "stepi" => match tokens.get(1).map(|n| n.parse()) {
+                None => Some(DebuggerCommand::StepInstruction(1)),
+                Some(Ok(count)) if count > 0 => Some(DebuggerCommand::StepInstruction(count)),
+                _ => {
+                    eprintln!("Usage: stepi [number of instructions]");
+                    None
+                }
+            },
+            "e" | "edit" => Some(DebuggerCommand::Edit),
+            "disp" | "display" => {
+                if tokens.len() > 1 {
+                    Some(DebuggerCommand::Display(Some(tokens[1..].join(" "))))
+                } else {
+                    Some(DebuggerCommand::Display(None))
+                }
+            },
+            "undisp" | "undisplay" => match tokens.get(1).and_then(|n| n.parse().ok()) {
+                Some(num) => Some(DebuggerCommand::Undisplay(num)),
+                None => {
+                    eprintln!("Usage: undisplay <display number>");
+                    None
+                }
+            },
+            "regs" | "registers" => Some(DebuggerCommand::Registers),
+            "x" | "examine" => {
+                let count = match tokens.get(2) {
+                    Some(count) => count.parse().ok(),
+                    None => Some(4),
+                };
+                match (tokens.get(1), count) {
+                    (Some(addr), Some(count)) => {
+                        Some(DebuggerCommand::Examine(addr.to_string(), count))
+                    }
+                    _ => {
+                        eprintln!("Usage: x <address> [number of words]");
+                        None
+                    }
+                }
+            },
+            "memwatch" => Some(DebuggerCommand::MemWatch(tokens.get(1).map(|s| s.to_string()))),
+            "watchdog" => Some(DebuggerCommand::Watchdog(tokens.get(1).map(|s| s.to_string()))),
+            "watch" => Some(DebuggerCommand::Watch(tokens.get(1).map(|s| s.to_string()))),
+            "unwatch" => match tokens.get(1).and_then(|n| n.parse().ok()) {
+                Some(num) => Some(DebuggerCommand::Unwatch(num)),
+                None => {
+                    eprintln!("Usage: unwatch <watchpoint number>");
+                    None
+                }
+            },
             _ => None,
         }
     }
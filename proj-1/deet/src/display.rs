@@ -0,0 +1,94 @@
+//! Display expressions: memory that is rendered as a typed value every time the inferior stops.
+//! An expression is a C-style cast of an address, optionally dereferenced:
+//!
+//! ```text
+//! display *(struct node *)0x7ffd5c1e3a40    the struct at that address, field by field
+//! display (struct node *)0x7ffd5c1e3a40     just the pointer
+//! display *(int *)0x555555558010            an int at that address
+//! ```
+//!
+//! When the program is run again, an address in the program's image (a global, say) is moved to
+//! where the same thing is in the new process; any other address is kept as it is.
+
+use crate::dwarf_data::DwarfData;
+use crate::inferior::Inferior;
+use crate::pretty;
+
+pub struct Display {
+    /// The name of the type the address was cast to a pointer to
+    type_name: String,
+    /// The type the address was cast to a pointer to
+    type_offset: usize,
+    /// Whether to show the value the pointer points at, rather than the pointer itself
+    deref: bool,
+    addr: usize,
+}
+
+impl Display {
+    /// Parses a display expression, looking up the type it names.
+    pub fn parse(text: &str, debug_data: &DwarfData) -> Result<Display, String> {
+        let expr = text.trim();
+        let (deref, expr) = match expr.strip_prefix('*') {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, expr),
+        };
+        let (cast, addr) = expr
+            .strip_prefix('(')
+            .and_then(|rest| rest.split_once(')'))
+            .ok_or_else(|| "expected an expression like *(struct node *)0x1234".to_string())?;
+        let type_name = cast
+            .trim()
+            .strip_suffix('*')
+            .ok_or_else(|| format!("can only cast to a pointer type, not ({})", cast.trim()))?;
+        // Allow any spacing inside the type name, e.g. "struct  node" or "unsigned   int"
+        let type_name = type_name.split_whitespace().collect::<Vec<_>>().join(" ");
+        let type_offset = debug_data
+            .find_type(&type_name)
+            .ok_or_else(|| format!("no type named {} in the debugging info", type_name))?;
+        let addr = addr.trim();
+        let hex = addr
+            .strip_prefix("0x")
+            .or_else(|| addr.strip_prefix("0X"))
+            .unwrap_or(addr);
+        let addr =
+            usize::from_str_radix(hex, 16).map_err(|_| format!("invalid address {}", addr))?;
+        Ok(Display {
+            type_name,
+            type_offset,
+            deref,
+            addr,
+        })
+    }
+
+    /// The expression, written out the same way however it was spaced when it was typed in
+    pub fn text(&self) -> String {
+        let deref = if self.deref { "*" } else { "" };
+        format!("{}({} *){:#x}", deref, self.type_name, self.addr)
+    }
+
+    /// The address the expression reads
+    pub fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// Points the expression at `addr` instead, e.g. where the same global is in a new process.
+    pub fn move_to(&mut self, addr: usize) {
+        self.addr = addr;
+    }
+
+    /// Reads the expression's memory from the inferior and renders it.
+    pub fn render(&self, debug_data: &DwarfData, inferior: &Inferior) -> String {
+        if !self.deref {
+            let type_name = pretty::type_name(debug_data, Some(self.type_offset));
+            return format!("({} *) {:#x}", type_name, self.addr);
+        }
+        let size = match debug_data.get_type_size(self.type_offset) {
+            Some(size) => size,
+            None => return "<type of unknown size>".to_string(),
+        };
+        match inferior.read_bytes(self.addr, size) {
+            Ok(bytes) => pretty::format_value(debug_data, Some(self.type_offset), &bytes),
+            Err(err) => format!("<cannot access memory at {:#x}: {}>", self.addr, err),
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use crate::gimli_wrapper;
+use crate::pretty;
 use addr2line::Context;
-use nix::unistd::Pid;
-use object::Object;
+use object::{Object, SymbolKind};
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::ffi::c_void;
+use std::mem::size_of;
 use std::{fmt, fs};
-use nix::sys::ptrace;
 
 #[derive(Debug)]
 pub enum Error {
@@ -15,7 +15,12 @@ pub enum Error {
 
 pub struct DwarfData {
     files: Vec<File>,
+    /// Every type in the program, keyed by its offset in .debug_info
+    types: HashMap<usize, TypeDef>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// Functions from the symbol table, sorted by address. These are all we have to go on when the
+    /// program was built without -g.
+    symbols: Vec<Symbol>,
 }
 
 impl fmt::Debug for DwarfData {
@@ -41,12 +46,57 @@ impl DwarfData {
         } else {
             gimli::RunTimeEndian::Big
         };
+        let (files, types) = gimli_wrapper::load_file(&object, endian)?;
         Ok(DwarfData {
-            files: gimli_wrapper::load_file(&object, endian)?,
+            files,
+            types,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            symbols: load_symbols(&object),
         })
     }
 
+    /// Whether the program was built with debugging information (-g). Without it, only the symbol
+    /// table is available: there are no source lines, variables or types.
+    pub fn has_debug_info(&self) -> bool {
+        self.files
+            .iter()
+            .any(|file| !file.lines.is_empty() || !file.functions.is_empty())
+    }
+
+    /// Returns the symbol table function containing `addr`, and how far into it `addr` is.
+    pub fn get_symbol_from_addr(&self, addr: usize) -> Option<(&str, usize)> {
+        // The last symbol starting at or before addr is the only one that can contain it
+        let idx = match self.symbols.binary_search_by_key(&addr, |sym| sym.address) {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        let symbol = &self.symbols[idx];
+        // Symbols without a size (e.g. hand-written assembly) are assumed to run up to the next one
+        let end = match (symbol.size, self.symbols.get(idx + 1)) {
+            (0, Some(next)) => next.address,
+            (0, None) => symbol.address + 1,
+            (size, _) => symbol.address + size,
+        };
+        if addr >= end {
+            return None;
+        }
+        Some((&symbol.name, addr - symbol.address))
+    }
+
+    /// Describes an address for the user: the source line if we have one, or else the symbol and
+    /// offset (e.g. `main+0x1f (0x401136)`), or else just the address.
+    pub fn describe_addr(&self, addr: usize) -> String {
+        if let Some(line) = self.get_line_from_addr(addr) {
+            return format!("{} ({:#x})", line, addr);
+        }
+        match self.get_symbol_from_addr(addr) {
+            Some((name, 0)) => format!("{} ({:#x})", name, addr),
+            Some((name, offset)) => format!("{}+{:#x} ({:#x})", name, offset, addr),
+            None => format!("{:#x}", addr),
+        }
+    }
+
     #[allow(dead_code)]
     fn get_target_file(&self, file: &str) -> Option<&File> {
         self.files.iter().find(|f| {
@@ -85,7 +135,11 @@ impl DwarfData {
                         return Some(func.address);
                     }
                 }
-                None
+                // Fall back to the symbol table, which also covers programs built without -g
+                self.symbols
+                    .iter()
+                    .find(|sym| sym.name == func_name)
+                    .map(|sym| sym.address)
             }
         }
     }
@@ -108,10 +162,13 @@ impl DwarfData {
         let frame = self
             .addr2line
             .find_frames(curr_addr.try_into().unwrap())
-            .ok()?
-            .next()
-            .ok()??;
-        Some(frame.function?.raw_name().ok()?.to_string())
+            .ok()
+            .and_then(|mut frames| frames.next().ok()?)
+            .and_then(|frame| frame.function?.raw_name().ok().map(|name| name.to_string()));
+        match frame {
+            Some(name) => Some(name),
+            None => Some(self.get_symbol_from_addr(curr_addr)?.0.to_string()),
+        }
     }
 
     #[allow(dead_code)]
@@ -125,7 +182,10 @@ impl DwarfData {
             for var in &file.global_variables {
                 println!(
                     "  * {} ({}, located at {}, declared at line {})",
-                    var.name, var.entity_type.name, var.location, var.line_number
+                    var.name,
+                    pretty::type_name(self, var.type_offset),
+                    var.location,
+                    var.line_number
                 );
             }
 
@@ -138,7 +198,10 @@ impl DwarfData {
                 for var in &func.variables {
                     println!(
                         "    * Variable: {} ({}, located at {}, declared at line {})",
-                        var.name, var.entity_type.name, var.location, var.line_number
+                        var.name,
+                        pretty::type_name(self, var.type_offset),
+                        var.location,
+                        var.line_number
                     );
                 }
             }
@@ -150,45 +213,151 @@ impl DwarfData {
         }
     }
 
-    pub fn print_var(&self, pid: Pid) {
-        for file in &self.files {
-            println!("Global variables:");
-            for var in &file.global_variables {
-                if let Location::Address(addr) = var.location {
-                    let value = ptrace::read(pid, addr as *mut c_void).unwrap();
-                    println!("    * {} ({}) = {}", var.name, var.entity_type.name, value);
+    pub fn get_type(&self, type_offset: usize) -> Option<&TypeDef> {
+        self.types.get(&type_offset)
+    }
+
+    /// Finds a type by the name it would have in a C cast, e.g. `int` or `struct node`. Struct
+    /// declarations without a body are skipped in favor of the definition.
+    pub fn find_type(&self, name: &str) -> Option<usize> {
+        let (want_struct, name) = match name.strip_prefix("struct ") {
+            Some(name) => (true, name.trim()),
+            None => (false, name),
+        };
+        self.types
+            .iter()
+            .find(|(_, def)| match def {
+                TypeDef::Struct { name: n, size, .. } => want_struct && n == name && *size > 0,
+                TypeDef::Base { name: n, .. } | TypeDef::Typedef { name: n, .. } => {
+                    !want_struct && n == name
                 }
+                _ => false,
+            })
+            .map(|(offset, _)| *offset)
+    }
+
+    /// Returns the number of bytes a value of the given type takes up.
+    pub fn get_type_size(&self, type_offset: usize) -> Option<usize> {
+        match self.types.get(&type_offset)? {
+            TypeDef::Base { size, .. } | TypeDef::Struct { size, .. } => Some(*size),
+            TypeDef::Pointer { .. } => Some(size_of::<usize>()),
+            TypeDef::Typedef { target, .. } | TypeDef::Qualified { target, .. } => {
+                self.get_type_size((*target)?)
             }
+            TypeDef::Array { element, dims } => {
+                Some(self.get_type_size((*element)?)? * dims.iter().product::<usize>())
+            }
+        }
+    }
 
-            println!("Functions:");
-            for func in &file.functions {
-                println!("  * {}",func.name);
-                for var in &func.variables {
-                    if let Location::FramePointerOffset(offset) = var.location {
-                        let regs = ptrace::getregs(pid).unwrap();
-                        let addr = (regs.rbp as isize + offset) as usize;
-                        let value = ptrace::read(pid, addr as *mut c_void).unwrap();
-                        println!("    * Variable: {} ({}) = {}", var.name, var.entity_type.name, value);
-                    }
-                }
+    /// Returns the function whose code contains `addr`.
+    pub fn get_function_containing(&self, addr: usize) -> Option<&Function> {
+        self.files
+            .iter()
+            .flat_map(|file| &file.functions)
+            .find(|func| (func.address..func.address + func.text_length).contains(&addr))
+    }
+
+    /// Finds the variable called `name` as code at `addr` sees it: a parameter or local of the
+    /// function containing `addr` if it has one by that name, or else a global. Locals come with
+    /// their function, since their locations are relative to its frame base.
+    pub fn find_variable(&self, addr: usize, name: &str) -> Option<(&Variable, Option<&Function>)> {
+        if let Some(func) = self.get_function_containing(addr) {
+            if let Some(var) = func.variables.iter().find(|var| var.name == name) {
+                return Some((var, Some(func)));
             }
         }
+        self.global_variables()
+            .find(|var| var.name == name)
+            .map(|var| (var, None))
+    }
+
+    pub fn global_variables(&self) -> impl Iterator<Item = &Variable> {
+        self.files.iter().flat_map(|file| &file.global_variables)
     }
 }
 
-#[derive(Debug, Clone, Default)]
-pub struct Type {
+/// A function from the object file's symbol table
+#[derive(Debug, Clone)]
+pub struct Symbol {
     pub name: String,
+    pub address: usize,
+    /// Length of the function in bytes, or 0 if the symbol table doesn't say
     pub size: usize,
 }
 
-impl Type {
-    pub fn new(name: String, size: usize) -> Self {
-        Type {
-            name: name,
-            size: size,
+/// Collects the function symbols from the symbol table, or from the dynamic symbol table if the
+/// binary has been stripped.
+fn load_symbols(object: &object::File) -> Vec<Symbol> {
+    let mut symbols = function_symbols(object.symbols());
+    if symbols.is_empty() {
+        symbols = function_symbols(object.dynamic_symbols());
+    }
+    symbols.sort_by_key(|sym| sym.address);
+    symbols.dedup_by_key(|sym| sym.address);
+    symbols
+}
+
+fn function_symbols<'data>(
+    table: impl Iterator<Item = (object::SymbolIndex, object::Symbol<'data>)>,
+) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    for (_, symbol) in table {
+        if symbol.kind() != SymbolKind::Text || symbol.is_undefined() || symbol.address() == 0 {
+            continue;
+        }
+        if let Some(name) = symbol.name() {
+            symbols.push(Symbol {
+                name: name.to_string(),
+                address: symbol.address() as usize,
+                size: symbol.size() as usize,
+            });
         }
     }
+    symbols
+}
+
+/// A type as described by the DWARF info, with enough detail to interpret a value of that type
+/// from raw memory. Types refer to each other by their offset in .debug_info; a missing offset
+/// means `void`.
+#[derive(Debug, Clone)]
+pub enum TypeDef {
+    Base {
+        name: String,
+        size: usize,
+        /// DW_ATE_* encoding (signed, unsigned, float, ...)
+        encoding: u8,
+    },
+    Pointer {
+        target: Option<usize>,
+    },
+    Struct {
+        name: String,
+        size: usize,
+        members: Vec<Member>,
+    },
+    Array {
+        element: Option<usize>,
+        /// Length of each dimension, outermost first
+        dims: Vec<usize>,
+    },
+    Typedef {
+        name: String,
+        target: Option<usize>,
+    },
+    /// `const` or `volatile`
+    Qualified {
+        qualifier: &'static str,
+        target: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub name: String,
+    pub type_offset: Option<usize>,
+    /// Byte offset of the member from the start of the struct
+    pub offset: usize,
 }
 
 #[derive(Clone)]
@@ -216,7 +385,8 @@ impl fmt::Debug for Location {
 #[derive(Debug, Clone)]
 pub struct Variable {
     pub name: String,
-    pub entity_type: Type,
+    /// The variable's type, as an offset into .debug_info (see TypeDef)
+    pub type_offset: Option<usize>,
     pub location: Location,
     pub line_number: usize, // Line number in source file
 }
@@ -227,9 +397,21 @@ pub struct Function {
     pub address: usize,
     pub text_length: usize,
     pub line_number: usize, // Line number in source file
+    /// What the FramePointerOffset locations of the function's variables are relative to
+    pub frame_base: FrameBase,
     pub variables: Vec<Variable>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FrameBase {
+    /// The canonical frame address: the stack pointer before the call that made the frame. With
+    /// frame pointers, that's just past the return address the caller pushed.
+    #[default]
+    Cfa,
+    /// The frame pointer register itself
+    FramePointer,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct File {
     pub name: String,
@@ -10,13 +10,18 @@ use gimli::{UnitOffset, UnitSectionOffset};
 use object::Object;
 use std::borrow;
 //use std::io::{BufWriter, Write};
-use crate::dwarf_data::{File, Function, Line, Location, Type, Variable};
+use crate::dwarf_data::{
+    File, FrameBase, Function, Line, Location, Member, TypeDef, Variable,
+};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt::Write;
 use std::{io, path};
 
-pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<Vec<File>, Error> {
+pub fn load_file(
+    object: &object::File,
+    endian: gimli::RunTimeEndian,
+) -> Result<(Vec<File>, HashMap<usize, TypeDef>), Error> {
     // Load a section and return as `Cow<[u8]>`.
     let load_section = |id: gimli::SectionId| -> Result<borrow::Cow<[u8]>, gimli::Error> {
         Ok(object
@@ -39,8 +44,8 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
     // Create `EndianSlice`s for all of the sections.
     let dwarf = dwarf_cow.borrow(&borrow_section);
 
-    // Define a mapping from type offsets to type structs
-    let mut offset_to_type: HashMap<usize, Type> = HashMap::new();
+    // Every type, keyed by .debug_info offset, for rendering values of arbitrary types
+    let mut types: HashMap<usize, TypeDef> = HashMap::new();
 
     let mut compilation_units: Vec<File> = Vec::new();
 
@@ -51,10 +56,14 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
 
         // Iterate over the Debugging Information Entries (DIEs) in the unit.
         let mut depth = 0;
+        // The struct and array types whose children (members or subranges) we are inside of, with
+        // their depths
+        let mut parent_types: Vec<(usize, isize)> = Vec::new();
         let mut entries = unit.entries();
         while let Some((delta_depth, entry)) = entries.next_dfs()? {
             depth += delta_depth;
-            // Update the offset_to_type mapping for types
+            parent_types.retain(|(_, parent_depth)| *parent_depth < depth);
+            // Update the types map for types
             // Update the variable list for formal params/variables
             match entry.tag() {
                 gimli::DW_TAG_compile_unit => {
@@ -97,9 +106,106 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                         // TODO: report error?
                         0
                     };
-                    let type_offset = entry.offset().0;
-                    offset_to_type
-                        .insert(type_offset, Type::new(name, byte_size.try_into().unwrap()));
+                    let encoding = match entry.attr_value(gimli::DW_AT_encoding) {
+                        Ok(Some(gimli::AttributeValue::Encoding(encoding))) => encoding.0,
+                        _ => 0,
+                    };
+                    types.insert(
+                        section_offset(entry, &unit),
+                        TypeDef::Base {
+                            name,
+                            size: byte_size.try_into().unwrap(),
+                            encoding,
+                        },
+                    );
+                }
+                gimli::DW_TAG_structure_type => {
+                    let offset = section_offset(entry, &unit);
+                    types.insert(
+                        offset,
+                        TypeDef::Struct {
+                            name: attr_str(entry, gimli::DW_AT_name, &unit, &dwarf)
+                                .unwrap_or_else(|| "<anonymous>".to_string()),
+                            size: attr_uint(entry, gimli::DW_AT_byte_size)
+                                .unwrap_or(0) as usize,
+                            members: Vec::new(),
+                        },
+                    );
+                    parent_types.push((offset, depth));
+                }
+                gimli::DW_TAG_member => {
+                    let parent = match parent_types.last() {
+                        Some((parent, parent_depth)) if depth == parent_depth + 1 => *parent,
+                        _ => continue,
+                    };
+                    let member = Member {
+                        name: attr_str(entry, gimli::DW_AT_name, &unit, &dwarf)
+                            .unwrap_or_else(|| "<anonymous>".to_string()),
+                        type_offset: attr_ref(entry, gimli::DW_AT_type, &unit, &dwarf),
+                        offset: member_offset(entry, &unit),
+                    };
+                    if let Some(TypeDef::Struct { members, .. }) = types.get_mut(&parent) {
+                        members.push(member);
+                    }
+                }
+                gimli::DW_TAG_array_type => {
+                    let offset = section_offset(entry, &unit);
+                    types.insert(
+                        offset,
+                        TypeDef::Array {
+                            element: attr_ref(entry, gimli::DW_AT_type, &unit, &dwarf),
+                            dims: Vec::new(),
+                        },
+                    );
+                    parent_types.push((offset, depth));
+                }
+                gimli::DW_TAG_subrange_type => {
+                    let parent = match parent_types.last() {
+                        Some((parent, parent_depth)) if depth == parent_depth + 1 => *parent,
+                        _ => continue,
+                    };
+                    // Arrays of unknown size (e.g. flexible array members) count as empty
+                    let len = attr_uint(entry, gimli::DW_AT_count)
+                        .or_else(|| {
+                            attr_uint(entry, gimli::DW_AT_upper_bound)
+                                .map(|upper| upper + 1)
+                        })
+                        .unwrap_or(0);
+                    if let Some(TypeDef::Array { dims, .. }) = types.get_mut(&parent) {
+                        dims.push(len as usize);
+                    }
+                }
+                gimli::DW_TAG_pointer_type => {
+                    types.insert(
+                        section_offset(entry, &unit),
+                        TypeDef::Pointer {
+                            target: attr_ref(entry, gimli::DW_AT_type, &unit, &dwarf),
+                        },
+                    );
+                }
+                gimli::DW_TAG_typedef => {
+                    types.insert(
+                        section_offset(entry, &unit),
+                        TypeDef::Typedef {
+                            name: attr_str(entry, gimli::DW_AT_name, &unit, &dwarf)
+                                .unwrap_or_default(),
+                            target: attr_ref(entry, gimli::DW_AT_type, &unit, &dwarf),
+                        },
+                    );
+                }
+                gimli::DW_TAG_const_type | gimli::DW_TAG_volatile_type => {
+                    let qualifier = if entry.tag() == gimli::DW_TAG_const_type {
+                        "const"
+                    } else {
+                        "volatile"
+                    };
+                    types.insert(
+                        section_offset(entry, &unit),
+                        TypeDef::Qualified {
+                            qualifier,
+                            target: attr_ref(entry, gimli::DW_AT_type, &unit, &dwarf),
+                        },
+                    );
                 }
                 gimli::DW_TAG_subprogram => {
                     let mut func: Function = Default::default();
@@ -129,6 +235,11 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                                     func.line_number = line_number.try_into().unwrap();
                                 }
                             }
+                            gimli::DW_AT_frame_base => {
+                                if let Some(frame_base) = get_frame_base(&attr, &unit) {
+                                    func.frame_base = frame_base;
+                                }
+                            }
                             _ => {}
                         }
                     }
@@ -136,7 +247,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                 }
                 gimli::DW_TAG_formal_parameter | gimli::DW_TAG_variable => {
                     let mut name = String::new();
-                    let mut entity_type: Option<Type> = None;
+                    let mut type_offset: Option<usize> = None;
                     let mut location: Option<Location> = None;
                     let mut line_number = 0;
                     let mut attrs = entry.attrs();
@@ -151,9 +262,7 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             }
                             gimli::DW_AT_type => {
                                 if let Ok(DebugValue::Size(offset)) = val {
-                                    if let Some(dtype) = offset_to_type.get(&offset).clone() {
-                                        entity_type = Some(dtype.clone());
-                                    }
+                                    type_offset = Some(offset);
                                 }
                             }
                             gimli::DW_AT_location => {
@@ -169,10 +278,10 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
                             _ => {}
                         }
                     }
-                    if entity_type.is_some() && location.is_some() {
+                    if type_offset.is_some() && location.is_some() {
                         let var = Variable {
                             name,
-                            entity_type: entity_type.unwrap(),
+                            type_offset,
                             location: location.unwrap(),
                             line_number: line_number.try_into().unwrap(),
                         };
@@ -240,7 +349,71 @@ pub fn load_file(object: &object::File, endian: gimli::RunTimeEndian) -> Result<
             }
         }
     }
-    Ok(compilation_units)
+    Ok((compilation_units, types))
+}
+
+/// Returns the offset of `entry` from the start of .debug_info, which is how DW_AT_type
+/// attributes refer to types.
+fn section_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> usize {
+    match entry.offset().to_unit_section_offset(unit) {
+        UnitSectionOffset::DebugInfoOffset(offset) => offset.0,
+        UnitSectionOffset::DebugTypesOffset(offset) => offset.0,
+    }
+}
+
+fn attr_str<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<String> {
+    match get_attr_value(&entry.attr(name).ok()??, unit, dwarf) {
+        Ok(DebugValue::Str(s)) => Some(s),
+        _ => None,
+    }
+}
+
+/// Reads a constant attribute of any size (get_attr_value only handles the variable-length ones).
+fn attr_uint<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+) -> Option<u64> {
+    entry.attr_value(name).ok()??.udata_value()
+}
+
+/// Returns the .debug_info offset of the DIE an attribute (e.g. DW_AT_type) refers to.
+fn attr_ref<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    name: gimli::DwAt,
+    unit: &gimli::Unit<R>,
+    dwarf: &gimli::Dwarf<R>,
+) -> Option<usize> {
+    match get_attr_value(&entry.attr(name).ok()??, unit, dwarf) {
+        Ok(DebugValue::Size(offset)) => Some(offset),
+        _ => None,
+    }
+}
+
+/// Returns a struct member's byte offset. DWARF 4 and later give it as a constant; older versions
+/// use a location expression that adds the offset to the struct's address.
+fn member_offset<R: Reader>(
+    entry: &gimli::DebuggingInformationEntry<R>,
+    unit: &gimli::Unit<R>,
+) -> usize {
+    match entry.attr_value(gimli::DW_AT_data_member_location) {
+        Ok(Some(gimli::AttributeValue::Udata(offset))) => offset as usize,
+        Ok(Some(gimli::AttributeValue::Exprloc(ref data))) => {
+            let mut pc = data.0.clone();
+            match gimli::Operation::parse(&mut pc, unit.encoding()) {
+                Ok(gimli::Operation::PlusConstant { value }) => value as usize,
+                _ => 0,
+            }
+        }
+        _ => 0,
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -312,6 +485,23 @@ fn get_location<R: Reader>(attr: &gimli::Attribute<R>, unit: &gimli::Unit<R>) ->
     None
 }
 
+/// Reads a function's DW_AT_frame_base. gcc describes it as the canonical frame address, and
+/// clang as the frame pointer register.
+fn get_frame_base<R: Reader>(
+    attr: &gimli::Attribute<R>,
+    unit: &gimli::Unit<R>,
+) -> Option<FrameBase> {
+    if let gimli::AttributeValue::Exprloc(ref data) = attr.value() {
+        let mut pc = data.0.clone();
+        match gimli::Operation::parse(&mut pc, unit.encoding()).ok()? {
+            gimli::Operation::CallFrameCFA => return Some(FrameBase::Cfa),
+            gimli::Operation::Register { .. } => return Some(FrameBase::FramePointer),
+            _ => {}
+        }
+    }
+    None
+}
+
 // based on dwarf_dump.rs
 fn get_attr_value<R: Reader>(
     attr: &gimli::Attribute<R>,
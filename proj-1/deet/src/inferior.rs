@@ -2,17 +2,24 @@ use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::mem::size_of;
+use std::ops::Range;
 use std::os::unix::process::CommandExt;
 use std::process::Child;
 use std::process::Command;
+use std::time::{Duration, Instant};
 
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::Read;
 
+use crate::arch::{Arch, Native};
 use crate::debugger::Breakpoint;
-use crate::dwarf_data::DwarfData;
+use crate::dwarf_data::{DwarfData, FrameBase, Function, Line, Location, Variable};
+use crate::source::SourceCache;
+use crate::watchdog;
+use crate::watchpoint::{self, Watchpoint};
 
 pub enum Status {
     /// Indicates inferior stopped. Contains the signal that stopped the process, as well as the
@@ -38,13 +45,109 @@ fn child_traceme() -> Result<(), std::io::Error> {
 
 pub struct Inferior {
     child: Child,
+    /// Keyed by the address in this process, which is the address in the debugging info plus
+    /// load_bias
     break_points: HashMap<usize, Breakpoint>,
+    /// How far this process was loaded from the addresses in the debugging info
+    load_bias: usize,
+    /// The addresses the program's code and globals span in the debugging info
+    image: Option<Range<usize>>,
+    /// One slot per debug register the CPU has for watchpoints
+    watchpoints: Vec<Option<Watchpoint>>,
+    /// Why each breakpoint that couldn't be installed wasn't, keyed like break_points
+    failed_breakpoints: HashMap<usize, String>,
 }
 
 fn align_addr_to_word(addr: usize) -> usize {
     addr & (-(size_of::<usize>() as isize) as usize)
 }
 
+/// Works out how far `target`, running as `pid`, was loaded from the addresses in its debugging
+/// info. A position-independent executable (ELF type ET_DYN) is linked at 0 and loaded wherever
+/// the kernel puts it, which changes from run to run; anything else is loaded where it was linked.
+fn load_bias(target: &str, pid: Pid) -> usize {
+    const ET_DYN: u16 = 3;
+    let mut header = [0_u8; 18];
+    let read_header = File::open(target).and_then(|mut file| file.read_exact(&mut header));
+    if read_header.is_err() || u16::from_le_bytes([header[16], header[17]]) != ET_DYN {
+        return 0;
+    }
+    let path = match std::fs::canonicalize(target) {
+        Ok(path) => path,
+        Err(_) => return 0,
+    };
+    let maps = std::fs::read_to_string(format!("/proc/{}/maps", pid)).unwrap_or_default();
+    // e.g. "555555554000-555555555000 r--p 00000000 08:01 1234    /path/to/target"
+    for line in maps.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || std::path::Path::new(fields[5]) != path || fields[2] != "00000000" {
+            continue;
+        }
+        let start = fields[0].split('-').next().unwrap();
+        if let Ok(start) = usize::from_str_radix(start, 16) {
+            return start;
+        }
+    }
+    0
+}
+
+/// The addresses `target`'s loadable segments span in its debugging info (where it was linked),
+/// read from its ELF program headers. The code and the globals, .bss included, are in there; the
+/// stack, the heap and shared libraries aren't.
+fn image_extent(target: &str) -> Option<Range<usize>> {
+    const PT_LOAD: u32 = 1;
+    let elf = std::fs::read(target).ok()?;
+    // Only 64-bit little-endian ELF files, like every target deet supports
+    if elf.get(..6)? != b"\x7fELF\x02\x01" {
+        return None;
+    }
+    let read = |at: usize, len: usize| -> Option<usize> {
+        let mut word = [0_u8; 8];
+        word[..len].copy_from_slice(elf.get(at..at + len)?);
+        Some(u64::from_le_bytes(word) as usize)
+    };
+    let (phoff, phentsize, phnum) = (read(32, 8)?, read(54, 2)?, read(56, 2)?);
+    let mut extent: Option<Range<usize>> = None;
+    for i in 0..phnum {
+        let header = phoff + i * phentsize;
+        if read(header, 4)? != PT_LOAD as usize {
+            continue;
+        }
+        let (vaddr, memsz) = (read(header + 16, 8)?, read(header + 40, 8)?);
+        extent = Some(match extent {
+            Some(extent) => min(extent.start, vaddr)..max(extent.end, vaddr + memsz),
+            None => vaddr..vaddr + memsz,
+        });
+    }
+    extent
+}
+
+/// Runs of at least this many identical frames are folded in backtraces
+const MIN_FOLDED_FRAMES: usize = 4;
+
+/// Prints backtrace frames, folding each run of identical frames (a function recursing from the
+/// same line) into its first frame and a note of how deep the recursion goes. A runaway recursion
+/// that overflowed the stack would otherwise print thousands of the same line.
+fn print_folded_frames(frames: &[String]) {
+    let mut i = 0;
+    while i < frames.len() {
+        let run = frames[i..].iter().take_while(|frame| **frame == frames[i]).count();
+        if run >= MIN_FOLDED_FRAMES {
+            println!("{}", frames[i]);
+            println!(
+                "  \u{2026} frame repeated {} more times (recursion depth {}) \u{2026}",
+                run - 1,
+                run
+            );
+        } else {
+            for frame in &frames[i..i + run] {
+                println!("{}", frame);
+            }
+        }
+        i += run;
+    }
+}
+
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
     /// an error is encountered.
@@ -60,18 +163,178 @@ impl Inferior {
             cmd.pre_exec(child_traceme);
         }
         let child = cmd.spawn().ok()?;
-        let mut bp_map = HashMap::new();
-        for bp in break_points {
-            bp_map.insert(bp.addr, bp.clone());
-        }
-        let infer = Inferior {
+        let mut infer = Inferior {
             child,
-            break_points: bp_map,
+            break_points: HashMap::new(),
+            load_bias: 0,
+            image: image_extent(target),
+            watchpoints: (0..Native::WATCHPOINTS).map(|_| None).collect(),
+            failed_breakpoints: HashMap::new(),
         };
         match infer.wait(None) {
-            Ok(Status::Stopped(signal::SIGTRAP, _)) => Some(infer),
-            _ => None,
+            Ok(Status::Stopped(signal::SIGTRAP, _)) => {}
+            _ => return None,
+        }
+        // The program is mapped by now, so we can see where it went
+        infer.load_bias = load_bias(target, infer.pid());
+        for bp in break_points.iter().filter(|bp| bp.enabled) {
+            // Any original bytes are from a previous run; they are saved afresh when the
+            // breakpoint is installed in this process
+            let bp = Breakpoint {
+                orig_bytes: Vec::new(),
+                ..bp.clone()
+            };
+            infer.break_points.insert(infer.runtime_addr(bp.addr), bp);
         }
+        // Install them now rather than at the first resume, so that any that can't be are
+        // reported before the program runs past them
+        infer.set_break_points();
+        Some(infer)
+    }
+
+    /// Adds a breakpoint to this process. It is installed when the process next resumes.
+    pub fn add_breakpoint(&mut self, bp: &Breakpoint) {
+        let bp = Breakpoint {
+            orig_bytes: Vec::new(),
+            ..bp.clone()
+        };
+        self.break_points.insert(self.runtime_addr(bp.addr), bp);
+    }
+
+    /// Watches the `len` bytes at `addr` (an address in this process) for writes, using a free
+    /// debug register. `expr` is what the user asked to watch, and `type_offset` the type to show
+    /// its values as. Returns the watchpoint's number.
+    pub fn add_watchpoint(
+        &mut self,
+        expr: &str,
+        addr: usize,
+        len: usize,
+        type_offset: Option<usize>,
+    ) -> Result<usize, String> {
+        let slot = match self.watchpoints.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.watchpoints.is_empty() => {
+                return Err("this architecture has no hardware watchpoints".to_string())
+            }
+            None => {
+                return Err(format!(
+                    "all {} hardware watchpoints are in use",
+                    self.watchpoints.len()
+                ))
+            }
+        };
+        self.set_watchpoint(slot, expr, addr, len, type_offset)?;
+        Ok(slot)
+    }
+
+    /// Like add_watchpoint, but as watchpoint number `slot`, which has to be free. This is how a
+    /// watchpoint keeps its number when it is set again in a new process.
+    pub fn set_watchpoint(
+        &mut self,
+        slot: usize,
+        expr: &str,
+        addr: usize,
+        len: usize,
+        type_offset: Option<usize>,
+    ) -> Result<(), String> {
+        if !watchpoint::is_watchable(addr, len) {
+            return Err(format!(
+                "can't watch {} bytes at {:#x}: watchpoints cover 1, 2, 4 or 8 bytes, aligned",
+                len, addr
+            ));
+        }
+        match self.watchpoints.get(slot) {
+            Some(None) => {}
+            Some(Some(_)) => return Err(format!("watchpoint {} is already set", slot)),
+            None => return Err(format!("there is no hardware watchpoint {}", slot)),
+        }
+        let value = self
+            .read_bytes(addr, len)
+            .map_err(|err| format!("can't read {:#x}: {}", addr, err))?;
+        Native::set_watchpoint(self.pid(), slot, addr, len)
+            .map_err(|err| format!("can't set a watchpoint at {:#x}: {}", addr, err))?;
+        self.watchpoints[slot] = Some(Watchpoint {
+            expr: expr.to_string(),
+            addr,
+            len,
+            type_offset,
+            value,
+        });
+        Ok(())
+    }
+
+    /// Removes watchpoint number `slot`.
+    pub fn remove_watchpoint(&mut self, slot: usize) -> Result<(), String> {
+        match self.watchpoints.get(slot) {
+            Some(Some(_)) => {}
+            _ => return Err(format!("no watchpoint number {}", slot)),
+        }
+        Native::clear_watchpoint(self.pid(), slot)
+            .map_err(|err| format!("can't clear watchpoint {}: {}", slot, err))?;
+        self.watchpoints[slot] = None;
+        Ok(())
+    }
+
+    /// If a watchpoint stopped the inferior, prints the watched value from before and after the
+    /// write and returns true. Either way, the debug status register is reset for the next stop.
+    fn report_watchpoint(&mut self, debug_data: &DwarfData) -> bool {
+        let slot = match Native::triggered_watchpoint(self.pid()) {
+            Ok(Some(slot)) => slot,
+            _ => return false,
+        };
+        let (addr, len) = match &self.watchpoints[slot] {
+            Some(wp) => (wp.addr, wp.len),
+            None => return false,
+        };
+        let new = match self.read_bytes(addr, len) {
+            Ok(new) => new,
+            Err(_) => return false,
+        };
+        let wp = self.watchpoints[slot].as_mut().unwrap();
+        println!("Watchpoint {}: {}", slot, wp.expr);
+        println!("Old value = {}", wp.format(debug_data, &wp.value));
+        println!("New value = {}", wp.format(debug_data, &new));
+        wp.value = new;
+        true
+    }
+
+    /// Why the breakpoint at `addr` (an address in the debugging info) couldn't be installed in
+    /// this process, if it couldn't.
+    pub fn breakpoint_failure(&self, addr: usize) -> Option<&str> {
+        self.failed_breakpoints
+            .get(&self.runtime_addr(addr))
+            .map(String::as_str)
+    }
+
+    /// Removes the breakpoint at `addr` (an address in the debugging info) from this process,
+    /// putting back the instruction bytes it replaced if it was installed.
+    pub fn remove_breakpoint(&mut self, addr: usize) {
+        let addr = self.runtime_addr(addr);
+        self.failed_breakpoints.remove(&addr);
+        if let Some(bp) = self.break_points.remove(&addr) {
+            if !bp.orig_bytes.is_empty() {
+                // Fails harmlessly if the process has exited
+                let _ = self.write_bytes(addr, &bp.orig_bytes);
+            }
+        }
+    }
+
+    /// Where `addr`, an address in the debugging info, is in this process.
+    pub fn runtime_addr(&self, addr: usize) -> usize {
+        addr.wrapping_add(self.load_bias)
+    }
+
+    /// Where `addr`, an address in this process, is in the debugging info, if it is in the
+    /// program's image. The same thing is then at runtime_addr of that in any other process the
+    /// program runs as, which isn't true of anything on the stack or the heap.
+    pub fn image_addr(&self, addr: usize) -> Option<usize> {
+        let addr = self.debug_addr(addr);
+        self.image.as_ref()?.contains(&addr).then_some(addr)
+    }
+
+    /// Where `addr`, an address in this process, is in the debugging info.
+    pub fn debug_addr(&self, addr: usize) -> usize {
+        addr.wrapping_sub(self.load_bias)
     }
 
     /// Returns the pid of this inferior.
@@ -82,88 +345,323 @@ impl Inferior {
     /// Calls waitpid on this inferior and returns a Status to indicate the state of the process
     /// after the waitpid call.
     pub fn wait(&self, options: Option<WaitPidFlag>) -> Result<Status, nix::Error> {
-        Ok(match waitpid(self.pid(), options)? {
+        self.status(waitpid(self.pid(), options)?)
+    }
+
+    /// Like wait, but with the watchdog printing its notice if the inferior runs for longer than
+    /// `watchdog`. Also returns whether it did.
+    fn wait_watched(&self, watchdog: Option<Duration>) -> Result<(Status, bool), nix::Error> {
+        let (status, noticed) = watchdog::wait(self.pid(), watchdog)?;
+        Ok((self.status(status)?, noticed))
+    }
+
+    fn status(&self, status: WaitStatus) -> Result<Status, nix::Error> {
+        Ok(match status {
             WaitStatus::Exited(_pid, exit_code) => Status::Exited(exit_code),
             WaitStatus::Signaled(_pid, signal, _core_dumped) => Status::Signaled(signal),
             WaitStatus::Stopped(_pid, signal) => {
-                let regs = ptrace::getregs(self.pid())?;
-                Status::Stopped(signal, regs.rip as usize)
+                Status::Stopped(signal, Native::get_pc(self.pid())?)
             }
             other => panic!("waitpid returned unexpected status: {:?}", other),
         })
     }
 
+    /// Installs the breakpoints that aren't installed yet. Installed breakpoints are skipped: the
+    /// bytes at their addresses are already breakpoint instructions, and saving those as the
+    /// original bytes would corrupt the instruction when the breakpoint is stepped over. A
+    /// breakpoint that can't be installed is reported the first time it fails, and tried again at
+    /// the next resume, in case its address has been mapped since.
     fn set_break_points(&mut self) {
-        let addrs: Vec<usize> = self.break_points.keys().copied().collect();
-        let mut orig_bytes = Vec::new();
-        for addr in &addrs {
-            let orig_byte = self.write_byte(*addr, 0xcc).unwrap();
-            orig_bytes.push(orig_byte);
+        let mut addrs: Vec<usize> = self
+            .break_points
+            .iter()
+            .filter(|(_, bp)| bp.orig_bytes.is_empty())
+            .map(|(addr, _)| *addr)
+            .collect();
+        // In the order they were set, so that failures are reported in that order
+        addrs.sort_by_key(|addr| self.break_points[addr].number);
+        for addr in addrs {
+            match self.install_breakpoint(addr) {
+                Ok(orig_bytes) => {
+                    self.failed_breakpoints.remove(&addr);
+                    if let Some(bp) = self.break_points.get_mut(&addr) {
+                        bp.orig_bytes = orig_bytes;
+                    }
+                }
+                Err(reason) => {
+                    let bp = &self.break_points[&addr];
+                    if self.failed_breakpoints.get(&addr) != Some(&reason) {
+                        println!(
+                            "Warning: breakpoint {} at {:#x} could not be set: {}",
+                            bp.number, bp.addr, reason
+                        );
+                    }
+                    self.failed_breakpoints.insert(addr, reason);
+                }
+            }
         }
-        for (addr, &byte) in addrs.iter().zip(orig_bytes.iter()) {
-            if let Some(bp) = self.break_points.get_mut(addr) {
-                bp.orig_byte = byte;
+    }
+
+    /// Writes a breakpoint instruction at `addr` and reads it back to make sure it is there.
+    /// Returns the bytes it replaced, or why it couldn't be written.
+    fn install_breakpoint(&mut self, addr: usize) -> Result<Vec<u8>, String> {
+        let orig_bytes = match self.write_bytes(addr, Native::BREAKPOINT) {
+            Ok(orig_bytes) => orig_bytes,
+            Err(err) => return Err(self.explain_unwritable(addr, err)),
+        };
+        match self.read_bytes(addr, Native::BREAKPOINT.len()) {
+            Ok(bytes) if bytes == Native::BREAKPOINT => Ok(orig_bytes),
+            Ok(_) => {
+                let _ = self.write_bytes(addr, &orig_bytes);
+                Err("the breakpoint instruction was not there when read back".to_string())
             }
+            Err(err) => Err(format!("it could not be read back ({})", err)),
+        }
+    }
+
+    /// Says why writing to `addr` failed with `err`, going by how the process's memory is mapped.
+    fn explain_unwritable(&self, addr: usize, err: nix::Error) -> String {
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.pid()));
+        let maps = match maps {
+            Ok(maps) => maps,
+            Err(_) => return format!("writing to it failed ({})", err),
+        };
+        // e.g. "555555554000-555555555000 r-xp 00000000 08:01 1234    /path/to/target"
+        for line in maps.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let range: Vec<usize> = fields[0]
+                .split('-')
+                .filter_map(|bound| usize::from_str_radix(bound, 16).ok())
+                .collect();
+            if range.len() == 2 && (range[0]..range[1]).contains(&addr) {
+                let mapping = fields.get(5).copied().unwrap_or("anonymous memory");
+                return format!("writing to it failed ({}), in {} ({})", err, mapping, fields[1]);
+            }
+        }
+        if addr < 0x1000 {
+            "it is not a valid code address (the symbol may not have been resolved)".to_string()
+        } else {
+            "nothing is mapped there (yet: a shared library may not have been loaded)".to_string()
         }
     }
 
     fn check_stop_at_b(&mut self) {
-        let regs = ptrace::getregs(self.pid()).unwrap();
-        let bp_addr = regs.rip as usize;
+        let bp_addr = Native::get_pc(self.pid()).unwrap();
         if self.break_points.contains_key(&bp_addr) {
-            let _ = ptrace::step(self.pid(), None);
-            let wait_result = self.wait(None);
-            match wait_result {
-                Ok(Status::Exited(_)) | Ok(Status::Signaled(_)) => {
-                    return;
+            if let Err(error) = self.step_instruction() {
+                println!("Error waiting for child: {}", error);
+            }
+        }
+    }
+
+    /// Executes one instruction and waits for the inferior to stop again. If there is a
+    /// breakpoint at the pc, the original instruction is put back for the step and the
+    /// breakpoint reinstalled after it.
+    fn step_instruction(&mut self) -> Result<Status, nix::Error> {
+        let pc = Native::get_pc(self.pid())?;
+        // Usually the original instruction is already back in place from the stop, but not if the
+        // breakpoint was added or enabled while stopped right here
+        let orig_bytes = match self.break_points.get(&pc) {
+            Some(bp) if !bp.orig_bytes.is_empty() => Some(bp.orig_bytes.clone()),
+            _ => None,
+        };
+        if let Some(orig_bytes) = &orig_bytes {
+            self.write_bytes(pc, orig_bytes)?;
+        }
+        ptrace::step(self.pid(), None)?;
+        let status = self.wait(None)?;
+        if orig_bytes.is_some() {
+            if let Status::Stopped(..) = status {
+                self.write_bytes(pc, Native::BREAKPOINT)?;
+            }
+        }
+        Ok(status)
+    }
+
+    /// Executes `count` instructions, stopping early if the inferior exits, gets a signal, writes
+    /// to a watched address or reaches a breakpoint, and then says where it is.
+    pub fn step_instructions(
+        &mut self,
+        count: usize,
+        debug_data: &DwarfData,
+        sources: &mut SourceCache,
+    ) -> Result<(), nix::Error> {
+        let started = Instant::now();
+        let cpu_before = self.cpu_time();
+        for _ in 0..count {
+            self.set_break_points();
+            match self.step_instruction()? {
+                Status::Exited(exit_code) => {
+                    println!("Child exited (status {})", exit_code);
+                    println!("Ran for {:.3}s", started.elapsed().as_secs_f64());
+                    return Ok(());
                 }
-                Ok(Status::Stopped(signal, rip)) => {
-                    let _ = self.write_byte(bp_addr, 0xcc);
+                Status::Signaled(signal) => {
+                    println!("Child terminated (signal {:?})", signal);
+                    println!("Ran for {:.3}s", started.elapsed().as_secs_f64());
+                    return Ok(());
                 }
-                Err(error) => {
-                    println!("Error waiting for child: {}", error);
-                    return;
+                Status::Stopped(signal::SIGTRAP, pc) => {
+                    // Stepping on would go past the breakpoint without it being hit
+                    if self.report_watchpoint(debug_data) || self.break_points.contains_key(&pc) {
+                        break;
+                    }
+                }
+                Status::Stopped(signal, _) => {
+                    println!("Child stopped (signal {:?})", signal);
+                    break;
                 }
             }
         }
+        self.print_run_time(started.elapsed(), cpu_before);
+        let pc = self.debug_addr(Native::get_pc(self.pid())?);
+        println!("Stopped at {}", debug_data.describe_addr(pc));
+        if let Some(line) = debug_data.get_line_from_addr(pc) {
+            let breakpoint_lines = self.breakpoint_lines(debug_data, &line.file);
+            sources.print_context(&line, &breakpoint_lines);
+        }
+        Ok(())
     }
 
     fn set_back_rip(&mut self) {
-        let mut regs = ptrace::getregs(self.pid()).unwrap();
-        let bp_addr = (regs.rip - 1) as usize;
+        let pc = Native::get_pc(self.pid()).unwrap();
+        let bp_addr = pc.wrapping_sub(Native::PC_OFFSET_AFTER_TRAP);
         if self.break_points.contains_key(&bp_addr) {
-            let _ = self.write_byte(bp_addr, self.break_points[&bp_addr].orig_byte);
-            regs.rip = regs.rip.wrapping_sub(1);         // %rip = %rip - 1
-            let _ = ptrace::setregs(self.pid(), regs); 
+            let orig_bytes = self.break_points[&bp_addr].orig_bytes.clone();
+            let _ = self.write_bytes(bp_addr, &orig_bytes);
+            // Point the pc back at the start of the breakpoint
+            let _ = Native::set_pc(self.pid(), bp_addr);
+        }
+    }
+
+    /// Says how long the inferior ran for before this stop, in `wall` time and in CPU time since
+    /// it had used `cpu_before`.
+    fn print_run_time(&self, wall: Duration, cpu_before: Option<Duration>) {
+        match (cpu_before, self.cpu_time()) {
+            (Some(before), Some(after)) => println!(
+                "Ran for {:.3}s ({:.3}s CPU) since the last stop",
+                wall.as_secs_f64(),
+                after.saturating_sub(before).as_secs_f64()
+            ),
+            _ => println!("Ran for {:.3}s since the last stop", wall.as_secs_f64()),
+        }
+    }
+
+    /// Returns the CPU time (user + system) the inferior has used so far, according to
+    /// /proc/<pid>/stat.
+    fn cpu_time(&self) -> Option<Duration> {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", self.pid())).ok()?;
+        // The command name (field 2) is parenthesized and may contain spaces, so start counting
+        // fields after it. utime and stime are fields 14 and 15, in clock ticks.
+        let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+        let utime: u64 = fields.get(11)?.parse().ok()?;
+        let stime: u64 = fields.get(12)?.parse().ok()?;
+        let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        if ticks_per_sec <= 0 {
+            return None;
         }
+        Some(Duration::from_secs_f64(
+            (utime + stime) as f64 / ticks_per_sec as f64,
+        ))
+    }
+
+    /// The lines in `file` that carry breakpoints.
+    fn breakpoint_lines(&self, debug_data: &DwarfData, file: &str) -> Vec<usize> {
+        self.break_points
+            .values()
+            .filter_map(|bp| debug_data.get_line_from_addr(bp.addr))
+            .filter(|line| line.file == file)
+            .map(|line| line.number)
+            .collect()
     }
 
-    pub fn continue_proc(&mut self, debug_data: &DwarfData) {
+    /// Resumes the inferior and waits for it to stop or exit, with the watchdog printing its
+    /// notice if that takes longer than `watchdog`. When it stops somewhere with debugging info,
+    /// the source around that line is printed from `sources`.
+    pub fn continue_proc(
+        &mut self,
+        debug_data: &DwarfData,
+        watchdog: Option<Duration>,
+        sources: &mut SourceCache,
+    ) {
         self.set_break_points();
         self.check_stop_at_b();
 
+        let started = Instant::now();
+        let cpu_before = self.cpu_time();
         let _ = ptrace::cont(self.pid(), None);
-        let wait_result = self.wait(None);
+        let wait_result = self.wait_watched(watchdog);
+        self.report_stop(wait_result, started, cpu_before, debug_data, sources);
+    }
+
+    /// Reports how the inferior stopped or exited after running since `started`, having used
+    /// `cpu_before` of CPU time by then, and gets it ready to resume from a breakpoint it stopped
+    /// at.
+    fn report_stop(
+        &mut self,
+        wait_result: Result<(Status, bool), nix::Error>,
+        started: Instant,
+        cpu_before: Option<Duration>,
+        debug_data: &DwarfData,
+        sources: &mut SourceCache,
+    ) {
+        let wall = started.elapsed();
         match wait_result {
-            Ok(Status::Exited(exit_code)) => {
+            Ok((Status::Exited(exit_code), _)) => {
                 println!("Child exited (status {})", exit_code);
+                // The process is gone, so there is no CPU time to read any more
+                println!("Ran for {:.3}s", wall.as_secs_f64());
                 return;
             }
-            Ok(Status::Signaled(signal)) => {
+            Ok((Status::Signaled(signal), _)) => {
                 println!("Child terminated (signal {:?})", signal);
+                println!("Ran for {:.3}s", wall.as_secs_f64());
             }
-            Ok(Status::Stopped(signal, rip)) => {
-                println!("Child stopped (signal {:?})", signal);
-                let line = debug_data.get_line_from_addr(rip).unwrap();
-                println!("Stopped at {}", line);
-                if let Ok(file) = File::open(line.file) {
-                    let lines: Vec<_> = io::BufReader::new(file).lines().collect();
-                    if line.number > 0 && line.number <= lines.len() {
-                        if let Ok(src) = &lines[line.number - 1] {
-                            println!("Source: {}", src);
-                        }
+            Ok((Status::Stopped(signal, rip), noticed)) => {
+                // A watchpoint traps after the write, so the pc is just past the instruction that
+                // did it. At a breakpoint, any watchpoint status left over is stale.
+                let at_breakpoint = self
+                    .break_points
+                    .contains_key(&rip.wrapping_sub(Native::PC_OFFSET_AFTER_TRAP));
+                let watched = if signal != signal::SIGTRAP {
+                    false
+                } else if at_breakpoint {
+                    let _ = Native::triggered_watchpoint(self.pid());
+                    false
+                } else {
+                    self.report_watchpoint(debug_data)
+                };
+                if !watched {
+                    println!("Child stopped (signal {:?})", signal);
+                }
+                self.print_run_time(wall, cpu_before);
+                match debug_data.get_line_from_addr(self.debug_addr(rip)) {
+                    Some(line) => {
+                        println!("Stopped at {}", line);
+                        let breakpoint_lines = self.breakpoint_lines(debug_data, &line.file);
+                        sources.print_context(&line, &breakpoint_lines);
+                    }
+                    // No debugging info for this address (e.g. inside libc, or a program built
+                    // without -g). The pc is past the breakpoint instruction if we hit one.
+                    None => {
+                        let bp_addr = rip.wrapping_sub(Native::PC_OFFSET_AFTER_TRAP);
+                        let addr = if self.break_points.contains_key(&bp_addr) {
+                            bp_addr
+                        } else {
+                            rip
+                        };
+                        println!("Stopped at {}", debug_data.describe_addr(self.debug_addr(addr)));
                     }
                 }
+                // Interrupted after the watchdog's notice: show where it was stuck
+                if noticed && signal == signal::SIGINT {
+                    println!("Backtrace:");
+                    let _ = self.print_backtrace(debug_data);
+                }
+                // Not at a breakpoint, so there is no instruction to put back
+                if watched {
+                    return;
+                }
             }
             Err(error) => {
                 println!("Error waiting for child: {}", error);
@@ -174,6 +672,20 @@ impl Inferior {
         self.set_back_rip();
     }
 
+    /// Returns the source line the inferior is currently stopped at, if it is stopped somewhere we
+    /// have debugging info for.
+    pub fn current_line(&self, debug_data: &DwarfData) -> Option<Line> {
+        let pc = Native::get_pc(self.pid()).ok()?;
+        debug_data.get_line_from_addr(self.debug_addr(pc))
+    }
+
+    /// Returns the address of the breakpoint the inferior is currently stopped at, if any, as it
+    /// is in the debugging info (like Breakpoint::addr).
+    pub fn breakpoint_addr(&self) -> Option<usize> {
+        let pc = Native::get_pc(self.pid()).ok()?;
+        self.break_points.get(&pc).map(|bp| bp.addr)
+    }
+
     pub fn kill(&mut self) {
         println!("Killing running inferior (pid {})", self.pid());
         let _ = Child::kill(&mut self.child);
@@ -181,44 +693,230 @@ impl Inferior {
     }
 
     pub fn print_backtrace(&self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs = ptrace::getregs(self.pid()).unwrap();
-        let mut rip = regs.rip as usize;
-        let mut rbp = regs.rbp as usize;
-
-        while true {
-            if let Some(function) = debug_data.get_function_from_addr(rip) {
-                print!("{} ", function);
-                if function == "main" {
-                    if let Some(line) = debug_data.get_line_from_addr(rip) {
-                        println!("({})", line);
-                    }
-                    break;
-                }
+        let mut rip = Native::get_pc(self.pid())?;
+        let mut rbp = Native::get_frame_pointer(self.pid())?;
+
+        // Collected first and printed once the walk is done, so that runs of identical frames can
+        // be folded; a frame that can't be read still gets the frames before it printed
+        let mut frames = Vec::new();
+        let walked = loop {
+            // A caller's rip is the return address, just past its call instruction, and may be on
+            // the next line (or past the end of the function, for a call that never returns), so
+            // callers are looked up at the call instead
+            let addr = if frames.is_empty() {
+                self.debug_addr(rip)
+            } else {
+                self.debug_addr(rip - 1)
+            };
+            let function = debug_data.get_function_from_addr(addr);
+            frames.push(match (&function, debug_data.get_line_from_addr(addr)) {
+                (Some(function), Some(line)) => format!("{} ({})", function, line),
+                // Without debugging info, all we can show is the symbol and offset
+                _ => debug_data.describe_addr(addr),
+            });
+            if function.as_deref() == Some("main") {
+                break Ok(());
             }
-            if let Some(line) = debug_data.get_line_from_addr(rip) {
-                println!("({})", line);
+            let caller = ptrace::read(
+                self.pid(),
+                (rbp + Native::RETURN_ADDRESS_OFFSET) as ptrace::AddressType,
+            )
+            .and_then(|rip| Ok((rip, ptrace::read(self.pid(), rbp as ptrace::AddressType)?)));
+            match caller {
+                Ok((caller_rip, caller_rbp)) => {
+                    rip = caller_rip as usize;
+                    rbp = caller_rbp as usize;
+                }
+                Err(err) => break Err(err),
             }
-            rip = ptrace::read(self.pid(), (rbp + 8) as ptrace::AddressType)? as usize;
-            rbp = ptrace::read(self.pid(), rbp as ptrace::AddressType)? as usize;
+        };
+        print_folded_frames(&frames);
+        walked
+    }
+
+    /// Prints the registers, in hex and in decimal.
+    pub fn print_registers(&self) -> Result<(), nix::Error> {
+        for (name, value) in Native::get_registers(self.pid())? {
+            println!("{:<8}{:#018x}  {}", name, value, value as isize);
         }
+        Ok(())
+    }
 
+    /// Prints `count` words of memory starting at `addr`, one per line.
+    pub fn print_memory(&self, addr: usize, count: usize) -> Result<(), nix::Error> {
+        let word_size = size_of::<usize>();
+        let bytes = self.read_bytes(addr, count * word_size)?;
+        for (i, word) in bytes.chunks(word_size).enumerate() {
+            let mut buf = [0_u8; size_of::<usize>()];
+            buf.copy_from_slice(word);
+            println!(
+                "{:#x}: {:#0width$x}",
+                addr + i * word_size,
+                usize::from_ne_bytes(buf),
+                width = 2 * word_size + 2
+            );
+        }
+        Ok(())
+    }
+    /// Runs the inferior to the next source line, stepping over calls: it single-steps until the
+    /// line changes, and when an instruction turns out to be a call, runs the callee at full speed
+    /// to a temporary breakpoint at the return address. That way loops, returns and calls all
+    /// land on whichever line really runs next. Stepping off the end of main, into code without
+    /// debugging info, lets the program run on.
+    pub fn step_to_next_line(
+        &mut self,
+        debug_data: &DwarfData,
+        watchdog: Option<Duration>,
+        sources: &mut SourceCache,
+    ) -> Result<(), nix::Error> {
+        let start_line = match self.current_line(debug_data) {
+            Some(line) => line,
+            None => {
+                println!("Error: not stopped at a known source line. Use stepi or continue.");
+                return Ok(());
+            }
+        };
+        let started = Instant::now();
+        let cpu_before = self.cpu_time();
+        loop {
+            let pc = Native::get_pc(self.pid())?;
+            let sp = Native::get_stack_pointer(self.pid())?;
+            self.set_break_points();
+            match self.step_instruction()? {
+                Status::Stopped(signal::SIGTRAP, _) => {}
+                status => {
+                    self.report_stop(Ok((status, false)), started, cpu_before, debug_data, sources);
+                    return Ok(());
+                }
+            }
+            if self.report_watchpoint(debug_data) {
+                break;
+            }
+            if let Some(ret) = Native::return_address_of_call(self.pid(), pc, sp)? {
+                if !self.finish_call(ret, sp, debug_data, watchdog, sources)? {
+                    return Ok(());
+                }
+            }
+            match self.current_line(debug_data) {
+                Some(line) if line.file == start_line.file && line.number == start_line.number => {}
+                Some(_) => break,
+                None => {
+                    self.continue_proc(debug_data, watchdog, sources);
+                    return Ok(());
+                }
+            }
+        }
+        let pc = self.debug_addr(Native::get_pc(self.pid())?);
+        if let Some(line) = debug_data.get_line_from_addr(pc) {
+            println!("Stopped at {}", line);
+            let breakpoint_lines = self.breakpoint_lines(debug_data, &line.file);
+            sources.print_context(&line, &breakpoint_lines);
+        }
         Ok(())
     }
 
-    pub fn step_to_next_line(&mut self, debug_data: &DwarfData) -> Result<(), nix::Error> {
-        let regs = ptrace::getregs(self.pid())?;
-        let current_rip = regs.rip as usize;
-        
-        if let Some(current_line) = debug_data.get_line_from_addr(current_rip) {
-            let next_line_num = current_line.number + 1;
-            if let Some(next_addr) = debug_data.get_addr_for_line(None, next_line_num) {
-                let orig_byte = self.write_byte(next_addr, 0xcc)?;
-                self.continue_proc(debug_data);
-                let _ = self.write_byte(next_addr, orig_byte);
+    /// Runs the function the inferior has just called until it returns to `ret`, with the stack
+    /// pointer back at `sp`, where it was before the call. Returns false if the inferior stopped
+    /// somewhere else first (at a breakpoint, on a signal, or by exiting), which is reported.
+    fn finish_call(
+        &mut self,
+        ret: usize,
+        sp: usize,
+        debug_data: &DwarfData,
+        watchdog: Option<Duration>,
+        sources: &mut SourceCache,
+    ) -> Result<bool, nix::Error> {
+        // A user breakpoint at the return address stops us there anyway, and is reported as such
+        let orig_bytes = match self.break_points.contains_key(&ret) {
+            true => None,
+            false => Some(self.write_bytes(ret, Native::BREAKPOINT)?),
+        };
+        let started = Instant::now();
+        let cpu_before = self.cpu_time();
+        let returned = loop {
+            let _ = ptrace::cont(self.pid(), None);
+            match self.wait_watched(watchdog) {
+                Ok((Status::Stopped(signal::SIGTRAP, rip), _))
+                    if orig_bytes.is_some()
+                        && rip.wrapping_sub(Native::PC_OFFSET_AFTER_TRAP) == ret =>
+                {
+                    Native::set_pc(self.pid(), ret)?;
+                    if Native::get_stack_pointer(self.pid())? == sp {
+                        break true;
+                    }
+                    // A deeper, recursive call of the same function returning to the same place:
+                    // step it past the temporary breakpoint and let it carry on
+                    self.write_bytes(ret, orig_bytes.as_ref().unwrap())?;
+                    ptrace::step(self.pid(), None)?;
+                    let status = self.wait(None)?;
+                    if let Status::Stopped(..) = status {
+                        self.write_bytes(ret, Native::BREAKPOINT)?;
+                    } else {
+                        let wait_result = Ok((status, false));
+                        self.report_stop(wait_result, started, cpu_before, debug_data, sources);
+                        break false;
+                    }
+                }
+                wait_result => {
+                    if let Some(orig_bytes) = &orig_bytes {
+                        let _ = self.write_bytes(ret, orig_bytes);
+                    }
+                    self.report_stop(wait_result, started, cpu_before, debug_data, sources);
+                    return Ok(false);
+                }
             }
+        };
+        if let Some(orig_bytes) = &orig_bytes {
+            // Fails harmlessly if the process has exited
+            let _ = self.write_bytes(ret, orig_bytes);
         }
+        Ok(returned)
+    }
 
-        Ok(())
+    /// Returns where `var` is in this process's memory. A local's location is relative to the
+    /// frame base of `function`, the function whose frame the inferior is stopped in; globals
+    /// don't need one.
+    pub fn variable_addr(
+        &self,
+        var: &Variable,
+        function: Option<&Function>,
+    ) -> Result<usize, nix::Error> {
+        let offset = match var.location {
+            Location::Address(addr) => return Ok(self.runtime_addr(addr)),
+            Location::FramePointerOffset(offset) => offset,
+        };
+        let frame_pointer = Native::get_frame_pointer(self.pid())?;
+        // The frame pointer points at the saved frame pointer, with the return address above it
+        let frame_base = match function.map(|func| func.frame_base) {
+            Some(FrameBase::FramePointer) => frame_pointer,
+            _ => frame_pointer + Native::RETURN_ADDRESS_OFFSET + size_of::<usize>(),
+        };
+        Ok((frame_base as isize + offset) as usize)
+    }
+
+    /// Reads `len` bytes of the inferior's memory starting at `addr`.
+    pub fn read_bytes(&self, addr: usize, len: usize) -> Result<Vec<u8>, nix::Error> {
+        let mut bytes = Vec::with_capacity(len);
+        let mut word_addr = align_addr_to_word(addr);
+        while word_addr < addr + len {
+            let word = ptrace::read(self.pid(), word_addr as ptrace::AddressType)? as usize;
+            for (i, byte) in word.to_ne_bytes().iter().enumerate() {
+                if (addr..addr + len).contains(&(word_addr + i)) {
+                    bytes.push(*byte);
+                }
+            }
+            word_addr += size_of::<usize>();
+        }
+        Ok(bytes)
+    }
+
+    /// Writes `vals` starting at `addr`, returning the bytes that were there before.
+    fn write_bytes(&mut self, addr: usize, vals: &[u8]) -> Result<Vec<u8>, nix::Error> {
+        let mut orig_bytes = Vec::with_capacity(vals.len());
+        for (i, val) in vals.iter().enumerate() {
+            orig_bytes.push(self.write_byte(addr + i, *val)?);
+        }
+        Ok(orig_bytes)
     }
 
     fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
@@ -1,8 +1,16 @@
+mod arch;
 mod debugger;
 mod debugger_command;
+mod display;
 mod inferior;
+mod memwatch;
 mod dwarf_data;
 mod gimli_wrapper;
+mod prebuild;
+mod pretty;
+mod source;
+mod watchdog;
+mod watchpoint;
 
 use crate::debugger::Debugger;
 use nix::sys::signal::{signal, SigHandler, Signal};
@@ -10,15 +18,38 @@ use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
-        std::process::exit(1);
-    }
-    let target = &args[1];
+    let built = match &args[1..] {
+        [target] => Ok(target.clone()),
+        [flag, command, target] if flag == "--build" => {
+            prebuild::run(command).map(|()| target.clone())
+        }
+        [flag, bin] if flag == "--cargo" => prebuild::cargo(bin),
+        _ => {
+            println!("Usage: {} <target program>", args[0]);
+            println!("       {} --build \"<build command>\" <target program>", args[0]);
+            println!("       {} --cargo <binary name>", args[0]);
+            std::process::exit(1);
+        }
+    };
+    let target = match built {
+        Ok(target) if args.len() == 2 => target,
+        Ok(target) => match prebuild::check_debug_info(&target) {
+            Ok(()) => target,
+            Err(status) => std::process::exit(status),
+        },
+        Err(status) => std::process::exit(status),
+    };
+    // A bare name would be looked up on PATH when the target is started, rather than in the
+    // current directory
+    let target = if target.contains('/') {
+        target
+    } else {
+        format!("./{}", target)
+    };
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    Debugger::new(&target).run();
 }
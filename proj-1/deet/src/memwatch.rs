@@ -0,0 +1,74 @@
+//! Memory growth reports. Each time the inferior stops, deet reads its resident set size (RSS) and
+//! virtual size (VSZ) from /proc/<pid>/status and prints how much they changed since the last
+//! stop, and warns when RSS grew by more than a threshold. Memory that keeps growing while you step
+//! through a loop is an early hint of a leak.
+//!
+//! ```text
+//! memwatch           show the current threshold
+//! memwatch 512       warn when RSS grows by more than 512 kB between stops
+//! memwatch off       stop reporting memory at each stop
+//! ```
+
+use nix::unistd::Pid;
+use std::fs;
+
+/// Warn about RSS growth above this many kB between stops, unless the user picks something else
+pub const DEFAULT_THRESHOLD_KB: u64 = 1024;
+
+#[derive(Clone, Copy)]
+pub struct MemoryUsage {
+    pub rss_kb: u64,
+    pub vsz_kb: u64,
+}
+
+impl MemoryUsage {
+    /// Reads the memory usage of `pid`. Returns None if it can't be read, e.g. because the process
+    /// has exited (a zombie's status has no Vm lines).
+    pub fn of(pid: Pid) -> Option<MemoryUsage> {
+        let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+        let field = |name: &str| -> Option<u64> {
+            let line = status.lines().find(|line| line.starts_with(name))?;
+            // e.g. "VmRSS:	    1234 kB"
+            line[name.len()..].trim().trim_end_matches("kB").trim().parse().ok()
+        };
+        Some(MemoryUsage {
+            rss_kb: field("VmRSS:")?,
+            vsz_kb: field("VmSize:")?,
+        })
+    }
+}
+
+fn signed_delta(now: u64, before: u64) -> String {
+    if now >= before {
+        format!("+{}", now - before)
+    } else {
+        format!("-{}", before - now)
+    }
+}
+
+/// Prints `now`, and how it changed since `before` (the previous stop), if there was one. Warns
+/// if RSS grew by more than `threshold_kb`.
+pub fn report(now: MemoryUsage, before: Option<MemoryUsage>, threshold_kb: u64) {
+    let before = match before {
+        Some(before) => before,
+        None => {
+            println!("Memory: RSS {} kB, VSZ {} kB", now.rss_kb, now.vsz_kb);
+            return;
+        }
+    };
+    println!(
+        "Memory: RSS {} kB ({} kB), VSZ {} kB ({} kB)",
+        now.rss_kb,
+        signed_delta(now.rss_kb, before.rss_kb),
+        now.vsz_kb,
+        signed_delta(now.vsz_kb, before.vsz_kb)
+    );
+    if now.rss_kb > before.rss_kb + threshold_kb {
+        println!(
+            "Warning: RSS grew by {} kB since the last stop (threshold {} kB). Is something \
+             allocating without freeing?",
+            now.rss_kb - before.rss_kb,
+            threshold_kb
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Building the target before debugging it, so that the edit-compile-debug loop is one command:
+//!
+//! ```text
+//! deet --build "gcc -g main.c -o main" main    run the command, then debug ./main
+//! deet --cargo myprog                          cargo build the myprog binary, then debug it
+//! ```
+//!
+//! If the build fails, deet exits with the build's status instead of debugging a stale binary.
+//! A target built without debug info is still debugged, at the assembly level, but deet points out
+//! that the build command is missing -g, since that's rarely on purpose.
+
+use crate::dwarf_data::DwarfData;
+use std::process::{Command, Stdio};
+
+/// Runs `command` with the shell, with its output going to the terminal.
+pub fn run(command: &str) -> Result<(), i32> {
+    println!("Building: {}", command);
+    let status = Command::new("sh").arg("-c").arg(command).status().map_err(|err| {
+        println!("Could not run the build command: {}", err);
+        1
+    })?;
+    if !status.success() {
+        println!("Build failed ({}); not starting the debugger", status);
+        return Err(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+/// Runs `cargo build --bin bin` and returns the path of the executable it built. Compiler
+/// diagnostics still go to the terminal.
+pub fn cargo(bin: &str) -> Result<String, i32> {
+    println!("Building: cargo build --bin {}", bin);
+    let output = Command::new("cargo")
+        .args(&["build", "--bin", bin, "--message-format=json-render-diagnostics"])
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| {
+            println!("Could not run cargo: {}", err);
+            1
+        })?;
+    if !output.status.success() {
+        println!("Build failed ({}); not starting the debugger", output.status);
+        return Err(output.status.code().unwrap_or(1));
+    }
+    // Each line of output is a JSON message. Only the artifact for the binary itself has a
+    // non-null "executable".
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(executable_path)
+        .last()
+        .ok_or_else(|| {
+            println!("cargo did not report an executable for {}", bin);
+            1
+        })
+}
+
+/// Pulls the "executable" path out of one line of cargo's JSON messages.
+fn executable_path(message: &str) -> Option<String> {
+    const KEY: &str = "\"executable\":\"";
+    let start = message.find(KEY)? + KEY.len();
+    let mut path = String::new();
+    let mut chars = message[start..].chars();
+    loop {
+        match chars.next()? {
+            '"' => return Some(path),
+            '\\' => path.push(chars.next()?),
+            c => path.push(c),
+        }
+    }
+}
+
+/// Makes sure the freshly built `target` exists, and warns if it has no debug info.
+pub fn check_debug_info(target: &str) -> Result<(), i32> {
+    match DwarfData::from_file(target) {
+        Ok(debug_data) if debug_data.has_debug_info() => Ok(()),
+        Ok(_) => {
+            println!(
+                "Warning: {} was built without debug info; add -g to the build command (or keep \
+                 debug = true in the cargo profile) to debug it at the source level",
+                target
+            );
+            Ok(())
+        }
+        Err(_) => {
+            println!("Could not load {} after building it; did the build produce it?", target);
+            Err(1)
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Renders values read out of the inferior's memory according to their DWARF types, in the same
+//! style as gdb: `{value = 3, next = 0x5555555592a0, name = "head"}`.
+
+use crate::dwarf_data::{DwarfData, TypeDef};
+use std::convert::TryInto;
+use std::mem::size_of;
+
+const DW_ATE_BOOLEAN: u8 = 0x02;
+const DW_ATE_FLOAT: u8 = 0x04;
+const DW_ATE_SIGNED: u8 = 0x05;
+const DW_ATE_SIGNED_CHAR: u8 = 0x06;
+const DW_ATE_UNSIGNED: u8 = 0x07;
+const DW_ATE_UNSIGNED_CHAR: u8 = 0x08;
+
+/// Returns the C spelling of a type, e.g. `struct node *`.
+pub fn type_name(debug_data: &DwarfData, type_offset: Option<usize>) -> String {
+    let offset = match type_offset {
+        Some(offset) => offset,
+        None => return "void".to_string(),
+    };
+    match debug_data.get_type(offset) {
+        Some(TypeDef::Base { name, .. }) | Some(TypeDef::Typedef { name, .. }) => name.clone(),
+        Some(TypeDef::Struct { name, .. }) => format!("struct {}", name),
+        Some(TypeDef::Pointer { target }) => format!("{} *", type_name(debug_data, *target)),
+        Some(TypeDef::Array { element, dims }) => {
+            let dims: String = dims.iter().map(|len| format!("[{}]", len)).collect();
+            format!("{}{}", type_name(debug_data, *element), dims)
+        }
+        Some(TypeDef::Qualified { qualifier, target }) => {
+            format!("{} {}", qualifier, type_name(debug_data, *target))
+        }
+        None => "<unknown type>".to_string(),
+    }
+}
+
+/// Renders `bytes` as a value of the given type. `bytes` should hold at least as many bytes as the
+/// type is large. Pointers are shown as addresses and not followed.
+pub fn format_value(debug_data: &DwarfData, type_offset: Option<usize>, bytes: &[u8]) -> String {
+    let def = match type_offset.and_then(|offset| debug_data.get_type(offset)) {
+        Some(def) => def,
+        None => return "<unknown type>".to_string(),
+    };
+    match def {
+        TypeDef::Base { size, encoding, .. } => match bytes.get(..*size) {
+            Some(bytes) => format_base(*encoding, bytes),
+            None => "<unavailable>".to_string(),
+        },
+        TypeDef::Pointer { .. } => match read_uint(bytes, size_of::<usize>()) {
+            Some(addr) => format!("{:#x}", addr),
+            None => "<unavailable>".to_string(),
+        },
+        TypeDef::Struct { members, .. } => {
+            let fields: Vec<String> = members
+                .iter()
+                .map(|member| {
+                    let value = match bytes.get(member.offset..) {
+                        Some(rest) => format_value(debug_data, member.type_offset, rest),
+                        None => "<unavailable>".to_string(),
+                    };
+                    format!("{} = {}", member.name, value)
+                })
+                .collect();
+            format!("{{{}}}", fields.join(", "))
+        }
+        TypeDef::Array { element, dims } => format_array(debug_data, *element, dims, bytes),
+        TypeDef::Typedef { target, .. } | TypeDef::Qualified { target, .. } => {
+            format_value(debug_data, *target, bytes)
+        }
+    }
+}
+
+fn format_array(
+    debug_data: &DwarfData,
+    element: Option<usize>,
+    dims: &[usize],
+    bytes: &[u8],
+) -> String {
+    let (len, inner_dims) = match dims.split_first() {
+        Some((len, inner_dims)) => (*len, inner_dims),
+        None => return "{}".to_string(),
+    };
+    let element_size = element
+        .and_then(|element| debug_data.get_type_size(element))
+        .unwrap_or(0);
+    let stride = element_size * inner_dims.iter().product::<usize>();
+    // Like gdb, show char arrays as strings, up to the first NUL
+    if inner_dims.is_empty() && is_char(debug_data, element) {
+        let chars = &bytes[..len.min(bytes.len())];
+        let end = chars.iter().position(|b| *b == 0).unwrap_or(chars.len());
+        return format!("{:?}", String::from_utf8_lossy(&chars[..end]));
+    }
+    let items: Vec<String> = (0..len)
+        .map(|i| match bytes.get(i * stride..) {
+            Some(rest) if inner_dims.is_empty() => format_value(debug_data, element, rest),
+            Some(rest) => format_array(debug_data, element, inner_dims, rest),
+            None => "<unavailable>".to_string(),
+        })
+        .collect();
+    format!("{{{}}}", items.join(", "))
+}
+
+/// Returns true for `char` and its signed/unsigned variants, looking through typedefs and
+/// qualifiers.
+fn is_char(debug_data: &DwarfData, type_offset: Option<usize>) -> bool {
+    match type_offset.and_then(|offset| debug_data.get_type(offset)) {
+        Some(TypeDef::Base { encoding, .. }) => {
+            *encoding == DW_ATE_SIGNED_CHAR || *encoding == DW_ATE_UNSIGNED_CHAR
+        }
+        Some(TypeDef::Typedef { target, .. }) | Some(TypeDef::Qualified { target, .. }) => {
+            is_char(debug_data, *target)
+        }
+        _ => false,
+    }
+}
+
+fn format_base(encoding: u8, bytes: &[u8]) -> String {
+    let size = bytes.len();
+    match encoding {
+        DW_ATE_BOOLEAN => (bytes.iter().any(|b| *b != 0)).to_string(),
+        DW_ATE_FLOAT if size == 4 => f32::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        DW_ATE_FLOAT if size == 8 => f64::from_ne_bytes(bytes.try_into().unwrap()).to_string(),
+        DW_ATE_SIGNED | DW_ATE_SIGNED_CHAR if (1..=8).contains(&size) => {
+            // Sign-extend from the value's own width
+            let shift = 64 - 8 * size as u32;
+            let value = ((read_uint(bytes, size).unwrap() << shift) as i64) >> shift;
+            if encoding == DW_ATE_SIGNED_CHAR {
+                format_char(value, bytes[0])
+            } else {
+                value.to_string()
+            }
+        }
+        DW_ATE_UNSIGNED | DW_ATE_UNSIGNED_CHAR if (1..=8).contains(&size) => {
+            let value = read_uint(bytes, size).unwrap();
+            if encoding == DW_ATE_UNSIGNED_CHAR {
+                format_char(value as i64, bytes[0])
+            } else {
+                value.to_string()
+            }
+        }
+        _ => {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}", hex)
+        }
+    }
+}
+
+/// Formats a char as gdb does, as its number followed by the character: `65 'A'`
+fn format_char(value: i64, byte: u8) -> String {
+    format!("{} {:?}", value, byte as char)
+}
+
+/// Reads a native-endian unsigned integer of `size` bytes from the start of `bytes`.
+fn read_uint(bytes: &[u8], size: usize) -> Option<u64> {
+    let bytes = bytes.get(..size)?;
+    let mut buf = [0_u8; 8];
+    if cfg!(target_endian = "little") {
+        buf[..size].copy_from_slice(bytes);
+    } else {
+        buf[8 - size..].copy_from_slice(bytes);
+    }
+    Some(u64::from_ne_bytes(buf))
+}
@@ -0,0 +1,67 @@
+//! Source listings around the line the inferior stopped at. Each stop prints a few lines either
+//! side of it, with markers for the current line and for lines that carry breakpoints:
+//!
+//! ```text
+//!       8      int total = 0;
+//!  *    9      for (int i = 0; i < n; i++) {
+//! =>   10          total += values[i];
+//!      11      }
+//! ```
+//!
+//! Source files are read once and kept, so stepping through a loop doesn't reread the file at
+//! every stop.
+
+use crate::dwarf_data::Line;
+use std::collections::HashMap;
+use std::fs;
+
+/// How many lines are shown on each side of the current line
+pub const CONTEXT_LINES: usize = 3;
+
+pub struct SourceCache {
+    /// The lines of each file read so far, or None if it couldn't be read
+    files: HashMap<String, Option<Vec<String>>>,
+}
+
+impl SourceCache {
+    pub fn new() -> SourceCache {
+        SourceCache {
+            files: HashMap::new(),
+        }
+    }
+
+    /// Returns the lines of `path`, reading it if it hasn't been read yet. Returns None if the file
+    /// can't be read (e.g. it was built somewhere else, or deleted since).
+    pub fn lines(&mut self, path: &str) -> Option<&[String]> {
+        self.files
+            .entry(path.to_string())
+            .or_insert_with(|| {
+                let text = fs::read_to_string(path).ok()?;
+                Some(text.lines().map(str::to_string).collect())
+            })
+            .as_deref()
+    }
+
+    /// Prints the lines around `line`, marking it with => and each line in `breakpoint_lines`
+    /// with *. Prints nothing if the source file can't be read.
+    pub fn print_context(&mut self, line: &Line, breakpoint_lines: &[usize]) {
+        let lines = match self.lines(&line.file) {
+            Some(lines) => lines,
+            None => return,
+        };
+        if line.number == 0 || line.number > lines.len() {
+            return;
+        }
+        let first = line.number.saturating_sub(CONTEXT_LINES).max(1);
+        let last = (line.number + CONTEXT_LINES).min(lines.len());
+        for number in first..=last {
+            let marker = match (number == line.number, breakpoint_lines.contains(&number)) {
+                (true, true) => "*>",
+                (true, false) => "=>",
+                (false, true) => " *",
+                (false, false) => "  ",
+            };
+            println!("{} {:>4}  {}", marker, number, lines[number - 1]);
+        }
+    }
+}
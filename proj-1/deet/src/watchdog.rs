@@ -0,0 +1,50 @@
+//! Hung-inferior watchdog. If the inferior runs for longer than a threshold after `run`,
+//! `continue` or `next` without stopping, deet prints a notice (while the inferior keeps running)
+//! suggesting Ctrl+C, which interrupts it wherever it is. When the interrupt comes after the notice,
+//! deet shows a backtrace straight away, so an infinite loop can be found without knowing how
+//! Ctrl+C and the debugger interact.
+//!
+//! ```text
+//! watchdog           show the current threshold
+//! watchdog 30        print the notice after 30 seconds without a stop
+//! watchdog off       never print the notice
+//! ```
+
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Print the notice after this long without a stop, unless the user picks something else
+pub const DEFAULT_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// How often the inferior is checked on until the notice has been printed
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Waits for `pid` to stop or exit, like waitpid, printing the notice if that takes longer than
+/// `threshold`. Returns the status and whether the notice was printed.
+pub fn wait(pid: Pid, threshold: Option<Duration>) -> Result<(WaitStatus, bool), nix::Error> {
+    let threshold = match threshold {
+        Some(threshold) => threshold,
+        None => return Ok((waitpid(pid, None)?, false)),
+    };
+    let started = Instant::now();
+    loop {
+        match waitpid(pid, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::StillAlive => {}
+            status => return Ok((status, false)),
+        }
+        let elapsed = started.elapsed();
+        if elapsed >= threshold {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL.min(threshold - elapsed));
+    }
+    println!(
+        "\nThe program has been running for {}s without stopping. If it is stuck (in an infinite \
+         loop, say), press Ctrl+C to interrupt it and see where it is.",
+        threshold.as_secs_f64()
+    );
+    // Nothing more to say until it stops, so there's no need to keep polling
+    Ok((waitpid(pid, None)?, true))
+}
@@ -0,0 +1,65 @@
+//! Hardware watchpoints: stop the inferior right after it writes to a variable or address, and
+//! show how the value changed. Memory corruption tends to be noticed far from where it happened;
+//! a watchpoint on the clobbered value stops at the write that did it.
+//!
+//! ```text
+//! watch counter      stop whenever counter is written
+//! watch 0x404028     stop whenever the word at that address is written
+//! watch              list the watchpoints
+//! unwatch 0          remove watchpoint 0
+//! ```
+//!
+//! Watchpoints use the CPU's debug registers, so there are only a few of them (four on x86), and
+//! each covers 1, 2, 4 or 8 bytes at an address aligned to that size. When the program is run
+//! again, watchpoints on globals and other addresses in the program are set again, wherever those
+//! are in the new process; watchpoints on locals are dropped, since their frames don't exist yet.
+//! Writes the kernel makes on the program's behalf (read() filling a buffer, say) don't set them
+//! off.
+
+use crate::dwarf_data::DwarfData;
+use crate::pretty;
+use std::convert::TryInto;
+use std::mem::size_of;
+
+pub struct Watchpoint {
+    /// What the user asked to watch, e.g. `counter` or `0x404028`
+    pub expr: String,
+    pub addr: usize,
+    pub len: usize,
+    /// The watched variable's type, for showing its values; None for a bare address
+    pub type_offset: Option<usize>,
+    /// The value when it was last reported, to compare the next one with
+    pub value: Vec<u8>,
+}
+
+/// A watchpoint as the user set it, which outlives the process it was set in so that it can be set
+/// again when the program is run again.
+pub struct Spec {
+    pub expr: String,
+    /// In the process the watchpoint was last set in
+    pub addr: usize,
+    pub len: usize,
+    pub type_offset: Option<usize>,
+    /// Whether it watches a local variable, whose address only means something while its frame
+    /// lasts
+    pub local: bool,
+}
+
+/// Whether a watchpoint can cover `len` bytes at `addr`.
+pub fn is_watchable(addr: usize, len: usize) -> bool {
+    matches!(len, 1 | 2 | 4 | 8) && len <= size_of::<usize>() && addr % len == 0
+}
+
+impl Watchpoint {
+    /// Renders `bytes`, a value of the watched memory: by its type if it's a variable, or else as
+    /// a hex number.
+    pub fn format(&self, debug_data: &DwarfData, bytes: &[u8]) -> String {
+        if self.type_offset.is_some() {
+            return pretty::format_value(debug_data, self.type_offset, bytes);
+        }
+        // Only x86, which is little-endian, has watchpoints
+        let mut word = [0_u8; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        format!("{:#x}", u64::from_le_bytes(word.try_into().unwrap()))
+    }
+}
@@ -0,0 +1,161 @@
+//! Runs deet on programs generated by deet-fixtures, feeding it commands on stdin and checking
+//! what it prints. These need a C compiler and permission to ptrace, which containers often don't
+//! grant; where either is missing, each test says so and passes without checking anything.
+
+use nix::sys::ptrace;
+use nix::sys::signal::Signal;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::Pid;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Whether the fixtures can be built and debugged here, printing why not if they can't: there has
+/// to be a C compiler ($CC, or cc) for deet-fixtures, and we have to be allowed to trace a child
+/// process the way deet does.
+fn can_run_fixtures() -> bool {
+    let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let has_cc = Command::new(&cc)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !has_cc {
+        eprintln!("Skipping: no C compiler ({}) to build the fixtures with", cc);
+        return false;
+    }
+    let mut cmd = Command::new("true");
+    unsafe {
+        cmd.pre_exec(|| {
+            ptrace::traceme().map_err(|_| std::io::Error::from(std::io::ErrorKind::Other))
+        });
+    }
+    let traced = match cmd.spawn() {
+        Ok(mut child) => {
+            let stopped = waitpid(Pid::from_raw(child.id() as i32), None);
+            let _ = child.kill();
+            let _ = child.wait();
+            matches!(stopped, Ok(WaitStatus::Stopped(_, Signal::SIGTRAP)))
+        }
+        Err(_) => false,
+    };
+    if !traced {
+        eprintln!("Skipping: not allowed to ptrace child processes here");
+    }
+    traced
+}
+
+/// A scratch directory of its own for each test, so that tests running at once don't collide
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("deet-test-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Generates a fixture at `output` with deet-fixtures' `args`.
+fn generate(output: &Path, args: &[&str]) {
+    let status = Command::new(env!("CARGO_BIN_EXE_deet-fixtures"))
+        .args(args)
+        .arg("-o")
+        .arg(output)
+        .status()
+        .expect("Could not run deet-fixtures");
+    assert!(status.success(), "deet-fixtures failed ({})", status);
+}
+
+/// Runs deet on `target`, typing in `commands`, and returns everything it printed.
+fn run_deet(target: &Path, commands: &[&str], home: &Path) -> String {
+    let mut deet = Command::new(env!("CARGO_BIN_EXE_deet"))
+        .arg(target)
+        // deet keeps its command history in $HOME
+        .env("HOME", home)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Could not run deet");
+    let mut stdin = deet.stdin.take().unwrap();
+    for command in commands {
+        writeln!(stdin, "{}", command).unwrap();
+    }
+    drop(stdin);
+    let output = deet.wait_with_output().unwrap();
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+/// A breakpoint on the recursive function stops at its first line, and once it's deleted the
+/// program runs on to the segfault, where the backtrace folds the recursion into one line.
+#[test]
+fn test_break_and_backtrace_on_recursion() {
+    if !can_run_fixtures() {
+        return;
+    }
+    let dir = scratch_dir("recursion");
+    let target = dir.join("recurse_12_segfault");
+    generate(&target, &["--depth", "12", "--segfault"]);
+    let source = target.with_extension("c");
+
+    let output = run_deet(
+        &target,
+        &["break recurse", "run", "delete 0", "continue", "back", "quit"],
+        &dir,
+    );
+    assert!(output.contains("Set breakpoint 0 at"), "{}", output);
+    assert!(
+        output.contains(&format!("Stopped at {}:5", source.display())),
+        "{}",
+        output
+    );
+    assert!(output.contains("Child stopped (signal SIGSEGV)"), "{}", output);
+
+    let backtrace: Vec<&str> = output
+        .lines()
+        .skip_while(|line| !line.starts_with("recurse ("))
+        .take(4)
+        .collect();
+    assert_eq!(
+        backtrace,
+        vec![
+            format!("recurse ({}:8)", source.display()),
+            format!("recurse ({}:11)", source.display()),
+            "  … frame repeated 10 more times (recursion depth 11) …".to_string(),
+            format!("main ({}:15)", source.display()),
+        ],
+        "{}",
+        output
+    );
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+/// `stepi` stops on a breakpoint it reaches instead of stepping past it, and counts the hit.
+#[test]
+fn test_stepi_stops_at_breakpoint() {
+    if !can_run_fixtures() {
+        return;
+    }
+    let dir = scratch_dir("stepi");
+    let target = dir.join("recurse_3");
+    generate(&target, &["--depth", "3"]);
+    let source = target.with_extension("c");
+
+    let output = run_deet(
+        &target,
+        &["break main", "break recurse", "run", "stepi 100", "info breakpoints", "quit"],
+        &dir,
+    );
+    assert!(
+        output.contains(&format!("Stopped at {}:5 (0x", source.display())),
+        "{}",
+        output
+    );
+    let recurse_row = output
+        .lines()
+        .find(|line| line.trim_start().starts_with("1  y") && line.contains("recurse at"))
+        .unwrap_or_else(|| panic!("no row for breakpoint 1:\n{}", output));
+    assert_eq!(recurse_row.split_whitespace().nth(3), Some("1"), "{}", output);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
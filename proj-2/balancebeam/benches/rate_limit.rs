@@ -0,0 +1,88 @@
+//! Benchmarks for the rate limiter's per-client state under contention. Run with `cargo bench`.
+//!
+//! Several threads count requests from their own clients at once, against the sharded map and
+//! against one map behind one lock (the previous implementation). The difference only shows on a
+//! machine with several cores; with one, the threads never actually run at the same time.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[allow(dead_code)]
+#[path = "../src/sliding_window.rs"]
+mod sliding_window;
+
+const THREADS: usize = 8;
+const CLIENTS_PER_THREAD: usize = 256;
+const WINDOW: Duration = Duration::from_secs(60);
+/// Clients soon hit this, so after warming up most hits are refused rather than recorded, and the
+/// deques stay small
+const LIMIT: usize = 100;
+
+/// The previous implementation: one map behind one lock.
+fn single_lock_hit(map: &Mutex<HashMap<String, VecDeque<Instant>>>, key: String, now: Instant) {
+    let cutoff = now - WINDOW;
+    let mut map = map.lock().unwrap();
+    let deque = map.entry(key).or_default();
+    while matches!(deque.front(), Some(ts) if *ts < cutoff) {
+        deque.pop_front();
+    }
+    if deque.len() < LIMIT {
+        deque.push_back(now);
+    }
+}
+
+/// Runs `hit` for `iters` requests on each of THREADS threads at once, and returns how long the
+/// slowest thread took.
+fn contended<F>(iters: u64, hit: F) -> Duration
+where
+    F: Fn(String, Instant) + Send + Sync + 'static,
+{
+    let hit = Arc::new(hit);
+    let barrier = Arc::new(Barrier::new(THREADS + 1));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|t| {
+            let hit = Arc::clone(&hit);
+            let barrier = Arc::clone(&barrier);
+            let keys: Vec<String> = (0..CLIENTS_PER_THREAD)
+                .map(|c| format!("10.{}.{}.{}", t, c / 256, c % 256))
+                .collect();
+            thread::spawn(move || {
+                barrier.wait();
+                let started = Instant::now();
+                for i in 0..iters as usize {
+                    hit(keys[i % keys.len()].clone(), Instant::now());
+                }
+                started.elapsed()
+            })
+        })
+        .collect();
+    barrier.wait();
+    handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .max()
+        .unwrap()
+}
+
+fn rate_limit_benchmarks(c: &mut Criterion) {
+    c.bench_function("rate limit, single lock", |b| {
+        b.iter_custom(|iters| {
+            let map = Mutex::new(HashMap::new());
+            contended(iters, move |key, now| single_lock_hit(&map, key, now))
+        })
+    });
+    c.bench_function("rate limit, sharded", |b| {
+        b.iter_custom(|iters| {
+            let windows = sliding_window::SlidingWindows::new(0);
+            contended(iters, move |key, now| {
+                windows.hit(key, LIMIT, WINDOW, now);
+            })
+        })
+    });
+}
+
+criterion_group!(benches, rate_limit_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,119 @@
+//! Benchmarks for writing requests and responses to a socket. Run with `cargo bench`.
+//!
+//! balancebeam is a binary crate, so the modules under test are pulled in by path.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::runtime::Runtime;
+
+#[allow(dead_code)]
+#[path = "../src/buffer_pool.rs"]
+mod buffer_pool;
+#[allow(dead_code)]
+#[path = "../src/chunked.rs"]
+mod chunked;
+#[allow(dead_code)]
+#[path = "../src/error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../src/request.rs"]
+mod request;
+#[allow(dead_code)]
+#[path = "../src/response.rs"]
+mod response;
+#[allow(dead_code)]
+#[path = "../src/throttle.rs"]
+mod throttle;
+
+fn sample_request() -> http::Request<Vec<u8>> {
+    let mut builder = http::Request::builder()
+        .method(http::Method::POST)
+        .uri("/api/v1/items?page=3");
+    for i in 0..16 {
+        builder = builder.header(format!("x-bench-header-{}", i), "some moderately long value");
+    }
+    builder
+        .header("content-length", "512")
+        .body(vec![b'a'; 512])
+        .unwrap()
+}
+
+fn sample_response() -> http::Response<Vec<u8>> {
+    let mut builder = http::Response::builder().status(http::StatusCode::OK);
+    for i in 0..16 {
+        builder = builder.header(format!("x-bench-header-{}", i), "some moderately long value");
+    }
+    builder
+        .header("content-length", "512")
+        .body(vec![b'a'; 512])
+        .unwrap()
+}
+
+/// The previous implementation, which issued one write per request line fragment and header.
+async fn naive_write_request(request: &http::Request<Vec<u8>>, stream: &mut TcpStream) {
+    stream
+        .write_all(request::format_request_line(request).as_bytes())
+        .await
+        .unwrap();
+    stream.write_all(b"\r\n").await.unwrap();
+    for (header_name, header_value) in request.headers() {
+        stream
+            .write_all(format!("{}: ", header_name).as_bytes())
+            .await
+            .unwrap();
+        stream.write_all(header_value.as_bytes()).await.unwrap();
+        stream.write_all(b"\r\n").await.unwrap();
+    }
+    stream.write_all(b"\r\n").await.unwrap();
+    stream.write_all(request.body()).await.unwrap();
+}
+
+/// Opens a loopback connection whose far end discards everything written to it.
+async fn discarding_connection() -> TcpStream {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0_u8; 64 * 1024];
+        while let Ok(n) = conn.read(&mut buf).await {
+            if n == 0 {
+                return;
+            }
+        }
+    });
+    TcpStream::connect(address).await.unwrap()
+}
+
+fn serialization_benchmarks(c: &mut Criterion) {
+    let request = sample_request();
+    let response = sample_response();
+
+    c.bench_function("request::serialize_head", |b| {
+        b.iter(|| {
+            let mut head = buffer_pool::take();
+            request::serialize_head(&request, &mut head);
+        })
+    });
+    c.bench_function("response::serialize_head", |b| {
+        b.iter(|| {
+            let mut head = buffer_pool::take();
+            response::serialize_head(&response, &mut head);
+        })
+    });
+
+    let rt = Runtime::new().unwrap();
+    let mut conn = rt.block_on(discarding_connection());
+    c.bench_function("request::write_to_stream", |b| {
+        b.iter(|| rt.block_on(request::write_to_stream(&request, &mut conn)).unwrap())
+    });
+    c.bench_function("request naive per-header writes", |b| {
+        b.iter(|| rt.block_on(naive_write_request(&request, &mut conn)))
+    });
+    c.bench_function("response::write_to_stream", |b| {
+        b.iter(|| rt.block_on(response::write_to_stream(&response, &mut conn)).unwrap())
+    });
+}
+
+criterion_group!(benches, serialization_benchmarks);
+criterion_main!(benches);
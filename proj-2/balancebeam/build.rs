@@ -0,0 +1,87 @@
+//! Records the git commit and build time so that a running balancebeam can report exactly what
+//! it was built from (see src/build_info.rs).
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Runs git with `args`, returning its output, or None if it fails (e.g. outside a checkout).
+fn git(args: &[&str]) -> Option<String> {
+    Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Asks Cargo to rerun this script whenever the checked-out commit changes: when HEAD moves to
+/// another branch, or the branch it's on moves, which may update the loose ref or packed-refs.
+/// Files that don't exist are left out, since Cargo would rerun the script on every build for them.
+fn rerun_if_head_changes() {
+    let head = match git(&["rev-parse", "--git-path", "HEAD"]) {
+        Some(head) => head,
+        None => return,
+    };
+    let mut watched = vec![head.clone()];
+    if let Some(branch) = std::fs::read_to_string(&head)
+        .ok()
+        .and_then(|contents| contents.strip_prefix("ref:").map(|r| r.trim().to_string()))
+    {
+        watched.extend(git(&["rev-parse", "--git-path", &branch]));
+    }
+    watched.extend(git(&["rev-parse", "--git-path", "packed-refs"]));
+    for path in watched {
+        if Path::new(&path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn main() {
+    let commit = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=BALANCEBEAM_GIT_COMMIT={}", commit);
+    rerun_if_head_changes();
+
+    // Honor SOURCE_DATE_EPOCH so that reproducible builds stay reproducible
+    let build_time = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0)
+        });
+    println!(
+        "cargo:rustc-env=BALANCEBEAM_BUILD_TIME={}",
+        format_utc(build_time)
+    );
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
+
+/// Formats a Unix timestamp as an RFC 3339 UTC time, e.g. 2024-01-31T12:00:00Z.
+fn format_utc(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+    // Civil-from-days, from Howard Hinnant's date algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
@@ -0,0 +1,154 @@
+//! Access logging to a file. Every request gets one line, in a format close to the common log
+//! format, with the upstream that served it and how long it took added on the end:
+//!
+//! ```text
+//! 10.0.0.7 - [1700000000.123] "GET /api/items HTTP/1.1" 200 5120 "127.0.0.1:8080" 12ms
+//! ```
+//!
+//! Lines are handed to a single writer task over a channel, so connection tasks never wait on the
+//! disk and lines from different connections can't interleave. The writer also does the rotation:
+//! when the file would grow past --access-log-max-size, or has been open for
+//! --access-log-rotate-interval, it is renamed to FILE.1 (FILE.1 to FILE.2, and so on, dropping the
+//! oldest beyond --access-log-keep) and a new FILE is started. Lines that arrive meanwhile wait in
+//! the channel, so none are lost.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+pub struct Config {
+    pub path: String,
+    /// Rotate once the file would grow past this many bytes (0 = never)
+    pub max_size: u64,
+    /// Rotate after the file has been open this long
+    pub rotate_interval: Option<Duration>,
+    /// Number of rotated files to keep
+    pub keep: usize,
+}
+
+/// What gets logged about one request
+pub struct Entry<'a> {
+    pub client_ip: &'a str,
+    pub request_line: String,
+    pub status: http::StatusCode,
+    /// Response body bytes sent to the client
+    pub bytes: usize,
+    /// The upstream that answered, or None if balancebeam answered itself
+    pub upstream: Option<&'a str>,
+    /// When the request was read from the client
+    pub started: Instant,
+}
+
+/// Handle for sending lines to the writer task
+pub struct AccessLog {
+    lines: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    /// Opens the log file and starts the writer task. Must be called from within the runtime.
+    pub async fn start(config: Config) -> Result<AccessLog, String> {
+        let file = open(&config.path)
+            .await
+            .map_err(|err| format!("could not open {}: {}", config.path, err))?;
+        let (lines, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_lines(config, file, receiver));
+        Ok(AccessLog { lines })
+    }
+
+    pub fn record(&self, entry: Entry) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{} - [{}.{:03}] \"{}\" {} {} \"{}\" {}ms\n",
+            entry.client_ip,
+            now.as_secs(),
+            now.subsec_millis(),
+            entry.request_line.escape_default(),
+            entry.status.as_str(),
+            entry.bytes,
+            entry.upstream.unwrap_or("-"),
+            entry.started.elapsed().as_millis()
+        );
+        // This only fails if the writer task has died, in which case it has already logged why
+        let _ = self.lines.send(line);
+    }
+}
+
+async fn open(path: &str) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path).await
+}
+
+/// Writes lines to the log file in the order they were sent, rotating the file as configured.
+async fn write_lines(config: Config, mut file: File, mut lines: mpsc::UnboundedReceiver<String>) {
+    let mut size = match file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    let mut opened = tokio::time::Instant::now();
+    loop {
+        let rotate_at = config.rotate_interval.map(|interval| opened + interval);
+        let line = tokio::select! {
+            line = lines.recv() => match line {
+                Some(line) => Some(line),
+                None => return,
+            },
+            _ = tokio::time::sleep_until(rotate_at.unwrap_or(opened)), if rotate_at.is_some() => {
+                None
+            }
+        };
+        let rotate_now = match &line {
+            Some(line) => {
+                config.max_size > 0 && size > 0 && size + line.len() as u64 > config.max_size
+            }
+            // An interval rotation of an empty file would only shuffle empty files around
+            None => size > 0,
+        };
+        if rotate_now {
+            file = match rotate(&config, file).await {
+                Ok(file) => file,
+                Err(err) => {
+                    log::error!("Could not rotate access log {}: {}", config.path, err);
+                    return;
+                }
+            };
+            size = 0;
+        }
+        if rotate_now || line.is_none() {
+            opened = tokio::time::Instant::now();
+        }
+        if let Some(line) = line {
+            if let Err(err) = write(&mut file, &line).await {
+                log::error!("Could not write to access log {}: {}", config.path, err);
+                continue;
+            }
+            size += line.len() as u64;
+        }
+    }
+}
+
+async fn write(file: &mut File, line: &str) -> std::io::Result<()> {
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await
+}
+
+/// Shifts FILE.N to FILE.N+1 (dropping whatever falls off the end), moves the current file to
+/// FILE.1, and opens a fresh FILE.
+async fn rotate(config: &Config, mut file: File) -> std::io::Result<File> {
+    file.flush().await?;
+    drop(file);
+    let rotated = |n: usize| format!("{}.{}", config.path, n);
+    if config.keep == 0 {
+        tokio::fs::remove_file(&config.path).await?;
+    } else {
+        for n in (1..config.keep).rev() {
+            match tokio::fs::rename(rotated(n), rotated(n + 1)).await {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        tokio::fs::rename(&config.path, rotated(1)).await?;
+    }
+    open(&config.path).await
+}
@@ -0,0 +1,196 @@
+//! Byte accounting for chargeback. Request and response body bytes are tallied per upstream, per
+//! route (the longest matching --accounting-route prefix) and per API key (the value of the
+//! --accounting-api-key-header request header, which a forward auth service can set), and every
+//! --accounting-interval the tallies are appended to the --accounting-file as JSON lines, one
+//! record per upstream/route/key that saw traffic during the period:
+//!
+//! ```text
+//! {"period_start":1700000000,"period_end":1700000060,"dimension":"route","key":"/api","requests":12,"bytes_in":3400,"bytes_out":91000}
+//! ```
+//!
+//! Tallies since the last flush are lost if the process is killed.
+
+use crate::ProxyState;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tokio::time::sleep;
+
+/// What a tally is broken down by
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Dimension {
+    Upstream,
+    Route,
+    ApiKey,
+}
+
+impl Dimension {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Dimension::Upstream => "upstream",
+            Dimension::Route => "route",
+            Dimension::ApiKey => "api_key",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Usage {
+    requests: u64,
+    /// Body bytes received from clients
+    bytes_in: u64,
+    /// Body bytes sent back to clients
+    bytes_out: u64,
+}
+
+/// The route and API key a request is billed to, besides its upstream
+#[derive(Default)]
+pub struct Labels {
+    route: Option<String>,
+    api_key: Option<String>,
+}
+
+/// Accumulates usage between flushes
+pub struct Ledger {
+    path: String,
+    interval: Duration,
+    /// Route prefixes that usage is broken down by
+    routes: Vec<String>,
+    /// Request header holding the API key that usage is broken down by
+    api_key_header: Option<http::header::HeaderName>,
+    usage: Mutex<HashMap<(Dimension, String), Usage>>,
+}
+
+impl Ledger {
+    pub fn new(
+        path: String,
+        interval: Duration,
+        routes: Vec<String>,
+        api_key_header: Option<http::header::HeaderName>,
+    ) -> Ledger {
+        Ledger {
+            path,
+            interval,
+            routes,
+            api_key_header,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Works out which route and API key `request` should be billed to.
+    pub fn labels<T>(&self, request: &http::Request<T>) -> Labels {
+        let path = request.uri().path();
+        let route = self
+            .routes
+            .iter()
+            .filter(|prefix| path.starts_with(prefix.as_str()))
+            .max_by_key(|prefix| prefix.len())
+            .cloned();
+        let api_key = self
+            .api_key_header
+            .as_ref()
+            .and_then(|name| request.headers().get(name))
+            .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned());
+        Labels { route, api_key }
+    }
+
+    /// Records one proxied exchange (or tunnel) against its upstream, route and API key.
+    pub fn record(&self, upstream: &str, labels: &Labels, bytes_in: usize, bytes_out: usize) {
+        let mut usage = self.usage.lock().unwrap();
+        let mut add = |dimension: Dimension, key: &str| {
+            let entry = usage.entry((dimension, key.to_string())).or_default();
+            entry.requests += 1;
+            entry.bytes_in += bytes_in as u64;
+            entry.bytes_out += bytes_out as u64;
+        };
+        add(Dimension::Upstream, upstream);
+        if let Some(route) = &labels.route {
+            add(Dimension::Route, route);
+        }
+        if let Some(api_key) = &labels.api_key {
+            add(Dimension::ApiKey, api_key);
+        }
+    }
+}
+
+/// Appends the accumulated usage to the accounting file every interval, until the process exits.
+pub async fn flush_periodically(state: Arc<ProxyState>) {
+    let ledger = match &state.accounting {
+        Some(ledger) => ledger,
+        None => return,
+    };
+    let mut period_start = unix_time();
+    loop {
+        sleep(ledger.interval).await;
+        let period_end = unix_time();
+        let usage = std::mem::take(&mut *ledger.usage.lock().unwrap());
+        if usage.is_empty() {
+            period_start = period_end;
+            continue;
+        }
+
+        let mut records: Vec<_> = usage.into_iter().collect();
+        records.sort_by(|(a, _), (b, _)| (a.0.as_str(), &a.1).cmp(&(b.0.as_str(), &b.1)));
+        let mut out = String::new();
+        for ((dimension, key), usage) in records {
+            let _ = writeln!(
+                out,
+                "{{\"period_start\":{},\"period_end\":{},\"dimension\":\"{}\",\"key\":{},\
+                 \"requests\":{},\"bytes_in\":{},\"bytes_out\":{}}}",
+                period_start,
+                period_end,
+                dimension.as_str(),
+                json_string(&key),
+                usage.requests,
+                usage.bytes_in,
+                usage.bytes_out
+            );
+        }
+        if let Err(err) = append(&ledger.path, out.as_bytes()).await {
+            log::error!(
+                "Could not write accounting records to {}: {}",
+                ledger.path,
+                err
+            );
+        }
+        period_start = period_end;
+    }
+}
+
+async fn append(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(data).await?;
+    file.flush().await
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+/// Quotes `s` as a JSON string. API keys come straight from client headers, so they may contain
+/// anything.
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,198 @@
+//! The admin listener serves operational endpoints (metrics and the like) on a separate address
+//! from the proxied traffic, so that it can be firewalled off from clients.
+
+use crate::{health_report, probes, request, response, route_test, upstreams, ProxyState};
+use std::fmt::Write;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Accepts connections on the admin listener until the process exits.
+pub async fn serve(listener: TcpListener, state: Arc<ProxyState>) {
+    serve_with(listener, state, false).await;
+}
+
+/// Accepts connections on the --probe-bind listener, which only serves /healthz and /readyz,
+/// until the process exits.
+pub async fn serve_probes(listener: TcpListener, state: Arc<ProxyState>) {
+    serve_with(listener, state, true).await;
+}
+
+async fn serve_with(listener: TcpListener, state: Arc<ProxyState>, probes_only: bool) {
+    loop {
+        let (stream, _addr) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(err) => {
+                log::error!("admin accept error: {}", err);
+                continue;
+            }
+        };
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            handle_connection(stream, state, probes_only).await;
+        });
+    }
+}
+
+async fn handle_connection(mut conn: TcpStream, state: Arc<ProxyState>, probes_only: bool) {
+    let mut leftover = Vec::new();
+    let limits = &state.request_limits;
+    loop {
+        let request = match request::read_from_stream(&mut conn, &mut leftover, limits).await {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let response = if probes_only {
+            handle_probe(&request, &state)
+        } else {
+            handle_request(&request, &state).await
+        };
+        log::debug!(
+            "admin: {} -> {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(err) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", err);
+            return;
+        }
+    }
+}
+
+fn handle_probe(request: &http::Request<Vec<u8>>, state: &ProxyState) -> http::Response<Vec<u8>> {
+    match probes::respond(state, request.uri().path()) {
+        Some(response) if request.method() == http::Method::GET => response,
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+async fn handle_request(
+    request: &http::Request<Vec<u8>>,
+    state: &Arc<ProxyState>,
+) -> http::Response<Vec<u8>> {
+    match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/healthz" | "/readyz") => handle_probe(request, state),
+        (&http::Method::GET, "/metrics") => response::make_response(
+            http::StatusCode::OK,
+            "text/plain; version=0.0.4",
+            render_metrics(state).into_bytes(),
+        ),
+        (&http::Method::GET, "/info") => response::make_response(
+            http::StatusCode::OK,
+            "application/json",
+            state.build_info.to_json().into_bytes(),
+        ),
+        (&http::Method::GET, "/maintenance") => {
+            let mode = if state.maintenance.is_on() { "on\n" } else { "off\n" };
+            response::make_response(http::StatusCode::OK, "text/plain", mode.into())
+        }
+        (&http::Method::POST, "/maintenance/on") => {
+            state.maintenance.set(true);
+            response::make_response(http::StatusCode::OK, "text/plain", Vec::new())
+        }
+        (&http::Method::POST, "/maintenance/off") => {
+            state.maintenance.set(false);
+            response::make_response(http::StatusCode::OK, "text/plain", Vec::new())
+        }
+        (&http::Method::GET, "/capture") => {
+            let mode = if state.capture.is_on() { "on\n" } else { "off\n" };
+            response::make_response(http::StatusCode::OK, "text/plain", mode.into())
+        }
+        (&http::Method::POST, "/capture/on") => {
+            state.capture.set(true);
+            response::make_response(http::StatusCode::OK, "text/plain", Vec::new())
+        }
+        (&http::Method::POST, "/capture/off") => {
+            state.capture.set(false);
+            response::make_response(http::StatusCode::OK, "text/plain", Vec::new())
+        }
+        (&http::Method::GET, "/upstreams") => {
+            let mut pool = state.default_pool().join("\n");
+            pool.push('\n');
+            response::make_response(http::StatusCode::OK, "text/plain", pool.into_bytes())
+        }
+        (&http::Method::GET, "/upstreams/health") => response::make_response(
+            http::StatusCode::OK,
+            "application/json",
+            health_report::render(state).into_bytes(),
+        ),
+        (&http::Method::GET, "/upstreams/groups") => response::make_response(
+            http::StatusCode::OK,
+            "text/plain",
+            render_groups(state).await.into_bytes(),
+        ),
+        (&http::Method::POST, "/upstreams/switch") => match upstream_address(request) {
+            Some(name) => match upstreams::switch(state, &name).await {
+                Ok(()) => response::make_response(http::StatusCode::OK, "text/plain", Vec::new()),
+                Err(upstreams::SwitchError::UnknownGroup) => {
+                    response::make_http_error(http::StatusCode::NOT_FOUND)
+                }
+                Err(upstreams::SwitchError::GroupDown) => {
+                    response::make_http_error(http::StatusCode::CONFLICT)
+                }
+            },
+            None => response::make_http_error(http::StatusCode::BAD_REQUEST),
+        },
+        (&http::Method::POST, "/upstreams/add") => match upstream_address(request) {
+            Some(address) if !upstreams::is_valid_address(&address) => {
+                response::make_http_error(http::StatusCode::BAD_REQUEST)
+            }
+            Some(address) if upstreams::add(state, &address).await => {
+                response::make_response(http::StatusCode::OK, "text/plain", Vec::new())
+            }
+            Some(_) => response::make_http_error(http::StatusCode::CONFLICT),
+            None => response::make_http_error(http::StatusCode::BAD_REQUEST),
+        },
+        (&http::Method::POST, "/upstreams/remove") => match upstream_address(request) {
+            Some(address) if upstreams::remove(state, &address).await => {
+                response::make_response(http::StatusCode::OK, "text/plain", Vec::new())
+            }
+            Some(_) => response::make_http_error(http::StatusCode::NOT_FOUND),
+            None => response::make_http_error(http::StatusCode::BAD_REQUEST),
+        },
+        (&http::Method::POST, "/route-test") => route_test::respond(state, request).await,
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// The upstream address (or group name) in the body of a POST /upstreams/* request, if there is one
+fn upstream_address(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let address = std::str::from_utf8(request.body()).ok()?.trim();
+    if address.is_empty() || address.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(address.to_string())
+}
+
+/// One line per --upstream-group, like `green (active): 10.0.0.7:8080=up 10.0.0.8:8080=down`
+async fn render_groups(state: &ProxyState) -> String {
+    let live = state.liveing_upstreams.load();
+    let groups = state.upstream_groups.read().unwrap();
+    let mut out = String::new();
+    for (index, group) in groups.groups.iter().enumerate() {
+        let active = if index == groups.active { " (active)" } else { "" };
+        let _ = write!(out, "{}{}:", group.name, active);
+        for address in &group.upstreams {
+            let health = if live.contains(address) { "up" } else { "down" };
+            let _ = write!(out, " {}={}", address, health);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+pub fn render_metrics(state: &ProxyState) -> String {
+    let mut out = String::new();
+    for address in state.upstream_addresses.read().unwrap().iter() {
+        let upstream = state.upstreams.get(address);
+        upstream.stats.render(address, &mut out);
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_ejected{{upstream=\"{}\"}} {}",
+            address,
+            upstream.outlier.is_ejected() as u8
+        );
+    }
+    state.retry_budget.render(&mut out);
+    state.load_shedder.render(&mut out);
+    out
+}
@@ -0,0 +1,285 @@
+//! Balancing strategies, i.e. how an upstream is picked among the candidates. Each strategy is a
+//! LoadBalancer; connect_to_upstream works out which upstreams are candidates (live, with a free
+//! connection slot, and not ejected) and leaves the choice between them to the strategy, so a new
+//! strategy only needs a LoadBalancer implementation and a --balance value. Besides picking, a
+//! strategy hears about what became of its picks, so that it can go by more than the candidates:
+//!
+//! * every upstream connection it led to, from connection_opened to connection_closed (a Lease
+//!   covers the span in between), for strategies that go by the connections each upstream has;
+//! * the outcome of every exchange with an upstream (report_result), whether a response with its
+//!   status and response time or a failure, for strategies that go by speed or errors.
+//!
+//! * `random` (the default) picks at random for each client connection. --rng-seed makes the
+//!   sequence of picks reproducible, for tests.
+//! * `round-robin` takes the candidates in turn, one client connection each.
+//! * `path-hash` sends each HTTP/1 request to the upstream that its path hashes to, so that
+//!   requests for the same resource keep hitting the same upstream and its cache. A connection
+//!   whose next request hashes elsewhere is moved to the new upstream. HTTP/2 connections and
+//!   tcp/tls-passthrough listeners have no path to go by, and pick at random per connection.
+//! * `least-response-time` picks the upstream with the lowest moving average response time, so
+//!   that slower upstreams get less traffic when the upstreams aren't all alike. Upstreams that
+//!   haven't answered anything yet are tried first, and a small fraction of picks
+//!   (EXPLORATION_RATE) go to a random candidate instead, so that an upstream which was slow once
+//!   gets the chance to show that it has recovered. Response times are only measured for HTTP
+//!   requests; tcp/tls-passthrough tunnels don't have any.
+//!
+//! Paths are mapped to upstreams with rendezvous hashing: each upstream gets a score for the path,
+//! and the highest scoring candidate wins. When an upstream goes down (or comes back), only the
+//! paths that it wins move, rather than most of them being reshuffled.
+
+use crate::ProxyState;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
+
+/// Weight of the newest response time in least-response-time's moving averages
+const EWMA_WEIGHT: f64 = 0.2;
+
+/// Fraction of least-response-time picks that go to a random candidate instead
+const EXPLORATION_RATE: f64 = 0.05;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Pick a random upstream for each client connection
+    Random,
+    /// Take the upstreams in turn, one client connection each
+    RoundRobin,
+    /// Pick the upstream by a hash of each request's path
+    PathHash,
+    /// Pick the upstream with the lowest average response time
+    LeastResponseTime,
+}
+
+/// What became of an exchange with an upstream
+pub enum Outcome {
+    /// The upstream answered with `status`, `latency` after the request was sent
+    Response {
+        status: http::StatusCode,
+        latency: Duration,
+    },
+    /// The upstream couldn't be connected to, or broke off the exchange
+    Failed,
+}
+
+/// A way of picking upstreams.
+pub trait LoadBalancer: Send + Sync {
+    /// Picks one of `candidates`, which is never empty. `key` is what request_key returned for the
+    /// request being proxied, if anything.
+    fn pick<'a>(&self, candidates: &[&'a Arc<str>], key: Option<u64>) -> &'a Arc<str>;
+
+    /// Explains a pick among `candidates` candidates with `key`, for --log-scheduler-decisions.
+    fn describe(&self, candidates: usize, key: Option<u64>) -> String;
+
+    /// The key that decides where `request` goes, for strategies that pick per request rather than
+    /// per connection. None means the request can go wherever its connection already goes.
+    fn request_key(&self, _request: &http::Request<Vec<u8>>) -> Option<u64> {
+        None
+    }
+
+    /// Whether HTTP/1 connections wait for their first request's key before connecting upstream.
+    fn is_per_request(&self) -> bool {
+        false
+    }
+
+    /// Called when a connection to `address` is opened, which is once per successful pick.
+    fn connection_opened(&self, _address: &str) {}
+
+    /// Called when a connection that connection_opened was called for is done with.
+    fn connection_closed(&self, _address: &str) {}
+
+    /// Called with the outcome of every request proxied to `address`, and of every connection to
+    /// it that failed.
+    fn report_result(&self, _address: &str, _outcome: &Outcome) {}
+}
+
+/// An open connection to an upstream, as far as the strategy is concerned: connection_closed is
+/// called for it when this is dropped. Also holds the connection's slot, if the upstream has a
+/// connection cap.
+pub struct Lease {
+    state: Arc<ProxyState>,
+    address: Arc<str>,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Lease {
+    pub fn new(
+        state: Arc<ProxyState>,
+        address: Arc<str>,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> Lease {
+        state.balance.connection_opened(&address);
+        Lease {
+            state,
+            address,
+            _slot: slot,
+        }
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.state.balance.connection_closed(&self.address);
+    }
+}
+
+/// The LoadBalancer for `strategy`.
+pub fn new(strategy: Strategy, ignore_query: bool, seed: Option<u64>) -> Box<dyn LoadBalancer> {
+    match strategy {
+        Strategy::Random => Box::new(Random::new(seed)),
+        Strategy::RoundRobin => Box::new(RoundRobin {
+            next: AtomicUsize::new(0),
+        }),
+        Strategy::PathHash => Box::new(PathHash {
+            ignore_query,
+            fallback: Random::new(seed),
+        }),
+        Strategy::LeastResponseTime => Box::new(LeastResponseTime {
+            averages: Mutex::new(HashMap::new()),
+            explore: Random::new(seed),
+        }),
+    }
+}
+
+pub struct Random {
+    rng: Mutex<StdRng>,
+}
+
+impl Random {
+    fn new(seed: Option<u64>) -> Random {
+        let rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        Random {
+            rng: Mutex::new(rng),
+        }
+    }
+}
+
+impl LoadBalancer for Random {
+    fn pick<'a>(&self, candidates: &[&'a Arc<str>], _key: Option<u64>) -> &'a Arc<str> {
+        candidates[self.rng.lock().unwrap().gen_range(0..candidates.len())]
+    }
+
+    fn describe(&self, candidates: usize, _key: Option<u64>) -> String {
+        format!("picked at random from {} equally weighted candidates", candidates)
+    }
+}
+
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick<'a>(&self, candidates: &[&'a Arc<str>], _key: Option<u64>) -> &'a Arc<str> {
+        candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+    }
+
+    fn describe(&self, candidates: usize, _key: Option<u64>) -> String {
+        format!("next in turn among {} candidates", candidates)
+    }
+}
+
+pub struct PathHash {
+    /// Whether the query string is left out of the path hash
+    ignore_query: bool,
+    /// For connections that don't carry HTTP/1 requests
+    fallback: Random,
+}
+
+impl LoadBalancer for PathHash {
+    fn pick<'a>(&self, candidates: &[&'a Arc<str>], key: Option<u64>) -> &'a Arc<str> {
+        let key = match key {
+            Some(key) => key,
+            None => return self.fallback.pick(candidates, None),
+        };
+        candidates
+            .iter()
+            .copied()
+            .max_by_key(|address| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                address.hash(&mut hasher);
+                hasher.finish()
+            })
+            .expect("no candidates to pick from")
+    }
+
+    fn describe(&self, candidates: usize, key: Option<u64>) -> String {
+        match key {
+            Some(_) => format!("the request path hashes to it among {} candidates", candidates),
+            None => self.fallback.describe(candidates, None),
+        }
+    }
+
+    fn request_key(&self, request: &http::Request<Vec<u8>>) -> Option<u64> {
+        let uri = request.uri();
+        let mut hasher = DefaultHasher::new();
+        match uri.path_and_query() {
+            Some(path_and_query) if !self.ignore_query => path_and_query.as_str().hash(&mut hasher),
+            _ => uri.path().hash(&mut hasher),
+        }
+        Some(hasher.finish())
+    }
+
+    fn is_per_request(&self) -> bool {
+        true
+    }
+}
+
+pub struct LeastResponseTime {
+    /// Exponentially weighted moving average response time of each upstream, in seconds
+    averages: Mutex<HashMap<String, f64>>,
+    /// For the picks that explore
+    explore: Random,
+}
+
+impl LoadBalancer for LeastResponseTime {
+    fn pick<'a>(&self, candidates: &[&'a Arc<str>], _key: Option<u64>) -> &'a Arc<str> {
+        if self.explore.rng.lock().unwrap().gen_bool(EXPLORATION_RATE) {
+            return self.explore.pick(candidates, None);
+        }
+        let averages = self.averages.lock().unwrap();
+        // Upstreams without an average yet count as the fastest, so that they get measured
+        let average = |address: &str| averages.get(address).copied().unwrap_or(0.0);
+        candidates
+            .iter()
+            .copied()
+            .min_by(|a, b| average(a).total_cmp(&average(b)))
+            .expect("no candidates to pick from")
+    }
+
+    fn describe(&self, candidates: usize, _key: Option<u64>) -> String {
+        format!(
+            "lowest average response time among {} candidates (or at random, {}% of the time)",
+            candidates,
+            EXPLORATION_RATE * 100.0
+        )
+    }
+
+    fn report_result(&self, address: &str, outcome: &Outcome) {
+        let (status, latency) = match outcome {
+            Outcome::Response { status, latency } => (status, latency.as_secs_f64()),
+            Outcome::Failed => return,
+        };
+        let mut averages = self.averages.lock().unwrap();
+        averages
+            .entry(address.to_string())
+            .and_modify(|average| {
+                // An upstream that errors out quickly isn't fast, so errors can't lower its average
+                let latency = if status.is_server_error() {
+                    latency.max(*average)
+                } else {
+                    latency
+                };
+                *average += EWMA_WEIGHT * (latency - *average)
+            })
+            .or_insert(latency);
+    }
+}
@@ -0,0 +1,49 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+
+/// Maximum number of idle buffers kept around for reuse
+const MAX_POOLED_BUFFERS: usize = 64;
+/// Buffers that grew beyond this size are dropped instead of being returned to the pool, so that
+/// one huge message doesn't pin a huge allocation forever
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// A byte buffer borrowed from the pool. It is cleared and handed back to the pool when dropped.
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+}
+
+/// Takes an empty buffer from the pool, allocating a new one if the pool is empty.
+pub fn take() -> PooledBuffer {
+    let buffer = POOL.lock().unwrap().pop().unwrap_or_default();
+    PooledBuffer { buffer }
+}
+
+impl Deref for PooledBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if self.buffer.capacity() > MAX_POOLED_CAPACITY {
+            return;
+        }
+        let mut buffer = std::mem::take(&mut self.buffer);
+        buffer.clear();
+        let mut pool = POOL.lock().unwrap();
+        if pool.len() < MAX_POOLED_BUFFERS {
+            pool.push(buffer);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! What is running: the version and commit balancebeam was built from, which features are turned
+//! on, and the effective configuration (every option, including defaults). Logged as a banner at
+//! startup and served as JSON at GET /info on the admin listener.
+
+use crate::accounting::json_string;
+use crate::CmdOptions;
+use clap::CommandFactory;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Set by build.rs
+pub const GIT_COMMIT: &str = env!("BALANCEBEAM_GIT_COMMIT");
+pub const BUILD_TIME: &str = env!("BALANCEBEAM_BUILD_TIME");
+
+/// Options whose values are never shown
+const SECRET_OPTIONS: &[&str] = &["upstream_client_key"];
+
+pub struct BuildInfo {
+    features: Vec<&'static str>,
+    /// Each option's name and values (after defaults are applied), and whether it may be repeated
+    config: Vec<(String, Vec<String>, bool)>,
+}
+
+impl BuildInfo {
+    pub fn new(matches: &clap::ArgMatches, options: &CmdOptions) -> BuildInfo {
+        let mut config = Vec::new();
+        for arg in CmdOptions::command().get_arguments() {
+            let id = arg.get_id().as_str();
+            let values: Vec<String> = match matches.get_raw(id) {
+                Some(values) => values
+                    .map(|value| value.to_string_lossy().into_owned())
+                    .collect(),
+                None => continue,
+            };
+            let values = if SECRET_OPTIONS.contains(&id) {
+                values.iter().map(|_| "<redacted>".to_string()).collect()
+            } else {
+                values
+            };
+            let repeatable = matches!(arg.get_action(), clap::ArgAction::Append);
+            config.push((id.replace('_', "-"), values, repeatable));
+        }
+        BuildInfo {
+            features: features(options),
+            config,
+        }
+    }
+
+    pub fn log_banner(&self) {
+        log::info!(
+            "balancebeam {} (commit {}, built {})",
+            VERSION,
+            GIT_COMMIT,
+            BUILD_TIME
+        );
+        log::info!("Features: {}", self.features.join(", "));
+        let options: Vec<String> = self
+            .config
+            .iter()
+            .flat_map(|(name, values, _)| {
+                values
+                    .iter()
+                    .map(move |value| format!("--{} {}", name, value))
+            })
+            .collect();
+        log::info!("Configuration: {}", options.join(" "));
+    }
+
+    pub fn to_json(&self) -> String {
+        let features: Vec<String> = self.features.iter().map(|f| json_string(f)).collect();
+        let config: Vec<String> = self
+            .config
+            .iter()
+            .map(|(name, values, repeatable)| {
+                let values: Vec<String> = values.iter().map(|v| json_string(v)).collect();
+                let value = if *repeatable {
+                    format!("[{}]", values.join(","))
+                } else {
+                    values.join(",")
+                };
+                format!("{}:{}", json_string(name), value)
+            })
+            .collect();
+        format!(
+            "{{\"version\":{},\"git_commit\":{},\"build_time\":{},\"features\":[{}],\
+             \"config\":{{{}}}}}\n",
+            json_string(VERSION),
+            json_string(GIT_COMMIT),
+            json_string(BUILD_TIME),
+            features.join(","),
+            config.join(",")
+        )
+    }
+}
+
+/// The optional features that the options turn on
+fn features(options: &CmdOptions) -> Vec<&'static str> {
+    let enabled = [
+        (
+            "active-health-checks",
+            options.active_health_check_interval > 0,
+        ),
+        ("passive-health-checks", options.passive_health_ttl > 0),
+        (
+            "rate-limiting",
+            options.max_requests_per_minute > 0 || options.rate_limit_policy.is_some(),
+        ),
+        (
+            "connection-limits",
+            options.max_connections_per_upstream > 0,
+        ),
+        (
+            "outlier-detection",
+            options.outlier_consecutive_5xx > 0
+                || options.outlier_5xx_percent > 0
+                || options.outlier_latency_multiple > 0.0,
+        ),
+        ("retry-budget", options.retry_budget_percent > 0),
+        ("load-shedding", options.max_concurrent_requests > 0),
+        (
+            "upstream-tls",
+            options.upstream_client_cert.is_some()
+                || options.upstream_client_key.is_some()
+                || options.upstream_ca.is_some(),
+        ),
+        ("sni-routing", !options.sni_route.is_empty()),
+        ("forward-auth", !options.forward_auth.is_empty()),
+        ("url-rewrites", !options.rewrite.is_empty()),
+        ("cors", !options.cors.is_empty()),
+        ("compression", options.compression != crate::compression::Mode::Off),
+        ("connect-tunnels", !options.connect_allow.is_empty()),
+        ("trusted-proxies", !options.trusted_proxies.is_empty()),
+        ("idempotency-keys", options.idempotency_key_ttl > 0),
+        ("accounting", options.accounting_file.is_some()),
+        ("access-log", options.access_log.is_some()),
+        ("error-pages", options.error_pages.is_some()),
+        ("upstreams-file", options.upstreams_file.is_some()),
+        ("upstream-groups", !options.upstream_group.is_empty()),
+        (
+            "bandwidth-throttling",
+            options.client_bandwidth > 0 || !options.route_bandwidth.is_empty(),
+        ),
+        ("body-capture", options.capture_bodies),
+        ("admin", options.admin_bind.is_some()),
+    ];
+    enabled
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| *name)
+        .collect()
+}
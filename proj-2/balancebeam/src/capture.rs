@@ -0,0 +1,135 @@
+//! Body capture, for debugging what clients and upstreams are actually sending each other. While
+//! it is on, a sample of proxied HTTP/1 exchanges is logged in full: the request and response
+//! heads, and the first --capture-max-bytes of each body. Headers whose names match
+//! --capture-redact-headers (Authorization and Cookie and the like by default) are logged with
+//! their values blanked out, but bodies are logged as they are, so only turn this on briefly.
+//!
+//! It is off unless balancebeam is started with --capture-bodies, and is toggled through the admin
+//! API:
+//!
+//! ```text
+//! curl -X POST http://ADMIN/capture/on
+//! curl -X POST http://ADMIN/capture/off
+//! curl http://ADMIN/capture
+//! ```
+
+use rand::Rng;
+use regex::Regex;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Header names redacted when --capture-redact-headers isn't given
+pub const DEFAULT_REDACT_HEADERS: &str =
+    "(?i)^(authorization|proxy-authorization|cookie|set-cookie)$";
+
+pub struct Capture {
+    on: AtomicBool,
+    /// How much of each body to log
+    max_bytes: usize,
+    /// Percentage of exchanges to capture while on
+    sample_percent: u32,
+    /// Headers whose values are left out of the log
+    redact: Regex,
+}
+
+impl Capture {
+    pub fn new(on: bool, max_bytes: usize, sample_percent: u32, redact: Regex) -> Capture {
+        if on {
+            log::warn!("Starting with body capture on");
+        }
+        Capture {
+            on: AtomicBool::new(on),
+            max_bytes,
+            sample_percent,
+            redact,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::SeqCst)
+    }
+
+    /// Turns body capture on or off.
+    pub fn set(&self, on: bool) {
+        if self.on.swap(on, Ordering::SeqCst) != on {
+            if on {
+                log::warn!("Body capture is on");
+            } else {
+                log::warn!("Body capture is off");
+            }
+        }
+    }
+
+    /// Percentage of exchanges captured while on
+    pub fn sample_percent(&self) -> u32 {
+        self.sample_percent
+    }
+
+    /// Decides whether to capture the next exchange.
+    pub fn sample(&self) -> bool {
+        self.is_on() && rand::thread_rng().gen_range(0..100) < self.sample_percent
+    }
+
+    pub fn log_request(&self, client_ip: &str, request: &http::Request<Vec<u8>>) {
+        let mut out = format!(
+            "capture: {} -> {} {} {:?}\n",
+            client_ip,
+            request.method(),
+            request.uri(),
+            request.version()
+        );
+        self.write_headers(&mut out, request.headers());
+        self.write_body(&mut out, Some(request.body()));
+        log::info!("{}", out);
+    }
+
+    /// Logs a response. `body` is None if it was streamed to the client rather than buffered, in
+    /// which case there is nothing left of it to show.
+    pub fn log_response(
+        &self,
+        client_ip: &str,
+        response: &http::Response<Vec<u8>>,
+        body: Option<&[u8]>,
+    ) {
+        let mut out = format!(
+            "capture: {} <- {:?} {}\n",
+            client_ip,
+            response.version(),
+            response.status()
+        );
+        self.write_headers(&mut out, response.headers());
+        self.write_body(&mut out, body);
+        log::info!("{}", out);
+    }
+
+    fn write_headers(&self, out: &mut String, headers: &http::HeaderMap) {
+        for (name, value) in headers {
+            if self.redact.is_match(name.as_str()) {
+                let _ = writeln!(out, "  {}: [redacted]", name);
+            } else {
+                let value = String::from_utf8_lossy(value.as_bytes());
+                let _ = writeln!(out, "  {}: {}", name, value);
+            }
+        }
+    }
+
+    fn write_body(&self, out: &mut String, body: Option<&[u8]>) {
+        let body = match body {
+            Some(body) => body,
+            None => {
+                out.push_str("  (body streamed, not captured)");
+                return;
+            }
+        };
+        let shown = &body[..body.len().min(self.max_bytes)];
+        let _ = write!(
+            out,
+            "  body ({} bytes): {}",
+            body.len(),
+            String::from_utf8_lossy(shown).escape_debug()
+        );
+        if shown.len() < body.len() {
+            out.push_str("...");
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! The chunked transfer coding, for bodies that are read in full before they are forwarded
+//! (requests, and responses we don't stream). The body is decoded so that the rest of balancebeam
+//! sees the actual content, and the trailer fields sent after the last chunk are kept in a Trailers
+//! extension on the request or response. The writers see that extension and encode the body again,
+//! trailers included, so that gRPC-web and other APIs that put a status in the trailers keep
+//! working through the proxy. Streamed responses are relayed still encoded, and only go through
+//! the decoder to find where they end.
+
+use std::cmp::min;
+
+/// The trailer fields of a body that arrived chunked. Its presence on a request or response also
+/// tells the writers to send the body chunked, even if there are no trailers.
+#[derive(Clone, Debug)]
+pub struct Trailers(pub http::HeaderMap);
+
+#[derive(PartialEq)]
+enum State {
+    /// Reading a chunk size line
+    Size,
+    /// Inside a chunk; this many bytes of data remain
+    Data(usize),
+    /// Expecting the CRLF after a chunk's data
+    DataEnd,
+    /// Reading trailer lines after the last chunk
+    Trailers,
+    Done,
+}
+
+/// Decodes a chunked body incrementally, as its bytes arrive.
+pub struct Decoder {
+    state: State,
+    /// The partial size or trailer line read so far
+    line: Vec<u8>,
+    trailers: http::HeaderMap,
+    /// Bytes of trailers read so far
+    trailers_size: usize,
+    /// Most bytes accepted in a chunk size line, and in the trailers as a whole
+    max_line_size: usize,
+}
+
+impl Decoder {
+    pub fn new(max_line_size: usize) -> Decoder {
+        Decoder {
+            state: State::Size,
+            line: Vec::new(),
+            trailers: http::HeaderMap::new(),
+            trailers_size: 0,
+            max_line_size,
+        }
+    }
+
+    /// True once the last chunk and the trailers have been read
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// The trailers read so far (all of them, once is_done).
+    pub fn take_trailers(&mut self) -> http::HeaderMap {
+        std::mem::take(&mut self.trailers)
+    }
+
+    /// Consumes bytes from the start of `buf`, appending the chunk data in them to `body`. Returns
+    /// how many bytes were consumed, which is all of them unless the body ends partway through
+    /// `buf`.
+    pub fn feed(&mut self, buf: &[u8], body: &mut Vec<u8>) -> Result<usize, String> {
+        self.advance(buf, Some(body))
+    }
+
+    /// Like feed, but the chunk data is skipped over rather than kept, for a body that is passed
+    /// on as it is.
+    pub fn skip(&mut self, buf: &[u8]) -> Result<usize, String> {
+        self.advance(buf, None)
+    }
+
+    fn advance(&mut self, buf: &[u8], mut body: Option<&mut Vec<u8>>) -> Result<usize, String> {
+        let mut i = 0;
+        while i < buf.len() && self.state != State::Done {
+            if let State::Data(remaining) = self.state {
+                let n = min(remaining, buf.len() - i);
+                if let Some(body) = body.as_mut() {
+                    body.extend_from_slice(&buf[i..i + n]);
+                }
+                i += n;
+                self.state = if n == remaining {
+                    State::DataEnd
+                } else {
+                    State::Data(remaining - n)
+                };
+                continue;
+            }
+            let byte = buf[i];
+            i += 1;
+            if self.state == State::Trailers {
+                self.trailers_size += 1;
+                if self.trailers_size > self.max_line_size {
+                    return Err("trailers too long".to_string());
+                }
+            }
+            if byte != b'\n' {
+                if self.line.len() == self.max_line_size {
+                    return Err("chunk size line too long".to_string());
+                }
+                self.line.push(byte);
+                continue;
+            }
+            let line = std::mem::take(&mut self.line);
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            self.state = match self.state {
+                State::DataEnd if line.is_empty() => State::Size,
+                State::DataEnd => return Err("missing CRLF after chunk data".to_string()),
+                State::Size => {
+                    // Chunk extensions (after a ;) don't matter to us
+                    let line = String::from_utf8_lossy(line);
+                    let size = line.split(';').next().unwrap().trim();
+                    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(format!("invalid chunk size {:?}", size));
+                    }
+                    match usize::from_str_radix(size, 16) {
+                        Ok(0) => State::Trailers,
+                        Ok(size) => State::Data(size),
+                        Err(_) => return Err(format!("invalid chunk size {:?}", size)),
+                    }
+                }
+                State::Trailers if line.is_empty() => State::Done,
+                State::Trailers => {
+                    self.add_trailer(line)?;
+                    State::Trailers
+                }
+                State::Data(_) | State::Done => unreachable!(),
+            };
+        }
+        Ok(i)
+    }
+
+    fn add_trailer(&mut self, line: &[u8]) -> Result<(), String> {
+        let colon = line.iter().position(|b| *b == b':');
+        let (name, value) = match colon {
+            Some(colon) => (&line[..colon], &line[colon + 1..]),
+            None => return Err("trailer line without a colon".to_string()),
+        };
+        let name = http::header::HeaderName::from_bytes(name)
+            .map_err(|_| "invalid trailer name".to_string())?;
+        let value = http::HeaderValue::from_bytes(value.trim_ascii())
+            .map_err(|_| "invalid trailer value".to_string())?;
+        self.trailers.append(name, value);
+        Ok(())
+    }
+}
+
+/// Appends what goes before a body of `body_len` bytes, sent as a single chunk, to `out`. The body
+/// itself is written as it is, followed by what end_body appends.
+pub fn start_body(body_len: usize, out: &mut Vec<u8>) {
+    if body_len > 0 {
+        out.extend_from_slice(format!("{:x}\r\n", body_len).as_bytes());
+    }
+}
+
+/// Appends what follows a body of `body_len` bytes started with start_body to `out`: the end of its
+/// chunk, the last chunk, and `trailers`.
+pub fn end_body(body_len: usize, trailers: &http::HeaderMap, out: &mut Vec<u8>) {
+    if body_len > 0 {
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"0\r\n");
+    for (name, value) in trailers {
+        out.extend_from_slice(name.as_str().as_bytes());
+        out.extend_from_slice(b": ");
+        out.extend_from_slice(value.as_bytes());
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(b"\r\n");
+}
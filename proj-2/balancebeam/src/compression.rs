@@ -0,0 +1,188 @@
+//! Response compression, negotiated with the client's Accept-Encoding. With `--compression auto`,
+//! a response the upstream already compressed is passed through untouched, and one it sent
+//! uncompressed is gzipped here if the client accepts gzip. `--compression proxy` also strips
+//! Accept-Encoding from requests on their way upstream, so that upstreams always answer
+//! uncompressed and all the compression happens here; only the upstream sees the request without
+//! it.
+//!
+//! Only buffered responses to HTTP/1 requests are compressed. Streamed responses (see
+//! response::should_stream) and HTTP/2 responses are relayed as the upstream sent them, and so
+//! HTTP/2 requests keep their Accept-Encoding even with `--compression proxy`. A response is left
+//! alone if it is small, isn't text-like, is partial content, or has Cache-Control: no-transform.
+
+use flate2::write::GzEncoder;
+use http::header::{self, HeaderMap, HeaderValue};
+use std::io::Write;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Relay responses as the upstream sent them
+    Off,
+    /// Gzip responses the upstream sent uncompressed, if the client accepts gzip
+    Auto,
+    /// Like auto, and strip Accept-Encoding from requests sent upstream
+    Proxy,
+}
+
+/// Bodies smaller than this aren't worth compressing
+const MIN_SIZE: usize = 256;
+
+/// The quality (q-value) the Accept-Encoding headers in `headers` give `coding`, from 0 (not
+/// acceptable) to 1. A coding the client doesn't list gets the quality of *, if there is one, and
+/// otherwise only identity is taken to be acceptable: clients that can decompress say so.
+pub fn quality(headers: &HeaderMap, coding: &str) -> f32 {
+    let mut exact = None;
+    let mut wildcard = None;
+    for value in headers.get_all(header::ACCEPT_ENCODING) {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';').map(str::trim);
+            let name = params.next().unwrap_or("");
+            let q = params
+                .find_map(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .or_else(|| param.strip_prefix("Q="))
+                })
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok());
+            let q = match q {
+                Some(q) => q.clamp(0.0, 1.0),
+                None => continue,
+            };
+            // x-gzip is an old alias of gzip
+            let name = if name.eq_ignore_ascii_case("x-gzip") {
+                "gzip"
+            } else {
+                name
+            };
+            if name.eq_ignore_ascii_case(coding) {
+                exact = Some(q);
+            } else if name == "*" {
+                wildcard = Some(q);
+            }
+        }
+    }
+    let default = if coding.eq_ignore_ascii_case("identity") {
+        1.0
+    } else {
+        0.0
+    };
+    exact.or(wildcard).unwrap_or(default)
+}
+
+/// Whether `headers` say the client can take a response with content coding `coding`.
+pub fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    quality(headers, coding) > 0.0
+}
+
+/// Removes Accept-Encoding from a request that is about to be forwarded, if the proxy owns
+/// compression, and returns the values so that they can be put back with restore once the request
+/// has been sent.
+pub fn strip<T>(mode: Mode, request: &mut http::Request<T>) -> Vec<HeaderValue> {
+    if mode != Mode::Proxy {
+        return Vec::new();
+    }
+    let headers = request.headers_mut();
+    let values = headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .cloned()
+        .collect();
+    headers.remove(header::ACCEPT_ENCODING);
+    values
+}
+
+/// Puts back the Accept-Encoding values that strip took out.
+pub fn restore<T>(request: &mut http::Request<T>, values: Vec<HeaderValue>) {
+    for value in values {
+        request.headers_mut().append(header::ACCEPT_ENCODING, value);
+    }
+}
+
+fn is_text_like(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    let essence = essence.to_ascii_lowercase();
+    let subtype = essence.split('/').nth(1).unwrap_or("");
+    essence.starts_with("text/")
+        || matches!(subtype, "json" | "javascript" | "xml" | "x-javascript")
+        || subtype.ends_with("+json")
+        || subtype.ends_with("+xml")
+}
+
+/// Whether the proxy may compress `response`, a response to a `method` request, whatever the
+/// client accepts.
+fn is_compressible(method: &http::Method, response: &http::Response<Vec<u8>>) -> bool {
+    let headers = response.headers();
+    let status = response.status();
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let no_transform = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.to_ascii_lowercase().contains("no-transform"));
+    method != http::Method::HEAD
+        && status.is_success()
+        && status != http::StatusCode::NO_CONTENT
+        && status != http::StatusCode::PARTIAL_CONTENT
+        && response.body().len() >= MIN_SIZE
+        && !headers.contains_key(header::CONTENT_ENCODING)
+        && !headers.contains_key(header::CONTENT_RANGE)
+        && !headers.contains_key(header::TRANSFER_ENCODING)
+        && content_type.is_some_and(is_text_like)
+        && !no_transform
+}
+
+/// Gzips `response`, a buffered response to `request_method`, if `mode` allows it, the response
+/// is worth compressing and the client accepts gzip (going by `request_headers`). Responses the
+/// upstream already compressed are passed through. Compressible responses get Vary:
+/// Accept-Encoding whether or not they end up compressed, since what the next client gets depends
+/// on what it accepts.
+pub fn compress(
+    mode: Mode,
+    request_method: &http::Method,
+    request_headers: &HeaderMap,
+    response: &mut http::Response<Vec<u8>>,
+) {
+    if mode == Mode::Off || !is_compressible(request_method, response) {
+        return;
+    }
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    if !accepts(request_headers, "gzip") {
+        return;
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+    let compressed = match encoder
+        .write_all(response.body())
+        .and_then(|()| encoder.finish())
+    {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            log::warn!("Could not gzip a response: {}", err);
+            return;
+        }
+    };
+    if compressed.len() >= response.body().len() {
+        return;
+    }
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    headers.insert(header::CONTENT_LENGTH, compressed.len().into());
+    // The compressed body isn't byte-for-byte the representation a strong ETag vouches for
+    if let Some(etag) = headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+    *response.body_mut() = compressed;
+}
@@ -0,0 +1,121 @@
+//! Support for the CONNECT method. A client that sends `CONNECT host:port` gets a raw tunnel to
+//! that destination (not to one of the upstreams), which is how HTTPS and other protocols are sent
+//! through an HTTP proxy. Only destinations on the `--connect-allow` list can be reached, so that
+//! balancebeam can't be used as an open relay.
+
+use crate::{response, send_response};
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// A destination that CONNECT requests may tunnel to. Parsed from a `--connect-allow HOST:PORT`
+/// command-line option, where either the host or the port may be `*` to match anything.
+#[derive(Debug)]
+pub struct AllowRule {
+    /// Host to allow, or None for any host
+    host: Option<String>,
+    /// Port to allow, or None for any port
+    port: Option<u16>,
+}
+
+impl AllowRule {
+    pub fn parse(spec: &str) -> Result<AllowRule, String> {
+        let (host, port) = spec
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected HOST:PORT, got {}", spec))?;
+        let host = match host {
+            "" => return Err(format!("missing host in {}", spec)),
+            "*" => None,
+            host => Some(host.to_ascii_lowercase()),
+        };
+        let port = match port {
+            "*" => None,
+            port => Some(
+                port.parse::<u16>()
+                    .map_err(|_| format!("invalid port {} in {}", port, spec))?,
+            ),
+        };
+        Ok(AllowRule { host, port })
+    }
+
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.host.as_ref().is_none_or(|h| h.eq_ignore_ascii_case(host))
+            && self.port.is_none_or(|p| p == port)
+    }
+}
+
+/// Whether the allow list lets CONNECT requests tunnel to `host`:`port`.
+pub fn is_allowed(allowed: &[AllowRule], host: &str, port: u16) -> bool {
+    allowed.iter().any(|rule| rule.matches(host, port))
+}
+
+/// Handles a CONNECT request: checks the destination against the allow list, connects to it, and
+/// then copies bytes in both directions until either side hangs up. The client connection can't
+/// carry any more HTTP requests afterwards, so the caller should close it once this returns.
+/// `early_data` holds whatever the client sent after the request head, which is destined for the
+/// tunnel.
+pub async fn tunnel(
+    client_conn: &mut TcpStream,
+    request: &http::Request<Vec<u8>>,
+    early_data: &[u8],
+    client_ip: &str,
+    allowed: &[AllowRule],
+) {
+    let (host, port) = match request.uri().authority() {
+        Some(authority) if authority.port_u16().is_some() => {
+            (authority.host(), authority.port_u16().unwrap())
+        }
+        _ => {
+            log::debug!("CONNECT without a host:port target: {}", request.uri());
+            let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+            send_response(client_conn, &response).await;
+            return;
+        }
+    };
+    if !is_allowed(allowed, host, port) {
+        log::info!("{}: CONNECT to {}:{} is not allowed", client_ip, host, port);
+        let response = response::make_http_error(http::StatusCode::FORBIDDEN);
+        send_response(client_conn, &response).await;
+        return;
+    }
+
+    let mut target_conn = match TcpStream::connect((host, port)).await {
+        Ok(conn) => conn,
+        Err(err) => {
+            log::warn!("{}: CONNECT to {}:{} failed: {}", client_ip, host, port, err);
+            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+            send_response(client_conn, &response).await;
+            return;
+        }
+    };
+    let response = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .body(Vec::new())
+        .unwrap();
+    send_response(client_conn, &response).await;
+
+    // The client may have started talking to the destination (e.g. sent a TLS ClientHello) without
+    // waiting for our 200, in which case those bytes were read along with the request head
+    if !early_data.is_empty() {
+        if let Err(err) = target_conn.write_all(early_data).await {
+            log::warn!("{}: CONNECT to {}:{} failed: {}", client_ip, host, port, err);
+            return;
+        }
+    }
+    match copy_bidirectional(client_conn, &mut target_conn).await {
+        Ok((sent, received)) => log::info!(
+            "{}: CONNECT tunnel to {}:{} closed ({} bytes sent, {} bytes received)",
+            client_ip,
+            host,
+            port,
+            sent,
+            received
+        ),
+        Err(err) => log::info!(
+            "{}: CONNECT tunnel to {}:{} failed: {}",
+            client_ip,
+            host,
+            port,
+            err
+        ),
+    }
+}
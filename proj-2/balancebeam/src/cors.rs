@@ -0,0 +1,235 @@
+//! Cross-origin resource sharing, handled at the proxy so that every upstream doesn't have to
+//! implement it. Each `--cors [HOST]PREFIX=ORIGIN[,ORIGIN...]` option lets browsers on the given
+//! origins (or any origin, with `*`) call the routes under a path prefix, optionally only on one
+//! host. A rule for the request's host beats one for any host, and the longest matching prefix
+//! wins after that.
+//!
+//! On a covered route, a preflight (an OPTIONS request with Origin and
+//! Access-Control-Request-Method) is answered by balancebeam itself and never reaches an upstream:
+//! with a 204 and the Access-Control-Allow-* headers if the origin is allowed, or a 403 if it
+//! isn't. Other requests from an allowed origin are proxied as usual, and get
+//! Access-Control-Allow-Origin (plus Access-Control-Allow-Credentials and
+//! Access-Control-Expose-Headers, if configured) added to the response, replacing any the upstream
+//! sent. Requests from other origins are proxied untouched; it's up to the browser to refuse them.
+
+use crate::{error_pages, ProxyState};
+use http::header::{self, HeaderMap, HeaderValue};
+
+/// The origins a route is shared with. Parsed from a `--cors [HOST]PREFIX=ORIGIN[,ORIGIN...]`
+/// command-line option.
+#[derive(Debug)]
+pub struct Rule {
+    /// Host the rule is limited to (without a port), or None for any host
+    host: Option<String>,
+    /// Requests whose path starts with this prefix are covered by the rule
+    prefix: String,
+    /// Allowed origins, like https://app.example.com, or None for any origin
+    origins: Option<Vec<String>>,
+}
+
+impl Rule {
+    pub fn parse(spec: &str) -> Result<Rule, String> {
+        let (route, origins) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected [HOST]PREFIX=ORIGIN[,ORIGIN...], got {}", spec))?;
+        let (host, prefix) = match route.find('/') {
+            Some(idx) => (&route[..idx], &route[idx..]),
+            None => return Err(format!("route prefix must start with /, got {}", route)),
+        };
+        let origins: Vec<&str> = origins.split(',').map(str::trim).collect();
+        let origins = if origins == ["*"] {
+            None
+        } else {
+            for origin in &origins {
+                if !origin.contains("://") || origin.ends_with('/') {
+                    return Err(format!(
+                        "origins look like https://app.example.com, got {}",
+                        origin
+                    ));
+                }
+            }
+            Some(
+                origins
+                    .iter()
+                    .map(|origin| origin.to_ascii_lowercase())
+                    .collect(),
+            )
+        };
+        Ok(Rule {
+            host: (!host.is_empty()).then(|| host.to_ascii_lowercase()),
+            prefix: prefix.to_string(),
+            origins,
+        })
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        match &self.origins {
+            None => true,
+            Some(origins) => origins
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(origin)),
+        }
+    }
+}
+
+pub struct Cors {
+    rules: Vec<Rule>,
+    /// Access-Control-Allow-Methods for preflights
+    allow_methods: HeaderValue,
+    /// Access-Control-Allow-Headers for preflights, or None to allow whatever is asked for
+    allow_headers: Option<HeaderValue>,
+    /// Access-Control-Expose-Headers for other responses
+    expose_headers: Option<HeaderValue>,
+    allow_credentials: bool,
+    /// Access-Control-Max-Age for preflights, in seconds
+    max_age: u64,
+}
+
+impl Cors {
+    pub fn new(
+        rules: Vec<Rule>,
+        allow_methods: &str,
+        allow_headers: Option<&str>,
+        expose_headers: Option<&str>,
+        allow_credentials: bool,
+        max_age: u64,
+    ) -> Result<Cors, String> {
+        let value = |value: &str| {
+            HeaderValue::from_str(value).map_err(|_| format!("invalid header value {}", value))
+        };
+        Ok(Cors {
+            rules,
+            allow_methods: value(allow_methods)?,
+            allow_headers: allow_headers.map(value).transpose()?,
+            expose_headers: expose_headers.map(value).transpose()?,
+            allow_credentials,
+            max_age,
+        })
+    }
+
+    /// Returns the most specific rule covering the request, if any.
+    fn find_rule<T>(&self, request: &http::Request<T>) -> Option<&Rule> {
+        let host = request_host(request);
+        let path = request.uri().path();
+        self.rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .filter(|rule| rule.host.is_none() || rule.host == host)
+            .max_by_key(|rule| (rule.host.is_some(), rule.prefix.len()))
+    }
+
+    /// If the request comes from an origin that a rule shares its route with, returns the value
+    /// for Access-Control-Allow-Origin. Returns Err if a rule covers the route but not the origin.
+    fn allowed_origin<T>(&self, request: &http::Request<T>) -> Option<Result<HeaderValue, ()>> {
+        let origin = request.headers().get(header::ORIGIN)?;
+        let rule = self.find_rule(request)?;
+        if !rule.allows(origin.to_str().unwrap_or("")) {
+            return Some(Err(()));
+        }
+        // Any origin can be allowed with *, except that browsers won't send credentials to *
+        if rule.origins.is_none() && !self.allow_credentials {
+            return Some(Ok(HeaderValue::from_static("*")));
+        }
+        Some(Ok(origin.clone()))
+    }
+
+    /// The headers to add to the response to `request` when it is proxied (empty if there are
+    /// none).
+    pub fn response_headers<T>(&self, request: &http::Request<T>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(Ok(origin)) = self.allowed_origin(request) {
+            self.add_common_headers(&mut headers, origin);
+            if let Some(expose_headers) = &self.expose_headers {
+                headers.insert(
+                    header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                    expose_headers.clone(),
+                );
+            }
+        }
+        headers
+    }
+
+    fn add_common_headers(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        if origin != "*" {
+            // The response depends on who asked, so caches mustn't hand it to other origins
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.allow_credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+}
+
+/// The host the request was sent to, without a port: from Host for HTTP/1, or the URI's authority
+/// for HTTP/2.
+fn request_host<T>(request: &http::Request<T>) -> Option<String> {
+    let host = match request.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => request.uri().host()?,
+    };
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Answers `request` if it is a preflight on a route covered by a --cors rule. Returns None if the
+/// request should be proxied.
+pub fn preflight<T>(
+    state: &ProxyState,
+    request: &http::Request<T>,
+) -> Option<http::Response<Vec<u8>>> {
+    let cors = state.cors.as_ref()?;
+    if request.method() != http::Method::OPTIONS
+        || !request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return None;
+    }
+    let origin = match cors.allowed_origin(request)? {
+        Ok(origin) => origin,
+        Err(()) => {
+            let status = http::StatusCode::FORBIDDEN;
+            return Some(error_pages::make_error(
+                state,
+                status,
+                Some(request.headers()),
+            ));
+        }
+    };
+    let mut response = http::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap();
+    let headers = response.headers_mut();
+    cors.add_common_headers(headers, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        cors.allow_methods.clone(),
+    );
+    let requested_headers = request
+        .headers()
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS);
+    match (&cors.allow_headers, requested_headers) {
+        (Some(allow_headers), _) => {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers.clone());
+        }
+        (None, Some(requested)) => {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+            headers.append(
+                header::VARY,
+                HeaderValue::from_static("Access-Control-Request-Headers"),
+            );
+        }
+        (None, None) => {}
+    }
+    headers.insert(header::ACCESS_CONTROL_MAX_AGE, cors.max_age.into());
+    Some(response)
+}
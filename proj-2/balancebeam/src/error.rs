@@ -0,0 +1,273 @@
+//! Errors from reading a request from a client or a response from an upstream. Both share one type,
+//! so that what an error means for the client (the status it is answered with, whether its
+//! connection survives, and the code in X-Balancebeam-Error) is decided here, by exhaustive
+//! matches, instead of by match arms in the connection handlers that could drift out of sync as
+//! new errors are added.
+
+use crate::response;
+use std::fmt;
+
+/// Which end of the proxy an error came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    Client,
+    Upstream,
+}
+
+/// What was being read when an error happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// The request line or status line and the headers, including the framing they declare
+    Head,
+    /// The body, including relaying a streamed response body to the client
+    Body,
+}
+
+#[derive(Debug)]
+pub enum Kind {
+    /// The peer hung up before sending a complete head. Contains the number of bytes that were
+    /// successfully read before it hung up
+    Incomplete(usize),
+    /// The peer sent an invalid request or response. httparse::Error contains more details
+    Malformed(httparse::Error),
+    /// The Content-Length header is present, but does not contain a valid numeric value
+    InvalidContentLength,
+    /// The Content-Length header does not match the size of the body that was sent
+    ContentLengthMismatch,
+    /// The message has both Content-Length and Transfer-Encoding, or conflicting Content-Length
+    /// values, so it's unclear where its body ends
+    AmbiguousFraming,
+    /// The message has a Transfer-Encoding other than chunked, which we can't decode
+    UnsupportedTransferEncoding,
+    /// The chunked body is malformed, or the peer hung up before its last chunk
+    InvalidChunkedBody,
+    /// The body is bigger than we are willing to buffer
+    BodyTooLarge,
+    /// The head is bigger than we are willing to buffer
+    HeadersTooLarge,
+    /// The head has more headers than we are willing to parse
+    TooManyHeaders,
+    /// The request target (path and query string) is longer than the limit. Contains its length
+    /// and the limit, in bytes
+    UriTooLong { length: usize, limit: usize },
+    /// The query string is longer than the limit. Contains its length and the limit, in bytes
+    QueryTooLong { length: usize, limit: usize },
+    /// The peer didn't send the head in time once it had started
+    HeaderTimeout,
+    /// The peer didn't start another message in time
+    IdleTimeout,
+    /// The peer sent the body too slowly
+    BodyTooSlow,
+    /// Encountered an I/O error when reading/writing a TcpStream
+    ConnectionError(std::io::Error),
+    /// A streamed response broke off after its head had already been sent to the client
+    StreamInterrupted,
+    /// The client hung up while we were waiting for the upstream to respond
+    ClientDisconnected,
+}
+
+#[derive(Debug)]
+pub struct ProxyError {
+    pub peer: Peer,
+    pub phase: Phase,
+    pub kind: Kind,
+}
+
+impl ProxyError {
+    pub fn new(peer: Peer, phase: Phase, kind: Kind) -> ProxyError {
+        ProxyError { peer, phase, kind }
+    }
+
+    /// For map_err on errors reading from the client during `phase`.
+    pub fn client(phase: Phase) -> impl Fn(Kind) -> ProxyError + Copy {
+        move |kind| ProxyError::new(Peer::Client, phase, kind)
+    }
+
+    /// For map_err on errors reading from an upstream during `phase`. ClientDisconnected comes
+    /// up while waiting on an upstream, but is always the client's doing.
+    pub fn upstream(phase: Phase) -> impl Fn(Kind) -> ProxyError + Copy {
+        move |kind| match kind {
+            Kind::ClientDisconnected => ProxyError::new(Peer::Client, phase, kind),
+            kind => ProxyError::new(Peer::Upstream, phase, kind),
+        }
+    }
+
+    /// The status the client is answered with. Whatever goes wrong with an upstream is a 502 (a
+    /// 504 if it was too slow); a client gets told what was wrong with its request.
+    pub fn status(&self) -> http::StatusCode {
+        use http::StatusCode;
+        match (self.peer, &self.kind) {
+            (Peer::Upstream, Kind::HeaderTimeout | Kind::IdleTimeout | Kind::BodyTooSlow) => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            (Peer::Upstream, _) => StatusCode::BAD_GATEWAY,
+            (Peer::Client, kind) => match kind {
+                Kind::Incomplete(_)
+                | Kind::Malformed(_)
+                | Kind::InvalidContentLength
+                | Kind::ContentLengthMismatch
+                | Kind::AmbiguousFraming
+                | Kind::InvalidChunkedBody => StatusCode::BAD_REQUEST,
+                Kind::UnsupportedTransferEncoding => StatusCode::NOT_IMPLEMENTED,
+                Kind::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+                Kind::HeadersTooLarge | Kind::TooManyHeaders => {
+                    StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+                }
+                Kind::UriTooLong { .. } | Kind::QueryTooLong { .. } => StatusCode::URI_TOO_LONG,
+                Kind::HeaderTimeout | Kind::IdleTimeout | Kind::BodyTooSlow => {
+                    StatusCode::REQUEST_TIMEOUT
+                }
+                Kind::ConnectionError(_) | Kind::StreamInterrupted | Kind::ClientDisconnected => {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }
+            },
+        }
+    }
+
+    /// Whether the client connection has to be closed after the error response. The connection
+    /// can only carry on if the request was read to its very end, since that's where the next one
+    /// starts. That's the case only for a target that is too long, which is checked once the body
+    /// has been read. Any other error leaves us either not knowing where the request ends (its
+    /// framing is ambiguous or broken) or short of its end (we gave up on an oversized head or
+    /// body), and reading on would take the rest of it for a new request, which is how a request
+    /// gets smuggled past us. Clients that are too slow get hung up on as well, so that they can't
+    /// hold on to the connection. Upstream errors always end the exchange.
+    pub fn hangs_up(&self) -> bool {
+        match self.peer {
+            Peer::Upstream => true,
+            Peer::Client => match self.kind {
+                Kind::Incomplete(_)
+                | Kind::Malformed(_)
+                | Kind::InvalidContentLength
+                | Kind::ContentLengthMismatch
+                | Kind::AmbiguousFraming
+                | Kind::UnsupportedTransferEncoding
+                | Kind::InvalidChunkedBody
+                | Kind::BodyTooLarge
+                | Kind::HeadersTooLarge
+                | Kind::TooManyHeaders
+                | Kind::HeaderTimeout
+                | Kind::BodyTooSlow
+                | Kind::IdleTimeout
+                | Kind::ConnectionError(_)
+                | Kind::StreamInterrupted
+                | Kind::ClientDisconnected => true,
+                Kind::UriTooLong { .. } | Kind::QueryTooLong { .. } => false,
+            },
+        }
+    }
+
+    /// Returns a short, stable identifier for this error, like upstream-body-too-large. It is sent
+    /// to clients in the X-Balancebeam-Error header of the 502 we return when an upstream response
+    /// can't be read, so that the different failure modes can be told apart without digging
+    /// through the logs.
+    pub fn code(&self) -> String {
+        let message = match self.peer {
+            Peer::Client => "request",
+            Peer::Upstream => "response",
+        };
+        let kind = match self.kind {
+            Kind::Incomplete(_) => return format!("{}-incomplete-{}", self.peer, message),
+            Kind::Malformed(_) => return format!("{}-malformed-{}", self.peer, message),
+            Kind::InvalidContentLength => "invalid-content-length",
+            Kind::ContentLengthMismatch => "content-length-mismatch",
+            Kind::AmbiguousFraming => "ambiguous-framing",
+            Kind::UnsupportedTransferEncoding => "unsupported-transfer-encoding",
+            Kind::InvalidChunkedBody => "invalid-chunked-body",
+            Kind::BodyTooLarge => "body-too-large",
+            Kind::HeadersTooLarge => "headers-too-large",
+            Kind::TooManyHeaders => "too-many-headers",
+            Kind::UriTooLong { .. } => "uri-too-long",
+            Kind::QueryTooLong { .. } => "query-too-long",
+            Kind::HeaderTimeout => "header-timeout",
+            Kind::IdleTimeout => "idle-timeout",
+            Kind::BodyTooSlow => "body-too-slow",
+            Kind::ConnectionError(_) => "connection-error",
+            Kind::StreamInterrupted => "stream-interrupted",
+            Kind::ClientDisconnected => "disconnected",
+        };
+        format!("{}-{}", self.peer, kind)
+    }
+
+    /// A JSON response saying which limit the client went over and by how much, for errors a
+    /// client (or a script) can do something about; None for errors that get the usual error page.
+    /// The code is in X-Balancebeam-Error as well as in the body, e.g.
+    /// `{"error":"client-uri-too-long","status":414,"length":9000,"limit":8192}`.
+    pub fn detailed_response(&self) -> Option<http::Response<Vec<u8>>> {
+        let (length, limit) = match self.kind {
+            Kind::UriTooLong { length, limit } | Kind::QueryTooLong { length, limit } => {
+                (length, limit)
+            }
+            _ => return None,
+        };
+        let code = self.code();
+        let status = self.status();
+        let body = format!(
+            "{{\"error\":\"{}\",\"status\":{},\"length\":{},\"limit\":{}}}\n",
+            code,
+            status.as_u16(),
+            length,
+            limit
+        );
+        let mut response = response::make_response(status, "application/json", body.into_bytes());
+        response
+            .headers_mut()
+            .insert("X-Balancebeam-Error", code.parse().unwrap());
+        Some(response)
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Client => write!(f, "client"),
+            Peer::Upstream => write!(f, "upstream"),
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Phase::Head => write!(f, "head"),
+            Phase::Body => write!(f, "body"),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Incomplete(bytes) => write!(f, "hung up after {} bytes", bytes),
+            Kind::Malformed(err) => write!(f, "malformed: {}", err),
+            Kind::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Kind::ContentLengthMismatch => write!(f, "body doesn't match Content-Length"),
+            Kind::AmbiguousFraming => write!(f, "ambiguous framing"),
+            Kind::UnsupportedTransferEncoding => write!(f, "unsupported Transfer-Encoding"),
+            Kind::InvalidChunkedBody => write!(f, "invalid chunked body"),
+            Kind::BodyTooLarge => write!(f, "body too large"),
+            Kind::HeadersTooLarge => write!(f, "headers too large"),
+            Kind::TooManyHeaders => write!(f, "too many headers"),
+            Kind::UriTooLong { length, limit } => {
+                write!(f, "request target of {} bytes (limit {})", length, limit)
+            }
+            Kind::QueryTooLong { length, limit } => {
+                write!(f, "query string of {} bytes (limit {})", length, limit)
+            }
+            Kind::HeaderTimeout => write!(f, "timed out sending headers"),
+            Kind::IdleTimeout => write!(f, "idle for too long"),
+            Kind::BodyTooSlow => write!(f, "body sent too slowly"),
+            Kind::ConnectionError(err) => write!(f, "{}", err),
+            Kind::StreamInterrupted => write!(f, "streamed response interrupted"),
+            Kind::ClientDisconnected => write!(f, "hung up while waiting for a response"),
+        }
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.peer, self.phase, self.kind)
+    }
+}
+
+impl std::error::Error for ProxyError {}
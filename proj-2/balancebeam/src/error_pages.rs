@@ -0,0 +1,187 @@
+//! Operator-supplied error pages for errors that balancebeam generates itself (429 when a client is
+//! rate limited, 502 when no upstream answers, and so on), instead of the bare text from
+//! response::make_http_error. --error-pages names a directory of HTML templates:
+//!
+//! ```text
+//! 502.html     used for 502 responses
+//! 429.html     used for 429 responses
+//! error.html   used for any other status (optional)
+//! ```
+//!
+//! maintenance.html, if there is one, is used for the 503s sent in maintenance mode.
+//!
+//! Templates may contain `{{status}}`, `{{reason}}` and `{{request_id}}`, which are replaced by the
+//! status code, its reason phrase and the request ID. The request ID is the client's X-Request-Id
+//! if it sent one, and a new random ID otherwise; either way it is also sent back in X-Request-Id,
+//! so that a user reporting an error page can be matched up with the logs. The directory is reread
+//! periodically, so pages can be changed without a restart; if it can't be read, the old pages stay
+//! in effect.
+
+use crate::{response, ProxyState};
+use rand::Rng;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Template used for statuses that have no page of their own
+const FALLBACK: &str = "error";
+/// Template used in maintenance mode
+const MAINTENANCE: &str = "maintenance";
+
+pub struct ErrorPages {
+    dir: String,
+    reload_interval: Duration,
+    /// Template contents, keyed by file name without the .html ("502", "error")
+    templates: RwLock<HashMap<String, String>>,
+}
+
+impl ErrorPages {
+    /// Reads the templates. Unlike a reload, an unreadable directory here is an error.
+    pub fn load(dir: String, reload_interval: Duration) -> Result<ErrorPages, String> {
+        let templates = read_templates(&dir)?;
+        log::info!(
+            "Loaded {} error page templates from {}",
+            templates.len(),
+            dir
+        );
+        Ok(ErrorPages {
+            dir,
+            reload_interval,
+            templates: RwLock::new(templates),
+        })
+    }
+
+    /// Makes an error response, from the template for `status` if there is one. `request_headers`
+    /// are the headers of the request being answered, if it got far enough to have any.
+    pub fn render(
+        &self,
+        status: http::StatusCode,
+        request_headers: Option<&http::HeaderMap>,
+    ) -> http::Response<Vec<u8>> {
+        self.render_first(&[status.as_str(), FALLBACK], status, request_headers)
+    }
+
+    /// Makes an error response from the first of the named templates that exists.
+    fn render_first(
+        &self,
+        names: &[&str],
+        status: http::StatusCode,
+        request_headers: Option<&http::HeaderMap>,
+    ) -> http::Response<Vec<u8>> {
+        let templates = self.templates.read().unwrap();
+        let template = match names.iter().find_map(|name| templates.get(*name)) {
+            Some(template) => template,
+            None => return response::make_http_error(status),
+        };
+        let request_id = request_headers
+            .and_then(|headers| headers.get("x-request-id"))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(new_request_id);
+        let body = template
+            .replace("{{status}}", status.as_str())
+            .replace("{{reason}}", status.canonical_reason().unwrap_or(""))
+            .replace("{{request_id}}", &escape_html(&request_id));
+        let mut response =
+            response::make_response(status, "text/html; charset=utf-8", body.into_bytes());
+        if let Ok(value) = request_id.parse() {
+            response.headers_mut().insert("X-Request-Id", value);
+        }
+        response
+    }
+}
+
+/// Makes an error response, using the --error-pages template for the status if there is one.
+pub fn make_error(
+    state: &ProxyState,
+    status: http::StatusCode,
+    request_headers: Option<&http::HeaderMap>,
+) -> http::Response<Vec<u8>> {
+    match &state.error_pages {
+        Some(pages) => pages.render(status, request_headers),
+        None => response::make_http_error(status),
+    }
+}
+
+/// Makes the 503 response sent in maintenance mode.
+pub fn make_maintenance(
+    state: &ProxyState,
+    request_headers: Option<&http::HeaderMap>,
+) -> http::Response<Vec<u8>> {
+    let status = http::StatusCode::SERVICE_UNAVAILABLE;
+    match &state.error_pages {
+        Some(pages) => pages.render_first(
+            &[MAINTENANCE, status.as_str(), FALLBACK],
+            status,
+            request_headers,
+        ),
+        None => response::make_http_error(status),
+    }
+}
+
+/// Rereads the templates every reload interval, until the process exits.
+pub async fn reload_periodically(state: Arc<ProxyState>) {
+    let pages = match &state.error_pages {
+        Some(pages) => pages,
+        None => return,
+    };
+    loop {
+        sleep(pages.reload_interval).await;
+        match read_templates(&pages.dir) {
+            Ok(templates) => {
+                let mut current = pages.templates.write().unwrap();
+                if *current != templates {
+                    log::info!(
+                        "Reloaded {} error page templates from {}",
+                        templates.len(),
+                        pages.dir
+                    );
+                    *current = templates;
+                }
+            }
+            Err(err) => {
+                log::error!("Keeping the old error pages: {}", err);
+            }
+        }
+    }
+}
+
+fn read_templates(dir: &str) -> Result<HashMap<String, String>, String> {
+    let error = |err: std::io::Error| format!("could not read {}: {}", dir, err);
+    let mut templates = HashMap::new();
+    for entry in std::fs::read_dir(dir).map_err(error)? {
+        let path = entry.map_err(error)?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("html") {
+            continue;
+        }
+        let name = match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        let template = std::fs::read_to_string(&path)
+            .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+        templates.insert(name, template);
+    }
+    Ok(templates)
+}
+
+fn new_request_id() -> String {
+    format!("{:016x}", rand::thread_rng().gen::<u64>())
+}
+
+/// The request ID may come from the client, so it mustn't be able to inject markup into the page
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,136 @@
+use crate::{error_pages, request, response, ProxyState};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+/// A route whose requests must be approved by an external auth service before they are proxied.
+/// Parsed from a `--forward-auth PREFIX=HOST:PORT[/PATH]` command-line option.
+#[derive(Debug)]
+pub struct Rule {
+    /// Requests whose path starts with this prefix are covered by the rule
+    pub prefix: String,
+    /// Address of the auth service
+    pub address: String,
+    /// Path to send the auth subrequest to
+    pub path: String,
+}
+
+impl Rule {
+    pub fn parse(spec: &str) -> Result<Rule, String> {
+        let (prefix, target) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=HOST:PORT[/PATH], got {}", spec))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("route prefix must start with /, got {}", prefix));
+        }
+        let (address, path) = match target.find('/') {
+            Some(idx) => (&target[..idx], &target[idx..]),
+            None => (target, "/"),
+        };
+        if address.is_empty() {
+            return Err(format!("missing auth service address in {}", spec));
+        }
+        Ok(Rule {
+            prefix: prefix.to_string(),
+            address: address.to_string(),
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Returns the most specific rule covering the request path, if any.
+pub fn find_rule<'a>(rules: &'a [Rule], path: &str) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .filter(|rule| path.starts_with(&rule.prefix))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// Asks the auth service whether `request` may be proxied. The auth service receives the request
+/// line and headers (but not the body) as a GET, with the original method and URI passed along in
+/// X-Forwarded-Method and X-Forwarded-Uri.
+///
+/// Returns None if the auth service answered with a 2xx, in which case the --forward-auth-header
+/// headers present on the auth response have been copied onto `request`. Otherwise, returns the
+/// response that should be sent back to the client: the auth service's own response if it refused
+/// the request, a 502 error page if it couldn't be reached, or a 504 if it didn't answer within
+/// --forward-auth-timeout.
+pub async fn authorize(
+    state: &ProxyState,
+    rule: &Rule,
+    request: &mut http::Request<Vec<u8>>,
+    client_ip: &str,
+) -> Option<http::Response<Vec<u8>>> {
+    let copy_headers = &state.forward_auth_headers;
+    // Never trust identity headers supplied by the client itself; only the auth service may set them
+    for name in copy_headers {
+        request.headers_mut().remove(name);
+    }
+
+    let mut auth_request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(rule.path.as_str())
+        .body(Vec::new())
+        .unwrap();
+    for (name, value) in request.headers() {
+        if name != http::header::CONTENT_LENGTH && name != http::header::TRANSFER_ENCODING {
+            auth_request.headers_mut().append(name, value.clone());
+        }
+    }
+    let headers = auth_request.headers_mut();
+    headers.insert(http::header::HOST, rule.address.parse().unwrap());
+    headers.insert("x-forwarded-method", request.method().as_str().parse().unwrap());
+    headers.insert(
+        "x-forwarded-uri",
+        http::HeaderValue::from_str(&request.uri().to_string()).unwrap(),
+    );
+    request::extend_header_value(&mut auth_request, "x-forwarded-for", client_ip);
+
+    let asked = ask(&rule.address, &auth_request);
+    let asked = match state.forward_auth_timeout {
+        Some(limit) => timeout(limit, asked).await,
+        None => Ok(asked.await),
+    };
+    let auth_response = match asked {
+        Ok(Ok(response)) => response,
+        Ok(Err(err)) => {
+            log::error!("Forward auth request to {} failed: {}", rule.address, err);
+            let status = http::StatusCode::BAD_GATEWAY;
+            return Some(error_pages::make_error(state, status, Some(request.headers())));
+        }
+        Err(_) => {
+            log::error!("Forward auth service {} didn't answer in time", rule.address);
+            let status = http::StatusCode::GATEWAY_TIMEOUT;
+            return Some(error_pages::make_error(state, status, Some(request.headers())));
+        }
+    };
+    if !auth_response.status().is_success() {
+        log::info!(
+            "Forward auth service {} rejected {} with {}",
+            rule.address,
+            request::format_request_line(request),
+            auth_response.status()
+        );
+        return Some(auth_response);
+    }
+    for name in copy_headers {
+        for value in auth_response.headers().get_all(name) {
+            request.headers_mut().append(name, value.clone());
+        }
+    }
+    None
+}
+
+async fn ask(
+    address: &str,
+    auth_request: &http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, String> {
+    let mut conn = TcpStream::connect(address)
+        .await
+        .map_err(|err| format!("connect failed: {}", err))?;
+    request::write_to_stream(auth_request, &mut conn)
+        .await
+        .map_err(|err| format!("write failed: {}", err))?;
+    response::read_from_stream(&mut conn, auth_request.method())
+        .await
+        .map_err(|err| format!("read failed: {}", err.kind))
+}
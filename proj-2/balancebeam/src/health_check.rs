@@ -0,0 +1,287 @@
+//! Active health checks. Every upstream gets its own probe timer, and each timer is jittered so
+//! that a large pool isn't probed in synchronized bursts.
+//!
+//! With --passive-health-ttl, real traffic counts as a health check too: a probe is skipped if a
+//! proxied request to the upstream succeeded within the TTL. Busy upstreams then hardly get any
+//! synthetic requests, and only idle ones are actually probed.
+//!
+//! A probe passes if the upstream answers with a 200. Some upstreams answer 200 even when they are
+//! degraded, and only say so in the body (`{"status":"degraded"}`); for those, the body can be
+//! required to contain a string (--active-health-check-body-contains '"status":"ok"') or to match
+//! a regex (--active-health-check-body-regex) as well.
+
+use crate::{request, response, supervise, upstream_tls, upstreams, ProxyState};
+use rand::Rng;
+use regex::bytes::Regex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// The latest health checks of one upstream, for the health report
+#[derive(Clone, Default)]
+pub struct ProbeHistory {
+    /// When the upstream was last probed, and why that probe failed if it did
+    pub last: Option<(Instant, Result<(), String>)>,
+    /// How many probes in a row have failed
+    pub consecutive_failures: usize,
+}
+
+/// What a health check response's body must have in it for the probe to pass
+pub enum BodyMatch {
+    Contains(String),
+    Regex(Regex),
+}
+
+impl BodyMatch {
+    fn check(&self, body: &[u8]) -> Result<(), String> {
+        let matched = match self {
+            BodyMatch::Contains(expected) => {
+                expected.is_empty()
+                    || body
+                        .windows(expected.len())
+                        .any(|window| window == expected.as_bytes())
+            }
+            BodyMatch::Regex(regex) => regex.is_match(body),
+        };
+        if matched {
+            Ok(())
+        } else {
+            let pattern = match self {
+                BodyMatch::Contains(expected) => expected.as_str(),
+                BodyMatch::Regex(regex) => regex.as_str(),
+            };
+            Err(format!("body does not match {:?}", pattern))
+        }
+    }
+}
+
+/// Health check settings for one upstream that differ from the global ones. Parsed from a
+/// `--health-check-override HOST:PORT=[SECS][/PATH]` command-line option.
+#[derive(Debug)]
+pub struct Override {
+    /// Address of the upstream the override applies to
+    pub upstream: String,
+    /// Probe interval in seconds, if overridden
+    pub interval: Option<usize>,
+    /// Probe path, if overridden
+    pub path: Option<String>,
+}
+
+impl Override {
+    pub fn parse(spec: &str) -> Result<Override, String> {
+        let (upstream, settings) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected HOST:PORT=[SECS][/PATH], got {}", spec))?;
+        let (interval, path) = match settings.find('/') {
+            Some(idx) => (&settings[..idx], Some(&settings[idx..])),
+            None => (settings, None),
+        };
+        let interval = if interval.is_empty() {
+            None
+        } else {
+            match interval.parse::<usize>() {
+                Ok(secs) if secs > 0 => Some(secs),
+                _ => return Err(format!("invalid interval {} in {}", interval, spec)),
+            }
+        };
+        if interval.is_none() && path.is_none() {
+            return Err(format!("nothing to override in {}", spec));
+        }
+        Ok(Override {
+            upstream: upstream.to_string(),
+            interval,
+            path: path.map(str::to_string),
+        })
+    }
+}
+
+/// Spawns one probe task per upstream. The tasks run until the process exits, or until their
+/// upstream is removed.
+pub fn spawn_all(state: &Arc<ProxyState>) {
+    let addresses = state.upstream_addresses.read().unwrap().clone();
+    for address in addresses {
+        let upstream = state.upstreams.get(&address);
+        spawn(state, address, &upstream);
+    }
+}
+
+/// Spawns the probe task for one upstream.
+pub fn spawn(state: &Arc<ProxyState>, address: String, upstream: &upstreams::Upstream) {
+    let overrides = state
+        .health_check_overrides
+        .iter()
+        .find(|o| o.upstream == address);
+    let interval = overrides
+        .and_then(|o| o.interval)
+        .unwrap_or(state.active_health_check_interval);
+    let path = overrides
+        .and_then(|o| o.path.clone())
+        .unwrap_or_else(|| state.active_health_check_path.clone());
+    let generation = upstream.start_health_checks();
+    let state = Arc::clone(state);
+    let interval = Duration::from_secs(interval as u64);
+    let name = format!("health checks for {}", address);
+    supervise::spawn(name, move || {
+        let state = Arc::clone(&state);
+        probe_loop(state, address.clone(), generation, interval, path.clone())
+    });
+}
+
+/// Records the outcome of a proxied request for --passive-health-ttl. A response that isn't a 5xx
+/// shows that the upstream is up.
+pub fn record_traffic(
+    state: &ProxyState,
+    upstream: &upstreams::Upstream,
+    status: http::StatusCode,
+) {
+    if !state.passive_health_ttl.is_zero() && !status.is_server_error() {
+        upstream.record_success();
+    }
+}
+
+/// Returns how long to wait before the next probe: the interval plus a random extra of up to
+/// `jitter_percent` percent of it.
+fn jittered(interval: Duration, jitter_percent: usize) -> Duration {
+    let max_extra = interval.mul_f64(jitter_percent as f64 / 100.0);
+    interval + max_extra.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+async fn probe_loop(
+    state: Arc<ProxyState>,
+    upstream: String,
+    generation: usize,
+    interval: Duration,
+    path: String,
+) {
+    // The first probe happens one interval after startup, like every later one, but each upstream
+    // starts at a random point in its cycle so that timers that share an interval don't all fire
+    // together
+    let phase = interval.mul_f64(rand::thread_rng().gen::<f64>());
+    sleep(interval + phase).await;
+    let entry = state.upstreams.get(&upstream);
+    while entry.keep_probing(generation) {
+        let healthy = if entry.succeeded_within(state.passive_health_ttl) {
+            log::debug!("health check: {} recently served traffic, not probing", upstream);
+            true
+        } else {
+            let result = if state.active_health_check_tcp {
+                probe_tcp(&state, &upstream).await
+            } else if state.active_health_check_http2 {
+                probe_http2(&state, &upstream, &path).await
+            } else {
+                probe(&state, &upstream, &path).await
+            };
+            if let Err(err) = &result {
+                log::error!("health check of {} failed: {}", upstream, err);
+            }
+            entry.record_probe(result)
+        };
+        let still_probing = state.liveing_upstreams.update(|live| {
+            // The upstream may have been removed while we were probing it. (Removal stops the
+            // probes before updating the live set, so checking here can't race with it.)
+            if !entry.keep_probing(generation) {
+                return false;
+            }
+            let position = live.iter().position(|address| **address == *upstream);
+            match (healthy, position) {
+                (true, None) => {
+                    log::info!("health check: {} is back up", upstream);
+                    live.push(Arc::from(upstream.as_str()));
+                }
+                (false, Some(idx)) => {
+                    live.remove(idx);
+                }
+                _ => {}
+            }
+            true
+        });
+        if !still_probing {
+            break;
+        }
+        sleep(jittered(interval, state.active_health_check_jitter)).await;
+    }
+}
+
+/// Checks that the upstream is accepting TCP connections. Used when balancebeam is only tunneling
+/// TCP, so there is no telling what protocol the upstream speaks. If upstreams use TLS, the
+/// handshake has to succeed as well.
+async fn probe_tcp(state: &ProxyState, upstream: &str) -> Result<(), String> {
+    match upstream_tls::connect(state.upstream_tls.as_ref(), upstream).await {
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("connect failed: {}", err)),
+    }
+}
+
+/// Sends a single health check request. Succeeds if the upstream answered with a 200.
+async fn probe(state: &ProxyState, upstream: &str, path: &str) -> Result<(), String> {
+    let req = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", upstream)
+        .body(Vec::new())
+        .unwrap();
+
+    let mut conn = upstream_tls::connect(state.upstream_tls.as_ref(), upstream)
+        .await
+        .map_err(|err| format!("connect failed: {}", err))?;
+    request::write_to_stream(&req, &mut conn)
+        .await
+        .map_err(|err| format!("write failed: {}", err))?;
+    let response = response::read_from_stream(&mut conn, req.method())
+        .await
+        .map_err(|err| format!("read failed: {}", err.kind))?;
+    check_status(response.status())?;
+    check_body(state, response.body())
+}
+
+/// Like probe, but over cleartext HTTP/2 with prior knowledge.
+async fn probe_http2(state: &ProxyState, upstream: &str, path: &str) -> Result<(), String> {
+    let req = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(format!("http://{}{}", upstream, path))
+        .body(())
+        .unwrap();
+
+    let conn = upstream_tls::connect(state.upstream_tls.as_ref(), upstream)
+        .await
+        .map_err(|err| format!("connect failed: {}", err))?;
+    let result = async {
+        let (client, connection) = h2::client::handshake(conn).await?;
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+        let (response, _) = client.ready().await?.send_request(req, true)?;
+        let (head, mut body) = response.await?.into_parts();
+        // Only read the body if something is going to look at it
+        let mut data = Vec::new();
+        if state.active_health_check_body.is_some() {
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk?;
+                let _ = body.flow_control().release_capacity(chunk.len());
+                data.extend_from_slice(&chunk);
+            }
+        }
+        Ok::<_, h2::Error>((head.status, data))
+    };
+    match result.await {
+        Ok((status, body)) => {
+            check_status(status)?;
+            check_body(state, &body)
+        }
+        Err(err) => Err(format!("HTTP/2 request failed: {}", err)),
+    }
+}
+
+fn check_status(status: http::StatusCode) -> Result<(), String> {
+    if status.as_u16() != 200 {
+        return Err(format!("returned non-200 status: {}", status));
+    }
+    Ok(())
+}
+
+fn check_body(state: &ProxyState, body: &[u8]) -> Result<(), String> {
+    match &state.active_health_check_body {
+        Some(body_match) => body_match.check(body),
+        None => Ok(()),
+    }
+}
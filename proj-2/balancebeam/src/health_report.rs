@@ -0,0 +1,92 @@
+//! A report of why each upstream is or isn't getting traffic, so that operators don't have to dig
+//! through debug logs when traffic shifts. It is served as JSON by the admin listener, and logged
+//! when balancebeam gets SIGUSR1:
+//!
+//! ```text
+//! curl http://ADMIN/upstreams/health
+//! kill -USR1 $(pidof balancebeam)
+//! ```
+//!
+//! Each upstream (from every pool and group) is listed with its state ("up", "down" after failing
+//! health checks, or "ejected" by outlier detection), whether it is in the pool that gets traffic,
+//! its latest health check and how many have failed in a row, and stats over its latest responses.
+
+use crate::accounting::json_string;
+use crate::{outlier, ProxyState};
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Renders the report as a JSON document.
+pub fn render(state: &ProxyState) -> String {
+    let live = state.liveing_upstreams.load();
+    let pool = state.default_pool();
+    let mut upstreams = Vec::new();
+    for address in state.upstream_addresses.read().unwrap().iter() {
+        let upstream = state.upstreams.get(address);
+        let ejected_for = upstream.outlier.ejected_for();
+        let status = if !live.contains(address) {
+            "down"
+        } else if ejected_for.is_some() {
+            "ejected"
+        } else {
+            "up"
+        };
+        let probes = upstream.probe_history();
+        let last_probe = match &probes.last {
+            Some((at, Ok(()))) => format!(
+                "{{\"seconds_ago\":{},\"ok\":true}}",
+                seconds(at.elapsed())
+            ),
+            Some((at, Err(err))) => format!(
+                "{{\"seconds_ago\":{},\"ok\":false,\"error\":{}}}",
+                seconds(at.elapsed()),
+                json_string(err)
+            ),
+            None => "null".to_string(),
+        };
+        let (requests, errors) = upstream.stats.counts();
+
+        let mut out = String::new();
+        let _ = write!(
+            out,
+            "{{\"address\":{},\"state\":\"{}\",\"in_pool\":{},\"last_probe\":{},\
+             \"consecutive_probe_failures\":{},\"ejected_seconds_left\":{},\
+             \"requests\":{},\"errors\":{},\"recent_latency\":{}}}",
+            json_string(address),
+            status,
+            pool.contains(address),
+            last_probe,
+            probes.consecutive_failures,
+            ejected_for.map_or("null".to_string(), seconds),
+            requests,
+            errors,
+            latency_stats(&upstream.stats.recent_latencies())
+        );
+        upstreams.push(out);
+    }
+    format!("{{\"upstreams\":[{}]}}\n", upstreams.join(","))
+}
+
+/// Summarizes sorted latencies, or null if there are none.
+fn latency_stats(latencies: &[Duration]) -> String {
+    if latencies.is_empty() {
+        return "null".to_string();
+    }
+    let mean = latencies.iter().sum::<Duration>() / latencies.len() as u32;
+    format!(
+        "{{\"samples\":{},\"mean_ms\":{},\"p50_ms\":{},\"p99_ms\":{},\"max_ms\":{}}}",
+        latencies.len(),
+        millis(mean),
+        millis(outlier::percentile(latencies, 0.5)),
+        millis(outlier::percentile(latencies, 0.99)),
+        millis(latencies[latencies.len() - 1])
+    )
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
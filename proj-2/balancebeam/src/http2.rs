@@ -0,0 +1,420 @@
+//! HTTP/2 proxying, mainly so that gRPC services can be load balanced. Clients that open a
+//! connection with the HTTP/2 connection preface (cleartext HTTP/2 with prior knowledge, which is
+//! what gRPC uses without TLS) are proxied to the upstream over HTTP/2 as well. Each stream is
+//! forwarded as it arrives, including trailers, which is where gRPC puts grpc-status and
+//! grpc-message.
+
+use crate::error::{Kind, Peer, Phase, ProxyError};
+use crate::{
+    access_log, balance, connect_to_upstream, cors, error_pages, forward_auth, health_check,
+    load_shed, log_local_response, maintenance, rate_limit, request, response, rewrite,
+    trusted_proxies, ProxyState,
+};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{RecvStream, SendStream};
+use std::future::poll_fn;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+/// Every HTTP/2 connection starts with this
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Returns true if the client opened the connection with the HTTP/2 connection preface. This only
+/// peeks at the stream, so an HTTP/1 request is left in place to be read as usual.
+///
+/// Waiting for the preface is bounded like reading a request head: by the idle timeout until the
+/// first byte arrives, and by the header timeout after that, failing with IdleTimeout or
+/// HeaderTimeout. Either way the connection can't be read from any more (waiting for more of the
+/// preface uses up the socket's readiness, and a read waits for it even with bytes in the socket),
+/// so the client has to be hung up on. The part of the preface that did arrive is taken out of the
+/// socket first, so that hanging up doesn't reset the connection under an error response.
+pub async fn is_http2(conn: &TcpStream, limits: &request::Limits) -> Result<bool, Kind> {
+    let mut buf = [0_u8; PREFACE.len()];
+    let mut peeked = 0;
+    let mut deadline = None;
+    loop {
+        if peeked > 0 && deadline.is_none() {
+            deadline = limits.header_timeout.map(|timeout| Instant::now() + timeout);
+        }
+        let limit = match deadline {
+            Some(deadline) => Some(deadline.saturating_duration_since(Instant::now())),
+            None if peeked == 0 => limits.idle_timeout,
+            None => None,
+        };
+        let ready = match limit {
+            Some(limit) => match tokio::time::timeout(limit, conn.readable()).await {
+                Ok(ready) => ready,
+                Err(_) if peeked == 0 => return Err(Kind::IdleTimeout),
+                Err(_) => {
+                    let _ = recv(conn, &mut buf, libc::MSG_DONTWAIT);
+                    return Err(Kind::HeaderTimeout);
+                }
+            },
+            None => conn.readable().await,
+        };
+        if ready.is_err() {
+            return Ok(false);
+        }
+        // Peeking doesn't use up the socket's readiness, so when no more of the preface has
+        // arrived, report WouldBlock to clear it and have readable() wait for more
+        let n = match conn.try_io(Interest::READABLE, || peek(conn, &mut buf, peeked)) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(_) => return Ok(false),
+        };
+        if n == 0 || buf[..n] != PREFACE[..n] {
+            return Ok(false);
+        }
+        if n == PREFACE.len() {
+            return Ok(true);
+        }
+        peeked = n;
+    }
+}
+
+/// Peeks at what the client has sent so far, failing with WouldBlock unless there's more than the
+/// `peeked` bytes there were last time (or the client has hung up).
+fn peek(conn: &TcpStream, buf: &mut [u8], peeked: usize) -> io::Result<usize> {
+    match recv(conn, buf, libc::MSG_PEEK | libc::MSG_DONTWAIT)? {
+        n if n > 0 && n == peeked => Err(io::ErrorKind::WouldBlock.into()),
+        n => Ok(n),
+    }
+}
+
+/// recv(2) on the connection's socket, bypassing tokio, which only peeks with a wait for readiness.
+fn recv(conn: &TcpStream, buf: &mut [u8], flags: libc::c_int) -> io::Result<usize> {
+    // SAFETY: recv writes at most buf.len() bytes into buf, which is valid for that many
+    let n = unsafe {
+        libc::recv(
+            conn.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            flags,
+        )
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+/// Proxies an HTTP/2 client connection, opened at `opened`. Like HTTP/1 connections, all of the
+/// client's streams go to a single upstream connection.
+pub async fn serve(
+    client_conn: TcpStream,
+    peer_ip: IpAddr,
+    state: Arc<ProxyState>,
+    opened: Instant,
+) {
+    // h2 answers 431 by itself when a header list goes over this
+    let max_header_list_size =
+        state.request_limits.max_header_size.min(u32::MAX as usize) as u32;
+    let handshake = h2::server::Builder::new()
+        .max_header_list_size(max_header_list_size)
+        .handshake(client_conn);
+    let mut connection = match handshake.await {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", peer_ip, err);
+            return;
+        }
+    };
+
+    let (upstream_address, upstream_conn, _upstream_slot) =
+        match connect_to_upstream(Arc::clone(&state), &state.default_pool(), peer_ip, None).await {
+            Ok(conn) => conn,
+            Err(error) => {
+                log::warn!("Could not connect to an upstream: {:?}", error);
+                let status = error.status();
+                while let Some(Ok((request, mut respond))) = connection.accept().await {
+                    let response = error_pages::make_error(&state, status, Some(request.headers()));
+                    let _ = send_local_response(&mut respond, response);
+                }
+                return;
+            }
+        };
+    let upstream = match h2::client::handshake(upstream_conn).await {
+        Ok((upstream, upstream_connection)) => {
+            tokio::spawn(async move {
+                if let Err(err) = upstream_connection.await {
+                    log::debug!("HTTP/2 upstream connection closed: {}", err);
+                }
+            });
+            upstream
+        }
+        Err(err) => {
+            state.upstreams.get(&upstream_address).stats.record_error();
+            state
+                .balance
+                .report_result(&upstream_address, &balance::Outcome::Failed);
+            log::error!("HTTP/2 handshake with upstream {} failed: {}", upstream_address, err);
+            let status = http::StatusCode::BAD_GATEWAY;
+            while let Some(Ok((request, mut respond))) = connection.accept().await {
+                let response = error_pages::make_error(&state, status, Some(request.headers()));
+                let _ = send_local_response(&mut respond, response);
+            }
+            return;
+        }
+    };
+
+    let mut accepted = 0;
+    let mut closing = false;
+    while let Some(result) = connection.accept().await {
+        let (request, respond) = match result {
+            Ok(stream) => stream,
+            Err(err) => {
+                log::debug!("HTTP/2 connection from {} closed: {}", peer_ip, err);
+                return;
+            }
+        };
+        accepted += 1;
+        // Streams already open are still served; the GOAWAY only stops the client opening more
+        if !closing && state.connection_spent(accepted, opened) {
+            log::debug!("Recycling the HTTP/2 connection from {}", peer_ip);
+            connection.graceful_shutdown();
+            closing = true;
+        }
+        let state = Arc::clone(&state);
+        let upstream = upstream.clone();
+        let upstream_address = upstream_address.clone();
+        tokio::spawn(async move {
+            proxy_stream(state, peer_ip, &upstream_address, upstream, request, respond).await;
+        });
+    }
+}
+
+async fn proxy_stream(
+    state: Arc<ProxyState>,
+    peer_ip: IpAddr,
+    upstream_address: &str,
+    upstream: h2::client::SendRequest<Bytes>,
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+) {
+    let arrived = Instant::now();
+    let client_ip = peer_ip.to_string();
+    let (parts, client_body) = request.into_parts();
+    // Rate limiting and forward auth only look at the request head, so run them against a copy of
+    // the request without the body
+    let mut head = http::Request::from_parts(parts, Vec::new());
+    if head.headers().len() > state.request_limits.max_headers {
+        let status = http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        let response = error_pages::make_error(&state, status, Some(head.headers()));
+        log_local_response(&state, &client_ip, &head, &response, arrived);
+        let _ = send_local_response(&mut respond, response);
+        return;
+    }
+    if let Err(kind) = request::check_target(head.uri(), &state.request_limits) {
+        let error = ProxyError::new(Peer::Client, Phase::Head, kind);
+        let response = error.detailed_response().unwrap_or_else(|| {
+            error_pages::make_error(&state, error.status(), Some(head.headers()))
+        });
+        log_local_response(&state, &client_ip, &head, &response, arrived);
+        let _ = send_local_response(&mut respond, response);
+        return;
+    }
+    let client_identity = trusted_proxies::client_identity(&state.trusted_proxies, peer_ip, &head);
+    let request_client_ip = client_identity.to_string();
+    log::info!(
+        "{} -> {}: {}",
+        request_client_ip,
+        upstream_address,
+        request::format_request_line(&head)
+    );
+
+    if let Some(response) = maintenance::respond(&state, client_identity, Some(head.headers())) {
+        log_local_response(&state, &request_client_ip, &head, &response, arrived);
+        let _ = send_local_response(&mut respond, response);
+        return;
+    }
+
+    if state.rate_limiting_enabled() {
+        if let Some(response) = rate_limit(&state, request_client_ip.clone(), &head).await {
+            log_local_response(&state, &request_client_ip, &head, &response, arrived);
+            let _ = send_local_response(&mut respond, response);
+            return;
+        }
+    }
+    if let Some(response) = cors::preflight(&state, &head) {
+        log_local_response(&state, &request_client_ip, &head, &response, arrived);
+        let _ = send_local_response(&mut respond, response);
+        return;
+    }
+    // Held until the stream is done
+    let _admitted = match state.load_shedder.admit().await {
+        Some(admitted) => admitted,
+        None => {
+            log::warn!("Too many requests in flight; shedding a request from {}", client_ip);
+            let response = load_shed::respond(&state, Some(head.headers()));
+            log_local_response(&state, &request_client_ip, &head, &response, arrived);
+            let _ = send_local_response(&mut respond, response);
+            return;
+        }
+    };
+    if let Some(rule) = forward_auth::find_rule(&state.forward_auth_rules, head.uri().path()) {
+        if let Some(response) = forward_auth::authorize(&state, rule, &mut head, &client_ip).await {
+            log_local_response(&state, &request_client_ip, &head, &response, arrived);
+            let _ = send_local_response(&mut respond, response);
+            return;
+        }
+    }
+    let labels = state.accounting.as_ref().map(|ledger| ledger.labels(&head));
+    let cors_headers = state
+        .cors
+        .as_ref()
+        .map(|cors| cors.response_headers(&head))
+        .unwrap_or_default();
+    request::extend_header_value(&mut head, "x-forwarded-for", &client_ip);
+    // The head is about to be handed to h2, so keep what the access log needs
+    let request_line = state
+        .access_log
+        .as_ref()
+        .map(|_| request::format_request_line(&head));
+    let request_headers = state.error_pages.as_ref().map(|_| head.headers().clone());
+    // Only the upstream sees a rewritten path
+    rewrite::apply(&state.rewrite_rules, &mut head);
+    let log_access = |status, bytes, upstream| {
+        if let (Some(access_log), Some(request_line)) = (&state.access_log, &request_line) {
+            access_log.record(access_log::Entry {
+                client_ip: &request_client_ip,
+                request_line: request_line.clone(),
+                status,
+                bytes,
+                upstream,
+                started: arrived,
+            });
+        }
+    };
+    let (parts, _) = head.into_parts();
+
+    let entry = state.upstreams.get(upstream_address);
+    let stats = &entry.stats;
+    let started = Instant::now();
+    let request = http::Request::from_parts(parts, ());
+    match forward(upstream, request, client_body, &mut respond, &cors_headers).await {
+        Ok((status, sent, received)) => {
+            let latency = started.elapsed();
+            stats.record_response(latency, sent, received);
+            let outcome = balance::Outcome::Response { status, latency };
+            state.balance.report_result(upstream_address, &outcome);
+            health_check::record_traffic(&state, &entry, status);
+            if let (Some(ledger), Some(labels)) = (&state.accounting, &labels) {
+                ledger.record(upstream_address, labels, sent, received);
+            }
+            entry.outlier.record(
+                &state.outlier_config,
+                upstream_address,
+                status,
+                latency,
+            );
+            log_access(status, received, Some(upstream_address));
+        }
+        Err(err) => {
+            stats.record_error();
+            state
+                .balance
+                .report_result(upstream_address, &balance::Outcome::Failed);
+            log::error!("HTTP/2 stream to upstream {} failed: {}", upstream_address, err);
+            // If we haven't started the response yet, the client gets a 502; otherwise all we can
+            // do is reset the stream
+            let status = http::StatusCode::BAD_GATEWAY;
+            let response = error_pages::make_error(&state, status, request_headers.as_ref());
+            log_access(response.status(), response.body().len(), None);
+            if send_local_response(&mut respond, response).is_err() {
+                respond.send_reset(h2::Reason::INTERNAL_ERROR);
+            }
+        }
+    }
+}
+
+/// Sends `request` upstream and relays the response back, streaming both bodies at the same time
+/// (gRPC streams can run in both directions at once), with `extra_headers` added to the response.
+/// Returns the response status and the number of body bytes sent and received.
+async fn forward(
+    upstream: h2::client::SendRequest<Bytes>,
+    request: http::Request<()>,
+    client_body: RecvStream,
+    respond: &mut SendResponse<Bytes>,
+    extra_headers: &http::HeaderMap,
+) -> Result<(http::StatusCode, usize, usize), h2::Error> {
+    let mut upstream = upstream.ready().await?;
+    let end_of_stream = client_body.is_end_stream();
+    let (response, upstream_body) = upstream.send_request(request, end_of_stream)?;
+
+    let request_pump = async {
+        if end_of_stream {
+            Ok(0)
+        } else {
+            pipe(client_body, upstream_body).await
+        }
+    };
+    let response_pump = async {
+        let (mut parts, body) = response.await?.into_parts();
+        response::add_headers(&mut parts.headers, extra_headers);
+        let status = parts.status;
+        let end_of_stream = body.is_end_stream();
+        let client_stream =
+            respond.send_response(http::Response::from_parts(parts, ()), end_of_stream)?;
+        let received = if end_of_stream {
+            0
+        } else {
+            pipe(body, client_stream).await?
+        };
+        Ok::<_, h2::Error>((status, received))
+    };
+    let (sent, response) = tokio::join!(request_pump, response_pump);
+    let (status, received) = response?;
+    Ok((status, sent?, received))
+}
+
+/// Copies a body (its data frames, then its trailers if there are any) from one stream to another,
+/// only sending as much as the receiving side's flow control window allows. Returns the number of
+/// body bytes copied.
+async fn pipe(mut from: RecvStream, mut to: SendStream<Bytes>) -> Result<usize, h2::Error> {
+    let mut copied = 0;
+    while let Some(chunk) = from.data().await {
+        let mut chunk = chunk?;
+        let len = chunk.len();
+        while !chunk.is_empty() {
+            to.reserve_capacity(chunk.len());
+            let available = match poll_fn(|cx| to.poll_capacity(cx)).await {
+                Some(available) => available?,
+                None => return Err(h2::Reason::CANCEL.into()),
+            };
+            if available == 0 {
+                continue;
+            }
+            to.send_data(chunk.split_to(available.min(chunk.len())), false)?;
+        }
+        // Only let the sender send more once we've passed this chunk on, so that a slow receiver
+        // slows the sender down instead of making us buffer
+        from.flow_control().release_capacity(len)?;
+        copied += len;
+    }
+    match from.trailers().await? {
+        Some(trailers) => to.send_trailers(trailers)?,
+        None => to.send_data(Bytes::new(), true)?,
+    }
+    Ok(copied)
+}
+
+/// Sends a response generated by balancebeam itself (e.g. a 429) on an HTTP/2 stream.
+fn send_local_response(
+    respond: &mut SendResponse<Bytes>,
+    response: http::Response<Vec<u8>>,
+) -> Result<(), h2::Error> {
+    let (mut parts, body) = response.into_parts();
+    parts.version = http::Version::HTTP_2;
+    let end_of_stream = body.is_empty();
+    let mut stream = respond.send_response(http::Response::from_parts(parts, ()), end_of_stream)?;
+    if !end_of_stream {
+        stream.send_data(Bytes::from(body), true)?;
+    }
+    Ok(())
+}
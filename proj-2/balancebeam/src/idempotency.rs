@@ -0,0 +1,192 @@
+//! Duplicate request suppression for requests carrying an Idempotency-Key header. The first
+//! request with a given key is forwarded as usual and its response remembered; a retry with the
+//! same key gets the remembered response instead of being forwarded again, so that a client retrying
+//! a POST after a timeout doesn't make the upstream process it twice. Keys are scoped to the client
+//! that sent them, so one client can't replay another's responses by guessing its keys.
+//!
+//! Only HTTP/1 requests with buffered responses are covered; HTTP/2 streams and streamed responses
+//! are always forwarded.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const HEADER: &str = "idempotency-key";
+
+enum Entry {
+    /// The first request with this key is still waiting for its response
+    InFlight { fingerprint: u64 },
+    Done {
+        fingerprint: u64,
+        response: http::Response<Vec<u8>>,
+        stored_at: Instant,
+    },
+}
+
+/// Remembered responses, keyed by client and idempotency key
+pub struct Cache {
+    /// How long a response is remembered (zero disables the cache)
+    ttl: Duration,
+    /// Maximum number of keys remembered at once
+    max_keys: usize,
+    entries: Mutex<HashMap<(String, String), Entry>>,
+}
+
+/// What to do with a request, according to the cache
+pub enum Begin<'a> {
+    /// Forward the request. If it has an idempotency key, its response should be handed to the
+    /// ticket.
+    Forward(Option<Ticket<'a>>),
+    /// Send this response back instead of forwarding the request
+    Respond(http::Response<Vec<u8>>),
+    /// Answer with an error with this status instead of forwarding the request: the key is in use
+    /// by a request still in flight, or was first used for a different request
+    Refuse(http::StatusCode),
+}
+
+/// Reserves an idempotency key for a request that is being forwarded. If the ticket is dropped
+/// without a response being stored (e.g. because the upstream failed), the key is released so
+/// that a retry gets forwarded.
+pub struct Ticket<'a> {
+    cache: &'a Cache,
+    key: (String, String),
+    fingerprint: u64,
+    finished: bool,
+}
+
+/// Identifies the request a key was first used for, so that reusing a key for a different request
+/// can be caught
+fn fingerprint(request: &http::Request<Vec<u8>>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    request.method().as_str().hash(&mut hasher);
+    request.uri().to_string().hash(&mut hasher);
+    request.body().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// http::Response isn't Clone, so copy one by hand
+fn copy_response(response: &http::Response<Vec<u8>>) -> http::Response<Vec<u8>> {
+    let mut copy = http::Response::new(response.body().clone());
+    *copy.status_mut() = response.status();
+    *copy.version_mut() = response.version();
+    *copy.headers_mut() = response.headers().clone();
+    copy
+}
+
+impl Cache {
+    pub fn new(ttl: Duration, max_keys: usize) -> Cache {
+        Cache {
+            ttl,
+            max_keys,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The request's idempotency key, if it has one and keys are enabled
+    pub fn key_of<T>(&self, request: &http::Request<T>) -> Option<String> {
+        match request.headers().get(HEADER) {
+            Some(key) if !self.ttl.is_zero() => {
+                Some(String::from_utf8_lossy(key.as_bytes()).into_owned())
+            }
+            _ => None,
+        }
+    }
+
+    /// Looks up the request's idempotency key, if it has one.
+    pub fn begin(&self, client: &str, request: &http::Request<Vec<u8>>) -> Begin<'_> {
+        let key = match self.key_of(request) {
+            Some(key) => key,
+            None => return Begin::Forward(None),
+        };
+        let key = (client.to_string(), key);
+        let fingerprint = fingerprint(request);
+        let now = Instant::now();
+
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(&key) {
+            Some(Entry::Done {
+                fingerprint: stored,
+                response: stored_response,
+                stored_at,
+            }) if now - *stored_at < self.ttl => {
+                // The key was first used for a different request
+                if *stored != fingerprint {
+                    return Begin::Refuse(http::StatusCode::UNPROCESSABLE_ENTITY);
+                }
+                log::info!("Replaying stored response for idempotency key {:?}", key.1);
+                let mut replay = copy_response(stored_response);
+                replay
+                    .headers_mut()
+                    .insert("Idempotent-Replayed", "true".parse().unwrap());
+                return Begin::Respond(replay);
+            }
+            // The original request hasn't been answered yet, so there's nothing to replay
+            Some(Entry::InFlight { fingerprint: stored }) => {
+                let status = if *stored != fingerprint {
+                    http::StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    http::StatusCode::CONFLICT
+                };
+                return Begin::Refuse(status);
+            }
+            _ => {}
+        }
+
+        if entries.len() >= self.max_keys && !entries.contains_key(&key) {
+            let ttl = self.ttl;
+            entries.retain(|_, entry| match entry {
+                Entry::Done { stored_at, .. } => now - *stored_at < ttl,
+                Entry::InFlight { .. } => true,
+            });
+            // Still full: forget the oldest stored response
+            if entries.len() >= self.max_keys {
+                let oldest = entries
+                    .iter()
+                    .filter_map(|(key, entry)| match entry {
+                        Entry::Done { stored_at, .. } => Some((key.clone(), *stored_at)),
+                        Entry::InFlight { .. } => None,
+                    })
+                    .min_by_key(|(_, stored_at)| *stored_at)
+                    .map(|(key, _)| key);
+                match oldest {
+                    Some(oldest) => {
+                        entries.remove(&oldest);
+                    }
+                    // Every slot is taken by a request in flight; don't track this one
+                    None => return Begin::Forward(None),
+                }
+            }
+        }
+        entries.insert(key.clone(), Entry::InFlight { fingerprint });
+        Begin::Forward(Some(Ticket {
+            cache: self,
+            key,
+            fingerprint,
+            finished: false,
+        }))
+    }
+}
+
+impl Ticket<'_> {
+    /// Remembers the response to the request the ticket was issued for.
+    pub fn finish(mut self, response: &http::Response<Vec<u8>>) {
+        let entry = Entry::Done {
+            fingerprint: self.fingerprint,
+            response: copy_response(response),
+            stored_at: Instant::now(),
+        };
+        let mut entries = self.cache.entries.lock().unwrap();
+        entries.insert(self.key.clone(), entry);
+        self.finished = true;
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.cache.entries.lock().unwrap().remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+//! The set of upstreams that are currently passing health checks. Every new connection reads it to
+//! pick an upstream, while it only changes when a health check flips or the pool is edited, so it
+//! is kept as an immutable snapshot that writers replace wholesale. Readers grab the current
+//! snapshot without taking a lock, so picking an upstream never waits on a health checker that is
+//! in the middle of an update. Old snapshots are freed with epoch-based reclamation once no reader
+//! can still be looking at them.
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+/// The live upstreams at one point in time. Cheap to clone, and addresses are shared rather than
+/// copied.
+#[derive(Clone, PartialEq)]
+pub struct Snapshot(Arc<Vec<Arc<str>>>);
+
+impl Snapshot {
+    pub fn contains(&self, address: &str) -> bool {
+        self.0.iter().any(|live| &**live == address)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<str>> {
+        self.0.iter()
+    }
+}
+
+pub struct LiveUpstreams {
+    /// Never null
+    current: Atomic<Snapshot>,
+    /// Held by writers, so that two updates don't both start from the same snapshot and lose one
+    /// of the changes
+    writer: Mutex<()>,
+}
+
+impl LiveUpstreams {
+    pub fn new(addresses: &[String]) -> LiveUpstreams {
+        let addresses = addresses.iter().map(|address| Arc::from(address.as_str()));
+        LiveUpstreams {
+            current: Atomic::new(Snapshot(Arc::new(addresses.collect()))),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// The current snapshot. Never blocks.
+    pub fn load(&self) -> Snapshot {
+        let guard = &epoch::pin();
+        let current = self.current.load(Ordering::Acquire, guard);
+        // SAFETY: `current` is never null, and the snapshot it points to isn't freed until every
+        // thread that was pinned when it was replaced (like this one) has unpinned.
+        unsafe { current.deref() }.clone()
+    }
+
+    /// Changes the set with `f`, and publishes the result as the new snapshot if it is any
+    /// different. Returns what `f` returns.
+    pub fn update<R>(&self, f: impl FnOnce(&mut Vec<Arc<str>>) -> R) -> R {
+        let _writer = self.writer.lock().unwrap();
+        let current = self.load();
+        let mut next = current.0.as_ref().clone();
+        let result = f(&mut next);
+        if *current.0 != next {
+            let guard = &epoch::pin();
+            let next = Owned::new(Snapshot(Arc::new(next)));
+            let old = self.current.swap(next, Ordering::AcqRel, guard);
+            // SAFETY: `old` is no longer reachable from `current`, so only readers that are
+            // already pinned can see it, and it is destroyed after they unpin.
+            unsafe { guard.defer_destroy(old) };
+        }
+        result
+    }
+}
+
+impl Drop for LiveUpstreams {
+    fn drop(&mut self) {
+        // SAFETY: having `&mut self` means no other thread can be reading the snapshot.
+        unsafe {
+            drop(
+                self.current
+                    .load(Ordering::Relaxed, epoch::unprotected())
+                    .into_owned(),
+            )
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Load shedding. With --max-concurrent-requests, at most that many requests are proxied at once.
+//! Up to --max-queued-requests more wait for one of them to finish, for at most --queue-timeout
+//! seconds; anything beyond that (or waiting longer) is answered with a 503 and Retry-After
+//! straight away, before any work is done for it upstream. Under overload, some clients get a
+//! quick error instead of every client getting a slow answer, and the upstreams only see as much
+//! traffic as they were sized for.
+//!
+//! Requests are admitted after maintenance mode and rate limiting have had their say, so requests
+//! that those turn away don't take up a slot. CONNECT tunnels aren't counted, since they last as
+//! long as the client wants. The in-flight and queued counts and the number of requests shed are
+//! on /metrics.
+
+use crate::{error_pages, ProxyState};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Sent with shed responses, in seconds
+const RETRY_AFTER: u64 = 1;
+
+pub struct LoadShedder {
+    /// One permit per request that may be in flight, or None if concurrency isn't limited
+    slots: Option<Arc<Semaphore>>,
+    /// How many requests may wait for a slot
+    max_queued: usize,
+    /// How long a request may wait for a slot
+    queue_timeout: Duration,
+    in_flight: AtomicUsize,
+    queued: AtomicUsize,
+    shed: AtomicU64,
+}
+
+/// Counts a request as in flight (and holds its slot, if any) until dropped.
+pub struct Admitted<'a> {
+    shedder: &'a LoadShedder,
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Admitted<'_> {
+    fn drop(&mut self) {
+        self.shedder.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a request counted as queued until dropped, including when the wait is abandoned because
+/// the client went away.
+struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl LoadShedder {
+    /// Creates a shedder allowing `max_concurrent` requests in flight (0 = unlimited) and
+    /// `max_queued` waiting for up to `queue_timeout`.
+    pub fn new(max_concurrent: usize, max_queued: usize, queue_timeout: Duration) -> LoadShedder {
+        LoadShedder {
+            slots: (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent))),
+            max_queued,
+            queue_timeout,
+            in_flight: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    /// Admits a request, waiting in the queue if every slot is taken. Returns None if the request
+    /// should be shed.
+    pub async fn admit(&self) -> Option<Admitted<'_>> {
+        let slot = match &self.slots {
+            None => None,
+            Some(slots) => match Arc::clone(slots).try_acquire_owned() {
+                Ok(slot) => Some(slot),
+                Err(_) => Some(self.wait_in_queue(slots).await?),
+            },
+        };
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        Some(Admitted {
+            shedder: self,
+            _slot: slot,
+        })
+    }
+
+    async fn wait_in_queue(&self, slots: &Arc<Semaphore>) -> Option<OwnedSemaphorePermit> {
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= self.max_queued {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        let _queued = Queued(&self.queued);
+        let acquire = Arc::clone(slots).acquire_owned();
+        match tokio::time::timeout(self.queue_timeout, acquire).await {
+            Ok(Ok(slot)) => Some(slot),
+            _ => {
+                self.shed.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Appends the in-flight and queued counts and the number of requests shed to `out` in
+    /// Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let _ = writeln!(
+            out,
+            "balancebeam_requests_in_flight {}",
+            self.in_flight.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_requests_queued {}",
+            self.queued.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_requests_shed_total {}",
+            self.shed.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// The response for a request that was shed.
+pub fn respond(
+    state: &ProxyState,
+    request_headers: Option<&http::HeaderMap>,
+) -> http::Response<Vec<u8>> {
+    let status = http::StatusCode::SERVICE_UNAVAILABLE;
+    let mut response = error_pages::make_error(state, status, request_headers);
+    response
+        .headers_mut()
+        .insert("Retry-After", RETRY_AFTER.into());
+    response
+}
@@ -1,38 +1,385 @@
+mod access_log;
+mod accounting;
+mod admin;
+mod balance;
+mod buffer_pool;
+mod build_info;
+mod capture;
+mod chunked;
+mod compression;
+mod connect;
+mod cors;
+mod error;
+mod error_pages;
+mod forward_auth;
+mod health_check;
+mod health_report;
+mod idempotency;
+mod live_upstreams;
+mod load_shed;
+mod maintenance;
+mod http2;
+mod outlier;
+mod probes;
+mod rate_limit_policy;
 mod request;
 mod response;
+mod retry_budget;
+mod rewrite;
+mod route_test;
+mod shutdown;
+mod sliding_window;
+mod sni;
+mod socket_activation;
+mod stats;
+mod supervise;
+mod tcp;
+mod throttle;
+mod trusted_proxies;
+mod upstream_tls;
+mod upstreams;
 
-use clap::Parser;
-use rand::{Rng, SeedableRng};
-use std::collections::{HashMap, VecDeque};
+use clap::{CommandFactory, FromArgMatches, Parser};
+use error::ProxyError;
 
 use std::time::{Duration, Instant};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use std::io::{Error, ErrorKind};
+use std::net::IpAddr;
 use tokio::time::sleep;
 
 use std::sync::Arc;
 
+/// Length of the sliding window used for rate limiting
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long we keep reading (and discarding) what a client sends after we've sent it an error and
+/// decided to hang up
+const LINGER_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug)]
 #[command(about = "Fun with load balancing")]
 struct CmdOptions {
-    /// "IP/port to bind to"
+    /// "IP/port to bind to (may be repeated to listen on several addresses). Append =http or =tcp
+    /// to override --mode for this listener. Ignored if systemd passes listening sockets"
     #[arg(short, long, default_value = "0.0.0.0:1100")]
-    bind: String,
+    bind: Vec<String>,
+    /// "What listeners proxy: HTTP requests, raw TCP connections (for databases and other non-HTTP
+    /// protocols), or TLS connections routed by SNI hostname without being decrypted"
+    #[arg(long, value_enum, default_value = "http")]
+    mode: tcp::Mode,
+    /// "Number of accept workers per bind address. Values above 1 bind the address several times
+    /// with SO_REUSEPORT so the kernel spreads new connections across the workers"
+    #[arg(long, default_value = "1")]
+    workers: usize,
     /// "Upstream host to forward requests to"
     #[arg(short, long)]
     upstream: Vec<String>,
+    /// "Read more upstream hosts from this file (or stdin, if -), separated by newlines or commas.
+    /// Without this or --upstream, the BB_UPSTREAMS environment variable is read the same way"
+    #[arg(long)]
+    upstream_file: Option<String>,
+    /// "Save the --upstream pool to this file whenever it is changed through the admin API, and
+    /// start with the saved pool instead of --upstream if the file exists"
+    #[arg(long)]
+    upstreams_file: Option<String>,
+    /// "A named group of upstreams, as NAME=HOST:PORT[,HOST:PORT...] (may be repeated). Only the
+    /// active group gets traffic, but all groups are health checked. Use instead of --upstream"
+    #[arg(long)]
+    upstream_group: Vec<String>,
+    /// "The --upstream-group that gets traffic at startup (default: the first one)"
+    #[arg(long)]
+    active_upstream_group: Option<String>,
+    /// "How to pick an upstream: at random or in turn for each client connection, by a hash of
+    /// each HTTP/1 request's path so that requests for the same resource hit the same upstream, or
+    /// by which upstream has been answering fastest"
+    #[arg(long, value_enum, default_value = "random")]
+    balance: balance::Strategy,
+    /// "Leave the query string out of the path hash for --balance path-hash"
+    #[arg(long)]
+    path_hash_ignore_query: bool,
+    /// "Seed for random upstream picks, to make them reproducible (default: a random seed)"
+    #[arg(long)]
+    rng_seed: Option<u64>,
+    /// "Send tls-passthrough connections for this SNI hostname to their own upstreams, as
+    /// HOSTNAME=HOST:PORT[,HOST:PORT...] (may be repeated). Other connections go to --upstream"
+    #[arg(long)]
+    sni_route: Vec<String>,
+    /// "PEM certificate (chain) to present to upstreams. Connections to upstreams use TLS when
+    /// this, --upstream-client-key or --upstream-ca is given"
+    #[arg(long)]
+    upstream_client_cert: Option<String>,
+    /// "PEM private key (PKCS#8) for --upstream-client-cert"
+    #[arg(long)]
+    upstream_client_key: Option<String>,
+    /// "PEM CA certificate to verify upstream certificates against, instead of the system's root
+    /// certificates"
+    #[arg(long)]
+    upstream_ca: Option<String>,
     /// "Perform active health checks on this interval (in seconds)"
     #[arg(long, default_value = "10")]
     active_health_check_interval: usize,
     /// "Path to send request to for active health checks"
     #[arg(long, default_value = "/")]
     active_health_check_path: String,
+    /// "Send active health checks over cleartext HTTP/2 (for upstreams that only speak HTTP/2,
+    /// like most gRPC servers)"
+    #[arg(long)]
+    active_health_check_http2: bool,
+    /// "Fail HTTP health checks whose response body doesn't contain this string"
+    #[arg(long)]
+    active_health_check_body_contains: Option<String>,
+    /// "Fail HTTP health checks whose response body doesn't match this regex"
+    #[arg(long)]
+    active_health_check_body_regex: Option<String>,
+    /// "Delay each health check by a random extra of up to this percentage of the interval"
+    #[arg(long, default_value = "10")]
+    active_health_check_jitter: usize,
+    /// "Count a proxied request that didn't get a 5xx as a passing health check for this long (in
+    /// seconds), and skip active probes of upstreams that passed within it (0 = always probe)"
+    #[arg(long, default_value = "0")]
+    passive_health_ttl: u64,
+    /// "Use a different health check interval and/or path for one upstream, as
+    /// HOST:PORT=[SECS][/PATH] (may be repeated)"
+    #[arg(long)]
+    health_check_override: Vec<String>,
+    /// "Maximum number of concurrent connections to each upstream (0 = unlimited). Saturated
+    /// upstreams are skipped; clients get a 503 if every live upstream is saturated"
+    #[arg(long, default_value = "0")]
+    max_connections_per_upstream: usize,
+    /// "Take an upstream out of rotation after this many consecutive 5xx responses (0 = disabled)"
+    #[arg(long, default_value = "0")]
+    outlier_consecutive_5xx: usize,
+    /// "Take an upstream out of rotation when at least this percentage of its responses within
+    /// the outlier window are 5xx (0 = disabled)"
+    #[arg(long, default_value = "0")]
+    outlier_5xx_percent: usize,
+    /// "Take an upstream out of rotation when its p99 latency within the outlier window exceeds
+    /// this multiple of the median latency across the other upstreams (0 = disabled)"
+    #[arg(long, default_value = "0")]
+    outlier_latency_multiple: f64,
+    /// "Length of the window used by --outlier-5xx-percent and --outlier-latency-multiple (in
+    /// seconds)"
+    #[arg(long, default_value = "30")]
+    outlier_window: u64,
+    /// "Minimum number of responses in the window before --outlier-5xx-percent applies"
+    #[arg(long, default_value = "10")]
+    outlier_min_requests: usize,
+    /// "How long an upstream stays out of rotation after being ejected (in seconds)"
+    #[arg(long, default_value = "30")]
+    outlier_ejection_time: u64,
+    /// "Limit failover retries (connecting to another upstream after a connection fails) to this
+    /// percentage of first attempts within the retry budget window (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    retry_budget_percent: u64,
+    /// "Retries allowed within each retry budget window on top of --retry-budget-percent"
+    #[arg(long, default_value = "3")]
+    retry_budget_min_retries: u64,
+    /// "Length of the window used by --retry-budget-percent (in seconds)"
+    #[arg(long, default_value = "10")]
+    retry_budget_window: u64,
+    /// "Maximum number of requests proxied at once (0 = unlimited). Requests beyond this (and
+    /// --max-queued-requests) get a 503 without being forwarded"
+    #[arg(long, default_value = "0")]
+    max_concurrent_requests: usize,
+    /// "Number of requests that may wait for one of the --max-concurrent-requests slots"
+    #[arg(long, default_value = "0")]
+    max_queued_requests: usize,
+    /// "How long a queued request waits for a slot before getting a 503 (in seconds)"
+    #[arg(long, default_value = "1")]
+    queue_timeout: u64,
     /// "Maximum number of requests to accept per IP per minute (0 = unlimited)"
     #[arg(long, default_value = "0")]
     max_requests_per_minute: usize,
+    /// "File of per-CIDR and per-API-key limits that override --max-requests-per-minute, reread
+    /// whenever it changes"
+    #[arg(long)]
+    rate_limit_policy: Option<String>,
+    /// "How often to check the --rate-limit-policy file for changes (in seconds)"
+    #[arg(long, default_value = "5")]
+    rate_limit_policy_reload_interval: u64,
+    /// "Request header holding the API key that --rate-limit-policy key: rules match"
+    #[arg(long, default_value = "X-Api-Key")]
+    rate_limit_api_key_header: String,
+    /// "Maximum number of clients tracked by the rate limiter; when it is reached, a client that
+    /// hasn't been seen for a while is forgotten to make room for a new one (0 = unlimited)"
+    #[arg(long, default_value = "0")]
+    rate_limit_max_clients: usize,
+    /// "Remember responses to requests with an Idempotency-Key header for this long (in seconds),
+    /// and answer retries with the same key from memory instead of forwarding them again (0 =
+    /// disabled)"
+    #[arg(long, default_value = "0")]
+    idempotency_key_ttl: u64,
+    /// "Maximum number of idempotency keys remembered at once"
+    #[arg(long, default_value = "10000")]
+    idempotency_max_keys: usize,
+    /// "Require approval from an external auth service for a route, as PREFIX=HOST:PORT[/PATH]
+    /// (may be repeated)"
+    #[arg(long)]
+    forward_auth: Vec<String>,
+    /// "Response header to copy from the auth service onto the proxied request, e.g. X-User (may be
+    /// repeated)"
+    #[arg(long)]
+    forward_auth_header: Vec<String>,
+    /// "How long the auth service has to answer a --forward-auth check before the request gets a
+    /// 504 (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "10")]
+    forward_auth_timeout: u64,
+    /// "Rewrite request paths before forwarding them, as PREFIX=REPLACEMENT or ~REGEX=REPLACEMENT;
+    /// the first matching rule applies (may be repeated)"
+    #[arg(long)]
+    rewrite: Vec<String>,
+    /// "Answer CORS preflights and add CORS headers to responses for a route, as
+    /// [HOST]PREFIX=ORIGIN[,ORIGIN...], where ORIGIN may be * (may be repeated)"
+    #[arg(long)]
+    cors: Vec<String>,
+    /// "Access-Control-Allow-Methods to answer CORS preflights with"
+    #[arg(long, default_value = "GET, HEAD, POST, PUT, PATCH, DELETE")]
+    cors_allow_methods: String,
+    /// "Access-Control-Allow-Headers to answer CORS preflights with (by default, whatever headers
+    /// the preflight asks for)"
+    #[arg(long)]
+    cors_allow_headers: Option<String>,
+    /// "Access-Control-Expose-Headers to add to responses on CORS routes"
+    #[arg(long)]
+    cors_expose_headers: Option<String>,
+    /// "Let browsers send credentials (cookies, HTTP auth) on cross-origin requests to CORS routes"
+    #[arg(long)]
+    cors_allow_credentials: bool,
+    /// "How long browsers may cache CORS preflight answers (in seconds)"
+    #[arg(long, default_value = "600")]
+    cors_max_age: u64,
+    /// "Gzip responses at the proxy for clients that accept it: off, auto (only responses the
+    /// upstream didn't compress), or proxy (also strip Accept-Encoding toward upstreams, so that
+    /// they never compress)"
+    #[arg(long, value_enum, default_value = "off")]
+    compression: compression::Mode,
+    /// "Allow CONNECT tunnels to this destination, as HOST:PORT where either half may be * (may be
+    /// repeated). CONNECT requests are refused if none are given"
+    #[arg(long)]
+    connect_allow: Vec<String>,
+    /// "Treat X-Forwarded-For from these front proxies as the client identity, as a CIDR or IP
+    /// (may be repeated)"
+    #[arg(long)]
+    trusted_proxies: Vec<trusted_proxies::Cidr>,
+    /// "Append per-upstream, per-route and per-API-key byte counts to this file as JSON lines
+    /// (disabled if not given)"
+    #[arg(long)]
+    accounting_file: Option<String>,
+    /// "How often accounting records are written (in seconds)"
+    #[arg(long, default_value = "60")]
+    accounting_interval: u64,
+    /// "Break accounting records down by this path prefix; requests count towards the longest
+    /// matching prefix (may be repeated)"
+    #[arg(long)]
+    accounting_route: Vec<String>,
+    /// "Break accounting records down by the value of this request header, e.g. X-Api-Key"
+    #[arg(long)]
+    accounting_api_key_header: Option<String>,
+    /// "Write an access log line for every request to this file (disabled if not given)"
+    #[arg(long)]
+    access_log: Option<String>,
+    /// "Rotate the access log once it would grow past this many bytes (0 = no size limit)"
+    #[arg(long, default_value = "0")]
+    access_log_max_size: u64,
+    /// "Rotate the access log after this many seconds (0 = no time limit)"
+    #[arg(long, default_value = "0")]
+    access_log_rotate_interval: u64,
+    /// "Number of rotated access log files to keep, as FILE.1 (newest) to FILE.N"
+    #[arg(long, default_value = "5")]
+    access_log_keep: usize,
+    /// "Directory of HTML templates (502.html, 429.html, error.html, ...) for errors that
+    /// balancebeam generates itself"
+    #[arg(long)]
+    error_pages: Option<String>,
+    /// "How often to reread the --error-pages templates (in seconds)"
+    #[arg(long, default_value = "5")]
+    error_pages_reload_interval: u64,
+    /// "Start in maintenance mode, answering requests with a 503 (can be toggled on the admin
+    /// listener)"
+    #[arg(long)]
+    maintenance: bool,
+    /// "Keep proxying requests from these clients in maintenance mode, as a CIDR or IP (may be
+    /// repeated)"
+    #[arg(long)]
+    maintenance_allow: Vec<trusted_proxies::Cidr>,
+    /// "Retry-After to send in maintenance mode (in seconds)"
+    #[arg(long, default_value = "300")]
+    maintenance_retry_after: u64,
+    /// "Start with body capture on, logging the heads and the start of the bodies of a sample of
+    /// requests and responses (can be toggled on the admin listener)"
+    #[arg(long)]
+    capture_bodies: bool,
+    /// "How much of each body to log when capturing (in bytes)"
+    #[arg(long, default_value = "1024")]
+    capture_max_bytes: usize,
+    /// "Percentage of requests to capture while body capture is on"
+    #[arg(long, default_value = "100")]
+    capture_sample_percent: u32,
+    /// "Regex matching the names of headers whose values are left out of captures (default:
+    /// Authorization, Proxy-Authorization, Cookie and Set-Cookie)"
+    #[arg(long)]
+    capture_redact_headers: Option<String>,
+    /// "Maximum size of a client's request line and headers (in bytes). Bigger requests get a 431"
+    #[arg(long, default_value = "16384")]
+    max_request_header_size: usize,
+    /// "Maximum number of headers in a client's request. Requests with more get a 431"
+    #[arg(long, default_value = "100")]
+    max_request_headers: usize,
+    /// "Maximum length of a client's request target (the path and query string, in bytes).
+    /// Requests with longer ones get a 414 (0 = no limit)"
+    #[arg(long, default_value = "8192")]
+    max_request_target_length: usize,
+    /// "Maximum length of a client's query string (in bytes). Requests with longer ones get a 414
+    /// (0 = no limit)"
+    #[arg(long, default_value = "0")]
+    max_query_length: usize,
+    /// "How long a client has to send a complete request head once it has started sending it (in
+    /// seconds, 0 = no limit)"
+    #[arg(long, default_value = "30")]
+    client_header_timeout: u64,
+    /// "Hang up on keep-alive connections that have been idle between requests for this long (in
+    /// seconds, 0 = never)"
+    #[arg(long, default_value = "60")]
+    client_idle_timeout: u64,
+    /// "Hang up on a client connection after answering this many requests on it, telling the
+    /// client so with Connection: close on the last response (0 = no limit)"
+    #[arg(long, visible_alias = "client-max-requests-per-conn", default_value = "0")]
+    max_requests_per_connection: usize,
+    /// "Hang up on a client connection after the first request that arrives once it has been open
+    /// this long, telling the client so with Connection: close (in seconds, 0 = no limit)"
+    #[arg(long, default_value = "0")]
+    client_max_conn_age: u64,
+    /// "After SIGTERM, wait at most this long for open client connections to finish before
+    /// exiting (in seconds)"
+    #[arg(long, default_value = "30")]
+    drain_timeout: u64,
+    /// "Hang up on clients that send request bodies slower than this many bytes per second on
+    /// average (0 = no minimum)"
+    #[arg(long, default_value = "0")]
+    min_body_rate: usize,
+    /// "Limit how fast response bodies are sent to each client (in bytes per second, 0 = no
+    /// limit)"
+    #[arg(long, default_value = "0")]
+    client_bandwidth: usize,
+    /// "Limit how fast responses to requests under a path prefix are sent to each client, as
+    /// PREFIX=BYTES_PER_SEC (may be repeated)"
+    #[arg(long)]
+    route_bandwidth: Vec<String>,
+    /// "Log (at debug level) every upstream pick: the candidates, their free connection slots and
+    /// mean latencies, and why the chosen one won"
+    #[arg(long)]
+    log_scheduler_decisions: bool,
+    /// "IP/port for the admin listener serving /metrics and /info (disabled if not given)"
+    #[arg(long)]
+    admin_bind: Option<String>,
+    /// "IP/port for a listener serving only the /healthz and /readyz probes (which the admin
+    /// listener serves too), for liveness and readiness checks by an orchestrator"
+    #[arg(long)]
+    probe_bind: Option<String>,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
@@ -46,15 +393,114 @@ struct ProxyState {
     /// Where we should send requests when doing active health checks (Milestone 4)
     #[allow(dead_code)]
     active_health_check_path: String,
+    /// Whether health checks are sent over HTTP/2
+    active_health_check_http2: bool,
+    /// What the body of a health check response must contain, if anything
+    active_health_check_body: Option<health_check::BodyMatch>,
+    /// Whether health checks only open a TCP connection instead of sending a request. Set when
+    /// no listener is in http mode, since the upstreams may not speak HTTP at all.
+    active_health_check_tcp: bool,
+    /// Maximum random delay added to each health check, as a percentage of its interval
+    active_health_check_jitter: usize,
+    /// How long a successful proxied request stands in for a health check (zero = never)
+    passive_health_ttl: Duration,
+    /// Per-upstream health check interval/path overrides
+    health_check_overrides: Vec<health_check::Override>,
     /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
     #[allow(dead_code)]
     max_requests_per_minute: usize,
-    /// Addresses of servers that we are proxying to
-    upstream_addresses: Vec<String>,
+    /// Addresses of servers that we are proxying to, across all pools
+    upstream_addresses: std::sync::RwLock<Vec<String>>,
+    /// The --upstream pool, which gets everything that isn't routed elsewhere. May be changed
+    /// through the admin API.
+    default_pool: std::sync::RwLock<Vec<String>>,
+    /// --upstream-group groups, and which one the default pool belongs to
+    upstream_groups: std::sync::RwLock<upstreams::Groups>,
+    /// Where the default pool is saved when it changes
+    upstreams_file: Option<String>,
+    /// Upstream pools for tls-passthrough connections, by SNI hostname
+    sni_routes: Vec<sni::Route>,
+    /// TLS settings for connections to upstreams, or None to connect in cleartext
+    upstream_tls: Option<upstream_tls::Config>,
+    /// How upstreams are picked
+    balance: Box<dyn balance::LoadBalancer>,
+    /// Stats, outlier detection state and connection slots for each upstream
+    upstreams: upstreams::Registry,
+    /// Thresholds for ejecting upstreams that keep returning 5xx responses
+    outlier_config: outlier::Config,
     /// Addresses of servers that are alive
-    liveing_upstreams: RwLock<Vec<String>>,
-    /// Map for rate limit count
-    rate_sliding_window: Mutex<HashMap<String, VecDeque<Instant>>>,
+    liveing_upstreams: live_upstreams::LiveUpstreams,
+    /// Limits failover retries so that failing upstreams don't multiply the load on the rest
+    retry_budget: retry_budget::RetryBudget,
+    /// Caps the number of requests in flight, turning away the excess
+    load_shedder: load_shed::LoadShedder,
+    /// Recent request times of each client, for rate limiting
+    rate_sliding_window: sliding_window::SlidingWindows,
+    /// Per-client limits that override max_requests_per_minute, if --rate-limit-policy is given
+    rate_limit_policy: Option<rate_limit_policy::Policy>,
+    /// Responses remembered for requests with an Idempotency-Key
+    idempotency: idempotency::Cache,
+    /// Routes that must be approved by an external auth service before being proxied
+    forward_auth_rules: Vec<forward_auth::Rule>,
+    /// Headers copied from auth service responses onto approved requests
+    forward_auth_headers: Vec<http::header::HeaderName>,
+    /// How long the auth service has to answer, if there's a limit
+    forward_auth_timeout: Option<Duration>,
+    /// Path rewrites, tried in order
+    rewrite_rules: Vec<rewrite::Rule>,
+    /// CORS rules and settings, if there are any --cors rules
+    cors: Option<cors::Cors>,
+    /// Who compresses responses, if anyone
+    compression: compression::Mode,
+    /// Front proxies whose X-Forwarded-For headers we believe
+    trusted_proxies: Vec<trusted_proxies::Cidr>,
+    /// Destinations that CONNECT requests may tunnel to
+    connect_allow: Vec<connect::AllowRule>,
+    /// Usage counted for chargeback, if --accounting-file is given
+    accounting: Option<accounting::Ledger>,
+    /// Where access log lines go, if --access-log is given
+    access_log: Option<access_log::AccessLog>,
+    /// Templates for balancebeam's own error responses, if --error-pages is given
+    error_pages: Option<error_pages::ErrorPages>,
+    /// Whether requests are being turned away for maintenance
+    maintenance: maintenance::Maintenance,
+    /// Debug logging of request and response bodies
+    capture: capture::Capture,
+    /// How big client request heads may get, and how slowly clients may send requests
+    request_limits: request::Limits,
+    /// How many requests a client connection may carry (0 = no limit)
+    max_requests_per_connection: usize,
+    /// How long a client connection may stay open before it is recycled
+    max_connection_age: Option<Duration>,
+    /// Open client connections, and whether we are shutting down
+    shutdown: shutdown::Shutdown,
+    /// Bandwidth limits for response bodies sent to clients
+    throttle: throttle::Throttle,
+    /// Whether to log why each upstream was picked
+    log_scheduler_decisions: bool,
+    /// Version and effective configuration, for GET /info
+    build_info: build_info::BuildInfo,
+}
+
+impl ProxyState {
+    /// A snapshot of the --upstream pool
+    fn default_pool(&self) -> Vec<String> {
+        self.default_pool.read().unwrap().clone()
+    }
+
+    /// Whether requests need to go through rate_limit at all
+    fn rate_limiting_enabled(&self) -> bool {
+        self.max_requests_per_minute > 0 || self.rate_limit_policy.is_some()
+    }
+
+    /// Whether the `served`th request on a client connection opened at `opened` has to be the last
+    /// one it carries, going by --max-requests-per-connection and --client-max-conn-age. Recycling
+    /// long-lived connections keeps per-connection state from growing without bound, and lets
+    /// clients that stick to one connection move to upstreams that were added since.
+    fn connection_spent(&self, served: usize, opened: Instant) -> bool {
+        served == self.max_requests_per_connection
+            || self.max_connection_age.is_some_and(|age| opened.elapsed() >= age)
+    }
 }
 
 #[tokio::main]
@@ -67,262 +513,1422 @@ async fn main() {
     }
     pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
+    // Parse the command line arguments passed to this program. The matches are kept as well as the
+    // parsed options so that the effective configuration can be reported.
+    let matches = CmdOptions::command().get_matches();
+    let mut options = CmdOptions::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let build_info = build_info::BuildInfo::new(&matches, &options);
+    build_info.log_banner();
+    if let Some(path) = &options.upstream_file {
+        match upstreams::read_list(path) {
+            Ok(list) => {
+                log::info!("Upstreams from --upstream-file {}: {:?}", path, list);
+                options.upstream.extend(list);
+            }
+            Err(err) => {
+                log::error!("Invalid --upstream-file: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else if options.upstream.is_empty() && options.upstream_group.is_empty() {
+        if let Ok(list) = std::env::var(upstreams::UPSTREAMS_ENV) {
+            options.upstream = upstreams::parse_list(&list);
+            log::info!(
+                "Upstreams from {}: {:?}",
+                upstreams::UPSTREAMS_ENV,
+                options.upstream
+            );
+        }
+    }
+    let mut upstream_groups = upstreams::Groups::default();
+    for spec in &options.upstream_group {
+        match upstreams::Group::parse(spec) {
+            Ok(group) => upstream_groups.groups.push(group),
+            Err(err) => {
+                log::error!("Invalid --upstream-group option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    if !upstream_groups.groups.is_empty() && !options.upstream.is_empty() {
+        log::error!("--upstream or --upstream-file can't be used with --upstream-group.");
+        std::process::exit(1);
+    }
+    if let Some(name) = &options.active_upstream_group {
+        match upstream_groups.groups.iter().position(|group| &group.name == name) {
+            Some(index) => upstream_groups.active = index,
+            None => {
+                log::error!("--active-upstream-group {} is not an --upstream-group.", name);
+                std::process::exit(1);
+            }
+        }
+    }
+    // A pool saved after changes through the admin API takes precedence over --upstream
+    let saved_pool = match options.upstreams_file.as_deref().map(upstreams::load_pool) {
+        Some(Ok(pool)) => pool,
+        Some(Err(err)) => {
+            log::error!("Invalid --upstreams-file: {}", err);
+            std::process::exit(1);
+        }
+        None => None,
+    };
+    if let Some(saved) = &saved_pool {
+        let groups = &upstream_groups.groups;
+        if let Some(index) = saved.group.as_ref().and_then(|name| {
+            groups.iter().position(|group| &group.name == name)
+        }) {
+            upstream_groups.active = index;
+        }
+    }
+    let default_pool = match (saved_pool, upstream_groups.groups.get_mut(upstream_groups.active)) {
+        (Some(saved), group) => {
+            log::info!(
+                "Using the upstream pool saved in --upstreams-file: {:?}",
+                saved.upstreams
+            );
+            if let Some(group) = group {
+                group.upstreams = saved.upstreams.clone();
+            }
+            saved.upstreams
+        }
+        (None, Some(group)) => group.upstreams.clone(),
+        (None, None) => options.upstream.clone(),
+    };
+    if let Some(group) = upstream_groups.active() {
+        log::info!("Sending traffic to upstream group {}", group.name);
+    }
+    if default_pool.len() < 1 {
+        log::error!(
+            "At least one upstream server must be specified using --upstream, --upstream-file \
+             or {}.",
+            upstreams::UPSTREAMS_ENV
+        );
         std::process::exit(1);
     }
+    if options.workers == 0 {
+        log::error!("--workers must be at least 1.");
+        std::process::exit(1);
+    }
+    if options.max_request_header_size == 0 || options.max_request_headers == 0 {
+        log::error!("--max-request-header-size and --max-request-headers must be at least 1.");
+        std::process::exit(1);
+    }
+    if options.capture_sample_percent > 100 {
+        log::error!("--capture-sample-percent must be at most 100.");
+        std::process::exit(1);
+    }
+    let capture_redact = options
+        .capture_redact_headers
+        .as_deref()
+        .unwrap_or(capture::DEFAULT_REDACT_HEADERS);
+    let capture_redact = match regex::Regex::new(capture_redact) {
+        Ok(regex) => regex,
+        Err(err) => {
+            log::error!("Invalid --capture-redact-headers option: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let active_health_check_body = match (
+        options.active_health_check_body_contains,
+        &options.active_health_check_body_regex,
+    ) {
+        (Some(_), Some(_)) => {
+            log::error!(
+                "--active-health-check-body-contains and --active-health-check-body-regex can't \
+                 be used together."
+            );
+            std::process::exit(1);
+        }
+        (Some(expected), None) => Some(health_check::BodyMatch::Contains(expected)),
+        (None, Some(pattern)) => match regex::bytes::Regex::new(pattern) {
+            Ok(regex) => Some(health_check::BodyMatch::Regex(regex)),
+            Err(err) => {
+                log::error!("Invalid --active-health-check-body-regex option: {}", err);
+                std::process::exit(1);
+            }
+        },
+        (None, None) => None,
+    };
+
+    let mut forward_auth_rules = Vec::with_capacity(options.forward_auth.len());
+    for spec in &options.forward_auth {
+        match forward_auth::Rule::parse(spec) {
+            Ok(rule) => forward_auth_rules.push(rule),
+            Err(err) => {
+                log::error!("Invalid --forward-auth option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut forward_auth_headers = Vec::with_capacity(options.forward_auth_header.len());
+    for name in &options.forward_auth_header {
+        match http::header::HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => forward_auth_headers.push(name),
+            Err(err) => {
+                log::error!("Invalid --forward-auth-header {}: {}", name, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut rewrite_rules = Vec::with_capacity(options.rewrite.len());
+    for spec in &options.rewrite {
+        match rewrite::Rule::parse(spec) {
+            Ok(rule) => rewrite_rules.push(rule),
+            Err(err) => {
+                log::error!("Invalid --rewrite option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut cors_rules = Vec::with_capacity(options.cors.len());
+    for spec in &options.cors {
+        match cors::Rule::parse(spec) {
+            Ok(rule) => cors_rules.push(rule),
+            Err(err) => {
+                log::error!("Invalid --cors option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let cors = if cors_rules.is_empty() {
+        None
+    } else {
+        match cors::Cors::new(
+            cors_rules,
+            &options.cors_allow_methods,
+            options.cors_allow_headers.as_deref(),
+            options.cors_expose_headers.as_deref(),
+            options.cors_allow_credentials,
+            options.cors_max_age,
+        ) {
+            Ok(cors) => Some(cors),
+            Err(err) => {
+                log::error!("Invalid CORS settings: {}", err);
+                std::process::exit(1);
+            }
+        }
+    };
+
+    let mut connect_allow = Vec::with_capacity(options.connect_allow.len());
+    for spec in &options.connect_allow {
+        match connect::AllowRule::parse(spec) {
+            Ok(rule) => connect_allow.push(rule),
+            Err(err) => {
+                log::error!("Invalid --connect-allow option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let mut sni_routes = Vec::with_capacity(options.sni_route.len());
+    for spec in &options.sni_route {
+        match sni::Route::parse(spec) {
+            Ok(route) => sni_routes.push(route),
+            Err(err) => {
+                log::error!("Invalid --sni-route option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut bandwidth_routes = Vec::with_capacity(options.route_bandwidth.len());
+    for spec in &options.route_bandwidth {
+        match throttle::Route::parse(spec) {
+            Ok(route) => bandwidth_routes.push(route),
+            Err(err) => {
+                log::error!("Invalid --route-bandwidth option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    // Upstreams from every pool get the same health checks, stats and outlier detection
+    let mut all_upstreams = default_pool.clone();
+    for upstream in upstream_groups.groups.iter().flat_map(|group| &group.upstreams) {
+        if !all_upstreams.contains(upstream) {
+            all_upstreams.push(upstream.clone());
+        }
+    }
+    for route in &sni_routes {
+        for upstream in &route.upstreams {
+            if !all_upstreams.contains(upstream) {
+                all_upstreams.push(upstream.clone());
+            }
+        }
+    }
+
+    let upstream_tls = if options.upstream_client_cert.is_some()
+        || options.upstream_client_key.is_some()
+        || options.upstream_ca.is_some()
+    {
+        match upstream_tls::Config::load(
+            options.upstream_client_cert.as_deref(),
+            options.upstream_client_key.as_deref(),
+            options.upstream_ca.as_deref(),
+        ) {
+            Ok(config) => Some(config),
+            Err(err) => {
+                log::error!("Invalid upstream TLS settings: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    let accounting = match &options.accounting_file {
+        Some(path) => {
+            if options.accounting_interval == 0 {
+                log::error!("--accounting-interval must be at least 1.");
+                std::process::exit(1);
+            }
+            let api_key_header = match &options.accounting_api_key_header {
+                Some(name) => match http::header::HeaderName::from_bytes(name.as_bytes()) {
+                    Ok(name) => Some(name),
+                    Err(err) => {
+                        log::error!("Invalid --accounting-api-key-header {}: {}", name, err);
+                        std::process::exit(1);
+                    }
+                },
+                None => None,
+            };
+            Some(accounting::Ledger::new(
+                path.clone(),
+                Duration::from_secs(options.accounting_interval),
+                options.accounting_route.clone(),
+                api_key_header,
+            ))
+        }
+        None => None,
+    };
+
+    let error_pages = match &options.error_pages {
+        Some(dir) => {
+            let reload_interval = Duration::from_secs(options.error_pages_reload_interval);
+            match error_pages::ErrorPages::load(dir.clone(), reload_interval) {
+                Ok(pages) => Some(pages),
+                Err(err) => {
+                    log::error!("Invalid --error-pages: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let access_log = match &options.access_log {
+        Some(path) => {
+            let config = access_log::Config {
+                path: path.clone(),
+                max_size: options.access_log_max_size,
+                rotate_interval: match options.access_log_rotate_interval {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                },
+                keep: options.access_log_keep,
+            };
+            match access_log::AccessLog::start(config).await {
+                Ok(access_log) => Some(access_log),
+                Err(err) => {
+                    log::error!("Could not start the access log: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let rate_limit_policy = match &options.rate_limit_policy {
+        Some(path) => {
+            let header = match http::header::HeaderName::from_bytes(
+                options.rate_limit_api_key_header.as_bytes(),
+            ) {
+                Ok(header) => header,
+                Err(err) => {
+                    log::error!(
+                        "Invalid --rate-limit-api-key-header {}: {}",
+                        options.rate_limit_api_key_header,
+                        err
+                    );
+                    std::process::exit(1);
+                }
+            };
+            let reload_interval = Duration::from_secs(options.rate_limit_policy_reload_interval);
+            match rate_limit_policy::Policy::load(path.clone(), header, reload_interval) {
+                Ok(policy) => Some(policy),
+                Err(err) => {
+                    log::error!("Invalid --rate-limit-policy: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut health_check_overrides = Vec::with_capacity(options.health_check_override.len());
+    for spec in &options.health_check_override {
+        match health_check::Override::parse(spec) {
+            Ok(o) if all_upstreams.contains(&o.upstream) => health_check_overrides.push(o),
+            Ok(o) => {
+                log::error!("--health-check-override for unknown upstream {}", o.upstream);
+                std::process::exit(1);
+            }
+            Err(err) => {
+                log::error!("Invalid --health-check-override option: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    // Start listening for connections
-    let listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
+    // Start listening for connections. We bind every address up front so that a typo in one of
+    // them fails fast instead of leaving a half-started proxy.
+    let mut listeners = Vec::with_capacity(options.bind.len());
+    let activated = match socket_activation::listeners(options.mode) {
+        Ok(activated) => activated.unwrap_or_default(),
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            log::error!("Socket activation failed: {}", err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    for (name, listener, mode) in activated {
+        match TcpListener::from_std(listener) {
+            Ok(listener) => {
+                log::info!("Listening for {:?} connections on {} from systemd", mode, name);
+                listeners.push((name, listener, mode));
+            }
+            Err(err) => {
+                log::error!("Could not use passed socket {}: {}", name, err);
+                std::process::exit(1);
+            }
+        }
+    }
+    // --bind is only used if systemd didn't pass us any sockets
+    let binds: &[String] = if listeners.is_empty() { &options.bind } else { &[] };
+    for spec in binds {
+        let (bind, mode) = match spec.split_once('=') {
+            Some((bind, mode)) => match clap::ValueEnum::from_str(mode, true) {
+                Ok(mode) => (bind, mode),
+                Err(err) => {
+                    log::error!("Invalid mode in --bind {}: {}", spec, err);
+                    std::process::exit(1);
+                }
+            },
+            None => (spec.as_str(), options.mode),
+        };
+        if options.workers == 1 {
+            match TcpListener::bind(bind).await {
+                Ok(listener) => {
+                    log::info!("Listening for {:?} connections on {}", mode, bind);
+                    listeners.push((bind.to_string(), listener, mode));
+                }
+                Err(err) => {
+                    log::error!("Could not bind to {}: {}", bind, err);
+                    std::process::exit(1);
+                }
+            }
+        } else {
+            match bind_reuseport(bind, options.workers).await {
+                Ok(worker_listeners) => {
+                    log::info!(
+                        "Listening for {:?} connections on {} with {} SO_REUSEPORT workers",
+                        mode,
+                        bind,
+                        options.workers
+                    );
+                    for (worker, listener) in worker_listeners.into_iter().enumerate() {
+                        listeners.push((format!("{} (worker {})", bind, worker), listener, mode));
+                    }
+                }
+                Err(err) => {
+                    log::error!("Could not bind to {}: {}", bind, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    let tcp_only = listeners.iter().all(|(_, _, mode)| *mode != tcp::Mode::Http);
 
     // Handle incoming connections
     let state = Arc::new(ProxyState {
-        upstream_addresses: options.upstream.clone(),
-        liveing_upstreams: RwLock::new(options.upstream),
+        balance: balance::new(
+            options.balance,
+            options.path_hash_ignore_query,
+            options.rng_seed,
+        ),
+        upstreams: upstreams::Registry::new(&all_upstreams, options.max_connections_per_upstream),
+        outlier_config: outlier::Config {
+            consecutive_5xx: options.outlier_consecutive_5xx,
+            percent_5xx: options.outlier_5xx_percent,
+            latency_multiple: options.outlier_latency_multiple,
+            window: Duration::from_secs(options.outlier_window),
+            min_requests: options.outlier_min_requests,
+            ejection_time: Duration::from_secs(options.outlier_ejection_time),
+        },
+        upstream_addresses: std::sync::RwLock::new(all_upstreams.clone()),
+        liveing_upstreams: live_upstreams::LiveUpstreams::new(&all_upstreams),
+        retry_budget: retry_budget::RetryBudget::new(
+            options.retry_budget_percent,
+            options.retry_budget_min_retries,
+            Duration::from_secs(options.retry_budget_window),
+        ),
+        load_shedder: load_shed::LoadShedder::new(
+            options.max_concurrent_requests,
+            options.max_queued_requests,
+            Duration::from_secs(options.queue_timeout),
+        ),
+        default_pool: std::sync::RwLock::new(default_pool),
+        upstream_groups: std::sync::RwLock::new(upstream_groups),
+        upstreams_file: options.upstreams_file,
+        sni_routes,
+        upstream_tls,
         active_health_check_interval: options.active_health_check_interval,
         active_health_check_path: options.active_health_check_path,
+        active_health_check_http2: options.active_health_check_http2,
+        active_health_check_body,
+        active_health_check_tcp: tcp_only,
+        active_health_check_jitter: options.active_health_check_jitter,
+        passive_health_ttl: Duration::from_secs(options.passive_health_ttl),
+        health_check_overrides,
         max_requests_per_minute: options.max_requests_per_minute,
-        rate_sliding_window: Mutex::new(HashMap::new()),
+        rate_sliding_window: sliding_window::SlidingWindows::new(options.rate_limit_max_clients),
+        rate_limit_policy,
+        idempotency: idempotency::Cache::new(
+            Duration::from_secs(options.idempotency_key_ttl),
+            options.idempotency_max_keys,
+        ),
+        forward_auth_rules,
+        forward_auth_headers,
+        forward_auth_timeout: match options.forward_auth_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        rewrite_rules,
+        cors,
+        compression: options.compression,
+        trusted_proxies: options.trusted_proxies,
+        connect_allow,
+        accounting,
+        access_log,
+        error_pages,
+        maintenance: maintenance::Maintenance::new(
+            options.maintenance,
+            options.maintenance_allow,
+            options.maintenance_retry_after,
+        ),
+        capture: capture::Capture::new(
+            options.capture_bodies,
+            options.capture_max_bytes,
+            options.capture_sample_percent,
+            capture_redact,
+        ),
+        request_limits: request::Limits {
+            max_header_size: options.max_request_header_size,
+            max_headers: options.max_request_headers,
+            max_target_length: options.max_request_target_length,
+            max_query_length: options.max_query_length,
+            header_timeout: match options.client_header_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            idle_timeout: match options.client_idle_timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            min_body_rate: options.min_body_rate,
+        },
+        max_requests_per_connection: options.max_requests_per_connection,
+        max_connection_age: match options.client_max_conn_age {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        shutdown: shutdown::Shutdown::new(Duration::from_secs(options.drain_timeout)),
+        throttle: throttle::Throttle::new(options.client_bandwidth, bandwidth_routes),
+        log_scheduler_decisions: options.log_scheduler_decisions,
+        build_info,
     });
 
-    let state_temp = Arc::clone(&state);
-    tokio::spawn(async move {
-        active_health_check(state_temp).await;
-    });
+    health_check::spawn_all(&state);
 
-    // Handle incoming connections.
-    loop {
-        let (stream, _addr) = match listener.accept().await {
-            Ok(pair) => pair,
+    if state.outlier_config.latency_multiple > 0.0 {
+        let state = Arc::clone(&state);
+        supervise::spawn("outlier latency watch".to_string(), move || {
+            outlier::watch_latency(Arc::clone(&state))
+        });
+    }
+
+    if state.rate_limiting_enabled() {
+        let state = Arc::clone(&state);
+        supervise::spawn("rate limit eviction".to_string(), move || {
+            evict_stale_rate_limit_entries(Arc::clone(&state))
+        });
+    }
+
+    if state.rate_limit_policy.is_some() {
+        let state = Arc::clone(&state);
+        supervise::spawn("rate limit policy reload".to_string(), move || {
+            rate_limit_policy::reload_periodically(Arc::clone(&state))
+        });
+    }
+
+    if state.error_pages.is_some() {
+        let state = Arc::clone(&state);
+        supervise::spawn("error page reload".to_string(), move || {
+            error_pages::reload_periodically(Arc::clone(&state))
+        });
+    }
+
+    if state.accounting.is_some() {
+        let state = Arc::clone(&state);
+        supervise::spawn("accounting flush".to_string(), move || {
+            accounting::flush_periodically(Arc::clone(&state))
+        });
+    }
+
+    if let Some(admin_bind) = &options.admin_bind {
+        let admin_listener = match TcpListener::bind(admin_bind).await {
+            Ok(listener) => listener,
             Err(err) => {
-                log::error!("accept error: {}", err);
-                continue;
+                log::error!("Could not bind admin listener to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!("Admin listener on {}", admin_bind);
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            admin::serve(admin_listener, state).await;
+        });
+    }
+
+    if let Some(probe_bind) = &options.probe_bind {
+        let probe_listener = match TcpListener::bind(probe_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind probe listener to {}: {}", probe_bind, err);
+                std::process::exit(1);
             }
         };
+        log::info!("Probe listener on {}", probe_bind);
         let state = Arc::clone(&state);
         tokio::spawn(async move {
-            handle_connection(stream, state).await;
+            admin::serve_probes(probe_listener, state).await;
         });
     }
+
+    // Handle incoming connections. Each listener gets its own accept loop; they all share the same
+    // ProxyState.
+    let mut accept_tasks = tokio::task::JoinSet::new();
+    for (address, listener, mode) in listeners {
+        let state = Arc::clone(&state);
+        accept_tasks.spawn(async move {
+            accept_loop(address, listener, mode, state).await;
+        });
+    }
+    let status = shutdown::wait_for_signal(&state, accept_tasks).await;
+    std::process::exit(status);
 }
 
-async fn active_health_check(state: Arc<ProxyState>) {
-    loop {
-        // Sleep for the configured interval before each probe round
-        let interval_secs = state.active_health_check_interval as u64;
-        sleep(Duration::from_secs(interval_secs)).await;
-
-        let targets = state.upstream_addresses.clone();
-        let mut healthy = Vec::with_capacity(targets.len());
-
-        for upstream in targets {
-            let req = http::Request::builder()
-                .method(http::Method::GET)
-                .uri(state.active_health_check_path.as_str())
-                .header("Host", upstream.as_str())
-                .body(Vec::new())
-                .unwrap();
-
-            match TcpStream::connect(&upstream).await {
-                Ok(mut conn) => {
-                    if let Err(err) = request::write_to_stream(&req, &mut conn).await {
-                        log::error!("health check write to {} failed: {}", upstream, err);
-                        continue;
-                    }
+/// Binds `workers` separate sockets to the same address with SO_REUSEPORT, so that the kernel load
+/// balances incoming connections between them instead of funneling every accept through one socket.
+async fn bind_reuseport(address: &str, workers: usize) -> Result<Vec<TcpListener>, Error> {
+    let addr = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "address did not resolve"))?;
+    let mut listeners = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(addr)?;
+        listeners.push(socket.listen(1024)?);
+    }
+    Ok(listeners)
+}
 
-                    let response = match response::read_from_stream(&mut conn, &req.method()).await {
-                        Ok(r) => r,
-                        Err(err) => {
-                            log::error!("health check read from {} failed: {:?}", upstream, err);
-                            continue;
-                        }
-                    };
-
-                    if response.status().as_u16() == 200 {
-                        healthy.push(upstream);
-                    } else {
-                        log::warn!(
-                            "health check {} returned non-200 status: {}",
-                            upstream,
-                            response.status()
-                        );
-                    }
-                }
-                Err(err) => {
-                    log::error!("health check connect to {} failed: {}", upstream, err);
+/// Accepts connections on a single listener and spawns a task to handle each one. Keeps a couple of
+/// per-listener counters so that the logs show how traffic is split between listeners.
+async fn accept_loop(
+    address: String,
+    listener: TcpListener,
+    mode: tcp::Mode,
+    state: Arc<ProxyState>,
+) {
+    let mut accepted: u64 = 0;
+    let mut accept_errors: u64 = 0;
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(pair) => pair,
+            Err(err) => {
+                accept_errors += 1;
+                log::error!(
+                    "accept error on {}: {} ({} errors so far)",
+                    address,
+                    err,
+                    accept_errors
+                );
+                continue;
+            }
+        };
+        accepted += 1;
+        log::debug!(
+            "Listener {}: {} connections accepted, {} accept errors",
+            address,
+            accepted,
+            accept_errors
+        );
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            let tracked = Arc::clone(&state);
+            let _tracked = tracked.shutdown.track(&stream);
+            match mode {
+                tcp::Mode::Http => handle_connection(stream, state).await,
+                tcp::Mode::Tcp => {
+                    let pool = state.default_pool();
+                    tcp::tunnel(stream, addr.ip(), Arc::clone(&state), &pool).await
                 }
+                tcp::Mode::TlsPassthrough => sni::route(stream, addr.ip(), state).await,
             }
-        }
-
-        let mut live = state.liveing_upstreams.write().await;
-        *live = healthy;
+        });
     }
 }
 
-async fn rate_limiting_check(state: Arc<ProxyState>, client: &mut TcpStream) -> Result<(), Error> {
-    let client_ip = client.peer_addr().unwrap().ip().to_string();
+/// Periodically forgets clients that haven't sent a request within the rate limiting window. Their
+/// deques would be empty (or entirely stale) anyway, and without this the map grows forever as new
+/// clients come and go.
+async fn evict_stale_rate_limit_entries(state: Arc<ProxyState>) {
+    loop {
+        sleep(RATE_LIMIT_WINDOW).await;
+        let cutoff = Instant::now() - RATE_LIMIT_WINDOW;
+        let (evicted, remaining) = state.rate_sliding_window.evict_stale(cutoff);
+        log::debug!(
+            "Evicted {} stale rate limit entries ({} clients still tracked)",
+            evicted,
+            remaining
+        );
+    }
+}
 
+/// Counts a request from `client_ip` against its rate limit. Returns the 429 response to send back
+/// if the client is over the limit.
+async fn rate_limit(
+    state: &ProxyState,
+    client_ip: String,
+    request: &http::Request<Vec<u8>>,
+) -> Option<http::Response<Vec<u8>>> {
+    // The policy file may give this client (or its API key) a limit of its own
+    let (client_ip, limit) = match &state.rate_limit_policy {
+        Some(policy) => policy.limit_for(&client_ip, request, state.max_requests_per_minute),
+        None => (client_ip, state.max_requests_per_minute),
+    };
+    if limit == 0 {
+        return None;
+    }
     let now = Instant::now();
-    let window = Duration::from_secs(60);
-    let cutoff = now - window;
-
-    let mut map = state.rate_sliding_window.lock().await;
-    let deque = map.entry(client_ip).or_insert(VecDeque::new());
-
-    while matches!(deque.front(), Some(ts) if *ts < cutoff) {
-        deque.pop_front();
+    let windows = &state.rate_sliding_window;
+    if let Some(wait) = windows.hit(client_ip, limit, RATE_LIMIT_WINDOW, now) {
+        // The client can send again once the oldest request in the window falls out of it. Round
+        // up so that clients honoring Retry-After don't come back a fraction of a second too early.
+        let retry_after = (wait.as_millis() as u64).div_ceil(1000);
+        let retry_after = retry_after.max(1).to_string();
+        let status = http::StatusCode::TOO_MANY_REQUESTS;
+        let mut response = error_pages::make_error(state, status, Some(request.headers()));
+        let headers = response.headers_mut();
+        headers.insert("Retry-After", retry_after.parse().unwrap());
+        headers.insert("RateLimit-Limit", limit.into());
+        headers.insert("RateLimit-Remaining", 0.into());
+        headers.insert("RateLimit-Reset", retry_after.parse().unwrap());
+        return Some(response);
     }
+    None
+}
 
-    if deque.len() >= state.max_requests_per_minute {
-        let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-        if let Err(e) = response::write_to_stream(&response, client).await {
-            log::warn!("Failed to send 429: {}", e);
+/// Reasons we couldn't open a connection to any upstream
+#[derive(Debug)]
+enum UpstreamError {
+    /// Every upstream is dead
+    NoneAvailable,
+    /// Some upstreams are alive, but all of them are already at their connection cap
+    AtCapacity,
+    /// A connection failed, and the retry budget doesn't allow trying another upstream
+    RetryBudgetSpent,
+}
+
+impl UpstreamError {
+    /// The status to send back to the client
+    fn status(&self) -> http::StatusCode {
+        match self {
+            UpstreamError::NoneAvailable => http::StatusCode::BAD_GATEWAY,
+            UpstreamError::AtCapacity | UpstreamError::RetryBudgetSpent => {
+                http::StatusCode::SERVICE_UNAVAILABLE
+            }
         }
-        return Err(Error::new(ErrorKind::Other, "Too many requests"));
     }
-
-    deque.push_back(now);
-    Ok(())
 }
 
-async fn connect_to_upstream(state: Arc<ProxyState>) -> Result<TcpStream, std::io::Error> {
-    let mut rng = rand::rngs::StdRng::from_entropy();
+/// Connects to a live upstream from `pool`, failing over to another one if the connection fails.
+/// The upstream is picked by the --balance strategy, with `key` (see
+/// balance::LoadBalancer::request_key) if there is one. The strategy counts the connection as
+/// open, and it keeps its slot, for as long as the returned Lease is kept.
+/// `peer_ip` is only used for logging.
+async fn connect_to_upstream(
+    state: Arc<ProxyState>,
+    pool: &[String],
+    peer_ip: IpAddr,
+    key: Option<u64>,
+) -> Result<(Arc<str>, upstream_tls::Stream, balance::Lease), UpstreamError> {
+    state.retry_budget.record_attempt();
+    // Whether a connection has failed, so that trying another upstream is a retry
+    let mut failed = false;
     loop {
-        let upstreams = state.liveing_upstreams.read().await;
+        let live = state.liveing_upstreams.load();
+        let upstreams: Vec<&Arc<str>> = live
+            .iter()
+            .filter(|address| pool.iter().any(|a| a == &***address))
+            .collect();
         if upstreams.len() == 0 {
             break;
         }
-        let upstream_idx = rng.gen_range(0..upstreams.len());
-        let upstream_ip = upstreams[upstream_idx].clone();
-        drop(upstreams);
+        // Only consider upstreams that have a free connection slot
+        let open: Vec<&Arc<str>> = upstreams
+            .iter()
+            .copied()
+            .filter(|address| match &state.upstreams.get(address).slots {
+                Some(slots) => slots.available_permits() > 0,
+                None => true,
+            })
+            .collect();
+        if open.is_empty() {
+            return Err(UpstreamError::AtCapacity);
+        }
+        // Skip upstreams that outlier detection has ejected. If every one of them has been
+        // ejected, keep using them anyway rather than failing every request.
+        let not_ejected: Vec<&Arc<str>> = open
+            .iter()
+            .copied()
+            .filter(|address| !state.upstreams.get(address).outlier.is_ejected())
+            .collect();
+        let all_ejected = not_ejected.is_empty();
+        let candidates = if all_ejected { open } else { not_ejected };
+        if failed && !state.retry_budget.try_retry() {
+            log::warn!("Retry budget spent; not failing over to another upstream");
+            return Err(UpstreamError::RetryBudgetSpent);
+        }
+        failed = false;
+        let upstream_ip = Arc::clone(state.balance.pick(&candidates, key));
+        if state.log_scheduler_decisions {
+            let decision = (&candidates[..], &*upstream_ip, all_ejected, key);
+            log_scheduler_decision(&state, peer_ip, pool, &live, decision);
+        }
+
+        let permit = match &state.upstreams.get(&upstream_ip).slots {
+            Some(slots) => match Arc::clone(slots).try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                // Someone else took the last slot since we looked; pick again
+                Err(_) => continue,
+            },
+            None => None,
+        };
 
-        match TcpStream::connect(&upstream_ip).await {
-            Ok(stream) => return Ok(stream),
-            Err(_) => {
-                let mut upstreams = state.liveing_upstreams.write().await;
-                upstreams.remove(upstream_idx);
+        match upstream_tls::connect(state.upstream_tls.as_ref(), &upstream_ip).await {
+            Ok(stream) => {
+                let lease =
+                    balance::Lease::new(Arc::clone(&state), Arc::clone(&upstream_ip), permit);
+                return Ok((upstream_ip, stream, lease));
+            }
+            Err(err) => {
+                log::warn!("Could not connect to upstream {}: {}", upstream_ip, err);
+                state.upstreams.get(&upstream_ip).stats.record_error();
+                state.balance.report_result(&upstream_ip, &balance::Outcome::Failed);
+                let upstreams = &state.liveing_upstreams;
+                upstreams.update(|live| live.retain(|address| *address != upstream_ip));
+                failed = true;
             }
         }
     }
     // Implement failover (milestone 3)
-    Err(std::io::Error::new(
-    std::io::ErrorKind::Other,
-    "No available upstream servers",
-    ))
+    Err(UpstreamError::NoneAvailable)
+}
+
+/// The upstream in `pool` that requests with `key` belong on: the one connect_to_upstream would
+/// pick if every upstream had a free connection slot. None if no upstream in `pool` is live.
+fn preferred_upstream(state: &ProxyState, pool: &[String], key: u64) -> Option<Arc<str>> {
+    let live = state.liveing_upstreams.load();
+    let upstreams: Vec<&Arc<str>> = live
+        .iter()
+        .filter(|address| pool.iter().any(|a| a == &***address))
+        .collect();
+    if upstreams.is_empty() {
+        return None;
+    }
+    let not_ejected: Vec<&Arc<str>> = upstreams
+        .iter()
+        .copied()
+        .filter(|address| !state.upstreams.get(address).outlier.is_ejected())
+        .collect();
+    let candidates = if not_ejected.is_empty() { upstreams } else { not_ejected };
+    Some(Arc::clone(state.balance.pick(&candidates, Some(key))))
+}
+
+/// Logs which upstream connect_to_upstream picked for `peer_ip`, from which candidates, and why,
+/// along with the state of every upstream in `pool`. `decision` is the candidates, the upstream
+/// picked from them, whether they had all been ejected, and the key it was picked by.
+fn log_scheduler_decision(
+    state: &ProxyState,
+    peer_ip: IpAddr,
+    pool: &[String],
+    live: &live_upstreams::Snapshot,
+    decision: (&[&Arc<str>], &str, bool, Option<u64>),
+) {
+    let (candidates, chosen, all_ejected, key) = decision;
+    let mut upstreams = Vec::with_capacity(pool.len());
+    for address in pool {
+        let upstream = state.upstreams.get(address);
+        let slots_free = upstream.slots.as_ref().map(|slots| slots.available_permits());
+        let status = if !live.contains(address) {
+            "down"
+        } else if candidates.iter().any(|candidate| &***candidate == address) {
+            "candidate"
+        } else if slots_free == Some(0) {
+            "at capacity"
+        } else {
+            "ejected"
+        };
+        let mut details = Vec::new();
+        if let Some(slots_free) = slots_free {
+            details.push(format!("{} slots free", slots_free));
+        }
+        details.push(match upstream.stats.mean_latency() {
+            Some(latency) => format!("mean latency {:?}", latency),
+            None => "no responses yet".to_string(),
+        });
+        upstreams.push(format!("{} {} ({})", address, status, details.join(", ")));
+    }
+    let mut reason = if candidates.len() == 1 {
+        "the only candidate".to_string()
+    } else {
+        state.balance.describe(candidates.len(), key)
+    };
+    if all_ejected {
+        reason += ", since every upstream with a free slot is ejected";
+    }
+    log::debug!(
+        "Scheduler: {} -> {}: {}; {}",
+        peer_ip,
+        chosen,
+        reason,
+        upstreams.join("; ")
+    );
+}
+
+/// Returns `response`, with Connection: close added if `last` is set, to tell the client that
+/// we hang up after sending it.
+fn mark_last(mut response: http::Response<Vec<u8>>, last: bool) -> http::Response<Vec<u8>> {
+    if last {
+        response.headers_mut().insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+    }
+    response
 }
 
 async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
+    send_response_limited(client_conn, response, &throttle::Limiter::unlimited()).await
+}
+
+/// Like send_response, but the body is paced by `limiter`.
+async fn send_response_limited(
+    client_conn: &mut TcpStream,
+    response: &http::Response<Vec<u8>>,
+    limiter: &throttle::Limiter,
+) {
     let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
     log::info!(
         "{} <- {}",
         client_ip,
         response::format_response_line(&response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    if let Err(error) = response::write_to_stream_limited(&response, client_conn, limiter).await {
         log::warn!("Failed to send response to client: {}", error);
         return;
     }
 }
 
+/// Hangs up on a client after an error response. The client may still be sending the request we
+/// gave up on (a body we won't read, or the rest of an oversized head), and closing with that data
+/// unread would reset the connection, which can destroy the response before the client reads it.
+/// So we stop sending, then read and throw away whatever still comes for up to LINGER_TIMEOUT.
+async fn linger_close(mut client_conn: TcpStream) {
+    if client_conn.shutdown().await.is_err() {
+        return;
+    }
+    let mut buffer = [0_u8; 4096];
+    let drain = async {
+        while let Ok(1..) = client_conn.read(&mut buffer).await {}
+    };
+    let _ = tokio::time::timeout(LINGER_TIMEOUT, drain).await;
+}
+
+/// Writes an access log line for a request that balancebeam answered itself.
+fn log_local_response(
+    state: &ProxyState,
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    response: &http::Response<Vec<u8>>,
+    started: Instant,
+) {
+    let (status, bytes) = (response.status(), response.body().len());
+    log_access(state, client_ip, request, status, bytes, None, started);
+}
+
+/// Writes an access log line for a request, if access logging is on.
+fn log_access(
+    state: &ProxyState,
+    client_ip: &str,
+    request: &http::Request<Vec<u8>>,
+    status: http::StatusCode,
+    bytes: usize,
+    upstream: Option<&str>,
+    started: Instant,
+) {
+    if let Some(access_log) = &state.access_log {
+        access_log.record(access_log::Entry {
+            client_ip,
+            request_line: request::format_request_line(request),
+            status,
+            bytes,
+            upstream,
+            started,
+        });
+    }
+}
+
 async fn handle_connection(mut client_conn: TcpStream, state: Arc<ProxyState>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    let opened = Instant::now();
+    let peer_ip = client_conn.peer_addr().unwrap().ip();
+    let client_ip = peer_ip.to_string();
     log::info!("Connection received from {}", client_ip);
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(Arc::clone(&state)).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+    match http2::is_http2(&client_conn, &state.request_limits).await {
+        Ok(true) => {
+            http2::serve(client_conn, peer_ip, state, opened).await;
             return;
         }
-    };
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
+        Ok(false) => {}
+        Err(error::Kind::IdleTimeout) => {
+            log::debug!("{} was idle for too long. Shutting down connection", client_ip);
+            return;
+        }
+        Err(kind) => {
+            let error = ProxyError::new(error::Peer::Client, error::Phase::Head, kind);
+            log::debug!("Error reading the connection preface: {}", error);
+            let response = error_pages::make_error(&state, error.status(), None);
+            send_response(&mut client_conn, &mark_last(response, true)).await;
+            return;
+        }
+    }
+
+    // Open a connection to an upstream picked by --balance. The connection slot (if any) is held
+    // until the client hangs up, since the upstream connection lives that long. If the client hangs
+    // up while we're still connecting, there's no point in finishing. When upstreams are picked per
+    // request, the connection is opened once the first request says where it should go.
+    // Bytes the client sent past the end of the request we last read (the start of a pipelined
+    // request)
+    let mut leftover = Vec::new();
+    let pool = state.default_pool();
+    let mut upstream_connection = None;
+    if !state.balance.is_per_request() {
+        let connected = tokio::select! {
+            connected = connect_to_upstream(Arc::clone(&state), &pool, peer_ip, None) => connected,
+            _ = request::wait_for_hangup(&client_conn) => {
+                log::info!("{} hung up while we were connecting to an upstream", client_ip);
+                return;
+            }
+        };
+        match connected {
+            Ok(conn) => upstream_connection = Some(conn),
+            Err(error) => {
+                log::warn!("Could not connect to an upstream: {:?}", error);
+                // Read the client's request before answering. Otherwise we would close the
+                // connection with unread data in it, which resets it and may destroy our response
+                // before the client reads it.
+                let limits = &state.request_limits;
+                let request = request::read_from_stream(&mut client_conn, &mut leftover, limits)
+                    .await
+                    .ok();
+                let headers = request.as_ref().map(|request| request.headers());
+                // In maintenance, the upstreams being down is expected, and not what clients should
+                // be told
+                let client = match &request {
+                    Some(request) => {
+                        trusted_proxies::client_identity(&state.trusted_proxies, peer_ip, request)
+                    }
+                    None => peer_ip,
+                };
+                let response = match maintenance::respond(&state, client, headers) {
+                    Some(response) => response,
+                    None => error_pages::make_error(&state, error.status(), headers),
+                };
+                send_response(&mut client_conn, &mark_last(response, true)).await;
+                return;
+            }
+        }
+    }
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    let mut served = 0;
+    // Set once a response has told the client that we'll hang up after it
+    let mut last = false;
     loop {
+        if last {
+            log::debug!("Served {} requests to {}; closing the connection", served, client_ip);
+            return;
+        }
+        if state.shutdown.is_draining() && served > 0 {
+            log::debug!("Shutting down; closing the connection to {}", client_ip);
+            return;
+        }
         // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        let limits = &state.request_limits;
+        let read = request::read_from_stream(&mut client_conn, &mut leftover, limits).await;
+        let mut request = match read {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
+            Err(ProxyError {
+                kind: error::Kind::Incomplete(0),
+                ..
+            }) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
+            Err(ProxyError {
+                kind: error::Kind::IdleTimeout,
+                ..
+            }) => {
+                log::debug!("{} was idle for too long. Shutting down connection", client_ip);
+                return;
+            }
             // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
+            Err(ProxyError {
+                kind: error::Kind::ConnectionError(io_err),
+                ..
+            }) => {
                 log::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                });
+                log::debug!("Error parsing request: {}", error);
+                let response = error
+                    .detailed_response()
+                    .unwrap_or_else(|| error_pages::make_error(&state, error.status(), None));
+                let hang_up = error.hangs_up();
+                let response = mark_last(response, hang_up);
                 send_response(&mut client_conn, &response).await;
+                if hang_up {
+                    linger_close(client_conn).await;
+                    return;
+                }
+                continue;
+            }
+        };
+        served += 1;
+        // The last request the connection may carry; its response tells the client we'll hang up
+        last = state.connection_spent(served, opened) || state.shutdown.is_draining();
+        let arrived = Instant::now();
+        // When we sit behind trusted front proxies, the peer address is just the nearest proxy, so
+        // attribute the request to the client named in X-Forwarded-For instead
+        let client_identity =
+            trusted_proxies::client_identity(&state.trusted_proxies, peer_ip, &request);
+        let request_client_ip = client_identity.to_string();
+
+        let maintenance_response =
+            maintenance::respond(&state, client_identity, Some(request.headers()));
+        if let Some(response) = maintenance_response {
+            log_local_response(&state, &request_client_ip, &request, &response, arrived);
+            send_response(&mut client_conn, &mark_last(response, last)).await;
+            continue;
+        }
+
+        if state.rate_limiting_enabled() {
+            if let Some(response) = rate_limit(&state, request_client_ip.clone(), &request).await {
+                log_local_response(&state, &request_client_ip, &request, &response, arrived);
+                send_response(&mut client_conn, &mark_last(response, last)).await;
+                continue;
+            }
+        }
+
+        if let Some(response) = cors::preflight(&state, &request) {
+            log_local_response(&state, &request_client_ip, &request, &response, arrived);
+            send_response(&mut client_conn, &mark_last(response, last)).await;
+            continue;
+        }
+
+        // CONNECT asks for a tunnel to the given destination rather than to an upstream, and
+        // takes over the connection
+        if request.method() == http::Method::CONNECT {
+            let allowed = &state.connect_allow;
+            connect::tunnel(&mut client_conn, &request, &leftover, &client_ip, allowed).await;
+            return;
+        }
+
+        // Held until the response has been sent
+        let _admitted = match state.load_shedder.admit().await {
+            Some(admitted) => admitted,
+            None => {
+                log::warn!("Too many requests in flight; shedding a request from {}", client_ip);
+                let response = load_shed::respond(&state, Some(request.headers()));
+                log_local_response(&state, &request_client_ip, &request, &response, arrived);
+                send_response(&mut client_conn, &mark_last(response, last)).await;
+                continue;
+            }
+        };
+
+        if let Some(rule) = forward_auth::find_rule(&state.forward_auth_rules, request.uri().path()) {
+            if let Some(response) =
+                forward_auth::authorize(&state, rule, &mut request, &client_ip).await
+            {
+                log_local_response(&state, &request_client_ip, &request, &response, arrived);
+                send_response(&mut client_conn, &mark_last(response, last)).await;
+                continue;
+            }
+        }
+
+        // A retry of a request we've already answered gets the stored response
+        let ticket = match state.idempotency.begin(&request_client_ip, &request) {
+            idempotency::Begin::Forward(ticket) => ticket,
+            idempotency::Begin::Respond(response) => {
+                log_local_response(&state, &request_client_ip, &request, &response, arrived);
+                send_response(&mut client_conn, &mark_last(response, last)).await;
+                continue;
+            }
+            idempotency::Begin::Refuse(status) => {
+                let response = error_pages::make_error(&state, status, Some(request.headers()));
+                log_local_response(&state, &request_client_ip, &request, &response, arrived);
+                send_response(&mut client_conn, &mark_last(response, last)).await;
                 continue;
             }
         };
+
+        // With --balance path-hash, the request goes to the upstream its path hashes to, which may
+        // not be the one this connection has been talking to
+        if let Some(key) = state.balance.request_key(&request) {
+            let preferred = preferred_upstream(&state, &pool, key);
+            let stale = match (&upstream_connection, &preferred) {
+                (Some((address, ..)), Some(preferred)) => address != preferred,
+                _ => true,
+            };
+            if stale {
+                // Let go of the old connection, and its slot, before taking another
+                upstream_connection = None;
+                match connect_to_upstream(Arc::clone(&state), &pool, peer_ip, Some(key)).await {
+                    Ok(conn) => upstream_connection = Some(conn),
+                    Err(error) => {
+                        log::warn!("Could not connect to an upstream: {:?}", error);
+                        let headers = Some(request.headers());
+                        let response = error_pages::make_error(&state, error.status(), headers);
+                        let client = &request_client_ip;
+                        log_local_response(&state, client, &request, &response, arrived);
+                        send_response(&mut client_conn, &mark_last(response, last)).await;
+                        continue;
+                    }
+                }
+            }
+        }
+        let (upstream_address, upstream_conn, _) = upstream_connection
+            .as_mut()
+            .expect("no upstream connection to forward to");
+        let upstream_address = Arc::clone(upstream_address);
+        let upstream = state.upstreams.get(&upstream_address);
+        let upstream_stats = &upstream.stats;
         log::info!(
             "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
+            request_client_ip,
+            upstream_address,
             request::format_request_line(&request)
         );
 
-        if state.max_requests_per_minute > 0 {
-            let state = Arc::clone(&state);
-            if let Err(_) = rate_limiting_check(state, &mut client_conn).await {
-                continue;
-            }
-        }
+        // Work out who to bill now that forward auth has had its chance to add headers
+        let labels = state.accounting.as_ref().map(|ledger| ledger.labels(&request));
+        let cors_headers = state
+            .cors
+            .as_ref()
+            .map(|cors| cors.response_headers(&request))
+            .unwrap_or_default();
 
         // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
         // (We're the ones connecting directly to the upstream server, so without this header, the
         // upstream server will only know our IP, not the client's.)
         request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
+        // Forward the request to the server. Only the upstream sees a rewritten path; the logs and
+        // everything else after this go by the path the client asked for
+        let started = Instant::now();
+        let original_uri = rewrite::apply(&state.rewrite_rules, &mut request);
+        let accept_encoding = compression::strip(state.compression, &mut request);
+        let written = request::write_to_stream(&request, upstream_conn).await;
+        if let Some(uri) = original_uri {
+            *request.uri_mut() = uri;
+        }
+        compression::restore(&mut request, accept_encoding);
+        if let Err(error) = written {
+            upstream_stats.record_error();
+            state
+                .balance
+                .report_result(&upstream_address, &balance::Outcome::Failed);
             log::error!(
                 "Failed to send request to upstream {}: {}",
-                upstream_ip,
+                upstream_address,
                 error
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+            let status = http::StatusCode::BAD_GATEWAY;
+            let response = error_pages::make_error(&state, status, Some(request.headers()));
+            log_local_response(&state, &request_client_ip, &request, &response, arrived);
+            send_response(&mut client_conn, &mark_last(response, true)).await;
             return;
         }
-        log::debug!("Forwarded request to server");
+        log::debug!("Forwarded request to upstream {}", upstream_address);
+
+        let captured = state.capture.sample();
+        if captured {
+            state.capture.log_request(&request_client_ip, &request);
+        }
 
         // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
+        let limiter = state.throttle.limiter(&request_client_ip, request.uri().path());
+        let response = match response::read_from_stream_forwarding_informational(
+            upstream_conn,
+            request.method(),
+            &mut client_conn,
+            &limiter,
+            last || state.shutdown.is_draining(),
+            &cors_headers,
+        )
+        .await
         {
             Ok(response) => response,
+            Err(ProxyError {
+                peer,
+                kind: error::Kind::StreamInterrupted,
+                ..
+            }) => {
+                // Part of the response has already been sent, so all we can do is hang up. It's
+                // only the upstream's fault if it was the upstream that broke off
+                if peer == error::Peer::Upstream {
+                    upstream_stats.record_error();
+                    state
+                        .balance
+                        .report_result(&upstream_address, &balance::Outcome::Failed);
+                }
+                return;
+            }
+            Err(ProxyError {
+                kind: error::Kind::ClientDisconnected,
+                ..
+            }) => {
+                // Dropping the upstream connection abandons the request, and frees its connection
+                // slot for someone who is still waiting
+                log::info!(
+                    "{} hung up while waiting for a response from {}",
+                    client_ip,
+                    upstream_address
+                );
+                return;
+            }
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                if error.peer == error::Peer::Upstream {
+                    upstream_stats.record_error();
+                    state
+                        .balance
+                        .report_result(&upstream_address, &balance::Outcome::Failed);
+                }
+                log::error!(
+                    "Error reading response from upstream {}: {}",
+                    upstream_address,
+                    error
+                );
+                let mut response =
+                    error_pages::make_error(&state, error.status(), Some(request.headers()));
+                response
+                    .headers_mut()
+                    .insert("X-Balancebeam-Error", error.code().parse().unwrap());
+                log_local_response(&state, &request_client_ip, &request, &response, arrived);
+                send_response(&mut client_conn, &mark_last(response, true)).await;
                 return;
             }
         };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        let latency = started.elapsed();
+        let (status, body_len) = match &response {
+            response::Proxied::Buffered(response) => (response.status(), response.body().len()),
+            response::Proxied::Streamed { head, body_len, .. } => (head.status(), *body_len),
+        };
+        if captured {
+            match &response {
+                response::Proxied::Buffered(response) => {
+                    let body = Some(response.body().as_slice());
+                    state.capture.log_response(&request_client_ip, response, body);
+                }
+                response::Proxied::Streamed { head, .. } => {
+                    state.capture.log_response(&request_client_ip, head, None);
+                }
+            }
+        }
+        upstream_stats.record_response(latency, request.body().len(), body_len);
+        let outcome = balance::Outcome::Response { status, latency };
+        state.balance.report_result(&upstream_address, &outcome);
+        health_check::record_traffic(&state, &upstream, status);
+        if let (Some(ledger), Some(labels)) = (&state.accounting, &labels) {
+            ledger.record(&upstream_address, labels, request.body().len(), body_len);
+        }
+        upstream.outlier.record(
+            &state.outlier_config,
+            &upstream_address,
+            status,
+            latency,
+        );
+        log_access(
+            &state,
+            &request_client_ip,
+            &request,
+            status,
+            body_len,
+            Some(&upstream_address),
+            arrived,
+        );
+        match response {
+            // Forward the response to the client
+            response::Proxied::Buffered(mut response) => {
+                if let Some(ticket) = ticket {
+                    ticket.finish(&response);
+                }
+                compression::compress(
+                    state.compression,
+                    request.method(),
+                    request.headers(),
+                    &mut response,
+                );
+                // A drain may have started while we waited for the upstream
+                let response = mark_last(response, last || state.shutdown.is_draining());
+                send_response_limited(&mut client_conn, &response, &limiter).await;
+                log::debug!("Forwarded response from {} to {}", upstream_address, client_ip);
+            }
+            response::Proxied::Streamed { head, close, .. } => {
+                log::info!(
+                    "{} <- {}: {} (streamed {} bytes)",
+                    client_ip,
+                    upstream_address,
+                    response::format_response_line(&head),
+                    body_len
+                );
+                if close {
+                    return;
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,77 @@
+//! Maintenance mode. While it is on, every HTTP request is answered with a 503 and Retry-After
+//! instead of being proxied, except for requests from --maintenance-allow addresses, so that the
+//! people doing the maintenance can check the upstreams through the proxy before opening it up
+//! again. It is turned on at startup with --maintenance, and toggled through the admin API:
+//!
+//! ```text
+//! curl -X POST http://ADMIN/maintenance/on
+//! curl -X POST http://ADMIN/maintenance/off
+//! curl http://ADMIN/maintenance
+//! ```
+//!
+//! The 503 page is maintenance.html from --error-pages if there is one, and otherwise the usual
+//! page for 503. TCP and TLS passthrough listeners are not affected.
+
+use crate::trusted_proxies::Cidr;
+use crate::{error_pages, ProxyState};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub struct Maintenance {
+    on: AtomicBool,
+    /// Clients that are still proxied during maintenance
+    allow: Vec<Cidr>,
+    /// Seconds to send in Retry-After
+    retry_after: u64,
+}
+
+impl Maintenance {
+    pub fn new(on: bool, allow: Vec<Cidr>, retry_after: u64) -> Maintenance {
+        if on {
+            log::warn!("Starting in maintenance mode");
+        }
+        Maintenance {
+            on: AtomicBool::new(on),
+            allow,
+            retry_after,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::SeqCst)
+    }
+
+    /// Whether a request from `client_ip` gets the maintenance response right now.
+    pub fn turns_away(&self, client_ip: &IpAddr) -> bool {
+        self.is_on() && !self.allow.iter().any(|cidr| cidr.contains(client_ip))
+    }
+
+    /// Turns maintenance mode on or off.
+    pub fn set(&self, on: bool) {
+        if self.on.swap(on, Ordering::SeqCst) != on {
+            if on {
+                log::warn!("Maintenance mode is on");
+            } else {
+                log::warn!("Maintenance mode is off");
+            }
+        }
+    }
+}
+
+/// Returns the maintenance response for a request from `client_ip`, or None if the request should
+/// be proxied as usual.
+pub fn respond(
+    state: &ProxyState,
+    client_ip: IpAddr,
+    request_headers: Option<&http::HeaderMap>,
+) -> Option<http::Response<Vec<u8>>> {
+    let maintenance = &state.maintenance;
+    if !maintenance.turns_away(&client_ip) {
+        return None;
+    }
+    let mut response = error_pages::make_maintenance(state, request_headers);
+    response
+        .headers_mut()
+        .insert("Retry-After", maintenance.retry_after.into());
+    Some(response)
+}
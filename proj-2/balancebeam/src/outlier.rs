@@ -0,0 +1,195 @@
+//! Passive outlier detection. Watches the status codes and latencies each upstream returns and
+//! temporarily takes an upstream out of rotation when it keeps failing or is much slower than the
+//! rest of the pool, even if its health check path still returns 200.
+
+use crate::ProxyState;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// Maximum number of latency samples kept per upstream
+const MAX_LATENCY_SAMPLES: usize = 1000;
+/// How often upstream latencies are compared against each other
+const LATENCY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When to eject an upstream, and for how long
+#[derive(Debug)]
+pub struct Config {
+    /// Eject after this many 5xx responses in a row (0 = disabled)
+    pub consecutive_5xx: usize,
+    /// Eject when at least this percentage of the responses within `window` were 5xx (0 =
+    /// disabled)
+    pub percent_5xx: usize,
+    /// Eject when an upstream's p99 latency within `window` is more than this multiple of the
+    /// median latency across the rest of the pool (0 = disabled)
+    pub latency_multiple: f64,
+    pub window: Duration,
+    /// The percentage and latency rules only apply once the window holds at least this many
+    /// responses, so that one early failure (or slow response) doesn't count as 100%
+    pub min_requests: usize,
+    /// How long an ejected upstream stays out of rotation
+    pub ejection_time: Duration,
+}
+
+#[derive(Default)]
+struct Record {
+    consecutive_5xx: usize,
+    /// When each recent response arrived, and whether it was a 5xx
+    recent: VecDeque<(Instant, bool)>,
+    /// When each recent response arrived, and how long it took
+    latencies: VecDeque<(Instant, Duration)>,
+    ejected_until: Option<Instant>,
+}
+
+/// Outlier detection state for a single upstream
+#[derive(Default)]
+pub struct Detector {
+    record: Mutex<Record>,
+}
+
+impl Detector {
+    pub fn new() -> Detector {
+        Detector::default()
+    }
+
+    /// Records a response from the upstream, ejecting it if that tips it over one of the 5xx
+    /// thresholds. (Latency is judged periodically by watch_latency, since it depends on the rest
+    /// of the pool.)
+    pub fn record(
+        &self,
+        config: &Config,
+        upstream: &str,
+        status: http::StatusCode,
+        latency: Duration,
+    ) {
+        let now = Instant::now();
+        let is_5xx = status.is_server_error();
+        let mut record = self.record.lock().unwrap();
+
+        if config.latency_multiple > 0.0 {
+            if record.latencies.len() == MAX_LATENCY_SAMPLES {
+                record.latencies.pop_front();
+            }
+            record.latencies.push_back((now, latency));
+        }
+
+        record.consecutive_5xx = if is_5xx { record.consecutive_5xx + 1 } else { 0 };
+        let consecutive_tripped =
+            config.consecutive_5xx > 0 && record.consecutive_5xx >= config.consecutive_5xx;
+
+        let mut percent_tripped = false;
+        if config.percent_5xx > 0 {
+            record.recent.push_back((now, is_5xx));
+            let cutoff = now - config.window;
+            while matches!(record.recent.front(), Some((ts, _)) if *ts < cutoff) {
+                record.recent.pop_front();
+            }
+            let total = record.recent.len();
+            let failures = record.recent.iter().filter(|(_, is_5xx)| *is_5xx).count();
+            percent_tripped =
+                total >= config.min_requests.max(1) && failures * 100 >= config.percent_5xx * total;
+        }
+
+        if (consecutive_tripped || percent_tripped) && !is_ejected(&record, now) {
+            eject(&mut record, config, upstream, "repeated 5xx responses", now);
+        }
+    }
+
+    /// Returns the latencies recorded within the window, sorted.
+    fn recent_latencies(&self, window: Duration) -> Vec<Duration> {
+        let mut record = self.record.lock().unwrap();
+        let cutoff = Instant::now() - window;
+        while matches!(record.latencies.front(), Some((ts, _)) if *ts < cutoff) {
+            record.latencies.pop_front();
+        }
+        let mut latencies: Vec<Duration> = record.latencies.iter().map(|(_, l)| *l).collect();
+        latencies.sort();
+        latencies
+    }
+
+    /// How much longer the upstream stays ejected, or None if it isn't ejected.
+    pub fn ejected_for(&self) -> Option<Duration> {
+        let record = self.record.lock().unwrap();
+        let now = Instant::now();
+        record
+            .ejected_until
+            .filter(|until| now < *until)
+            .map(|until| until - now)
+    }
+
+    /// Returns true if the upstream is currently ejected and shouldn't be sent new connections.
+    pub fn is_ejected(&self) -> bool {
+        is_ejected(&self.record.lock().unwrap(), Instant::now())
+    }
+}
+
+fn eject(record: &mut Record, config: &Config, upstream: &str, reason: &str, now: Instant) {
+    log::warn!(
+        "Ejecting upstream {} for {:?} after {}",
+        upstream,
+        config.ejection_time,
+        reason
+    );
+    record.ejected_until = Some(now + config.ejection_time);
+    // Start counting afresh once the upstream comes back
+    record.consecutive_5xx = 0;
+    record.recent.clear();
+    record.latencies.clear();
+}
+
+/// Returns the value at percentile `p` (0 to 1) of a sorted, non-empty slice.
+pub fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Periodically compares each upstream's p99 latency against the median latency of the other
+/// upstreams, and ejects upstreams that are too slow. Only runs if latency-based ejection is enabled.
+pub async fn watch_latency(state: Arc<ProxyState>) {
+    let config = &state.outlier_config;
+    loop {
+        sleep(LATENCY_CHECK_INTERVAL).await;
+
+        let addresses = state.upstream_addresses.read().unwrap().clone();
+        let samples: Vec<(&String, Vec<Duration>)> = addresses
+            .iter()
+            .map(|address| {
+                let upstream = state.upstreams.get(address);
+                let latencies = upstream.outlier.recent_latencies(config.window);
+                (address, latencies)
+            })
+            .filter(|(_, latencies)| latencies.len() >= config.min_requests.max(1))
+            .collect();
+        // With a single upstream there's nothing to compare against
+        if samples.len() < 2 {
+            continue;
+        }
+        for (address, latencies) in &samples {
+            // Compare against the rest of the pool; a slow upstream's own samples would drag the
+            // median towards it
+            let mut pool: Vec<Duration> = samples
+                .iter()
+                .filter(|(other, _)| other != address)
+                .flat_map(|(_, l)| l.iter().copied())
+                .collect();
+            pool.sort();
+            let threshold = percentile(&pool, 0.5).mul_f64(config.latency_multiple);
+            let p99 = percentile(latencies, 0.99);
+            if p99 > threshold {
+                let upstream = state.upstreams.get(address);
+                let detector = &upstream.outlier;
+                let mut record = detector.record.lock().unwrap();
+                let now = Instant::now();
+                if !is_ejected(&record, now) {
+                    let reason = format!("p99 latency {:?} exceeded {:?}", p99, threshold);
+                    eject(&mut record, config, address, &reason, now);
+                }
+            }
+        }
+    }
+}
+
+fn is_ejected(record: &Record, now: Instant) -> bool {
+    matches!(record.ejected_until, Some(until) if now < until)
+}
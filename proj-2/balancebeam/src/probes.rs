@@ -0,0 +1,49 @@
+//! Liveness and readiness of balancebeam itself, for orchestrators like Kubernetes:
+//!
+//! * GET /healthz (liveness) answers 200 as long as balancebeam is accepting connections, or
+//!   draining after SIGTERM, and 503 once every accept loop has died. A process in that state
+//!   can't serve anything until it is restarted.
+//! * GET /readyz (readiness) answers 200 only if balancebeam is accepting connections, isn't
+//!   draining, and at least one upstream in the active pool is live, and 503 otherwise, so that
+//!   traffic is steered to other replicas while this one couldn't proxy it anyway. Maintenance
+//!   mode doesn't make balancebeam unready: its 503 page is what clients are meant to see.
+//!
+//! Both are served on the admin listener, and with --probe-bind on a listener of their own, which
+//! can be exposed to the kubelet without exposing the rest of the admin API.
+
+use crate::{response, ProxyState};
+
+/// The response to a probe of `path`, or None if `path` isn't a probe endpoint.
+pub fn respond(state: &ProxyState, path: &str) -> Option<http::Response<Vec<u8>>> {
+    let problem = match path {
+        "/healthz" => liveness_problem(state),
+        "/readyz" => readiness_problem(state),
+        _ => return None,
+    };
+    let (status, body) = match problem {
+        None => (http::StatusCode::OK, "ok\n".to_string()),
+        Some(problem) => (http::StatusCode::SERVICE_UNAVAILABLE, format!("{}\n", problem)),
+    };
+    Some(response::make_response(status, "text/plain", body.into_bytes()))
+}
+
+fn liveness_problem(state: &ProxyState) -> Option<&'static str> {
+    if !state.shutdown.is_accepting() && !state.shutdown.is_draining() {
+        return Some("not accepting connections");
+    }
+    None
+}
+
+fn readiness_problem(state: &ProxyState) -> Option<&'static str> {
+    if state.shutdown.is_draining() {
+        return Some("draining");
+    }
+    if !state.shutdown.is_accepting() {
+        return Some("not accepting connections");
+    }
+    let live = state.liveing_upstreams.load();
+    if !state.default_pool().iter().any(|address| live.contains(address)) {
+        return Some("no live upstreams");
+    }
+    None
+}
@@ -0,0 +1,159 @@
+//! Per-client rate limits from a policy file, so that partners with bigger quotas don't need a
+//! proxy of their own (or a restart every time a quota changes). Each line gives a CIDR or an API
+//! key, and how many requests per minute it may make:
+//!
+//! ```text
+//! # Internal batch jobs
+//! 10.20.0.0/16     600
+//! # Partner API keys, sent in --rate-limit-api-key-header
+//! key:partner-a    3000
+//! key:free-trial   30
+//! # 0 means unlimited
+//! 10.0.0.5         0
+//! ```
+//!
+//! An API key limit is shared by every client using that key. A CIDR limit applies to each address
+//! in it separately; when several CIDRs match, the most specific one wins. Clients that match
+//! nothing get --max-requests-per-minute. The file is reread whenever it changes; if the new
+//! version doesn't parse, the old rules stay in effect.
+
+use crate::trusted_proxies::Cidr;
+use crate::ProxyState;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+
+#[derive(Default)]
+struct Rules {
+    cidrs: Vec<(Cidr, usize)>,
+    api_keys: HashMap<String, usize>,
+}
+
+pub struct Policy {
+    path: String,
+    api_key_header: http::header::HeaderName,
+    reload_interval: Duration,
+    rules: RwLock<Rules>,
+    /// Modification time of the file the current rules were read from
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl Policy {
+    /// Reads the policy file. Unlike a reload, a bad file here is an error.
+    pub fn load(
+        path: String,
+        api_key_header: http::header::HeaderName,
+        reload_interval: Duration,
+    ) -> Result<Policy, String> {
+        let modified = modified_time(&path);
+        let rules = read_rules(&path)?;
+        Ok(Policy {
+            path,
+            api_key_header,
+            reload_interval,
+            rules: RwLock::new(rules),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Works out which rate limit bucket a request counts against, and that bucket's limit (0 =
+    /// unlimited). Buckets are named after the client's IP, or after its API key if it has a key
+    /// with its own limit.
+    pub fn limit_for<T>(
+        &self,
+        client_ip: &str,
+        request: &http::Request<T>,
+        default_limit: usize,
+    ) -> (String, usize) {
+        let rules = self.rules.read().unwrap();
+        let api_key = request
+            .headers()
+            .get(&self.api_key_header)
+            .and_then(|value| value.to_str().ok());
+        if let Some((key, limit)) = api_key.and_then(|key| rules.api_keys.get_key_value(key)) {
+            return (format!("key:{}", key), *limit);
+        }
+        let limit = client_ip
+            .parse::<IpAddr>()
+            .ok()
+            .and_then(|ip| {
+                rules
+                    .cidrs
+                    .iter()
+                    .filter(|(cidr, _)| cidr.contains(&ip))
+                    .max_by_key(|(cidr, _)| cidr.prefix_len())
+            })
+            .map(|(_, limit)| *limit)
+            .unwrap_or(default_limit);
+        (client_ip.to_string(), limit)
+    }
+}
+
+/// Rereads the policy file whenever its modification time changes, until the process exits.
+pub async fn reload_periodically(state: Arc<ProxyState>) {
+    let policy = match &state.rate_limit_policy {
+        Some(policy) => policy,
+        None => return,
+    };
+    loop {
+        sleep(policy.reload_interval).await;
+        let modified = modified_time(&policy.path);
+        if modified == *policy.modified.lock().unwrap() {
+            continue;
+        }
+        match read_rules(&policy.path) {
+            Ok(rules) => {
+                log::info!(
+                    "Reloaded rate limit policy {}: {} CIDRs, {} API keys",
+                    policy.path,
+                    rules.cidrs.len(),
+                    rules.api_keys.len()
+                );
+                *policy.rules.write().unwrap() = rules;
+            }
+            Err(err) => {
+                log::error!("Keeping the old rate limit policy: {}", err);
+            }
+        }
+        // Either way, don't try again until the file changes again
+        *policy.modified.lock().unwrap() = modified;
+    }
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn read_rules(path: &str) -> Result<Rules, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?;
+    let mut rules = Rules::default();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: &str| format!("{} line {}: {}", path, number + 1, message);
+        let (client, limit) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+            [client, limit] => (client, limit),
+            _ => return Err(error("expected a CIDR or key:API_KEY, then a limit")),
+        };
+        let limit = limit
+            .parse::<usize>()
+            .map_err(|_| error("the limit must be a number of requests per minute"))?;
+        match client.strip_prefix("key:") {
+            Some(key) => {
+                rules.api_keys.insert(key.to_string(), limit);
+            }
+            None => {
+                let cidr = client.parse::<Cidr>().map_err(|err| error(&err))?;
+                rules.cidrs.push((cidr, limit));
+            }
+        }
+    }
+    Ok(rules)
+}
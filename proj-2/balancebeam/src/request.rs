@@ -1,48 +1,105 @@
-use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::error::{Kind, Phase, ProxyError};
+use crate::{buffer_pool, chunked};
+use std::cmp::{max, min};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{timeout_at, Instant};
 
-const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
-
-#[derive(Debug)]
-pub enum Error {
-    /// Client hung up before sending a complete request. IncompleteRequest contains the number of
-    /// bytes that were successfully read before the client hung up
-    IncompleteRequest(usize),
-    /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedRequest(httparse::Error),
-    /// The Content-Length header is present, but does not contain a valid numeric value
-    InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+
+/// What we put up with from a client while reading a request. Going over the header limits gets
+/// a 431 Request Header Fields Too Large, and going over the target limits a 414 URI Too Long, so
+/// that upstreams with smaller fixed buffers never see such requests. The timing limits are there
+/// so that clients can't tie up connections by trickling a request in a byte at a time
+/// (slowloris), or by never sending one.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Maximum size of the request line and headers together, in bytes
+    pub max_header_size: usize,
+    /// Maximum number of headers
+    pub max_headers: usize,
+    /// Maximum length of the request target's path and query string, in bytes (0 = no limit)
+    pub max_target_length: usize,
+    /// Maximum length of the query string, in bytes (0 = no limit)
+    pub max_query_length: usize,
+    /// How long a client has to send the whole request head once it has sent the first byte
+    pub header_timeout: Option<Duration>,
+    /// How long a connection may sit idle waiting for the first byte of the next request
+    pub idle_timeout: Option<Duration>,
+    /// Minimum average rate a request body has to arrive at, in bytes per second (0 = no minimum)
+    pub min_body_rate: usize,
+}
+
+/// Checks the length of the request target (its path and query string) and of the query string
+/// on its own against `limits`.
+pub fn check_target(uri: &http::Uri, limits: &Limits) -> Result<(), Kind> {
+    let target_length = uri
+        .path_and_query()
+        .map_or(0, |target| target.as_str().len());
+    if limits.max_target_length > 0 && target_length > limits.max_target_length {
+        return Err(Kind::UriTooLong {
+            length: target_length,
+            limit: limits.max_target_length,
+        });
+    }
+    let query_length = uri.query().map_or(0, str::len);
+    if limits.max_query_length > 0 && query_length > limits.max_query_length {
+        return Err(Kind::QueryTooLong {
+            length: query_length,
+            limit: limits.max_query_length,
+        });
+    }
+    Ok(())
+}
+
+/// Returns whether the request body is chunked. The only Transfer-Encoding we can decode is chunked
+/// on its own; any other is refused, and so is a Transfer-Encoding alongside a Content-Length, since
+/// we and the upstream could then disagree about where the body ends.
+fn is_chunked(request: &http::Request<Vec<u8>>) -> Result<bool, Kind> {
+    if !request.headers().contains_key(http::header::TRANSFER_ENCODING) {
+        return Ok(false);
+    }
+    if request.headers().contains_key(http::header::CONTENT_LENGTH) {
+        return Err(Kind::AmbiguousFraming);
+    }
+    let mut codings = Vec::new();
+    for header_value in request.headers().get_all(http::header::TRANSFER_ENCODING) {
+        let header_value = header_value
+            .to_str()
+            .or(Err(Kind::UnsupportedTransferEncoding))?;
+        codings.extend(header_value.split(',').map(str::trim).filter(|v| !v.is_empty()));
+    }
+    match codings[..] {
+        [coding] if coding.eq_ignore_ascii_case("chunked") => Ok(true),
+        _ => Err(Kind::UnsupportedTransferEncoding),
+    }
 }
 
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
+/// Err(Kind) if Content-Length is present but invalid.
 ///
-/// You won't need to touch this function.
-fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
-    // Look for content-length header
-    if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
-        // If it doesn't exist, return None
-        Ok(None)
+/// Anything that could make us and the upstream disagree about where the body ends is refused:
+/// values other than plain digits (e.g. "+5"), and several Content-Length values that don't match.
+fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Kind> {
+    // Content-Length may be repeated, or hold a comma-separated list, as long as every value is
+    // the same
+    let mut content_length = None;
+    for header_value in request.headers().get_all(http::header::CONTENT_LENGTH) {
+        let header_value = header_value.to_str().or(Err(Kind::InvalidContentLength))?;
+        for value in header_value.split(',').map(str::trim) {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(Kind::InvalidContentLength);
+            }
+            let value = value.parse::<usize>().or(Err(Kind::InvalidContentLength))?;
+            if content_length.is_some_and(|length| length != value) {
+                return Err(Kind::AmbiguousFraming);
+            }
+            content_length = Some(value);
+        }
     }
+    Ok(content_length)
 }
 
 /// This function appends to a header value (adding a new header if the header is not already
@@ -71,13 +128,22 @@ pub fn extend_header_value(
 ///
 /// * If there is a complete and valid request in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
-/// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
+/// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Kind)
+///
+/// httparse refuses obsolete line folding (header values continued on a line starting with
+/// whitespace) in requests, so such requests come back as Malformed.
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
+fn parse_request(
+    buffer: &[u8],
+    max_headers: usize,
+) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Kind> {
+    let mut headers = vec![httparse::EMPTY_HEADER; max_headers];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Kind::TooManyHeaders,
+        err => Kind::Malformed(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -94,60 +160,125 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Parses a request head that is already complete, such as the sample request given to the admin
+/// API's /route-test. The request gets no body.
+pub fn parse_head(
+    buffer: &[u8],
+    max_headers: usize,
+) -> Result<http::Request<Vec<u8>>, ProxyError> {
+    let head_error = ProxyError::client(Phase::Head);
+    match parse_request(buffer, max_headers).map_err(head_error)? {
+        Some((request, _)) => Ok(request),
+        None => Err(head_error(Kind::Incomplete(buffer.len()))),
+    }
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Parsing starts with the bytes in `leftover`, which were read past the end of the previous
+/// request on this connection. `leftover` is emptied.
+///
+/// Returns Ok(http::Request) if a valid request is received, or Kind if not (including when the
+/// request head goes over `limits`). The header timeout starts with the first byte of the request;
+/// before that, the connection is idle, and the idle timeout applies instead.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers(
+    stream: &mut TcpStream,
+    leftover: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, Kind> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut bytes_read = leftover.len();
+    let mut request_buffer = std::mem::take(leftover);
+    request_buffer.resize(max(limits.max_header_size, bytes_read), 0);
+    let mut deadline = None;
     loop {
+        // See if we've read a valid request so far. A pipelining client may have sent a whole
+        // request along with the previous one, so this is checked before reading anything
+        if bytes_read > 0 {
+            let parsed = parse_request(&request_buffer[..bytes_read], limits.max_headers)?;
+            if let Some((mut request, headers_len)) = parsed {
+                // We've read a complete set of headers. We may also have read part of the body, or
+                // the start of the next request, out of the stream into request_buffer. Hand those
+                // bytes back in the request body so that we don't lose them; read_from_stream
+                // works out where this request ends
+                request
+                    .body_mut()
+                    .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+                return Ok(request);
+            }
+        }
+        // The buffer is full and still doesn't hold a complete request head
+        if bytes_read >= limits.max_header_size {
+            return Err(Kind::HeadersTooLarge);
+        }
+
+        if bytes_read > 0 && deadline.is_none() {
+            deadline = limits.header_timeout.map(|timeout| Instant::now() + timeout);
+        }
+
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..]).await
-            .unwrap();
+        let read = stream.read(&mut request_buffer[bytes_read..limits.max_header_size]);
+        let new_bytes = match (deadline, limits.idle_timeout) {
+            (Some(deadline), _) => timeout_at(deadline, read)
+                .await
+                .map_err(|_| Kind::HeaderTimeout)?,
+            (None, Some(idle_timeout)) if bytes_read == 0 => {
+                timeout_at(Instant::now() + idle_timeout, read)
+                    .await
+                    .map_err(|_| Kind::IdleTimeout)?
+            }
+            _ => read.await,
+        }
+        .map_err(Kind::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(Kind::Incomplete(bytes_read));
         }
         bytes_read += new_bytes;
-
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
-        }
     }
 }
 
 /// This function reads the body for a request from the stream. The client only sends a body if the
 /// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
+/// returns Ok(()) if successful, or Err(Kind) if Content-Length bytes couldn't be read.
+///
+/// Never reads past the end of the body, so that whatever the client sent after it (the next
+/// request, if the client is pipelining) stays in the stream.
+///
+/// If `min_rate` isn't 0, the body has to keep arriving at an average of at least `min_rate` bytes
+/// per second (with a second of slack), or BodyTooSlow is returned.
 ///
 /// You will need to modify this function in Milestone 2.
 async fn read_body(
     stream: &mut TcpStream,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
-) -> Result<(), Error> {
+    min_rate: usize,
+) -> Result<(), Kind> {
+    let started = Instant::now();
+    let already_read = request.body().len();
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await.unwrap();
+        // Read up to 512 bytes at a time. (If only a little of the body is left, then only allocate
+        // space to read that much.)
+        let mut buffer = vec![0_u8; min(512, content_length - request.body().len())];
+        let read = stream.read(&mut buffer);
+        let bytes_read = if min_rate > 0 {
+            let received = request.body().len() - already_read;
+            let allowed = (received + min_rate) as f64 / min_rate as f64;
+            timeout_at(started + Duration::from_secs_f64(allowed), read)
+                .await
+                .map_err(|_| Kind::BodyTooSlow)?
+        } else {
+            read.await
+        }
+        .map_err(Kind::ConnectionError)?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -157,15 +288,7 @@ async fn read_body(
                 request.body().len(),
                 content_length
             );
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+            return Err(Kind::ContentLengthMismatch);
         }
 
         // Store the received bytes in the request body
@@ -174,46 +297,175 @@ async fn read_body(
     Ok(())
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// Reads a chunked request body, decoding it into the request body and keeping its trailers in a
+/// chunked::Trailers extension, so that write_to_stream can send both on. The body bytes already
+/// read with the head are taken from the request; whatever is read past the end of the body (the
+/// next request, if the client is pipelining) is left in `leftover`.
+///
+/// As in read_body, the body has to keep arriving at `limits.min_body_rate` if that isn't 0.
+async fn read_chunked_body(
+    stream: &mut TcpStream,
+    request: &mut http::Request<Vec<u8>>,
+    leftover: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<(), Kind> {
+    let started = Instant::now();
+    let min_rate = limits.min_body_rate;
+    let mut decoder = chunked::Decoder::new(limits.max_header_size);
+    let mut pending = std::mem::take(request.body_mut());
+    let mut buffer = [0_u8; 4096];
+    let mut received = 0;
+    loop {
+        let consumed = decoder.feed(&pending, request.body_mut()).map_err(|err| {
+            log::debug!("Malformed chunked request body: {}", err);
+            Kind::InvalidChunkedBody
+        })?;
+        if request.body().len() > MAX_BODY_SIZE {
+            return Err(Kind::BodyTooLarge);
+        }
+        if decoder.is_done() {
+            *leftover = pending.split_off(consumed);
+            break;
+        }
+
+        let read = stream.read(&mut buffer);
+        let bytes_read = if min_rate > 0 {
+            let allowed = (received + min_rate) as f64 / min_rate as f64;
+            timeout_at(started + Duration::from_secs_f64(allowed), read)
+                .await
+                .map_err(|_| Kind::BodyTooSlow)?
+        } else {
+            read.await
+        }
+        .map_err(Kind::ConnectionError)?;
+        if bytes_read == 0 {
+            log::debug!("Client hung up before the end of a chunked body");
+            return Err(Kind::InvalidChunkedBody);
+        }
+        received += bytes_read;
+        pending.clear();
+        pending.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let trailers = decoder.take_trailers();
+    request.extensions_mut().insert(chunked::Trailers(trailers));
+    Ok(())
+}
+
+/// This function reads and returns an HTTP request from a stream, returning a ProxyError if the
+/// client closes the connection prematurely or sends an invalid request.
+///
+/// Clients may pipeline requests, sending the next one without waiting for the response to the
+/// previous one, so a single read can return more than one request. `leftover` carries the bytes
+/// read past the end of a request over to the next call; the caller should start each connection
+/// with an empty Vec and pass the same one to every call. The body only ever holds Content-Length
+/// bytes, or what the chunks up to the last one hold, so the following request is never mistaken
+/// for part of this one.
+///
+/// Requests that go over `limits` are rejected rather than buffered or waited on. A request whose
+/// target is too long is read to the end before it is rejected, so that the connection can carry
+/// on with the next one.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream(
+    stream: &mut TcpStream,
+    leftover: &mut Vec<u8>,
+    limits: &Limits,
+) -> Result<http::Request<Vec<u8>>, ProxyError> {
+    let head_error = ProxyError::client(Phase::Head);
+    let body_error = ProxyError::client(Phase::Body);
     // Read headers
-    let mut request = read_headers(stream).await
-        .unwrap();
+    let mut request = read_headers(stream, leftover, limits)
+        .await
+        .map_err(head_error)?;
+    if is_chunked(&request).map_err(head_error)? {
+        read_chunked_body(stream, &mut request, leftover, limits)
+            .await
+            .map_err(body_error)?;
+        check_target(request.uri(), limits).map_err(head_error)?;
+        return Ok(request);
+    }
+    // Whatever came after the headers belongs to this request only up to its Content-Length (zero
+    // if there is none); the rest is the start of the next request
+    let content_length = get_content_length(&request).map_err(head_error)?;
+    if let Some(content_length) = content_length {
+        // Forward a single, canonical Content-Length, whatever form the client sent it in
+        request
+            .headers_mut()
+            .insert(http::header::CONTENT_LENGTH, content_length.into());
+    }
+    let body_len = content_length.unwrap_or(0);
+    if request.body().len() > body_len {
+        *leftover = request.body_mut().split_off(body_len);
+    }
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request).unwrap() {
+    if let Some(content_length) = content_length {
         if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
+            return Err(body_error(Kind::BodyTooLarge));
         } else {
-            read_body(stream, &mut request, content_length).await;
+            read_body(stream, &mut request, content_length, limits.min_body_rate)
+                .await
+                .map_err(body_error)?;
         }
     }
+    check_target(request.uri(), limits).map_err(head_error)?;
     Ok(request)
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
+/// Resolves once the client closes its end of the connection (or the connection breaks), so that
+/// we can stop waiting on an upstream for a response nobody will read. A client that shuts down
+/// only its sending side after the request looks the same as one that has gone away, and is
+/// treated as gone. If the client sends more data instead (e.g. a pipelined request), we can't tell
+/// whether it is still there without reading that data, so this never resolves.
+pub async fn wait_for_hangup(stream: &TcpStream) {
+    let mut buf = [0_u8; 1];
+    match stream.peek(&mut buf).await {
+        Ok(0) | Err(_) => {}
+        Ok(_) => std::future::pending().await,
+    }
+}
+
+/// This function serializes a request to bytes and writes those bytes to the provided stream. The
+/// request line and headers are gathered into a single pooled buffer so that they go out in one
+/// write; the body is written straight from the request without being copied. A body that arrived
+/// chunked is sent chunked again, along with its trailers.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await;
-    stream.write(&['\r' as u8, '\n' as u8]).await; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await;
-        stream.write(header_value.as_bytes()).await;
-        stream.write(&['\r' as u8, '\n' as u8]).await; // \r\n
+    let trailers = request.extensions().get::<chunked::Trailers>();
+    let mut head = buffer_pool::take();
+    serialize_head(request, &mut head);
+    if trailers.is_some() {
+        chunked::start_body(request.body().len(), &mut head);
+    }
+    stream.write_all(&head).await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await;
+    if let Some(chunked::Trailers(trailers)) = trailers {
+        let mut tail = buffer_pool::take();
+        chunked::end_body(request.body().len(), trailers, &mut tail);
+        stream.write_all(&tail).await?;
     }
     Ok(())
 }
 
+/// Appends the request line and headers, including the blank line that terminates them, to
+/// `buffer`.
+pub fn serialize_head(request: &http::Request<Vec<u8>>, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(format_request_line(request).as_bytes());
+    buffer.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in request.headers() {
+        buffer.extend_from_slice(header_name.as_str().as_bytes());
+        buffer.extend_from_slice(b": ");
+        buffer.extend_from_slice(header_value.as_bytes());
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer.extend_from_slice(b"\r\n");
+}
+
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
     format!("{} {} {:?}", request.method(), request.uri(), request.version())
 }
@@ -1,41 +1,43 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::error::{Kind, Peer, Phase, ProxyError};
+use crate::{buffer_pool, chunked, request, throttle};
+use std::cmp::min;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
 const MAX_HEADERS_SIZE: usize = 8000;
 const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
-#[derive(Debug)]
-pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
-    /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedResponse(httparse::Error),
-    /// The Content-Length header is present, but does not contain a valid numeric value
-    InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+/// A response read from an upstream by read_from_stream_forwarding_informational
+pub enum Proxied {
+    /// The whole response has been read, and still needs to be sent to the client
+    Buffered(http::Response<Vec<u8>>),
+    /// The response was relayed to the client as it arrived (see should_stream). `head` is the
+    /// response without its body; `body_len` bytes of body were relayed.
+    Streamed {
+        head: http::Response<Vec<u8>>,
+        body_len: usize,
+        /// The body ran until the upstream closed the connection, so the client only knows the
+        /// response is over once we close its connection too
+        close: bool,
+    },
 }
 
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
+/// Err(Kind) if Content-Length is present but invalid.
 ///
 /// You won't need to touch this function.
-fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Error> {
+fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Kind> {
     // Look for content-length header
     if let Some(header_value) = response.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
         Ok(Some(
             header_value
                 .to_str()
-                .or(Err(Error::InvalidContentLength))?
+                .or(Err(Kind::InvalidContentLength))?
                 .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+                .or(Err(Kind::InvalidContentLength))?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -49,15 +51,16 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is a complete and valid response in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far response in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
-///   Err(Error)
+///   Err(Kind)
 ///
 /// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Kind> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Kind::TooManyHeaders,
+        err => Kind::Malformed(err),
+    })?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -77,26 +80,26 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// sent. This function only reads the response line and headers; the read_body function can
 /// subsequently be called in order to read the response body.
 ///
-/// Returns Ok(http::Response) if a valid response is received, or Error if not.
+/// `leftover` holds bytes that were already read from the stream but belong to this response (this
+/// happens when the previous response was an informational 1xx response and we read past its end).
+///
+/// If `client` is given, gives up with ClientDisconnected as soon as the client hangs up.
+///
+/// Returns Ok(http::Response) if a valid response is received, or Kind if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    leftover: &[u8],
+    client: Option<&TcpStream>,
+) -> Result<http::Response<Vec<u8>>, Kind> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
     let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    response_buffer[..leftover.len()].copy_from_slice(leftover);
+    let mut bytes_read = leftover.len();
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if new_bytes == 0 {
-            // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
-        }
-        bytes_read += new_bytes;
-
         // See if we've read a valid response so far
         if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
             // We've read a complete set of headers. We may have also read the first part of the
@@ -107,14 +110,51 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
                 .extend_from_slice(&response_buffer[headers_len..bytes_read]);
             return Ok(response);
         }
+
+        // Don't let a misbehaving upstream make us wait (or buffer) forever for the end of the
+        // headers
+        if bytes_read == MAX_HEADERS_SIZE {
+            return Err(Kind::HeadersTooLarge);
+        }
+
+        // Read bytes from the connection into the buffer, starting at position bytes_read
+        let read = stream.read(&mut response_buffer[bytes_read..]);
+        let new_bytes = match client {
+            Some(client) => tokio::select! {
+                result = read => result,
+                _ = request::wait_for_hangup(client) => return Err(Kind::ClientDisconnected),
+            },
+            None => read.await,
+        }
+        .or_else(|err| Err(Kind::ConnectionError(err)))?;
+        if new_bytes == 0 {
+            // We didn't manage to read a complete response
+            return Err(Kind::Incomplete(bytes_read));
+        }
+        bytes_read += new_bytes;
     }
 }
 
+/// Returns true for 1xx responses that are followed by another response to the same request (e.g.
+/// 100 Continue or 103 Early Hints). 101 Switching Protocols is excluded, since it is the final
+/// response before the connection changes protocols.
+fn is_informational(response: &http::Response<Vec<u8>>) -> bool {
+    response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+}
+
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Chunked bodies are decoded (see read_chunked_body).
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<(), Kind> {
+    if is_chunked(response) {
+        return read_chunked_body(stream, response).await;
+    }
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
@@ -124,7 +164,8 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer)
-            .await.unwrap();
+            .await
+            .map_err(Kind::ConnectionError)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -133,19 +174,19 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
             } else {
                 // Content-Length was set, but the server hung up before we managed to read that
                 // number of bytes
-                return Err(Error::ContentLengthMismatch);
+                return Err(Kind::ContentLengthMismatch);
             }
         }
 
         // Make sure the server doesn't send more bytes than it promised to send
         if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
         {
-            return Err(Error::ContentLengthMismatch);
+            return Err(Kind::ContentLengthMismatch);
         }
 
         // Make sure server doesn't send more bytes than we allow
         if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
+            return Err(Kind::BodyTooLarge);
         }
 
         // Append received bytes to the response body
@@ -154,48 +195,301 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
     Ok(())
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// Reads a chunked response body, decoding it into the response body and keeping its trailers in a
+/// chunked::Trailers extension, so that write_to_stream can send both on.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+) -> Result<(), Kind> {
+    let mut decoder = chunked::Decoder::new(MAX_HEADERS_SIZE);
+    let mut pending = std::mem::take(response.body_mut());
+    let mut buffer = [0_u8; 4096];
+    loop {
+        if let Err(err) = decoder.feed(&pending, response.body_mut()) {
+            log::warn!("Malformed chunked response from upstream: {}", err);
+            return Err(Kind::InvalidChunkedBody);
+        }
+        if response.body().len() > MAX_BODY_SIZE {
+            return Err(Kind::BodyTooLarge);
+        }
+        if decoder.is_done() {
+            break;
+        }
+        let bytes_read = stream
+            .read(&mut buffer)
+            .await
+            .map_err(Kind::ConnectionError)?;
+        if bytes_read == 0 {
+            return Err(Kind::InvalidChunkedBody);
+        }
+        pending.clear();
+        pending.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let trailers = decoder.take_trailers();
+    response.extensions_mut().insert(chunked::Trailers(trailers));
+    Ok(())
+}
+
+/// Adds `extra` headers to a proxied response's headers, replacing any of the same name, except for
+/// Vary, which is added to.
+pub fn add_headers(headers: &mut http::HeaderMap, extra: &http::HeaderMap) {
+    for name in extra.keys() {
+        if name != http::header::VARY {
+            headers.remove(name);
+        }
+    }
+    for (name, value) in extra {
+        headers.append(name, value.clone());
+    }
+}
+
+/// This function reads and returns an HTTP response from a stream, returning a ProxyError if the
+/// server closes the connection prematurely or sends an invalid response. Informational 1xx
+/// responses are skipped; only the final response is returned.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await.unwrap();
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await;
+) -> Result<http::Response<Vec<u8>>, ProxyError> {
+    let (mut response, has_body) = read_final_head(stream, request_method, None).await?;
+    if has_body {
+        read_body(stream, &mut response)
+            .await
+            .map_err(ProxyError::upstream(Phase::Body))?;
     }
     Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// Like read_from_stream, but informational 1xx responses are written to `client` as soon as they
+/// arrive instead of being skipped, and responses that may go on indefinitely (see should_stream)
+/// are relayed to `client` as they arrive instead of being buffered. If the client hangs up before
+/// the response head arrives, gives up with ClientDisconnected. The relayed body is paced by
+/// `limiter`. If `last` is set, a relayed response tells the client that its connection closes
+/// afterwards (Connection: close); a buffered one is left for the caller to mark. The
+/// `extra_headers` are added to the final response either way (see add_headers).
+pub async fn read_from_stream_forwarding_informational<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+    client: &mut TcpStream,
+    limiter: &throttle::Limiter,
+    last: bool,
+    extra_headers: &http::HeaderMap,
+) -> Result<Proxied, ProxyError> {
+    let (mut response, has_body) =
+        read_final_head(stream, request_method, Some(&mut *client)).await?;
+    add_headers(response.headers_mut(), extra_headers);
+    if has_body && should_stream(&response) {
+        if last {
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
+            );
+        }
+        return stream_body(stream, client, response, limiter).await;
+    }
+    if has_body {
+        read_body(stream, &mut response)
+            .await
+            .map_err(ProxyError::upstream(Phase::Body))?;
+    }
+    Ok(Proxied::Buffered(response))
+}
+
+/// Reads the head of the final (non-1xx) response. Returns the response, holding any body bytes
+/// that were read along with the head, and whether a body follows.
+async fn read_final_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+    mut client: Option<&mut TcpStream>,
+) -> Result<(http::Response<Vec<u8>>, bool), ProxyError> {
+    let mut leftover = Vec::new();
+    loop {
+        let mut response = read_headers(stream, &leftover, client.as_deref())
+            .await
+            .map_err(ProxyError::upstream(Phase::Head))?;
+        if is_informational(&response) {
+            // Informational responses have no body, so anything read past the headers is the
+            // start of the next response
+            leftover = std::mem::take(response.body_mut());
+            if let Some(client) = client.as_deref_mut() {
+                write_to_stream(&response, client).await.map_err(|err| {
+                    ProxyError::new(Peer::Client, Phase::Head, Kind::ConnectionError(err))
+                })?;
+            }
+            continue;
+        }
+        // A response may have a body as long as it is not responding to a HEAD request and as long
+        // as the response status code is not 1xx, 204 (no content), or 304 (not modified).
+        let has_body = !(request_method == http::Method::HEAD
+            || response.status().as_u16() < 200
+            || response.status() == http::StatusCode::NO_CONTENT
+            || response.status() == http::StatusCode::NOT_MODIFIED);
+        return Ok((response, has_body));
+    }
+}
+
+fn is_chunked(response: &http::Response<Vec<u8>>) -> bool {
+    response
+        .headers()
+        .get_all("transfer-encoding")
+        .iter()
+        .any(|value| value.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("chunked")))
+}
+
+/// Returns true if a response body should be relayed as it arrives rather than read in full first:
+/// Server-Sent Events, and any body whose length isn't known up front (chunked or delimited by the
+/// upstream closing the connection), since those may never end.
+fn should_stream(response: &http::Response<Vec<u8>>) -> bool {
+    let event_stream = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/event-stream"));
+    event_stream || is_chunked(response) || !response.headers().contains_key("content-length")
+}
+
+/// How the end of a streamed body is found
+enum Framing {
+    /// This many more bytes of body are to come
+    Length(usize),
+    /// The body is chunked, and ends after the zero-length chunk and the trailers
+    Chunked(chunked::Decoder),
+    /// The body ends when the upstream closes the connection
+    Close,
+}
+
+/// Sends the response head to `client`, then relays the body from `upstream` as it arrives. Every
+/// write goes straight out on the socket, so each event reaches the client as soon as the upstream
+/// sends it. Once the head has gone out, the client can't be sent an error response anymore, so
+/// any failure is logged and reported as StreamInterrupted, blaming whichever end it came from.
+async fn stream_body<S: AsyncRead + Unpin>(
+    upstream: &mut S,
+    client: &mut TcpStream,
+    mut response: http::Response<Vec<u8>>,
+    limiter: &throttle::Limiter,
+) -> Result<Proxied, ProxyError> {
+    let interrupted_by = |peer| ProxyError::new(peer, Phase::Body, Kind::StreamInterrupted);
+    let mut framing = if is_chunked(&response) {
+        Framing::Chunked(chunked::Decoder::new(MAX_HEADERS_SIZE))
+    } else {
+        match get_content_length(&response).map_err(ProxyError::upstream(Phase::Head))? {
+            Some(len) => Framing::Length(len),
+            None => Framing::Close,
+        }
+    };
+    let prefix = std::mem::take(response.body_mut());
+    if let Err(err) = write_to_stream(&response, client).await {
+        log::warn!("Failed to send streamed response head to client: {}", err);
+        return Err(interrupted_by(Peer::Client));
+    }
+
+    let mut buffer = [0_u8; 8192];
+    let mut pending: &[u8] = &prefix;
+    let mut body_len = 0;
+    loop {
+        // Work out how much of what we have belongs to this response, and whether that finishes it
+        let (len, done) = match &mut framing {
+            Framing::Length(remaining) => {
+                let len = min(*remaining, pending.len());
+                *remaining -= len;
+                (len, *remaining == 0)
+            }
+            Framing::Chunked(decoder) => match decoder.skip(pending) {
+                Ok(len) => (len, decoder.is_done()),
+                Err(err) => {
+                    log::warn!("Malformed chunked response from upstream: {}", err);
+                    return Err(interrupted_by(Peer::Upstream));
+                }
+            },
+            Framing::Close => (pending.len(), false),
+        };
+        if let Err(err) = limiter.write_all(client, &pending[..len]).await {
+            log::info!("Client went away during a streamed response: {}", err);
+            return Err(interrupted_by(Peer::Client));
+        }
+        body_len += len;
+        if done {
+            return Ok(Proxied::Streamed {
+                head: response,
+                body_len,
+                close: false,
+            });
+        }
+
+        let bytes_read = match upstream.read(&mut buffer).await {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => {
+                log::warn!("Error reading streamed response from upstream: {}", err);
+                return Err(interrupted_by(Peer::Upstream));
+            }
+        };
+        if bytes_read == 0 {
+            if let Framing::Close = framing {
+                return Ok(Proxied::Streamed {
+                    head: response,
+                    body_len,
+                    close: true,
+                });
+            }
+            log::warn!("Upstream hung up partway through a streamed response");
+            return Err(interrupted_by(Peer::Upstream));
+        }
+        pending = &buffer[..bytes_read];
+    }
+}
+
+/// This function serializes a response to bytes and writes those bytes to the provided stream. The
+/// status line and headers are gathered into a single pooled buffer so that they go out in one
+/// write; the body is written straight from the response without being copied.
 ///
 /// You will need to modify this function in Milestone 2.
 pub async fn write_to_stream(
     response: &http::Response<Vec<u8>>,
     stream: &mut TcpStream,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await.unwrap();
-    stream.write(&['\r' as u8, '\n' as u8]).await.unwrap(); // \r\n
-    for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await.unwrap();
-        stream.write(header_value.as_bytes()).await.unwrap();
-        stream.write(&['\r' as u8, '\n' as u8]).await.unwrap(); // \r\n
+    write_to_stream_limited(response, stream, &throttle::Limiter::unlimited()).await
+}
+
+/// Like write_to_stream, but the body is paced by `limiter`.
+pub async fn write_to_stream_limited(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut TcpStream,
+    limiter: &throttle::Limiter,
+) -> Result<(), std::io::Error> {
+    // A body that was decoded when it was read is sent chunked again, along with its trailers
+    let trailers = response.extensions().get::<chunked::Trailers>();
+    let mut head = buffer_pool::take();
+    serialize_head(response, &mut head);
+    if trailers.is_some() {
+        chunked::start_body(response.body().len(), &mut head);
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await.unwrap();
-    if response.body().len() > 0 {
-        stream.write(response.body()).await.unwrap();
+    stream.write_all(&head).await?;
+    if !response.body().is_empty() {
+        limiter.write_all(stream, response.body()).await?;
+    }
+    if let Some(chunked::Trailers(trailers)) = trailers {
+        let mut tail = buffer_pool::take();
+        chunked::end_body(response.body().len(), trailers, &mut tail);
+        stream.write_all(&tail).await?;
     }
     Ok(())
 }
 
+/// Appends the status line and headers, including the blank line that terminates them, to
+/// `buffer`.
+pub fn serialize_head(response: &http::Response<Vec<u8>>, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(format_response_line(response).as_bytes());
+    buffer.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in response.headers() {
+        buffer.extend_from_slice(header_name.as_str().as_bytes());
+        buffer.extend_from_slice(b": ");
+        buffer.extend_from_slice(header_value.as_bytes());
+        buffer.extend_from_slice(b"\r\n");
+    }
+    buffer.extend_from_slice(b"\r\n");
+}
+
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
     format!(
         "{:?} {} {}",
@@ -214,9 +508,19 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         status.canonical_reason().unwrap_or("")
     )
     .into_bytes();
+    make_response(status, "text/plain", body)
+}
+
+/// Creates an http::Response generated by balancebeam itself (rather than proxied from an upstream)
+/// with the given body.
+pub fn make_response(
+    status: http::StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
     http::Response::builder()
         .status(status)
-        .header("Content-Type", "text/plain")
+        .header("Content-Type", content_type)
         .header("Content-Length", body.len().to_string())
         .version(http::Version::HTTP_11)
         .body(body)
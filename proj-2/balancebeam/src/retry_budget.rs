@@ -0,0 +1,119 @@
+//! A global budget for failover retries. When a connection to an upstream fails,
+//! connect_to_upstream retries with another upstream. That's what you want when one upstream dies,
+//! but when many are failing at once (or one keeps coming back up only to fail again), every client
+//! connection turns into several connection attempts, and the extra load lands on the upstreams
+//! that are still standing, just when they can least afford it.
+//!
+//! With --retry-budget-percent, retries within the last --retry-budget-window seconds may not
+//! exceed that percentage of first attempts within the window, on top of --retry-budget-min-retries
+//! that are always allowed (so that failover still works when traffic is light). A client whose
+//! retry doesn't fit in the budget gets a 503 instead. The retry and denial counts are on
+//! /metrics.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct RetryBudget {
+    /// Retries allowed as a percentage of first attempts (0 = unlimited)
+    percent: u64,
+    /// Retries allowed per window regardless of traffic
+    min_retries: u64,
+    window: Duration,
+    /// First attempts and retries, counted per second of the window, oldest first. Each entry is
+    /// the second (since `start`) and the attempts and retries made in it.
+    counts: Mutex<VecDeque<(u64, u64, u64)>>,
+    start: Instant,
+    retries: AtomicU64,
+    denied: AtomicU64,
+}
+
+impl RetryBudget {
+    pub fn new(percent: u64, min_retries: u64, window: Duration) -> RetryBudget {
+        RetryBudget {
+            percent,
+            min_retries,
+            window,
+            counts: Mutex::new(VecDeque::new()),
+            start: Instant::now(),
+            retries: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.percent > 0
+    }
+
+    /// Returns the counts with entries older than the window dropped, and an entry for the current
+    /// second at the back.
+    fn current(&self) -> std::sync::MutexGuard<'_, VecDeque<(u64, u64, u64)>> {
+        let now = self.start.elapsed().as_secs();
+        let oldest = now.saturating_sub(self.window.as_secs().max(1) - 1);
+        let mut counts = self.counts.lock().unwrap();
+        while matches!(counts.front(), Some((second, _, _)) if *second < oldest) {
+            counts.pop_front();
+        }
+        if !matches!(counts.back(), Some((second, _, _)) if *second == now) {
+            counts.push_back((now, 0, 0));
+        }
+        counts
+    }
+
+    /// How many more retries the budget allows right now, given the counts within the window
+    fn remaining(&self, counts: &VecDeque<(u64, u64, u64)>) -> u64 {
+        let (attempts, retries) = counts
+            .iter()
+            .fold((0, 0), |(a, r), (_, attempts, retries)| {
+                (a + attempts, r + retries)
+            });
+        (self.min_retries + attempts * self.percent / 100).saturating_sub(retries)
+    }
+
+    /// Counts a first attempt at connecting to an upstream, which adds to the budget.
+    pub fn record_attempt(&self) {
+        if self.is_enabled() {
+            self.current().back_mut().unwrap().1 += 1;
+        }
+    }
+
+    /// Asks to retry after a failed attempt. Returns false if the budget is spent, in which case
+    /// the client should get an error instead.
+    pub fn try_retry(&self) -> bool {
+        if self.is_enabled() {
+            let mut counts = self.current();
+            if self.remaining(&counts) == 0 {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            counts.back_mut().unwrap().2 += 1;
+        }
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Appends the retry counters (and what's left of the budget, if there is one) to `out` in
+    /// Prometheus text format.
+    pub fn render(&self, out: &mut String) {
+        let _ = writeln!(
+            out,
+            "balancebeam_retries_total {}",
+            self.retries.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_retries_denied_total {}",
+            self.denied.load(Ordering::Relaxed)
+        );
+        if self.is_enabled() {
+            let counts = self.current();
+            let _ = writeln!(
+                out,
+                "balancebeam_retry_budget_remaining {}",
+                self.remaining(&counts)
+            );
+        }
+    }
+}
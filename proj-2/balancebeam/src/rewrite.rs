@@ -0,0 +1,97 @@
+//! Rewriting request paths before they are forwarded, so that upstreams can serve at their own
+//! paths whatever prefix the proxy exposes them under. Each `--rewrite` option is one rule:
+//!
+//! ```text
+//! --rewrite /api/v1=/                        /api/v1/users -> /users
+//! --rewrite /old-blog=/blog                  /old-blog/2020/post -> /blog/2020/post
+//! --rewrite '~^/u/([0-9]+)$=/users?id=$1'    /u/42 -> /users?id=42
+//! ```
+//!
+//! A prefix rule replaces the prefix, and only matches at a path segment boundary (/api/v1 covers
+//! /api/v1 and /api/v1/users, not /api/v10). A rule starting with ~ is a regex, matched against the
+//! path without the query string, with the first match replaced; $1 and so on in the replacement
+//! are capture groups. The first = separates the regex from the replacement, so an = in the regex
+//! has to be written as \x3d. Rules are tried in the order given, and the first one that matches
+//! wins. The query string is kept, and added to any query the replacement has.
+//!
+//! Everything except the upstream sees the path the client asked for: forward auth, routing
+//! policies, the access log and captures all use the original, and the rewrite itself is logged.
+
+use regex::Regex;
+
+#[derive(Debug)]
+pub enum Rule {
+    Prefix { prefix: String, replacement: String },
+    Regex { regex: Regex, replacement: String },
+}
+
+impl Rule {
+    pub fn parse(spec: &str) -> Result<Rule, String> {
+        if let Some(spec) = spec.strip_prefix('~') {
+            let (pattern, replacement) = spec
+                .split_once('=')
+                .ok_or_else(|| format!("expected ~REGEX=REPLACEMENT, got ~{}", spec))?;
+            let regex = Regex::new(pattern).map_err(|err| format!("{}: {}", pattern, err))?;
+            return Ok(Rule::Regex {
+                regex,
+                replacement: replacement.to_string(),
+            });
+        }
+        let (prefix, replacement) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=REPLACEMENT, got {}", spec))?;
+        if !prefix.starts_with('/') || !replacement.starts_with('/') {
+            return Err(format!("both paths must start with /, got {}", spec));
+        }
+        Ok(Rule::Prefix {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// The rewritten path (which may come with a query string), or None if the rule doesn't match
+    /// `path`.
+    fn apply(&self, path: &str) -> Option<String> {
+        match self {
+            Rule::Prefix {
+                prefix,
+                replacement,
+            } => {
+                let rest = path.strip_prefix(prefix.as_str())?;
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    return None;
+                }
+                // Don't double up the slash between the replacement and the rest of the path
+                let rest = rest.trim_start_matches('/');
+                Some(format!("{}/{}", replacement.trim_end_matches('/'), rest))
+            }
+            Rule::Regex { regex, replacement } => {
+                if !regex.is_match(path) {
+                    return None;
+                }
+                Some(regex.replace(path, replacement.as_str()).into_owned())
+            }
+        }
+    }
+}
+
+/// Returns what `uri` would be rewritten to by the first matching rule, or None if none match.
+pub fn rewrite(rules: &[Rule], uri: &http::Uri) -> Option<http::Uri> {
+    let rewritten = rules.iter().find_map(|rule| rule.apply(uri.path()))?;
+    let path_and_query = match (uri.query(), rewritten.contains('?')) {
+        (Some(query), true) => format!("{}&{}", rewritten, query),
+        (Some(query), false) => format!("{}?{}", rewritten, query),
+        (None, _) => rewritten,
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    http::Uri::from_parts(parts).ok()
+}
+
+/// Rewrites the URI of a request that is about to be forwarded, if a rule matches it, and returns
+/// the original URI so that it can be put back once the request has been sent.
+pub fn apply<T>(rules: &[Rule], request: &mut http::Request<T>) -> Option<http::Uri> {
+    let rewritten = rewrite(rules, request.uri())?;
+    log::info!("Rewrote {} to {}", request.uri(), rewritten);
+    Some(std::mem::replace(request.uri_mut(), rewritten))
+}
@@ -0,0 +1,189 @@
+//! A dry run of balancebeam's request handling, for checking a configuration without sending real
+//! traffic through it. POST a request head to the admin listener's /route-test, and it says which
+//! upstreams the request could go to and which policies would apply to it, without forwarding
+//! anything or counting the request against any limits:
+//!
+//! ```text
+//! curl --data-binary $'GET /api/users HTTP/1.1\r\nHost: example.com\r\n' \
+//!     'http://ADMIN/route-test?client=203.0.113.7'
+//! ```
+//!
+//! `client` is the address the request pretends to come from (127.0.0.1 if not given). As with real
+//! requests, X-Forwarded-For in the sample request is believed if `client` is a trusted proxy.
+
+use crate::{connect, forward_auth, request, response, rewrite, ProxyState, RATE_LIMIT_WINDOW};
+use std::fmt::Write;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Instant;
+
+/// Handles POST /route-test.
+pub async fn respond(
+    state: &ProxyState,
+    admin_request: &http::Request<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let peer_ip = match client_param(admin_request.uri()) {
+        Ok(ip) => ip,
+        Err(()) => return response::make_http_error(http::StatusCode::BAD_REQUEST),
+    };
+    // The sample only needs to be a request head, so don't insist on the blank line that ends it
+    let mut head = admin_request.body().clone();
+    while head.last().is_some_and(u8::is_ascii_whitespace) {
+        head.pop();
+    }
+    head.extend_from_slice(b"\r\n\r\n");
+    let sample = match request::parse_head(&head, state.request_limits.max_headers) {
+        Ok(sample) => sample,
+        Err(err) => {
+            let body = format!("Could not parse the sample request: {}\n", err.kind);
+            return response::make_response(
+                http::StatusCode::BAD_REQUEST,
+                "text/plain",
+                body.into_bytes(),
+            );
+        }
+    };
+    let report = simulate(state, peer_ip, &sample).await;
+    response::make_response(http::StatusCode::OK, "text/plain", report.into_bytes())
+}
+
+/// The `client` query parameter, or 127.0.0.1 if there isn't one
+fn client_param(uri: &http::Uri) -> Result<IpAddr, ()> {
+    let query = uri.query().unwrap_or("");
+    match query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("client="))
+    {
+        Some(client) => client.parse().map_err(|_| ()),
+        None => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+    }
+}
+
+/// Describes how a request from `peer_ip` would be handled, one `step: outcome` line per decision,
+/// in the order handle_connection makes them.
+async fn simulate(state: &ProxyState, peer_ip: IpAddr, request: &http::Request<Vec<u8>>) -> String {
+    let mut out = String::new();
+    let client = crate::trusted_proxies::client_identity(&state.trusted_proxies, peer_ip, request);
+    let client_ip = client.to_string();
+    let path = request.uri().path();
+    let host = request
+        .headers()
+        .get(http::header::HOST)
+        .map(|host| String::from_utf8_lossy(host.as_bytes()).into_owned());
+    let _ = writeln!(out, "request: {} {}", request.method(), request.uri());
+    let _ = writeln!(out, "host: {}", host.as_deref().unwrap_or("(none)"));
+    let _ = writeln!(out, "client: {}", client_ip);
+
+    let maintenance = if state.maintenance.turns_away(&client) {
+        "answered with 503 (maintenance mode)"
+    } else if state.maintenance.is_on() {
+        "proxied (client is allowed during maintenance)"
+    } else {
+        "off"
+    };
+    let _ = writeln!(out, "maintenance: {}", maintenance);
+
+    let (bucket, limit) = match &state.rate_limit_policy {
+        Some(policy) => policy.limit_for(&client_ip, request, state.max_requests_per_minute),
+        None => (client_ip.clone(), state.max_requests_per_minute),
+    };
+    if limit == 0 {
+        let _ = writeln!(out, "rate limit: none");
+    } else {
+        let cutoff = Instant::now() - RATE_LIMIT_WINDOW;
+        let used = state.rate_sliding_window.used(&bucket, cutoff);
+        let _ = writeln!(
+            out,
+            "rate limit: {} per minute for {} ({} used)",
+            limit, bucket, used
+        );
+    }
+
+    if request.method() == http::Method::CONNECT {
+        let verdict = match request.uri().authority() {
+            Some(authority) => match authority.port_u16() {
+                Some(port) if connect::is_allowed(&state.connect_allow, authority.host(), port) => {
+                    format!("tunnel to {}", authority)
+                }
+                Some(_) => format!("refused with 403 ({} is not on --connect-allow)", authority),
+                None => "refused with 400 (no port)".to_string(),
+            },
+            None => "refused with 400 (no host:port)".to_string(),
+        };
+        let _ = writeln!(out, "connect: {}", verdict);
+        return out;
+    }
+
+    match forward_auth::find_rule(&state.forward_auth_rules, path) {
+        Some(rule) => {
+            let _ = writeln!(
+                out,
+                "forward auth: {}{} (rule for {})",
+                rule.address, rule.path, rule.prefix
+            );
+        }
+        None => {
+            let _ = writeln!(out, "forward auth: none");
+        }
+    }
+
+    match state.idempotency.key_of(request) {
+        Some(key) => {
+            let _ = writeln!(out, "idempotency key: {:?}", key);
+        }
+        None => {
+            let _ = writeln!(out, "idempotency key: none");
+        }
+    }
+
+    match rewrite::rewrite(&state.rewrite_rules, request.uri()) {
+        Some(uri) => {
+            let _ = writeln!(out, "rewrite: {}", uri);
+        }
+        None => {
+            let _ = writeln!(out, "rewrite: none");
+        }
+    }
+
+    let pool = state.default_pool();
+    let pool_name = match state.upstream_groups.read().unwrap().active() {
+        Some(group) => format!("group {}", group.name),
+        None => "--upstream".to_string(),
+    };
+    let _ = writeln!(out, "pool: {}", pool_name);
+    let live = state.liveing_upstreams.load();
+    for address in &pool {
+        let upstream = state.upstreams.get(address);
+        let status = if !live.contains(address) {
+            "down"
+        } else if upstream.outlier.is_ejected() {
+            "ejected"
+        } else if matches!(&upstream.slots, Some(slots) if slots.available_permits() == 0) {
+            "at capacity"
+        } else {
+            "candidate"
+        };
+        let _ = writeln!(out, "upstream: {} {}", address, status);
+    }
+
+    let mut bandwidth = Vec::new();
+    if state.throttle.client_rate() > 0 {
+        bandwidth.push(format!(
+            "{} bytes/s per client",
+            state.throttle.client_rate()
+        ));
+    }
+    if let Some((_, route)) = state.throttle.route_for(path) {
+        bandwidth.push(format!("{} bytes/s under {}", route.rate, route.prefix));
+    }
+    if bandwidth.is_empty() {
+        bandwidth.push("unlimited".to_string());
+    }
+    let _ = writeln!(out, "bandwidth: {}", bandwidth.join(", "));
+
+    if state.capture.is_on() {
+        let _ = writeln!(out, "capture: {}% sampled", state.capture.sample_percent());
+    } else {
+        let _ = writeln!(out, "capture: off");
+    }
+    out
+}
@@ -0,0 +1,178 @@
+//! Shutting down on signals. The two signals mean different things to the process supervisor:
+//!
+//! * SIGTERM drains. The listeners are closed so that new connections go elsewhere, HTTP
+//!   connections are closed after the response they are working on (with Connection: close), and
+//!   balancebeam exits with status 0 once every client connection is gone, or after
+//!   --drain-timeout seconds, whichever comes first. Keep-alive connections that sit idle between
+//!   requests aren't interrupted; they close at --client-idle-timeout or when the drain times out.
+//! * SIGQUIT aborts. Every open client connection is reset (RST rather than FIN, so that clients
+//!   know their request was cut short rather than answered), the final upstream stats are logged,
+//!   and balancebeam exits with status 1 straight away. SIGQUIT during a drain aborts as well.
+
+use crate::{admin, health_report, ProxyState};
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Notify;
+use tokio::task::JoinSet;
+
+pub struct Shutdown {
+    draining: AtomicBool,
+    /// How many accept loops are running
+    accept_loops: AtomicUsize,
+    /// How long a drain waits for connections to finish before exiting anyway
+    drain_timeout: Duration,
+    /// File descriptors of the open client connections, by connection ID
+    connections: Mutex<HashMap<u64, RawFd>>,
+    next_id: AtomicU64,
+    /// Notified when the last open connection closes
+    all_closed: Notify,
+}
+
+/// Keeps a client connection on the list of open connections until dropped.
+pub struct Tracked<'a> {
+    shutdown: &'a Shutdown,
+    id: u64,
+}
+
+impl Drop for Tracked<'_> {
+    fn drop(&mut self) {
+        let mut connections = self.shutdown.connections.lock().unwrap();
+        connections.remove(&self.id);
+        if connections.is_empty() {
+            self.shutdown.all_closed.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    pub fn new(drain_timeout: Duration) -> Shutdown {
+        Shutdown {
+            draining: AtomicBool::new(false),
+            accept_loops: AtomicUsize::new(0),
+            drain_timeout,
+            connections: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(0),
+            all_closed: Notify::new(),
+        }
+    }
+
+    /// Whether a drain has started, in which case connections should close as soon as they are
+    /// between requests.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Whether any listener is accepting connections. False before the accept loops have started,
+    /// once a drain has closed them, and if they have all died.
+    pub fn is_accepting(&self) -> bool {
+        self.accept_loops.load(Ordering::SeqCst) > 0
+    }
+
+    /// Adds a client connection to the list of open connections, until the returned guard is
+    /// dropped.
+    pub fn track(&self, stream: &TcpStream) -> Tracked<'_> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(id, stream.as_raw_fd());
+        Tracked { shutdown: self, id }
+    }
+
+    fn open_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    /// Returns once there are no open connections.
+    async fn all_closed(&self) {
+        loop {
+            // Created before checking, so that a notification in between isn't missed
+            let notified = self.all_closed.notified();
+            if self.open_connections() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Sets SO_LINGER to zero on every open connection, so that the kernel resets them instead of
+    /// closing them gracefully when the process exits. Returns how many there were.
+    fn reset_all(&self) -> usize {
+        let connections = self.connections.lock().unwrap();
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        for fd in connections.values() {
+            // SAFETY: `linger` outlives the call. A connection that has just closed may still be on
+            // the list for a moment, so the descriptor could have been reused for another socket by
+            // now, but that's harmless: the process is about to exit, and every socket goes with it.
+            unsafe {
+                libc::setsockopt(
+                    *fd,
+                    libc::SOL_SOCKET,
+                    libc::SO_LINGER,
+                    &linger as *const libc::linger as *const libc::c_void,
+                    std::mem::size_of::<libc::linger>() as libc::socklen_t,
+                );
+            }
+        }
+        connections.len()
+    }
+}
+
+/// Waits for SIGTERM or SIGQUIT and shuts down accordingly, logging any accept loop that dies in
+/// the meantime, and the health report on SIGUSR1. Returns the status to exit with.
+pub async fn wait_for_signal(state: &ProxyState, mut accept_tasks: JoinSet<()>) -> i32 {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Could not handle SIGTERM");
+    let mut sigquit = signal(SignalKind::quit()).expect("Could not handle SIGQUIT");
+    let mut sigusr1 = signal(SignalKind::user_defined1()).expect("Could not handle SIGUSR1");
+    let shutdown = &state.shutdown;
+    shutdown.accept_loops.store(accept_tasks.len(), Ordering::SeqCst);
+    loop {
+        tokio::select! {
+            _ = sigterm.recv() => break,
+            _ = sigquit.recv() => return abort(state),
+            // Not a shutdown, but this is where the signals are
+            _ = sigusr1.recv() => {
+                log::info!("Health report: {}", health_report::render(state).trim_end());
+            }
+            Some(joined) = accept_tasks.join_next() => {
+                if let Err(err) = joined {
+                    log::error!("accept loop exited unexpectedly: {}", err);
+                }
+                shutdown.accept_loops.store(accept_tasks.len(), Ordering::SeqCst);
+            }
+        }
+    }
+
+    shutdown.draining.store(true, Ordering::SeqCst);
+    accept_tasks.abort_all();
+    shutdown.accept_loops.store(0, Ordering::SeqCst);
+    log::warn!(
+        "SIGTERM: no longer accepting connections, draining {} open connections",
+        shutdown.open_connections()
+    );
+    tokio::select! {
+        _ = shutdown.all_closed() => log::warn!("All connections closed; exiting"),
+        _ = tokio::time::sleep(shutdown.drain_timeout) => log::warn!(
+            "{} connections still open after {:?}; exiting anyway",
+            shutdown.open_connections(),
+            shutdown.drain_timeout
+        ),
+        _ = sigquit.recv() => return abort(state),
+    }
+    0
+}
+
+fn abort(state: &ProxyState) -> i32 {
+    let reset = state.shutdown.reset_all();
+    log::warn!("SIGQUIT: resetting {} open connections and exiting", reset);
+    log::warn!("Final stats:\n{}", admin::render_metrics(state));
+    1
+}
@@ -0,0 +1,110 @@
+//! Per-client request timestamps for rate limiting. Every proxied request looks up its client here,
+//! so with a single lock around one map, connection tasks would queue up behind each other. The map
+//! is split into shards by a hash of the client key instead, each behind its own lock, and two
+//! requests only contend if their clients happen to land in the same shard.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Number of shards. Plenty to keep contention low with a few dozen threads, and few enough that
+/// sweeping all of them for stale entries is cheap.
+const SHARDS: usize = 64;
+
+type Shard = HashMap<String, VecDeque<Instant>>;
+
+pub struct SlidingWindows {
+    shards: Vec<Mutex<Shard>>,
+    /// Picks the shard for a key. Randomly seeded, so clients can't choose keys that all land in
+    /// one shard.
+    hasher: RandomState,
+    /// Most clients tracked per shard (0 = unlimited)
+    max_per_shard: usize,
+}
+
+impl SlidingWindows {
+    /// Creates an empty set of windows tracking at most `max_clients` clients (0 = unlimited).
+    ///
+    /// The cap is split evenly between the shards (with fewer shards if the cap is small), and
+    /// each shard forgets its own least recently seen client when it is full. So the cap is never
+    /// exceeded, but a client may be forgotten a little before the map as a whole is full.
+    pub fn new(max_clients: usize) -> SlidingWindows {
+        let shards = if max_clients > 0 {
+            SHARDS.min(max_clients)
+        } else {
+            SHARDS
+        };
+        SlidingWindows {
+            shards: (0..shards).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            max_per_shard: max_clients / shards,
+        }
+    }
+
+    fn shard(&self, key: &str) -> &Mutex<Shard> {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+
+    /// Counts a request by `key` at `now` if fewer than `limit` of its requests fall within the
+    /// `window` before it. Otherwise the request is not counted, and the time until the oldest of
+    /// them falls out of the window is returned.
+    pub fn hit(
+        &self,
+        key: String,
+        limit: usize,
+        window: Duration,
+        now: Instant,
+    ) -> Option<Duration> {
+        let cutoff = now.checked_sub(window).unwrap_or(now);
+        let mut shard = self.shard(&key).lock().unwrap();
+        if self.max_per_shard > 0 && shard.len() >= self.max_per_shard && !shard.contains_key(&key)
+        {
+            // Make room by forgetting the client we heard from least recently
+            let least_recent = shard
+                .iter()
+                .min_by_key(|(_, deque)| deque.back().copied())
+                .map(|(key, _)| key.clone());
+            if let Some(key) = least_recent {
+                shard.remove(&key);
+            }
+        }
+        let deque = shard.entry(key).or_default();
+
+        while matches!(deque.front(), Some(ts) if *ts < cutoff) {
+            deque.pop_front();
+        }
+
+        if deque.len() >= limit {
+            let oldest = *deque.front().unwrap();
+            return Some((oldest + window).saturating_duration_since(now));
+        }
+        deque.push_back(now);
+        None
+    }
+
+    /// How many requests `key` has made since `cutoff`, without counting a new one
+    pub fn used(&self, key: &str, cutoff: Instant) -> usize {
+        self.shard(key)
+            .lock()
+            .unwrap()
+            .get(key)
+            .map_or(0, |deque| deque.iter().filter(|ts| **ts >= cutoff).count())
+    }
+
+    /// Forgets clients that haven't made a request since `cutoff`. Returns how many were
+    /// forgotten and how many are still tracked.
+    pub fn evict_stale(&self, cutoff: Instant) -> (usize, usize) {
+        let (mut evicted, mut remaining) = (0, 0);
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let before = shard.len();
+            shard.retain(|_, deque| matches!(deque.back(), Some(ts) if *ts >= cutoff));
+            evicted += before - shard.len();
+            remaining += shard.len();
+        }
+        (evicted, remaining)
+    }
+}
@@ -0,0 +1,179 @@
+//! TLS passthrough with SNI-based routing. Listeners in tls-passthrough mode read the server name
+//! (SNI) from the client's TLS ClientHello without consuming it, pick the upstream pool configured
+//! for that hostname, and then tunnel the still-encrypted connection to it like a tcp listener
+//! would. The TLS session is between the client and the upstream; balancebeam never decrypts it.
+
+use crate::{tcp, ProxyState};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+/// Largest TLS record (header included) that we are willing to wait for
+const MAX_RECORD_SIZE: usize = 5 + 16384;
+/// How long a client gets to send its ClientHello
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upstreams that serve one TLS hostname. Parsed from a
+/// `--sni-route HOSTNAME=HOST:PORT[,HOST:PORT...]` command-line option.
+#[derive(Debug)]
+pub struct Route {
+    /// Lowercased server name the route applies to
+    pub hostname: String,
+    pub upstreams: Vec<String>,
+}
+
+impl Route {
+    pub fn parse(spec: &str) -> Result<Route, String> {
+        let (hostname, upstreams) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected HOSTNAME=HOST:PORT[,HOST:PORT...], got {}", spec))?;
+        if hostname.is_empty() {
+            return Err(format!("missing hostname in {}", spec));
+        }
+        let upstreams: Vec<String> = upstreams
+            .split(',')
+            .filter(|upstream| !upstream.is_empty())
+            .map(str::to_string)
+            .collect();
+        if upstreams.is_empty() {
+            return Err(format!("no upstreams in {}", spec));
+        }
+        Ok(Route {
+            hostname: hostname.to_ascii_lowercase(),
+            upstreams,
+        })
+    }
+}
+
+/// Routes a TLS connection by the server name in its ClientHello. Connections without a server name
+/// (or with one that has no route) go to the default --upstream pool.
+pub async fn route(client_conn: TcpStream, peer_ip: IpAddr, state: Arc<ProxyState>) {
+    let server_name = match timeout(CLIENT_HELLO_TIMEOUT, peek_server_name(&client_conn)).await {
+        Ok(Ok(server_name)) => server_name,
+        Ok(Err(err)) => {
+            log::info!("{}: could not read TLS ClientHello: {}", peer_ip, err);
+            return;
+        }
+        Err(_) => {
+            log::info!("{}: timed out waiting for TLS ClientHello", peer_ip);
+            return;
+        }
+    };
+    let route = server_name.as_ref().and_then(|name| {
+        state
+            .sni_routes
+            .iter()
+            .find(|route| route.hostname.eq_ignore_ascii_case(name))
+    });
+    let pool = match route {
+        Some(route) => route.upstreams.clone(),
+        None => state.default_pool(),
+    };
+    log::debug!(
+        "{}: SNI {:?} routed to {:?}",
+        peer_ip,
+        server_name.as_deref().unwrap_or("(none)"),
+        pool
+    );
+    tcp::tunnel(client_conn, peer_ip, Arc::clone(&state), &pool).await;
+}
+
+/// Waits until the first TLS record has arrived and returns the server name from the ClientHello
+/// in it, if it has one. Only peeks at the stream, so the ClientHello is still there to be
+/// forwarded.
+async fn peek_server_name(conn: &TcpStream) -> Result<Option<String>, String> {
+    let mut buf = vec![0_u8; MAX_RECORD_SIZE];
+    loop {
+        let n = conn.peek(&mut buf).await.map_err(|err| err.to_string())?;
+        if n == 0 {
+            return Err("client hung up".to_string());
+        }
+        if n >= 5 {
+            if buf[0] != 0x16 {
+                return Err("not a TLS handshake".to_string());
+            }
+            let record_len = 5 + u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if record_len > MAX_RECORD_SIZE {
+                return Err("TLS record too large".to_string());
+            }
+            if n >= record_len {
+                return parse_server_name(&buf[5..record_len]);
+            }
+        }
+        // Only part of the record has arrived so far; wait for the rest
+        sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Finds the server_name extension in a ClientHello handshake message.
+fn parse_server_name(handshake: &[u8]) -> Result<Option<String>, String> {
+    let mut reader = Reader(handshake);
+    if reader.u8()? != 0x01 {
+        return Err("first handshake message is not a ClientHello".to_string());
+    }
+    reader.skip(3)?; // handshake length
+    reader.skip(2 + 32)?; // client version and random
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.skip(cipher_suites_len)?;
+    let compression_methods_len = reader.u8()? as usize;
+    reader.skip(compression_methods_len)?;
+    if reader.0.is_empty() {
+        // No extensions at all
+        return Ok(None);
+    }
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.take(extension_len)?);
+        if extension_type != 0x0000 {
+            continue;
+        }
+        let list_len = extension.u16()? as usize;
+        let mut names = Reader(extension.take(list_len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name_len = names.u16()? as usize;
+            let name = names.take(name_len)?;
+            // 0 is host_name, the only name type there is
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec())
+                    .map(Some)
+                    .map_err(|_| "server name is not valid UTF-8".to_string());
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reads big-endian fields off the front of a byte slice
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("truncated ClientHello".to_string());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        self.take(len).map(drop)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
@@ -0,0 +1,56 @@
+//! systemd socket activation. When systemd (or anything else speaking the same protocol) starts
+//! balancebeam with already-listening sockets, they are used instead of binding --bind. systemd
+//! keeps the sockets open while balancebeam restarts, so connections queue up instead of being
+//! refused. See sd_listen_fds(3).
+//!
+//! If the unit names the sockets (FileDescriptorName=), a name of http, tcp or tls-passthrough sets
+//! that listener's mode; other listeners use --mode.
+
+use crate::tcp;
+use std::os::unix::io::FromRawFd;
+
+/// Passed sockets start at this file descriptor
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// A passed socket, with a name for the logs and its mode
+pub type Listener = (String, std::net::TcpListener, tcp::Mode);
+
+/// Returns the sockets passed to us, or None if no sockets were passed.
+pub fn listeners(default_mode: tcp::Mode) -> Result<Option<Vec<Listener>>, String> {
+    let count = match std::env::var("LISTEN_FDS") {
+        Ok(count) => count,
+        Err(_) => return Ok(None),
+    };
+    // The variables are meant for one process only; if we inherited them from a parent that was
+    // socket activated, the file descriptors aren't ours
+    let pid = std::env::var("LISTEN_PID").unwrap_or_default();
+    if pid != std::process::id().to_string() {
+        return Ok(None);
+    }
+    let count: i32 = count
+        .parse()
+        .map_err(|_| format!("invalid LISTEN_FDS {}", count))?;
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let names: Vec<&str> = names.split(':').collect();
+    for var in ["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+
+    let mut listeners = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let fd = SD_LISTEN_FDS_START + i;
+        // Safety: LISTEN_PID says these descriptors were passed to this process, and nothing else
+        // in the process knows about them
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        let address = listener
+            .local_addr()
+            .map_err(|err| format!("passed file descriptor {} is not a TCP socket: {}", fd, err))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(|err| format!("could not use passed socket {}: {}", address, err))?;
+        let name = names.get(i as usize).copied().unwrap_or_default();
+        let mode = clap::ValueEnum::from_str(name, true).unwrap_or(default_mode);
+        listeners.push((format!("{} (fd {})", address, fd), listener, mode));
+    }
+    Ok(Some(listeners))
+}
@@ -0,0 +1,139 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the latency histogram buckets. There is an implicit +Inf bucket at
+/// the end.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// How many of the latest latencies are kept for the health report
+const RECENT_LATENCIES: usize = 32;
+
+/// Counters for a single upstream. Everything is atomic so that connection tasks can record into
+/// the stats without taking a lock.
+#[derive(Default)]
+pub struct UpstreamStats {
+    /// Requests that got a response back from the upstream
+    requests: AtomicU64,
+    /// Failed connection attempts and failed request/response exchanges
+    errors: AtomicU64,
+    /// Request body bytes sent to the upstream
+    bytes_sent: AtomicU64,
+    /// Response body bytes received from the upstream
+    bytes_received: AtomicU64,
+    /// Number of responses that fell into each latency bucket (non-cumulative; the last entry is
+    /// the +Inf bucket)
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    /// Sum of all recorded latencies, in microseconds
+    latency_sum_micros: AtomicU64,
+    /// The latest latencies in microseconds, as a ring buffer
+    recent_latencies_micros: [AtomicU64; RECENT_LATENCIES],
+    /// Total number of latencies ever written to recent_latencies_micros
+    recent_latencies_written: AtomicUsize,
+}
+
+impl UpstreamStats {
+    pub fn new() -> UpstreamStats {
+        UpstreamStats::default()
+    }
+
+    /// Records a completed request/response exchange.
+    pub fn record_response(&self, latency: Duration, bytes_sent: usize, bytes_received: usize) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent
+            .fetch_add(bytes_sent as u64, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes_received as u64, Ordering::Relaxed);
+        let seconds = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        let slot = self.recent_latencies_written.fetch_add(1, Ordering::Relaxed) % RECENT_LATENCIES;
+        self.recent_latencies_micros[slot].store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The latest recorded latencies (up to RECENT_LATENCIES of them), sorted.
+    pub fn recent_latencies(&self) -> Vec<Duration> {
+        let written = self.recent_latencies_written.load(Ordering::Relaxed);
+        let mut latencies: Vec<Duration> = self.recent_latencies_micros
+            [..written.min(RECENT_LATENCIES)]
+            .iter()
+            .map(|micros| Duration::from_micros(micros.load(Ordering::Relaxed)))
+            .collect();
+        latencies.sort();
+        latencies
+    }
+
+    /// The number of requests that got a response, and the number of errors
+    pub fn counts(&self) -> (u64, u64) {
+        (
+            self.requests.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+
+    /// The mean latency of the responses recorded so far, or None if there haven't been any.
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let requests = self.requests.load(Ordering::Relaxed);
+        if requests == 0 {
+            return None;
+        }
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed);
+        Some(Duration::from_micros(sum / requests))
+    }
+
+    /// Records a failure to connect to, write to, or read from the upstream.
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Appends this upstream's metrics to `out` in the Prometheus text exposition format.
+    pub fn render(&self, upstream: &str, out: &mut String) {
+        let label = format!("upstream=\"{}\"", upstream);
+        let counters = [
+            ("requests_total", &self.requests),
+            ("errors_total", &self.errors),
+            ("bytes_sent_total", &self.bytes_sent),
+            ("bytes_received_total", &self.bytes_received),
+        ];
+        for (name, counter) in counters.iter() {
+            let _ = writeln!(
+                out,
+                "balancebeam_upstream_{}{{{}}} {}",
+                name,
+                label,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+        let mut cumulative = 0;
+        for (i, count) in self.latency_buckets.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = match LATENCY_BUCKETS.get(i) {
+                Some(bound) => bound.to_string(),
+                None => "+Inf".to_string(),
+            };
+            let _ = writeln!(
+                out,
+                "balancebeam_upstream_latency_seconds_bucket{{{},le=\"{}\"}} {}",
+                label, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_latency_seconds_sum{{{}}} {}",
+            label,
+            self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_upstream_latency_seconds_count{{{}}} {}",
+            label, cumulative
+        );
+    }
+}
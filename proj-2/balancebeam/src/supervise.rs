@@ -0,0 +1,54 @@
+//! Supervised background tasks. A panic in a plain tokio task only kills that task, and nothing
+//! notices: if a health check probe panicked, the upstream would silently never be checked again.
+//! Background tasks (health checks, the periodic reloads, flushes and evictions) are spawned here
+//! instead, so that a panic is logged and the task is started again after a backoff that doubles
+//! with each consecutive panic, up to MAX_BACKOFF. A task that ran for RESET_AFTER before panicking
+//! starts over from INITIAL_BACKOFF. A task that returns normally is done and isn't restarted.
+
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A task that ran at least this long before panicking is taken to have been healthy
+const RESET_AFTER: Duration = Duration::from_secs(300);
+
+/// Spawns the task made by `make_task` under supervision, calling `make_task` again to restart it
+/// whenever it panics. `name` identifies the task in the logs.
+pub fn spawn<F, Fut>(name: String, make_task: F)
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let started = Instant::now();
+            let err = match tokio::spawn(make_task()).await {
+                Ok(()) => return,
+                Err(err) if err.is_panic() => err,
+                // Cancelled, which only happens when the runtime is shutting down
+                Err(_) => return,
+            };
+            if started.elapsed() >= RESET_AFTER {
+                backoff = INITIAL_BACKOFF;
+            }
+            let payload = err.into_panic();
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("(no message)");
+            log::error!(
+                "Background task {} panicked: {}; restarting it in {:?}",
+                name,
+                message,
+                backoff
+            );
+            sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
@@ -0,0 +1,76 @@
+//! Raw TCP tunneling, for load balancing databases and other protocols that aren't HTTP. Listeners
+//! in tcp mode never look at the bytes they carry: each client connection is paired with a
+//! connection to an upstream (picked, and failed over, the same way as for HTTP) and the two are
+//! spliced together until either side hangs up.
+
+use crate::{accounting, balance, connect_to_upstream, ProxyState};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpStream;
+
+/// How a listener treats the connections it accepts
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Parse and proxy HTTP requests
+    Http,
+    /// Tunnel raw TCP connections without parsing them
+    Tcp,
+    /// Tunnel TLS connections without decrypting them, picking the upstream pool by the SNI
+    /// hostname (see --sni-route)
+    TlsPassthrough,
+}
+
+/// Tunnels a client connection to an upstream from `pool`. There is no way to send the client an
+/// error, so if no upstream can be reached the connection is simply closed.
+pub async fn tunnel(
+    mut client_conn: TcpStream,
+    peer_ip: IpAddr,
+    state: Arc<ProxyState>,
+    pool: &[String],
+) {
+    let (upstream_address, mut upstream_conn, _upstream_slot) =
+        match connect_to_upstream(Arc::clone(&state), pool, peer_ip, None).await {
+            Ok(conn) => conn,
+            Err(error) => {
+                log::warn!("Could not connect {} to an upstream: {:?}", peer_ip, error);
+                return;
+            }
+        };
+    log::info!("{} -> {}: TCP tunnel opened", peer_ip, upstream_address);
+
+    // Each tunnel counts as one exchange in the upstream's stats, with the lifetime of the
+    // connection as its latency
+    let upstream = state.upstreams.get(&upstream_address);
+    let stats = &upstream.stats;
+    let started = Instant::now();
+    match copy_bidirectional(&mut client_conn, &mut upstream_conn).await {
+        Ok((sent, received)) => {
+            stats.record_response(started.elapsed(), sent as usize, received as usize);
+            if let Some(ledger) = &state.accounting {
+                let labels = accounting::Labels::default();
+                ledger.record(&upstream_address, &labels, sent as usize, received as usize);
+            }
+            log::info!(
+                "{} <- {}: TCP tunnel closed ({} bytes sent, {} bytes received)",
+                peer_ip,
+                upstream_address,
+                sent,
+                received
+            );
+        }
+        Err(err) => {
+            stats.record_error();
+            state
+                .balance
+                .report_result(&upstream_address, &balance::Outcome::Failed);
+            log::info!(
+                "{} <- {}: TCP tunnel failed: {}",
+                peer_ip,
+                upstream_address,
+                err
+            );
+        }
+    }
+}
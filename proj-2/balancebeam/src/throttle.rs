@@ -0,0 +1,204 @@
+//! Bandwidth throttling for response bodies, so that one client pulling down large files can't
+//! starve everyone else's interactive traffic. Each client gets a token bucket that fills at the
+//! configured rate (and holds at most one second's worth), and every write of a response body
+//! waits until the bucket has enough tokens for it.
+//!
+//! `--client-bandwidth` limits each client across all of its requests. `--route-bandwidth
+//! PREFIX=RATE` limits each client's requests under a path prefix separately (the longest matching
+//! prefix wins); when both apply, a write has to wait for both buckets. Only HTTP/1 responses are
+//! throttled; HTTP/2 has its own flow control.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Largest single write. Small enough that throttled output goes out smoothly rather than in
+/// bursts of a second's worth at a time.
+const MAX_CHUNK: usize = 16 * 1024;
+
+/// Once there are this many buckets, idle ones are forgotten before adding another. A bucket that
+/// has filled back up remembers nothing a fresh one wouldn't.
+const PRUNE_THRESHOLD: usize = 10000;
+
+/// A bandwidth limit for requests under a path prefix. Parsed from a `--route-bandwidth
+/// PREFIX=BYTES_PER_SEC` command-line option.
+#[derive(Debug)]
+pub struct Route {
+    pub prefix: String,
+    pub rate: usize,
+}
+
+impl Route {
+    pub fn parse(spec: &str) -> Result<Route, String> {
+        let (prefix, rate) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected PREFIX=BYTES_PER_SEC, got {}", spec))?;
+        if !prefix.starts_with('/') {
+            return Err(format!("path prefix must start with /, got {}", prefix));
+        }
+        match rate.parse::<usize>() {
+            Ok(rate) if rate > 0 => Ok(Route {
+                prefix: prefix.to_string(),
+                rate,
+            }),
+            _ => Err(format!("invalid rate {} in {}", rate, spec)),
+        }
+    }
+}
+
+struct Bucket {
+    /// Bytes per second, which is also how many tokens the bucket holds when full
+    rate: usize,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: usize) -> Bucket {
+        Bucket {
+            rate,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.refilled = now;
+    }
+
+    /// Takes `n` tokens if the bucket has them, or returns how long until it will. `n` must not be
+    /// more than the rate.
+    fn take(&mut self, n: usize) -> Option<Duration> {
+        self.refill(Instant::now());
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (n as f64 - self.tokens) / self.rate as f64,
+            ))
+        }
+    }
+
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.rate as f64
+    }
+}
+
+/// Identifies a bucket: the client, and the index of the route, or None for the client-wide one
+type BucketKey = (String, Option<usize>);
+
+pub struct Throttle {
+    /// Bytes per second for each client (0 = unlimited)
+    client_rate: usize,
+    routes: Vec<Route>,
+    buckets: Mutex<HashMap<BucketKey, Arc<Mutex<Bucket>>>>,
+}
+
+impl Throttle {
+    pub fn new(client_rate: usize, routes: Vec<Route>) -> Throttle {
+        Throttle {
+            client_rate,
+            routes,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Bytes per second for each client (0 = unlimited)
+    pub fn client_rate(&self) -> usize {
+        self.client_rate
+    }
+
+    /// Returns the route limiting requests to `path` (and its index), if any.
+    pub fn route_for(&self, path: &str) -> Option<(usize, &Route)> {
+        self.routes
+            .iter()
+            .enumerate()
+            .filter(|(_, route)| path.starts_with(&route.prefix))
+            .max_by_key(|(_, route)| route.prefix.len())
+    }
+
+    /// Returns the limiter for a response to `client` for a request to `path`.
+    pub fn limiter(&self, client: &str, path: &str) -> Limiter {
+        let mut wanted = Vec::new();
+        if self.client_rate > 0 {
+            wanted.push(((client.to_string(), None), self.client_rate));
+        }
+        if let Some((idx, route)) = self.route_for(path) {
+            wanted.push(((client.to_string(), Some(idx)), route.rate));
+        }
+        if wanted.is_empty() {
+            return Limiter::unlimited();
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            let now = Instant::now();
+            // Buckets held by a Limiter are in use, even if they happen to be full right now
+            buckets.retain(|_, bucket| {
+                Arc::strong_count(bucket) > 1 || !bucket.lock().unwrap().is_full(now)
+            });
+        }
+        Limiter {
+            buckets: wanted
+                .into_iter()
+                .map(|(key, rate)| {
+                    Arc::clone(
+                        buckets
+                            .entry(key)
+                            .or_insert_with(|| Arc::new(Mutex::new(Bucket::new(rate)))),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The buckets that writes of one response have to draw from
+pub struct Limiter {
+    buckets: Vec<Arc<Mutex<Bucket>>>,
+}
+
+impl Limiter {
+    /// A limiter that never waits
+    pub fn unlimited() -> Limiter {
+        Limiter {
+            buckets: Vec::new(),
+        }
+    }
+
+    /// Writes all of `bytes` to `stream`, waiting for the buckets to fill up as needed.
+    pub async fn write_all<W: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut W,
+        bytes: &[u8],
+    ) -> Result<(), std::io::Error> {
+        if self.buckets.is_empty() {
+            return stream.write_all(bytes).await;
+        }
+        // A chunk must fit in every bucket, or waiting for the tokens would never end
+        let min_rate = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.lock().unwrap().rate)
+            .min()
+            .unwrap();
+        for chunk in bytes.chunks(MAX_CHUNK.min(min_rate)) {
+            for bucket in &self.buckets {
+                loop {
+                    let wait = bucket.lock().unwrap().take(chunk.len());
+                    match wait {
+                        Some(wait) => tokio::time::sleep(wait).await,
+                        None => break,
+                    }
+                }
+            }
+            stream.write_all(chunk).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IP network in CIDR notation, e.g. 10.0.0.0/8 or fd00::/8. A bare address is treated as a
+/// single-host network.
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cidr, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid IP address in {}", s))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u32>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max_len,
+        };
+        Ok(Cidr {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Cidr {
+    pub fn prefix_len(&self) -> u32 {
+        self.prefix_len
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_trusted(trusted: &[Cidr], ip: &IpAddr) -> bool {
+    trusted.iter().any(|cidr| cidr.contains(ip))
+}
+
+/// Works out which address a request should be attributed to. If the peer we're talking to is one
+/// of our trusted front proxies, we walk its X-Forwarded-For header from right to left (the
+/// rightmost entry was added by the proxy closest to us) and return the first address that isn't a
+/// trusted proxy. Entries to the left of that could have been forged by the client, so they're
+/// ignored. Requests from untrusted peers are always attributed to the peer itself.
+pub fn client_identity<B>(trusted: &[Cidr], peer_ip: IpAddr, request: &http::Request<B>) -> IpAddr {
+    if !is_trusted(trusted, &peer_ip) {
+        return peer_ip;
+    }
+    let mut identity = peer_ip;
+    let headers: Vec<_> = request.headers().get_all("x-forwarded-for").iter().collect();
+    for header in headers.into_iter().rev() {
+        // Multiple X-Forwarded-For headers are equivalent to one comma-joined list, so we look at
+        // the headers in reverse too
+        let entries = match header.to_str() {
+            Ok(entries) => entries,
+            Err(_) => return identity,
+        };
+        for entry in entries.rsplit(',') {
+            match entry.trim().parse::<IpAddr>() {
+                Ok(ip) if is_trusted(trusted, &ip) => identity = ip,
+                Ok(ip) => return ip,
+                // Garbage in the header; don't trust anything beyond this point
+                Err(_) => return identity,
+            }
+        }
+    }
+    identity
+}
@@ -0,0 +1,116 @@
+//! TLS (and mutual TLS) for connections to upstreams. When any of the --upstream-client-cert,
+//! --upstream-client-key or --upstream-ca options is given, every connection to an upstream (for
+//! proxied requests, tcp tunnels and health checks) is wrapped in TLS. The upstream's certificate
+//! is checked against the configured CA, and the client certificate, if there is one, is presented
+//! so that the upstream can check ours in turn.
+
+use native_tls::{Certificate, Identity};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio_native_tls::{TlsConnector, TlsStream};
+
+/// How to set up TLS to the upstreams
+pub struct Config {
+    connector: TlsConnector,
+}
+
+impl Config {
+    /// Builds the TLS settings from PEM files. `cert` and `key` must be given together; the key has
+    /// to be in PKCS#8 form. If `ca` is given, upstream certificates are verified against it alone
+    /// instead of the system's root certificates.
+    pub fn load(cert: Option<&str>, key: Option<&str>, ca: Option<&str>) -> Result<Config, String> {
+        let mut builder = native_tls::TlsConnector::builder();
+        match (cert, key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8(&read(cert)?, &read(key)?)
+                    .map_err(|err| format!("invalid client certificate or key: {}", err))?;
+                builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => return Err("a client certificate and key must be given together".to_string()),
+        }
+        if let Some(ca) = ca {
+            let ca_cert = Certificate::from_pem(&read(ca)?)
+                .map_err(|err| format!("invalid CA certificate {}: {}", ca, err))?;
+            builder.add_root_certificate(ca_cert);
+            builder.disable_built_in_roots(true);
+        }
+        let connector = builder.build().map_err(|err| err.to_string())?;
+        Ok(Config {
+            connector: TlsConnector::from(connector),
+        })
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("could not read {}: {}", path, err))
+}
+
+/// A connection to an upstream, with or without TLS
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// Opens a connection to `address`, doing the TLS handshake if TLS to upstreams is configured. The
+/// upstream's certificate has to be valid for the host part of `address`.
+pub async fn connect(tls: Option<&Config>, address: &str) -> Result<Stream, String> {
+    let conn = TcpStream::connect(address)
+        .await
+        .map_err(|err| err.to_string())?;
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Ok(Stream::Plain(conn)),
+    };
+    let host = match address.rsplit_once(':') {
+        Some((host, _)) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => address,
+    };
+    match tls.connector.connect(host, conn).await {
+        Ok(conn) => Ok(Stream::Tls(Box::new(conn))),
+        Err(err) => Err(format!("TLS handshake failed: {}", err)),
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(conn) => Pin::new(conn).poll_read(cx, buf),
+            Stream::Tls(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(conn) => Pin::new(conn).poll_write(cx, buf),
+            Stream::Tls(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(conn) => Pin::new(conn).poll_flush(cx),
+            Stream::Tls(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(conn) => Pin::new(conn).poll_shutdown(cx),
+            Stream::Tls(conn) => Pin::new(conn).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Runtime changes to the --upstream pool. The admin API can add and remove upstreams without a
+//! restart:
+//!
+//! ```text
+//! curl -X POST --data 10.0.0.7:8080 http://ADMIN/upstreams/add
+//! curl -X POST --data 10.0.0.7:8080 http://ADMIN/upstreams/remove
+//! curl http://ADMIN/upstreams
+//! ```
+//!
+//! New upstreams take traffic and get health checks straight away. Removed upstreams stop getting
+//! new requests, but requests already in flight to them finish normally. If --upstreams-file is
+//! given, the pool is saved to it after every change and read back from it at startup, so changes
+//! survive a restart.
+//!
+//! Upstreams can also be given as named groups (--upstream-group blue=... --upstream-group
+//! green=...) for blue/green deploys. Only the active group gets traffic, but every group is health
+//! checked, so before switching over you can see whether the other group is up:
+//!
+//! ```text
+//! curl http://ADMIN/upstreams/groups
+//! curl -X POST --data green http://ADMIN/upstreams/switch
+//! ```
+//!
+//! A switch is refused if none of the new group's upstreams are passing health checks. Adding and
+//! removing upstreams changes the active group.
+//!
+//! For container workflows, where the upstream list comes from an orchestration template, the
+//! --upstream pool can be given as a list instead of one --upstream option per upstream: in a file
+//! (--upstream-file PATH, or --upstream-file - to read it from stdin), or in the BB_UPSTREAMS
+//! environment variable. BB_UPSTREAMS is only used when there are no --upstream options and no
+//! --upstream-file. Either way, upstreams are separated by newlines or commas, and lines starting
+//! with `#` are comments:
+//!
+//! ```text
+//! BB_UPSTREAMS=10.0.0.5:8080,10.0.0.6:8080 balancebeam
+//! printf '10.0.0.5:8080\n10.0.0.6:8080\n' | balancebeam --upstream-file -
+//! ```
+//!
+//! Everything balancebeam tracks about an upstream (stats, outlier detection, connection slots)
+//! lives in the Registry, which keeps an entry for every upstream it has ever been told about.
+//! Connection tasks can look up the upstream they are talking to even if it has been removed in
+//! the meantime, and an upstream that is removed and added back keeps its counters.
+
+use crate::{health_check, outlier, stats, ProxyState};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+
+/// What balancebeam tracks about one upstream
+pub struct Upstream {
+    /// Latency, traffic and error counters
+    pub stats: stats::UpstreamStats,
+    /// Outlier detection state
+    pub outlier: outlier::Detector,
+    /// Connection slots, or None if connections are uncapped
+    pub slots: Option<Arc<Semaphore>>,
+    /// Bumped whenever health checks of this upstream are started or stopped, so that a probe loop
+    /// knows when to stop (even if the upstream was added back since)
+    health_check_generation: AtomicUsize,
+    /// When a proxied request to this upstream last got a non-5xx response, if ever
+    last_success: Mutex<Option<Instant>>,
+    /// The latest active health checks
+    probes: Mutex<health_check::ProbeHistory>,
+}
+
+pub struct Registry {
+    upstreams: RwLock<HashMap<String, Arc<Upstream>>>,
+    /// Maximum number of concurrent connections to each upstream (0 = unlimited)
+    max_connections: usize,
+    /// Held while the pool is being changed, so that concurrent changes don't interleave
+    changing: tokio::sync::Mutex<()>,
+}
+
+impl Registry {
+    pub fn new(addresses: &[String], max_connections: usize) -> Registry {
+        let registry = Registry {
+            upstreams: RwLock::new(HashMap::new()),
+            max_connections,
+            changing: tokio::sync::Mutex::new(()),
+        };
+        for address in addresses {
+            registry.insert(address);
+        }
+        registry
+    }
+
+    /// Returns the entry for an upstream. Like indexing a HashMap, panics if balancebeam has never
+    /// heard of the upstream.
+    pub fn get(&self, address: &str) -> Arc<Upstream> {
+        Arc::clone(&self.upstreams.read().unwrap()[address])
+    }
+
+    /// Returns the entry for an upstream, creating it if this is a new upstream.
+    fn insert(&self, address: &str) -> Arc<Upstream> {
+        let mut upstreams = self.upstreams.write().unwrap();
+        let upstream = upstreams.entry(address.to_string()).or_insert_with(|| {
+            Arc::new(Upstream {
+                stats: stats::UpstreamStats::new(),
+                outlier: outlier::Detector::new(),
+                slots: if self.max_connections > 0 {
+                    Some(Arc::new(Semaphore::new(self.max_connections)))
+                } else {
+                    None
+                },
+                health_check_generation: AtomicUsize::new(0),
+                last_success: Mutex::new(None),
+                probes: Mutex::new(health_check::ProbeHistory::default()),
+            })
+        });
+        Arc::clone(upstream)
+    }
+}
+
+impl Upstream {
+    /// Called when a probe loop starts. Returns the generation to pass to keep_probing.
+    pub fn start_health_checks(&self) -> usize {
+        self.health_check_generation.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn stop_health_checks(&self) {
+        self.health_check_generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Whether the probe loop that started the given generation should keep going.
+    pub fn keep_probing(&self, generation: usize) -> bool {
+        self.health_check_generation.load(Ordering::SeqCst) == generation
+    }
+
+    /// Notes that a proxied request to this upstream just succeeded.
+    pub fn record_success(&self) {
+        *self.last_success.lock().unwrap() = Some(Instant::now());
+    }
+
+    /// Notes the outcome of a health check. Returns whether it passed.
+    pub fn record_probe(&self, result: Result<(), String>) -> bool {
+        let mut probes = self.probes.lock().unwrap();
+        let passed = result.is_ok();
+        probes.consecutive_failures = if passed { 0 } else { probes.consecutive_failures + 1 };
+        probes.last = Some((Instant::now(), result));
+        passed
+    }
+
+    pub fn probe_history(&self) -> health_check::ProbeHistory {
+        self.probes.lock().unwrap().clone()
+    }
+
+    /// Whether a proxied request to this upstream succeeded within the last `ttl`.
+    pub fn succeeded_within(&self, ttl: Duration) -> bool {
+        self.last_success
+            .lock()
+            .unwrap()
+            .is_some_and(|at| at.elapsed() < ttl)
+    }
+}
+
+/// A named set of upstreams, for blue/green deploys
+pub struct Group {
+    pub name: String,
+    pub upstreams: Vec<String>,
+}
+
+impl Group {
+    pub fn parse(spec: &str) -> Result<Group, String> {
+        let (name, upstreams) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=HOST:PORT[,HOST:PORT...], got {}", spec))?;
+        if name.is_empty() {
+            return Err(format!("missing group name in {}", spec));
+        }
+        let upstreams: Vec<String> = upstreams
+            .split(',')
+            .filter(|upstream| !upstream.is_empty())
+            .map(str::to_string)
+            .collect();
+        if upstreams.is_empty() {
+            return Err(format!("no upstreams in {}", spec));
+        }
+        Ok(Group {
+            name: name.to_string(),
+            upstreams,
+        })
+    }
+}
+
+/// The --upstream-group groups. Empty if upstreams were given with --upstream.
+#[derive(Default)]
+pub struct Groups {
+    pub groups: Vec<Group>,
+    /// Index of the group that gets traffic
+    pub active: usize,
+}
+
+impl Groups {
+    pub fn active(&self) -> Option<&Group> {
+        self.groups.get(self.active)
+    }
+
+    fn contains(&self, address: &str) -> bool {
+        self.groups
+            .iter()
+            .any(|group| group.upstreams.iter().any(|a| a == address))
+    }
+}
+
+/// The pool saved by a previous run
+pub struct SavedPool {
+    /// The active group, if groups are in use
+    pub group: Option<String>,
+    pub upstreams: Vec<String>,
+}
+
+/// Reads the pool saved by a previous run, if there is one.
+pub fn load_pool(path: &str) -> Result<Option<SavedPool>, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("could not read {}: {}", path, err)),
+    };
+    let mut saved = SavedPool {
+        group: None,
+        upstreams: Vec::new(),
+    };
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.strip_prefix("group ") {
+            Some(group) => saved.group = Some(group.trim().to_string()),
+            None => saved.upstreams.push(line.to_string()),
+        }
+    }
+    Ok(Some(saved))
+}
+
+/// The environment variable that --upstream-file and --upstream fall back to
+pub const UPSTREAMS_ENV: &str = "BB_UPSTREAMS";
+
+/// Splits a list of upstreams given as one string (see the module docs).
+pub fn parse_list(list: &str) -> Vec<String> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|address| !address.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Reads a list of upstreams from `path`, or from stdin if it is "-".
+pub fn read_list(path: &str) -> Result<Vec<String>, String> {
+    let list = if path == "-" {
+        std::io::read_to_string(std::io::stdin())
+            .map_err(|err| format!("could not read stdin: {}", err))?
+    } else {
+        std::fs::read_to_string(path).map_err(|err| format!("could not read {}: {}", path, err))?
+    };
+    Ok(parse_list(&list))
+}
+
+/// Whether `address` looks like HOST:PORT, where HOST is an IP address (IPv6 in brackets) or a
+/// host name, and PORT a nonzero port number. Host names aren't resolved here; that happens when
+/// the upstream is connected to.
+pub fn is_valid_address(address: &str) -> bool {
+    if let Ok(address) = address.parse::<std::net::SocketAddr>() {
+        return address.port() != 0;
+    }
+    let (host, port) = match address.rsplit_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let valid_host = !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && !label.starts_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    valid_host && matches!(port.parse::<u16>(), Ok(port) if port != 0)
+}
+
+/// Adds an upstream to the --upstream pool. Returns false if it was already in the pool.
+pub async fn add(state: &Arc<ProxyState>, address: &str) -> bool {
+    let _changing = state.upstreams.changing.lock().await;
+    {
+        let mut pool = state.default_pool.write().unwrap();
+        if pool.iter().any(|a| a == address) {
+            return false;
+        }
+        pool.push(address.to_string());
+        let mut groups = state.upstream_groups.write().unwrap();
+        let active = groups.active;
+        if let Some(group) = groups.groups.get_mut(active) {
+            group.upstreams.push(address.to_string());
+        }
+    }
+    let upstream = state.upstreams.insert(address);
+    let is_new = {
+        let mut addresses = state.upstream_addresses.write().unwrap();
+        let is_new = !addresses.iter().any(|a| a == address);
+        if is_new {
+            addresses.push(address.to_string());
+        }
+        is_new
+    };
+    // Like the upstreams given at startup, a new upstream is assumed to be up until a health check
+    // says otherwise
+    state.liveing_upstreams.update(|live| {
+        if !live.iter().any(|a| &**a == address) {
+            live.push(Arc::from(address));
+        }
+    });
+    // An upstream that is also in an SNI pool or another group is already being health checked
+    if is_new {
+        health_check::spawn(state, address.to_string(), &upstream);
+    }
+    log::info!("Added upstream {} to the pool", address);
+    save_pool(state).await;
+    true
+}
+
+/// Removes an upstream from the --upstream pool. Returns false if it wasn't in the pool.
+pub async fn remove(state: &Arc<ProxyState>, address: &str) -> bool {
+    let _changing = state.upstreams.changing.lock().await;
+    {
+        let mut pool = state.default_pool.write().unwrap();
+        match pool.iter().position(|a| a == address) {
+            Some(idx) => pool.remove(idx),
+            None => return false,
+        };
+        let mut groups = state.upstream_groups.write().unwrap();
+        let active = groups.active;
+        if let Some(group) = groups.groups.get_mut(active) {
+            group.upstreams.retain(|a| a != address);
+        }
+    }
+    let still_used = state
+        .sni_routes
+        .iter()
+        .any(|route| route.upstreams.iter().any(|a| a == address))
+        || state.upstream_groups.read().unwrap().contains(address);
+    if !still_used {
+        state.upstreams.get(address).stop_health_checks();
+        state
+            .upstream_addresses
+            .write()
+            .unwrap()
+            .retain(|a| a != address);
+        state
+            .liveing_upstreams
+            .update(|live| live.retain(|a| &**a != address));
+    }
+    log::info!("Removed upstream {} from the pool", address);
+    save_pool(state).await;
+    true
+}
+
+#[derive(Debug)]
+pub enum SwitchError {
+    /// There is no group with that name
+    UnknownGroup,
+    /// None of the group's upstreams are passing health checks
+    GroupDown,
+}
+
+/// Sends traffic to a different --upstream-group. Requests already in flight to the old group
+/// finish normally.
+pub async fn switch(state: &Arc<ProxyState>, name: &str) -> Result<(), SwitchError> {
+    let _changing = state.upstreams.changing.lock().await;
+    let (index, upstreams) = {
+        let groups = state.upstream_groups.read().unwrap();
+        match groups.groups.iter().position(|group| group.name == name) {
+            Some(index) => (index, groups.groups[index].upstreams.clone()),
+            None => return Err(SwitchError::UnknownGroup),
+        }
+    };
+    let live = state.liveing_upstreams.load();
+    if !upstreams.iter().any(|address| live.contains(address)) {
+        log::warn!("Not switching to upstream group {}: none of it is up", name);
+        return Err(SwitchError::GroupDown);
+    }
+    // Both locks are held while the pool changes, so nobody sees a mix of the two groups
+    {
+        let mut pool = state.default_pool.write().unwrap();
+        let mut groups = state.upstream_groups.write().unwrap();
+        *pool = upstreams;
+        groups.active = index;
+    }
+    log::info!("Switched traffic to upstream group {}", name);
+    save_pool(state).await;
+    Ok(())
+}
+
+/// Writes the pool to --upstreams-file, if it was given. The file is replaced atomically, so a
+/// crash mid-write can't leave a truncated pool behind.
+async fn save_pool(state: &ProxyState) {
+    let path = match &state.upstreams_file {
+        Some(path) => path,
+        None => return,
+    };
+    let mut contents = String::new();
+    if let Some(group) = state.upstream_groups.read().unwrap().active() {
+        contents.push_str(&format!("group {}\n", group.name));
+    }
+    for address in state.default_pool.read().unwrap().iter() {
+        contents.push_str(address);
+        contents.push('\n');
+    }
+    let tmp_path = format!("{}.tmp", path);
+    let result = async {
+        tokio::fs::write(&tmp_path, contents).await?;
+        tokio::fs::rename(&tmp_path, path).await
+    };
+    if let Err(err) = result.await {
+        log::error!("Could not save the upstream pool to {}: {}", path, err);
+    }
+}
@@ -3,6 +3,9 @@ mod common;
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
 use std::time::Duration;
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
 use tokio::time::sleep;
 
 async fn setup_with_params(
@@ -283,6 +286,20 @@ async fn test_rate_limiting() {
         log::info!("{:?}", response);
         log::info!("Checking to make sure the server responded with HTTP 429");
         assert_eq!(response.status().as_u16(), 429);
+        let retry_after: u64 = response
+            .headers()
+            .get("retry-after")
+            .expect("429 response is missing Retry-After")
+            .to_str()
+            .unwrap()
+            .parse()
+            .expect("Retry-After is not a number of seconds");
+        assert!((1..=60).contains(&retry_after));
+        assert_eq!(
+            response.headers().get("ratelimit-limit").unwrap(),
+            &rate_limit_threshold.to_string()
+        );
+        assert_eq!(response.headers().get("ratelimit-remaining").unwrap(), "0");
     }
 
     log::info!("Ensuring the extra requests didn't go through to the upstream servers");
@@ -294,3 +311,583 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Put balancebeam behind a "trusted proxy" (the test itself, on 127.0.0.1) and make sure rate
+/// limiting is applied per client named in X-Forwarded-For rather than per connecting address.
+#[tokio::test]
+async fn test_rate_limiting_trusted_proxies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let rate_limit_threshold = 2;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(rate_limit_threshold),
+        &["--trusted-proxies", "127.0.0.0/8"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let send_as = |forwarded_for: &'static str| {
+        client
+            .get(format!("http://{}/as-{}", balancebeam.address, forwarded_for))
+            .header("x-forwarded-for", format!("{}, 127.0.0.1", forwarded_for))
+            .send()
+    };
+
+    log::info!("Exhausting the rate limit for 10.0.0.1");
+    for _ in 0..rate_limit_threshold {
+        let response = send_as("10.0.0.1").await.expect("Error sending request");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    let response = send_as("10.0.0.1").await.expect("Error sending request");
+    assert_eq!(response.status().as_u16(), 429);
+
+    log::info!("Making sure 10.0.0.2 is unaffected");
+    let response = send_as("10.0.0.2").await.expect("Error sending request");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let total_request_count = Box::new(upstream).stop().await;
+    assert_eq!(total_request_count, rate_limit_threshold + 1);
+
+    log::info!("All done :)");
+}
+
+/// Give one upstream a much shorter health check interval than the global one, break it, and make
+/// sure it gets taken out of rotation on its own schedule.
+#[tokio::test]
+async fn test_health_check_override() {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+    ];
+    let addresses: Vec<String> = upstreams.iter().map(|upstream| upstream.address()).collect();
+    let failed_ip = addresses[1].clone();
+    let override_spec = format!("{}=1", failed_ip);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&addresses[0], &addresses[1]],
+        Some(60),
+        None,
+        &["--health-check-override", &override_spec],
+    )
+    .await;
+
+    log::info!("Replacing the overridden upstream with a server that returns Error 500s...");
+    upstreams.pop().unwrap().stop().await;
+    upstreams.push(Box::new(ErrorServer::new_at_address(failed_ip).await));
+
+    log::info!("Waiting for its (1 second) health check to notice...");
+    sleep(Duration::from_secs(3)).await;
+
+    for i in 0..8 {
+        let path = format!("/after-override-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "The overridden upstream is still in rotation; its health check interval was ignored"
+        );
+    }
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// With --passive-health-ttl, an upstream that keeps serving traffic should not get any active
+/// probes on top of it.
+#[tokio::test]
+async fn test_passive_health_ttl_skips_probes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(2),
+        None,
+        &["--passive-health-ttl", "60"],
+    )
+    .await;
+
+    // The first probe is due 2-4 seconds after startup, and BalanceBeam::new_with_args already
+    // waited for 1 of them
+    log::info!("Sending traffic across several health check intervals...");
+    let num_requests = 25;
+    for i in 0..num_requests {
+        let path = format!("/traffic-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        sleep(Duration::from_millis(200)).await;
+    }
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, num_requests,
+        "The upstream was probed even though it was serving traffic"
+    );
+    log::info!("All done :)");
+}
+
+/// Cap connections to each upstream at one, hold that connection open, and make sure further
+/// clients are turned away with a 503 until it is released.
+#[tokio::test]
+async fn test_max_connections_per_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-connections-per-upstream", "1"],
+    )
+    .await;
+
+    log::info!("Opening a connection that occupies the only upstream slot");
+    let mut held = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    held.write_all(b"GET /held HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0_u8; 1024];
+    let n = held.read(&mut buf).await.unwrap();
+    assert!(String::from_utf8_lossy(&buf[..n]).starts_with("HTTP/1.1 200"));
+
+    log::info!("Sending a request while the upstream is saturated. This should get a 503");
+    let response = reqwest::get(&format!("http://{}/saturated", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 503);
+
+    log::info!("Releasing the slot; requests should go through again");
+    drop(held);
+    sleep(Duration::from_millis(500)).await;
+    let response_text = balancebeam
+        .get("/released")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /released HTTP/1.1"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Put an upstream that always returns 500s next to a healthy one and make sure outlier detection
+/// takes it out of rotation after a couple of failures, long before any health check runs.
+#[tokio::test]
+async fn test_outlier_ejection() {
+    init_logging();
+    let healthy = EchoServer::new().await;
+    let failing = ErrorServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy.address, &failing.address],
+        Some(60),
+        None,
+        &["--outlier-consecutive-5xx", "2", "--outlier-ejection-time", "60"],
+    )
+    .await;
+
+    let mut errors = 0;
+    for i in 0..20 {
+        // Use a new connection for every request; an open connection stays with its upstream
+        let response = reqwest::Client::new()
+            .get(format!("http://{}/outlier-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status().is_server_error() {
+            errors += 1;
+        }
+    }
+    log::info!("Got {} errors out of 20 requests", errors);
+    assert!(
+        errors <= 2,
+        "The failing upstream should have been ejected after 2 consecutive 5xx responses"
+    );
+
+    Box::new(healthy).stop().await;
+    Box::new(failing).stop().await;
+    log::info!("All done :)");
+}
+
+/// Start a bare-bones upstream that waits `delay` before answering each request with "slow".
+async fn start_slow_server(delay: Duration) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind slow server");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    sleep(delay).await;
+                    let _ = conn
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                        .await;
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Put a slow upstream next to a fast one and make sure latency-based outlier detection takes the
+/// slow one out of rotation.
+#[tokio::test]
+async fn test_latency_outlier_ejection() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow_address = start_slow_server(Duration::from_millis(200)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow_address],
+        None,
+        None,
+        &[
+            "--outlier-latency-multiple",
+            "5",
+            "--outlier-min-requests",
+            "3",
+            "--outlier-ejection-time",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests so that both upstreams have some latency samples");
+    for i in 0..20 {
+        balancebeam
+            .get(&format!("/warmup-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    log::info!("Waiting for the latency check to run...");
+    sleep(Duration::from_millis(1500)).await;
+
+    for i in 0..10 {
+        let path = format!("/after-ejection-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Request was sent to the slow upstream even though it should have been ejected"
+        );
+    }
+
+    Box::new(fast).stop().await;
+    log::info!("All done :)");
+}
+
+/// Start one echo server per upstream and a balancebeam that balances between them with
+/// `balance_args`.
+async fn setup_balance(
+    n_upstreams: usize,
+    balance_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address())
+        .collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstream_addresses, None, None, balance_args).await;
+    (balancebeam, upstreams)
+}
+
+/// With --balance path-hash, every request for the same path should reach the same upstream, even
+/// with different query strings when --path-hash-ignore-query is given.
+#[tokio::test]
+async fn test_path_hash_same_upstream() {
+    let (balancebeam, mut upstreams) =
+        setup_balance(3, &["--balance", "path-hash", "--path-hash-ignore-query"]).await;
+
+    for i in 0..15 {
+        let path = format!("/cached/resource?v={}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    log::info!("Number of requests received by each upstream: {:?}", request_counters);
+    request_counters.sort();
+    assert_eq!(request_counters, vec![0, 0, 15]);
+
+    log::info!("All done :)");
+}
+
+/// Requests for different paths on one keep-alive connection should still be spread across the
+/// upstreams, with the connection moving between them as needed.
+#[tokio::test]
+async fn test_path_hash_keep_alive_spreads() {
+    let (balancebeam, mut upstreams) = setup_balance(3, &["--balance", "path-hash"]).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..20 {
+        let path = format!("/resource-{}", i);
+        let response_text = client
+            .get(format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.push(upstream.stop().await);
+    }
+    log::info!("Number of requests received by each upstream: {:?}", request_counters);
+    assert_eq!(request_counters.iter().sum::<usize>(), 20);
+    assert!(
+        request_counters.iter().filter(|count| **count > 0).count() > 1,
+        "Every path went to the same upstream"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Send `n_requests` requests, each on a new connection, and return how many each upstream got.
+async fn count_picks(balance_args: &[&str], n_requests: usize) -> Vec<usize> {
+    let (balancebeam, mut upstreams) = setup_balance(3, balance_args).await;
+    for i in 0..n_requests {
+        let path = format!("/pick-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!("Number of requests received by each upstream: {:?}", request_counters);
+    request_counters
+}
+
+/// --balance round-robin should hand connections to the upstreams in turn.
+#[tokio::test]
+async fn test_round_robin() {
+    let request_counters = count_picks(&["--balance", "round-robin"], 30).await;
+    assert_eq!(request_counters, vec![10, 10, 10]);
+
+    log::info!("All done :)");
+}
+
+/// With the same --rng-seed, random picks should come out the same every time.
+#[tokio::test]
+async fn test_rng_seed_reproducible() {
+    let first = count_picks(&["--rng-seed", "42"], 30).await;
+    let second = count_picks(&["--rng-seed", "42"], 30).await;
+    assert_eq!(first, second);
+
+    log::info!("All done :)");
+}
+
+/// With --balance least-response-time, a slow upstream should only get the requests it takes to
+/// measure it, plus the occasional exploring pick.
+#[tokio::test]
+async fn test_least_response_time() {
+    init_logging();
+    let fast = EchoServer::new().await;
+    let slow_address = start_slow_server(Duration::from_millis(100)).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&fast.address, &slow_address],
+        None,
+        None,
+        &["--balance", "least-response-time", "--rng-seed", "7"],
+    )
+    .await;
+
+    let mut slow_responses = 0;
+    for i in 0..40 {
+        let response_text = balancebeam
+            .get(&format!("/timed-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        if response_text == "slow" {
+            slow_responses += 1;
+        }
+    }
+    log::info!("The slow upstream answered {} of 40 requests", slow_responses);
+    assert!(slow_responses >= 1, "The slow upstream was never measured");
+    assert!(
+        slow_responses <= 5,
+        "The slow upstream got {} of 40 requests",
+        slow_responses
+    );
+
+    let requests_received = Box::new(fast).stop().await;
+    assert_eq!(requests_received, 40 - slow_responses);
+    log::info!("All done :)");
+}
+
+/// The upstream list can come from stdin (--upstream-file -) or BB_UPSTREAMS, separated by newlines
+/// or commas. Every upstream on the list should get its turn.
+#[tokio::test]
+async fn test_upstreams_from_stdin_and_env() {
+    init_logging();
+    for from_stdin in [true, false] {
+        let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+        for _ in 0..3 {
+            upstreams.push(Box::new(EchoServer::new().await));
+        }
+        let list = format!(
+            "# upstreams\n{},{}\n{}\n",
+            upstreams[0].address(),
+            upstreams[1].address(),
+            upstreams[2].address()
+        );
+        let balancebeam = if from_stdin {
+            BalanceBeam::new_with_input(
+                &["--balance", "round-robin", "--upstream-file", "-"],
+                &[],
+                Some(&list),
+            )
+            .await
+        } else {
+            BalanceBeam::new_with_input(
+                &["--balance", "round-robin"],
+                &[("BB_UPSTREAMS", &list)],
+                None,
+            )
+            .await
+        };
+        for _ in 0..6 {
+            balancebeam
+                .get("/")
+                .await
+                .expect("Error sending request to balancebeam");
+        }
+        let mut request_counters = Vec::new();
+        while let Some(upstream) = upstreams.pop() {
+            request_counters.insert(0, upstream.stop().await);
+        }
+        assert_eq!(request_counters, vec![2, 2, 2], "from stdin: {}", from_stdin);
+    }
+
+    log::info!("All done :)");
+}
+
+/// Starts an upstream that answers every request with a 200 and `body`.
+async fn start_status_server(body: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = conn.write_all(response.as_bytes()).await;
+                }
+            });
+        }
+    });
+    address
+}
+
+/// An upstream that answers health checks with a 200 but says it's degraded in the body should
+/// be taken out of rotation when the body has to match.
+#[tokio::test]
+async fn test_active_health_check_body_match() {
+    init_logging();
+    for body_args in [
+        ["--active-health-check-body-contains", "\"status\":\"ok\""],
+        ["--active-health-check-body-regex", "\"status\":\\s*\"ok\""],
+    ] {
+        let ok = start_status_server("{\"status\":\"ok\"}").await;
+        let degraded = start_status_server("{\"status\":\"degraded\"}").await;
+        let balancebeam =
+            BalanceBeam::new_with_args(&[&ok, &degraded], Some(1), None, &body_args).await;
+
+        log::info!("Waiting for health checks to notice the degraded upstream...");
+        sleep(Duration::from_secs(3)).await;
+        for _ in 0..10 {
+            let response_text = balancebeam
+                .get("/")
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response_text, "{\"status\":\"ok\"}", "{:?}", body_args);
+        }
+    }
+
+    log::info!("All done :)");
+}
+
+/// Each request's log line should name the upstream it went to, not the client it came from.
+#[tokio::test]
+async fn test_request_log_names_upstream() {
+    let (balancebeam, mut upstreams) =
+        setup_balance(2, &["--balance", "path-hash"]).await;
+    for i in 0..6 {
+        let path = format!("/attributed-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    sleep(Duration::from_millis(100)).await;
+
+    let output = balancebeam.output();
+    let addresses: Vec<String> = upstreams.iter().map(|upstream| upstream.address()).collect();
+    for i in 0..6 {
+        let request_line = format!("GET /attributed-{} HTTP/1.1", i);
+        let line = output
+            .iter()
+            .find(|line| line.contains(&request_line))
+            .unwrap_or_else(|| panic!("No log line for {}", request_line));
+        assert!(
+            addresses
+                .iter()
+                .any(|address| line.contains(&format!("127.0.0.1 -> {}: ", address))),
+            "Log line doesn't name an upstream: {}",
+            line
+        );
+    }
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,191 @@
+mod common;
+
+use common::{init_logging, start_tcp_echo_server, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Start balancebeam with two --bind addresses and make sure requests sent to either listener are
+/// proxied to the same upstream.
+#[tokio::test]
+async fn test_multiple_listeners() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let second_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--bind", &second_address],
+    )
+    .await;
+
+    log::info!("Sending a request to the first listener");
+    let response_text = balancebeam
+        .get("/first-listener")
+        .await
+        .expect("Error sending request to the first listener");
+    assert!(response_text.contains("GET /first-listener HTTP/1.1"));
+
+    log::info!("Sending a request to the second listener");
+    let response_text = reqwest::get(&format!("http://{}/second-listener", second_address))
+        .await
+        .expect("Error sending request to the second listener")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("GET /second-listener HTTP/1.1"));
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Run several SO_REUSEPORT accept workers on one address and make sure requests spread across
+/// fresh connections are all proxied correctly.
+#[tokio::test]
+async fn test_reuseport_workers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--workers", "4"]).await;
+
+    let num_requests = 12;
+    for i in 0..num_requests {
+        let path = format!("/worker-request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, num_requests,
+        "Upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Run a listener in tcp mode in front of a non-HTTP upstream and a dead one, and make sure every
+/// connection is tunneled to the live upstream byte for byte.
+#[tokio::test]
+async fn test_tcp_mode() {
+    init_logging();
+    let upstream = start_tcp_echo_server().await;
+    let dead_upstream = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&dead_upstream, &upstream], None, None, &["--mode", "tcp"])
+            .await;
+
+    for i in 0..5 {
+        log::info!("Sending raw bytes through tunnel {}", i);
+        let message = format!("\x00\x01 not an HTTP request {}\r\n", i);
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        conn.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0; message.len()];
+        conn.read_exact(&mut echoed)
+            .await
+            .expect("Tunnel did not echo the message back");
+        assert_eq!(echoed, message.as_bytes());
+    }
+
+    log::info!("All done :)");
+}
+
+/// Starts a TCP server that answers the first bytes it receives on each connection with `name` and
+/// then hangs up. Returns its address.
+async fn start_named_tcp_server(name: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                if let Ok(n) = conn.read(&mut buf).await {
+                    if n > 0 {
+                        let _ = conn.write_all(name.as_bytes()).await;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Builds a minimal TLS ClientHello record, with a server_name extension if a name is given.
+fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+    let mut extensions = Vec::new();
+    if let Some(name) = server_name {
+        let name = name.as_bytes();
+        let list_len = 3 + name.len();
+        extensions.extend_from_slice(&[0x00, 0x00]);
+        extensions.extend_from_slice(&(2 + list_len as u16).to_be_bytes());
+        extensions.extend_from_slice(&(list_len as u16).to_be_bytes());
+        extensions.push(0x00);
+        extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(name);
+    }
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0_u8; 32]); // random
+    body.push(0); // no session id
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // one cipher suite
+    body.extend_from_slice(&[0x01, 0x00]); // null compression
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&body);
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.extend_from_slice(&handshake);
+    record
+}
+
+/// Run a tls-passthrough listener with an SNI route, and make sure connections are tunneled to the
+/// pool for their server name, with everything else going to the default pool.
+#[tokio::test]
+async fn test_tls_passthrough_sni_routing() {
+    init_logging();
+    let default_upstream = start_named_tcp_server("default").await;
+    let api_upstream = start_named_tcp_server("api").await;
+    let sni_route = format!("api.example.com={}", api_upstream);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream],
+        None,
+        None,
+        &["--mode", "tls-passthrough", "--sni-route", &sni_route],
+    )
+    .await;
+
+    let cases = [
+        (Some("api.example.com"), "api"),
+        (Some("API.Example.com"), "api"),
+        (Some("www.example.com"), "default"),
+        (None, "default"),
+    ];
+    for (server_name, expected) in cases.iter() {
+        log::info!("Connecting with SNI {:?}", server_name);
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        conn.write_all(&client_hello(*server_name)).await.unwrap();
+        let mut reply = String::new();
+        conn.read_to_string(&mut reply).await.unwrap();
+        assert_eq!(&reply, expected, "SNI {:?} went to the wrong pool", server_name);
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,138 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Start a bare-bones auth service that answers every request with `response`, verbatim, or never
+/// answers at all if `response` is None.
+async fn start_canned_server(response: Option<Vec<u8>>) -> String {
+    // Port 0 lets the OS pick a free port, so this can't collide with another test's server
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind canned response server");
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => return,
+            };
+            let response = response.clone();
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    if let Some(response) = &response {
+                        let _ = conn.write_all(response).await;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Protect /private with an auth service that rejects everything, and /approved with one that
+/// accepts everything. Only requests outside /private should reach the upstream.
+#[tokio::test]
+async fn test_forward_auth() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let rejecting_auth = ErrorServer::new().await;
+    let approving_auth = EchoServer::new().await;
+    let private_rule = format!("/private={}/check", rejecting_auth.address);
+    let approved_rule = format!("/approved={}/check", approving_auth.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--forward-auth", &private_rule, "--forward-auth", &approved_rule],
+    )
+    .await;
+
+    log::info!("Sending a request to an unprotected path");
+    let response_text = balancebeam
+        .get("/public")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /public HTTP/1.1"));
+
+    log::info!("Sending a request that the auth service approves");
+    let response_text = balancebeam
+        .get("/approved/page")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /approved/page HTTP/1.1"));
+
+    log::info!("Sending a request that the auth service rejects");
+    let response = reqwest::get(&format!("http://{}/private/page", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 500);
+
+    assert_eq!(Box::new(approving_auth).stop().await, 1);
+    assert_eq!(Box::new(rejecting_auth).stop().await, 1);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Rejected request should not have reached the upstream"
+    );
+
+    log::info!("All done :)");
+}
+
+/// A rejection from the auth service whose body can't be read should be answered with a 502,
+/// rather than passed on with its body cut short.
+#[tokio::test]
+async fn test_forward_auth_malformed_rejection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let auth = start_canned_server(Some(
+        b"HTTP/1.1 401 Unauthorized\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\ndenied\r\n".to_vec(),
+    ))
+    .await;
+    let rule = format!("/private={}/check", auth);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--forward-auth", &rule])
+            .await;
+
+    let response = reqwest::get(&format!("http://{}/private/page", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
+
+/// An auth service that never answers should get the request a 504 once --forward-auth-timeout is
+/// up, rather than leave the client waiting.
+#[tokio::test]
+async fn test_forward_auth_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let auth = start_canned_server(None).await;
+    let rule = format!("/private={}/check", auth);
+    let args = ["--forward-auth", &rule, "--forward-auth-timeout", "1"];
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], None, None, &args).await;
+
+    let response = tokio::time::timeout(
+        Duration::from_secs(10),
+        reqwest::get(&format!("http://{}/private/page", balancebeam.address)),
+    )
+    .await
+    .expect("balancebeam kept waiting for the auth service")
+    .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+
+    log::info!("All done :)");
+}
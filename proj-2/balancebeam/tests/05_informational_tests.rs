@@ -0,0 +1,62 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use rand::Rng;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Start a bare-bones upstream that answers every request with a 103 Early Hints response followed
+/// by the real 200 response, written in a single chunk so that both land in the same read.
+async fn start_early_hints_server() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind early hints server");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    let _ = conn
+                        .write_all(
+                            b"HTTP/1.1 103 Early Hints\r\n\
+                            Link: </style.css>; rel=preload\r\n\r\n\
+                            HTTP/1.1 200 OK\r\n\
+                            Content-Length: 5\r\n\r\n\
+                            hello",
+                        )
+                        .await;
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Make sure an informational response from the upstream isn't mistaken for the final response.
+#[tokio::test]
+async fn test_early_hints_forwarded() {
+    init_logging();
+    let upstream_address = start_early_hints_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    for i in 0..3 {
+        let response = reqwest::get(&format!("http://{}/hints-{}", balancebeam.address, i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(
+            response.text().await.expect("Error reading response body"),
+            "hello"
+        );
+    }
+
+    log::info!("All done :)");
+}
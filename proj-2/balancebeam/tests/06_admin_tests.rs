@@ -0,0 +1,456 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+async fn setup_with_admin(extra_args: &[&str]) -> (BalanceBeam, EchoServer, String) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let mut args = vec!["--admin-bind", admin_address.as_str()];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], None, None, &args).await;
+    (balancebeam, upstream, admin_address)
+}
+
+async fn admin_get(admin_address: &str, path: &str) -> reqwest::Response {
+    reqwest::get(&format!("http://{}{}", admin_address, path))
+        .await
+        .expect("Error sending request to the admin listener")
+}
+
+/// Send a few requests through the proxy and make sure the per-upstream counters and latency
+/// histogram on /metrics reflect them.
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    let (balancebeam, upstream, admin_address) = setup_with_admin(&[]).await;
+
+    for i in 0..3 {
+        balancebeam
+            .post(&format!("/metrics-{}", i), "abc")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let metrics = admin_get(&admin_address, "/metrics")
+        .await
+        .text()
+        .await
+        .expect("Error reading metrics");
+    log::info!("Metrics:\n{}", metrics);
+    let label = format!("{{upstream=\"{}\"}}", upstream.address);
+    assert!(metrics.contains(&format!("balancebeam_upstream_requests_total{} 3", label)));
+    assert!(metrics.contains(&format!("balancebeam_upstream_errors_total{} 0", label)));
+    assert!(metrics.contains(&format!("balancebeam_upstream_bytes_sent_total{} 9", label)));
+    assert!(metrics.contains(&format!(
+        "balancebeam_upstream_latency_seconds_count{} 3",
+        label
+    )));
+
+    let response = admin_get(&admin_address, "/no-such-endpoint").await;
+    assert_eq!(response.status().as_u16(), 404);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// /info should say what version is running and with what configuration, without giving away
+/// secrets.
+#[tokio::test]
+async fn test_info_endpoint() {
+    let certs = format!("{}/tests/certs", env!("CARGO_MANIFEST_DIR"));
+    let (_balancebeam, upstream, admin_address) = setup_with_admin(&[
+        "--upstream-client-cert",
+        &format!("{}/client.pem", certs),
+        "--upstream-client-key",
+        &format!("{}/client-key.pem", certs),
+    ])
+    .await;
+
+    let response = admin_get(&admin_address, "/info").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let text = response.text().await.expect("Error reading /info");
+    log::info!("Info: {}", text);
+    let info: serde_json::Value = serde_json::from_str(&text).expect("/info is not valid JSON");
+    assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+    assert!(!info["git_commit"].as_str().unwrap().is_empty());
+    assert!(info["build_time"].as_str().unwrap().ends_with('Z'));
+    assert_eq!(info["config"]["upstream"][0], upstream.address.as_str());
+    assert_eq!(info["config"]["admin-bind"], admin_address.as_str());
+    // Defaults count as configuration too
+    assert_eq!(info["config"]["active-health-check-path"], "/");
+    assert_eq!(info["config"]["upstream-client-key"], "<redacted>");
+    assert!(!text.contains("client-key.pem"));
+    let features = info["features"].as_array().unwrap();
+    assert!(features.iter().any(|feature| feature == "admin"));
+    assert!(features.iter().any(|feature| feature == "upstream-tls"));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+async fn admin_post(admin_address: &str, path: &str, body: &str) -> u16 {
+    reqwest::Client::new()
+        .post(format!("http://{}{}", admin_address, path))
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to the admin listener")
+        .status()
+        .as_u16()
+}
+
+/// Upstreams added and removed through the admin API should take and stop taking traffic
+/// straight away, and the changed pool should be saved to --upstreams-file.
+#[tokio::test]
+async fn test_add_remove_upstreams() {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-upstreams-{}.txt",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let (balancebeam, first, admin_address) =
+        setup_with_admin(&["--upstreams-file", path.to_str().unwrap()]).await;
+    let second = EchoServer::new().await;
+
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/add", &second.address).await,
+        200
+    );
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/add", &second.address).await,
+        409
+    );
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/remove", &first.address).await,
+        200
+    );
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/remove", &first.address).await,
+        404
+    );
+    assert_eq!(admin_post(&admin_address, "/upstreams/add", "").await, 400);
+    for bad in ["10.0.0.7", "10.0.0.7:", "10.0.0.7:http", "10.0.0.7:0", ":8080", "::1:80"] {
+        assert_eq!(
+            admin_post(&admin_address, "/upstreams/add", bad).await,
+            400,
+            "{} should have been rejected",
+            bad
+        );
+    }
+
+    let pool = admin_get(&admin_address, "/upstreams")
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(pool, format!("{}\n", second.address));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), pool);
+
+    for i in 0..5 {
+        balancebeam
+            .get(&format!("/after-change-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert_eq!(Box::new(first).stop().await, 0);
+
+    // A restarted balancebeam should pick up the saved pool rather than --upstream
+    drop(balancebeam);
+    let unused = EchoServer::new().await;
+    let restarted = BalanceBeam::new_with_args(
+        &[&unused.address],
+        None,
+        None,
+        &["--upstreams-file", path.to_str().unwrap()],
+    )
+    .await;
+    restarted
+        .get("/after-restart")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(Box::new(unused).stop().await, 0);
+    assert_eq!(Box::new(second).stop().await, 6);
+
+    let _ = std::fs::remove_file(&path);
+    log::info!("All done :)");
+}
+
+/// Switching --upstream-group should move all traffic to the new group, but not to a group that is
+/// failing health checks.
+#[tokio::test]
+async fn test_switch_upstream_group() {
+    init_logging();
+    let blue = EchoServer::new().await;
+    let green = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let blue_group = format!("blue={}", blue.address);
+    let green_group = format!("green={}", green.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        Some(1),
+        None,
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--upstream-group",
+            &blue_group,
+            "--upstream-group",
+            &green_group,
+            // Nothing listens here, so this group fails its health checks
+            "--upstream-group",
+            "red=127.0.0.1:1",
+        ],
+    )
+    .await;
+
+    for i in 0..2 {
+        balancebeam
+            .get(&format!("/blue-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    // Give the health checks time to notice that red is down
+    sleep(Duration::from_secs(3)).await;
+    let groups = admin_get(&admin_address, "/upstreams/groups")
+        .await
+        .text()
+        .await
+        .unwrap();
+    log::info!("Groups:\n{}", groups);
+    assert!(groups.contains(&format!("blue (active): {}=up", blue.address)));
+    assert!(groups.contains(&format!("green: {}=up", green.address)));
+    assert!(groups.contains("red: 127.0.0.1:1=down"));
+
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/switch", "red").await,
+        409
+    );
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/switch", "purple").await,
+        404
+    );
+    assert_eq!(
+        admin_post(&admin_address, "/upstreams/switch", "green").await,
+        200
+    );
+
+    // With blue gone, requests only get through if they go to green. (Health check probes count as
+    // requests too, so request counts don't say much.)
+    assert!(Box::new(blue).stop().await >= 2);
+    for i in 0..3 {
+        let response = balancebeam
+            .get(&format!("/green-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response.contains(&format!("/green-{}", i)));
+    }
+
+    Box::new(green).stop().await;
+    log::info!("All done :)");
+}
+
+/// /route-test should report the policies that would apply to a sample request without forwarding
+/// it or counting it against the rate limit.
+#[tokio::test]
+async fn test_route_test_endpoint() {
+    let (balancebeam, upstream, admin_address) = setup_with_admin(&[
+        "--max-requests-per-minute",
+        "5",
+        "--forward-auth",
+        "/private=127.0.0.1:1/check",
+        "--route-bandwidth",
+        "/downloads=1000",
+    ])
+    .await;
+    balancebeam
+        .get("/counted")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    let route_test = |sample: &'static str| {
+        reqwest::Client::new()
+            .post(format!("http://{}/route-test", admin_address))
+            .body(sample)
+            .send()
+    };
+    let report = route_test("GET /private/downloads/x HTTP/1.1\r\nHost: example.com\r\n")
+        .await
+        .expect("Error sending request to the admin listener")
+        .text()
+        .await
+        .unwrap();
+    log::info!("Route test:\n{}", report);
+    assert!(report.contains("host: example.com\n"));
+    assert!(report.contains("rate limit: 5 per minute for 127.0.0.1 (1 used)\n"));
+    assert!(report.contains("forward auth: 127.0.0.1:1/check (rule for /private)\n"));
+    assert!(report.contains("bandwidth: unlimited\n"));
+    assert!(report.contains(&format!("upstream: {} candidate\n", upstream.address)));
+
+    let report = route_test("GET /downloads/x HTTP/1.1\r\n")
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert!(report.contains("forward auth: none\n"));
+    assert!(report.contains("bandwidth: 1000 bytes/s under /downloads\n"));
+    assert!(report.contains("rate limit: 5 per minute for 127.0.0.1 (1 used)\n"));
+
+    let response = route_test("not a request").await.unwrap();
+    assert_eq!(response.status().as_u16(), 400);
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// /upstreams/health should show which upstream is down and why, and latency stats for the one
+/// that is serving traffic.
+#[tokio::test]
+async fn test_health_report() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Nothing listens here once the listener is dropped
+    let dead_address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address, &dead_address],
+        Some(1),
+        None,
+        &["--admin-bind", &admin_address],
+    )
+    .await;
+
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/health-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    log::info!("Waiting for the health checks to run...");
+    sleep(Duration::from_secs(3)).await;
+
+    let text = admin_get(&admin_address, "/upstreams/health")
+        .await
+        .text()
+        .await
+        .unwrap();
+    log::info!("Health report: {}", text);
+    let report: serde_json::Value =
+        serde_json::from_str(&text).expect("/upstreams/health is not valid JSON");
+    let upstreams = report["upstreams"].as_array().unwrap();
+    let find = |address: &str| {
+        upstreams
+            .iter()
+            .find(|entry| entry["address"] == address)
+            .unwrap_or_else(|| panic!("{} is missing from the report", address))
+    };
+
+    let up = find(&upstream.address);
+    assert_eq!(up["state"], "up");
+    assert_eq!(up["in_pool"], true);
+    assert_eq!(up["last_probe"]["ok"], true);
+    assert_eq!(up["consecutive_probe_failures"], 0);
+    assert_eq!(up["requests"], 3);
+    assert_eq!(up["recent_latency"]["samples"], 3);
+
+    let down = find(&dead_address);
+    assert_eq!(down["state"], "down");
+    assert_eq!(down["last_probe"]["ok"], false);
+    assert!(down["last_probe"]["error"]
+        .as_str()
+        .unwrap()
+        .contains("connect failed"));
+    assert!(down["consecutive_probe_failures"].as_u64().unwrap() >= 1);
+    assert_eq!(down["requests"], 0);
+    assert!(down["recent_latency"].is_null());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// With every upstream refusing connections, failover tries them all and gives up with a 502,
+/// unless the retry budget runs out first, in which case the client gets a 503. Either way the
+/// retries show up on /metrics.
+#[tokio::test]
+async fn test_retry_budget() {
+    init_logging();
+    let dead_addresses: Vec<String> = (0..3)
+        .map(|_| {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        })
+        .collect();
+    let dead_addresses: Vec<&str> = dead_addresses.iter().map(String::as_str).collect();
+
+    for (budget_args, status, retries, denied) in [
+        (&[][..], 502, 2, 0),
+        (&["--retry-budget-percent", "10", "--retry-budget-min-retries", "1"][..], 503, 1, 1),
+    ] {
+        let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+        let mut args = vec!["--admin-bind", admin_address.as_str()];
+        args.extend_from_slice(budget_args);
+        let balancebeam = BalanceBeam::new_with_args(&dead_addresses, None, None, &args).await;
+
+        let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), status, "{:?}", budget_args);
+
+        let metrics = admin_get(&admin_address, "/metrics")
+            .await
+            .text()
+            .await
+            .unwrap();
+        log::info!("Metrics:\n{}", metrics);
+        assert!(metrics.contains(&format!("balancebeam_retries_total {}\n", retries)));
+        assert!(metrics.contains(&format!("balancebeam_retries_denied_total {}\n", denied)));
+    }
+
+    log::info!("All done :)");
+}
+
+/// /healthz and /readyz on the --probe-bind listener (and the admin listener): both pass while the
+/// upstream is up, and only /readyz fails once it goes down. Nothing else is served on the probe
+/// listener.
+#[tokio::test]
+async fn test_probe_endpoints() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let probe_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        Some(1),
+        None,
+        &["--admin-bind", &admin_address, "--probe-bind", &probe_address],
+    )
+    .await;
+
+    for address in [&probe_address, &admin_address] {
+        for path in ["/healthz", "/readyz"] {
+            let response = admin_get(address, path).await;
+            assert_eq!(response.status().as_u16(), 200, "{}{}", address, path);
+            assert_eq!(response.text().await.unwrap(), "ok\n");
+        }
+    }
+    let response = admin_get(&probe_address, "/metrics").await;
+    assert_eq!(response.status().as_u16(), 404);
+
+    Box::new(upstream).stop().await;
+    log::info!("Waiting for the health checks to notice the upstream is down...");
+    sleep(Duration::from_secs(3)).await;
+
+    let response = admin_get(&probe_address, "/healthz").await;
+    assert_eq!(response.status().as_u16(), 200);
+    let response = admin_get(&probe_address, "/readyz").await;
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.text().await.unwrap(), "no live upstreams\n");
+
+    log::info!("All done :)");
+}
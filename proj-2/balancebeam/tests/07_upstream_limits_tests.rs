@@ -0,0 +1,203 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Start a bare-bones upstream that answers every request with `response`, verbatim.
+async fn start_canned_server(response: Vec<u8>) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind canned response server");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => return,
+            };
+            let response = response.clone();
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    let _ = conn.write_all(&response).await;
+                }
+            });
+        }
+    });
+    address
+}
+
+async fn get_error_code(balancebeam: &BalanceBeam) -> String {
+    let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    response
+        .headers()
+        .get("x-balancebeam-error")
+        .expect("502 response is missing X-Balancebeam-Error")
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+/// An upstream that sends more headers than we're willing to parse should get a 502.
+#[tokio::test]
+async fn test_too_many_upstream_headers() {
+    init_logging();
+    let mut response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n".to_vec();
+    for i in 0..100 {
+        response.extend_from_slice(format!("X-Header-{}: {}\r\n", i, i).as_bytes());
+    }
+    response.extend_from_slice(b"\r\n");
+    let upstream_address = start_canned_server(response).await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    assert_eq!(get_error_code(&balancebeam).await, "upstream-too-many-headers");
+    log::info!("All done :)");
+}
+
+/// An upstream whose headers never end should get a 502 rather than being buffered indefinitely.
+#[tokio::test]
+async fn test_upstream_headers_too_large() {
+    init_logging();
+    let mut response = b"HTTP/1.1 200 OK\r\nX-Huge: ".to_vec();
+    response.extend_from_slice(&[b'a'; 20000]);
+    response.extend_from_slice(b"\r\nContent-Length: 0\r\n\r\n");
+    let upstream_address = start_canned_server(response).await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    assert_eq!(get_error_code(&balancebeam).await, "upstream-headers-too-large");
+    log::info!("All done :)");
+}
+
+/// An upstream that hangs up before sending the whole body its Content-Length promised should get
+/// a 502, rather than the client being sent the body cut short.
+#[tokio::test]
+async fn test_upstream_body_cut_short() {
+    init_logging();
+    let upstream_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&upstream_address)
+        .await
+        .expect("Could not bind upstream");
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let mut buf = [0_u8; 4096];
+            let _ = conn.read(&mut buf).await;
+            let _ = conn
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\nshort")
+                .await;
+        }
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    assert_eq!(
+        get_error_code(&balancebeam).await,
+        "upstream-content-length-mismatch"
+    );
+    log::info!("All done :)");
+}
+
+/// If the client hangs up while balancebeam is still waiting on a slow upstream, balancebeam should
+/// give up on the request and close its upstream connection instead of waiting for a response that
+/// nobody will read.
+#[tokio::test]
+async fn test_client_disconnect_cancels_upstream_request() {
+    init_logging();
+    // An upstream that reads requests but never answers, and reports when its connection closes
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind silent upstream");
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut buf = [0_u8; 4096];
+        while let Ok(n) = conn.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+        let _ = closed_tx.send(());
+    });
+    let balancebeam = BalanceBeam::new(&[&address], None, None).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    client
+        .write_all(b"GET /slow HTTP/1.1\r\nHost: example\r\n\r\n")
+        .await
+        .unwrap();
+    // Give balancebeam time to forward the request before hanging up
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    drop(client);
+
+    tokio::time::timeout(Duration::from_secs(5), closed_rx)
+        .await
+        .expect("Balancebeam kept waiting on the upstream after the client hung up")
+        .unwrap();
+
+    log::info!("All done :)");
+}
+
+/// With one request slot and room for one more in the queue, a third concurrent request should be
+/// shed with a 503 straight away, while the queued one waits its turn and gets through.
+#[tokio::test]
+async fn test_load_shedding() {
+    init_logging();
+    // An upstream that takes a second to answer each request
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    let response = b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow";
+                    let _ = conn.write_all(response).await;
+                }
+            });
+        }
+    });
+    let args = [
+        "--max-concurrent-requests",
+        "1",
+        "--max-queued-requests",
+        "1",
+        "--queue-timeout",
+        "5",
+    ];
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream_address], None, None, &args).await;
+
+    let url = format!("http://{}/", balancebeam.address);
+    let mut requests = Vec::new();
+    for _ in 0..3 {
+        requests.push(tokio::spawn(reqwest::get(url.clone())));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    let mut statuses = Vec::new();
+    for request in requests {
+        let response = request
+            .await
+            .unwrap()
+            .expect("Error sending request to balancebeam");
+        if response.status() == 503 {
+            assert_eq!(response.headers()["retry-after"], "1");
+        }
+        statuses.push(response.status().as_u16());
+    }
+    assert_eq!(statuses, vec![200, 200, 503]);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,86 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use rand::Rng;
+
+/// Start an HTTP/2-only upstream that answers like a gRPC server: a body, followed by trailers
+/// carrying grpc-status and grpc-message. The X-Forwarded-For header it received is echoed back.
+async fn start_grpc_like_server() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let bind_addr = address.parse().unwrap();
+    let service = make_service_fn(|_| async {
+        Ok::<_, hyper::Error>(service_fn(|req: Request<Body>| async move {
+            let forwarded_for = req
+                .headers()
+                .get("x-forwarded-for")
+                .cloned()
+                .unwrap_or_else(|| "none".parse().unwrap());
+            let (mut sender, body) = Body::channel();
+            tokio::spawn(async move {
+                let _ = sender.send_data("hello".into()).await;
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                trailers.insert("grpc-message", "ok".parse().unwrap());
+                let _ = sender.send_trailers(trailers).await;
+            });
+            Ok::<_, hyper::Error>(
+                Response::builder()
+                    .header("content-type", "application/grpc")
+                    .header("x-seen-forwarded-for", forwarded_for)
+                    .body(body)
+                    .unwrap(),
+            )
+        }))
+    });
+    let server = hyper::Server::bind(&bind_addr)
+        .http2_only(true)
+        .serve(service);
+    tokio::spawn(async move {
+        let _ = server.await;
+    });
+    address
+}
+
+/// Proxy an HTTP/2 request through balancebeam and make sure the body and trailers make it back.
+#[tokio::test]
+async fn test_http2_trailers_forwarded() {
+    init_logging();
+    let upstream_address = start_grpc_like_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<Body>();
+    for i in 0..3 {
+        let request = Request::builder()
+            .method("POST")
+            .uri(format!("http://{}/pkg.Service/Method{}", balancebeam.address, i))
+            .header("content-type", "application/grpc")
+            .body(Body::from("request"))
+            .unwrap();
+        let mut response = client
+            .request(request)
+            .await
+            .expect("Error sending HTTP/2 request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.headers()["x-seen-forwarded-for"], "127.0.0.1");
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.data().await {
+            body.extend_from_slice(&chunk.expect("Error reading response body"));
+        }
+        assert_eq!(body, b"hello");
+        let trailers = response
+            .trailers()
+            .await
+            .expect("Error reading trailers")
+            .expect("Trailers were not forwarded");
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers["grpc-message"], "ok");
+    }
+
+    log::info!("All done :)");
+}
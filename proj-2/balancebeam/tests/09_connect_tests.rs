@@ -0,0 +1,93 @@
+mod common;
+
+use common::{init_logging, start_tcp_echo_server, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Sends a CONNECT request over a new connection and returns the connection along with the status
+/// line balancebeam answered with.
+async fn send_connect(balancebeam: &BalanceBeam, target: &str) -> (BufReader<TcpStream>, String) {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", target, target);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut conn = BufReader::new(conn);
+    let mut status_line = String::new();
+    conn.read_line(&mut status_line).await.unwrap();
+    // Skip the rest of the response head
+    let mut line = String::new();
+    while conn.read_line(&mut line).await.unwrap() > 2 {
+        line.clear();
+    }
+    (conn, status_line)
+}
+
+/// CONNECT to an allowed destination should open a tunnel that carries raw bytes both ways.
+#[tokio::test]
+async fn test_connect_tunnel() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let target = start_tcp_echo_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--connect-allow", &target],
+    )
+    .await;
+
+    let (mut conn, status_line) = send_connect(&balancebeam, &target).await;
+    assert!(
+        status_line.starts_with("HTTP/1.1 200"),
+        "Unexpected CONNECT response: {}",
+        status_line
+    );
+
+    let message = b"\x16\x03\x01 definitely not HTTP";
+    conn.get_mut().write_all(message).await.unwrap();
+    let mut echoed = vec![0; message.len()];
+    conn.read_exact(&mut echoed)
+        .await
+        .expect("Tunnel did not echo the message back");
+    assert_eq!(echoed, message);
+    drop(conn);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 0,
+        "CONNECT should not have been forwarded to the upstream"
+    );
+
+    log::info!("All done :)");
+}
+
+/// CONNECT to a destination that isn't on the allow list should be refused without connecting.
+#[tokio::test]
+async fn test_connect_not_allowed() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let target = start_tcp_echo_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--connect-allow", "*:443"],
+    )
+    .await;
+
+    let (_conn, status_line) = send_connect(&balancebeam, &target).await;
+    assert!(
+        status_line.starts_with("HTTP/1.1 403"),
+        "Unexpected CONNECT response: {}",
+        status_line
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 0,
+        "CONNECT should not have been forwarded to the upstream"
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Start an upstream that serves a Server-Sent Events stream on /events (chunked, with no
+/// Content-Length) and a plain response everywhere else. The event stream sends one event, then
+/// waits for `release` to fire before sending a second event and ending the stream.
+async fn start_sse_server(release: oneshot::Receiver<()>) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let bind_addr = address.parse().unwrap();
+    let release = Arc::new(Mutex::new(Some(release)));
+    let service = make_service_fn(move |_| {
+        let release = Arc::clone(&release);
+        async move {
+            Ok::<_, hyper::Error>(service_fn(move |req: Request<Body>| {
+                let release = release.lock().unwrap().take();
+                async move {
+                    if req.uri().path() != "/events" {
+                        return Ok::<_, hyper::Error>(Response::new(Body::from("plain")));
+                    }
+                    let (mut sender, body) = Body::channel();
+                    tokio::spawn(async move {
+                        let _ = sender.send_data("data: first\n\n".into()).await;
+                        if let Some(release) = release {
+                            let _ = release.await;
+                        }
+                        let _ = sender.send_data("data: second\n\n".into()).await;
+                    });
+                    Ok(Response::builder()
+                        .header("content-type", "text/event-stream")
+                        .body(body)
+                        .unwrap())
+                }
+            }))
+        }
+    });
+    let server = hyper::Server::bind(&bind_addr).serve(service);
+    tokio::spawn(async move {
+        let _ = server.await;
+    });
+    address
+}
+
+/// Events should reach the client as the upstream sends them, not when the stream ends, and the
+/// connection should still be usable once the chunked stream is over.
+#[tokio::test]
+async fn test_event_stream_forwarded_incrementally() {
+    init_logging();
+    let (release_tx, release_rx) = oneshot::channel();
+    let upstream_address = start_sse_server(release_rx).await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let client = reqwest::Client::new();
+    let mut response = client
+        .get(format!("http://{}/events", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    // The upstream won't finish the stream until we release it, so if balancebeam waited for the
+    // whole body, this would time out
+    let first = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+        .await
+        .expect("First event was not forwarded before the stream ended")
+        .expect("Error reading event stream")
+        .expect("Event stream ended early");
+    assert_eq!(&first[..], b"data: first\n\n");
+
+    release_tx.send(()).unwrap();
+    let rest = response.text().await.expect("Error reading event stream");
+    assert_eq!(rest, "data: second\n\n");
+
+    // Same client, so this goes over the same connection
+    let plain = client
+        .get(format!("http://{}/plain", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request after the event stream")
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(plain, "plain");
+
+    log::info!("All done :)");
+}
+
+/// Start an upstream that answers every request with a chunked response whose body is `body`,
+/// verbatim, and then hangs up.
+async fn start_chunked_server(body: &'static str) -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind chunked response server");
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                let _ = conn.read(&mut buf).await;
+                let head = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n";
+                let _ = conn.write_all(format!("{}{}", head, body).as_bytes()).await;
+            });
+        }
+    });
+    address
+}
+
+/// A streamed chunked body with a chunk size that isn't plain hex, or with chunk data that isn't
+/// followed by a CRLF, can't be followed to its end, so balancebeam should cut the response off and
+/// hang up rather than guess where the body ends. A chunk size near the top of the range is taken
+/// at its word, and the response is cut off when the upstream hangs up partway through the chunk.
+#[tokio::test]
+async fn test_malformed_chunked_stream_cut_off() {
+    init_logging();
+    let bodies = [
+        "+5\r\nhello\r\n0\r\n\r\n",
+        "fffffffffffffffe\r\nhello\r\n0\r\n\r\n",
+        "ffffffffffffffff\r\nhello\r\n0\r\n\r\n",
+        "5\r\nhelloXX0\r\n\r\n",
+    ];
+    for body in bodies {
+        let upstream_address = start_chunked_server(body).await;
+        let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+            .await
+            .unwrap_or_else(|_| panic!("balancebeam didn't hang up after {:?}", body))
+            .unwrap();
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    }
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn post_with_key(balancebeam: &BalanceBeam, key: &str, body: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("http://{}/payments", balancebeam.address))
+        .header("Idempotency-Key", key)
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Retrying a POST with the same Idempotency-Key should get the first response back without the
+/// upstream seeing the request again. Reusing the key for a different request is an error.
+#[tokio::test]
+async fn test_idempotency_key_replay() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--idempotency-key-ttl", "60"],
+    )
+    .await;
+
+    log::info!("Sending the original request");
+    let first = post_with_key(&balancebeam, "payment-1", "amount=10").await;
+    assert_eq!(first.status().as_u16(), 200);
+    assert!(first.headers().get("idempotent-replayed").is_none());
+    let first_text = first.text().await.unwrap();
+    assert!(first_text.contains("amount=10"));
+
+    log::info!("Retrying it");
+    let retry = post_with_key(&balancebeam, "payment-1", "amount=10").await;
+    assert_eq!(retry.status().as_u16(), 200);
+    assert_eq!(
+        retry.headers().get("idempotent-replayed").map(|v| v.as_bytes()),
+        Some(&b"true"[..])
+    );
+    assert_eq!(retry.text().await.unwrap(), first_text);
+
+    log::info!("Reusing the key for a different request");
+    let mismatch = post_with_key(&balancebeam, "payment-1", "amount=99").await;
+    assert_eq!(mismatch.status().as_u16(), 422);
+
+    log::info!("Sending a request with a new key");
+    let second = post_with_key(&balancebeam, "payment-2", "amount=10").await;
+    assert_eq!(second.status().as_u16(), 200);
+    assert!(second.headers().get("idempotent-replayed").is_none());
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Only the requests with new keys should have reached the upstream"
+    );
+
+    log::info!("All done :)");
+}
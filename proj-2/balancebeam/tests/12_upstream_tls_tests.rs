@@ -0,0 +1,127 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use openssl::nid::Nid;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+/// Test certificates: a CA, and an upstream (127.0.0.1/localhost) and client certificate signed by
+/// it. Generated with `openssl req`/`openssl x509` and valid for 100 years.
+fn cert_path(name: &str) -> String {
+    format!("{}/tests/certs/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Starts an HTTPS upstream that requires a client certificate signed by the test CA, and answers
+/// every request with the common name of that certificate. Returns its address.
+fn start_mtls_server() -> String {
+    let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+    acceptor
+        .set_private_key_file(cert_path("upstream-key.pem"), SslFiletype::PEM)
+        .unwrap();
+    acceptor
+        .set_certificate_chain_file(cert_path("upstream.pem"))
+        .unwrap();
+    acceptor.set_ca_file(cert_path("ca.pem")).unwrap();
+    acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    let acceptor = Arc::new(acceptor.build());
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        for conn in listener.incoming() {
+            let acceptor = Arc::clone(&acceptor);
+            std::thread::spawn(move || {
+                let mut conn = match acceptor.accept(conn.unwrap()) {
+                    Ok(conn) => conn,
+                    Err(err) => {
+                        log::info!("Upstream TLS handshake failed: {}", err);
+                        return;
+                    }
+                };
+                let client_name = conn
+                    .ssl()
+                    .peer_certificate()
+                    .and_then(|cert| {
+                        let name = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+                        name.data().to_string().ok()
+                    })
+                    .unwrap_or_default();
+                // Answer each request once its head has arrived (the tests only send GETs)
+                let mut received = Vec::new();
+                let mut buf = [0_u8; 1024];
+                loop {
+                    let n = match conn.read(&mut buf) {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => n,
+                    };
+                    received.extend_from_slice(&buf[..n]);
+                    while let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+                        received.drain(..end + 4);
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                            client_name.len(),
+                            client_name
+                        );
+                        if conn.write_all(response.as_bytes()).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// With a client certificate configured, balancebeam should reach an upstream that demands one,
+/// and the upstream should see that certificate.
+#[tokio::test]
+async fn test_upstream_mutual_tls() {
+    init_logging();
+    let upstream = start_mtls_server();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &[
+            "--upstream-client-cert",
+            &cert_path("client.pem"),
+            "--upstream-client-key",
+            &cert_path("client-key.pem"),
+            "--upstream-ca",
+            &cert_path("ca.pem"),
+        ],
+    )
+    .await;
+
+    for _ in 0..2 {
+        let response = reqwest::get(format!("http://{}/", balancebeam.address))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        assert_eq!(response.text().await.unwrap(), "balancebeam test client");
+    }
+    log::info!("All done :)");
+}
+
+/// Without a client certificate, the upstream should refuse the connection, and the client should
+/// get a 502 instead of the request going through in cleartext or unauthenticated.
+#[tokio::test]
+async fn test_upstream_tls_without_client_cert() {
+    init_logging();
+    let upstream = start_mtls_server();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        None,
+        None,
+        &["--upstream-ca", &cert_path("ca.pem")],
+    )
+    .await;
+
+    let response = reqwest::get(format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    log::info!("All done :)");
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Adds up the requests and bytes recorded for one dimension/key across every flushed period.
+fn totals(records: &[serde_json::Value], dimension: &str, key: &str) -> (u64, u64, u64) {
+    let mut totals = (0, 0, 0);
+    for record in records {
+        if record["dimension"] == dimension && record["key"] == key {
+            totals.0 += record["requests"].as_u64().unwrap();
+            totals.1 += record["bytes_in"].as_u64().unwrap();
+            totals.2 += record["bytes_out"].as_u64().unwrap();
+        }
+    }
+    totals
+}
+
+/// Usage should be written to the accounting file, broken down by upstream, by route and by API key.
+#[tokio::test]
+async fn test_accounting_records() {
+    init_logging();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-accounting-{}.jsonl",
+        rand::thread_rng().gen::<u32>()
+    ));
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--accounting-file",
+            path.to_str().unwrap(),
+            "--accounting-interval",
+            "1",
+            "--accounting-route",
+            "/api",
+            "--accounting-route",
+            "/api/v2",
+            "--accounting-api-key-header",
+            "X-Api-Key",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let mut bytes_out = 0;
+    for (path, key) in [
+        ("/api/v2/items", Some("team-a")),
+        ("/api/v1", Some("team-a")),
+        ("/", None),
+    ] {
+        let mut request = client
+            .post(format!("http://{}{}", balancebeam.address, path))
+            .body("hello");
+        if let Some(key) = key {
+            request = request.header("X-Api-Key", key);
+        }
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+        bytes_out += response.bytes().await.unwrap().len() as u64;
+    }
+
+    log::info!("Waiting for the accounting records to be flushed");
+    sleep(Duration::from_millis(2500)).await;
+    let contents = std::fs::read_to_string(&path).expect("Accounting file was not written");
+    let _ = std::fs::remove_file(&path);
+    let records: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("Accounting record is not valid JSON"))
+        .collect();
+
+    assert_eq!(
+        totals(&records, "upstream", &upstream.address),
+        (3, 15, bytes_out)
+    );
+    let (requests, bytes_in, _) = totals(&records, "route", "/api");
+    assert_eq!(
+        (requests, bytes_in),
+        (1, 5),
+        "/api/v1 should count towards /api"
+    );
+    let (requests, bytes_in, _) = totals(&records, "route", "/api/v2");
+    assert_eq!(
+        (requests, bytes_in),
+        (1, 5),
+        "/api/v2/items should only count towards /api/v2"
+    );
+    let (requests, bytes_in, _) = totals(&records, "api_key", "team-a");
+    assert_eq!((requests, bytes_in), (2, 10));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
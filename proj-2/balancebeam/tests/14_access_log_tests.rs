@@ -0,0 +1,139 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::sleep;
+
+fn log_path() -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "balancebeam-access-{}.log",
+        rand::thread_rng().gen::<u32>()
+    ))
+}
+
+/// Returns the lines of the log file and its rotated copies, oldest first, and deletes them. Empty
+/// files are left out: a rotation can come due with nothing logged since the last one.
+fn read_logs(path: &Path, keep: usize) -> Vec<Vec<String>> {
+    let mut files = Vec::new();
+    for n in (0..=keep).rev() {
+        let file = match n {
+            0 => path.to_path_buf(),
+            n => PathBuf::from(format!("{}.{}", path.display(), n)),
+        };
+        if let Ok(contents) = std::fs::read_to_string(&file) {
+            if !contents.is_empty() {
+                files.push(contents.lines().map(str::to_string).collect());
+            }
+            let _ = std::fs::remove_file(&file);
+        }
+    }
+    files
+}
+
+/// Under concurrent requests, size-based rotation should keep every file under the limit without
+/// losing or mangling any lines.
+#[tokio::test]
+async fn test_access_log_size_rotation() {
+    init_logging();
+    let path = log_path();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-max-size",
+            "1000",
+            "--access-log-keep",
+            "5",
+        ],
+    )
+    .await;
+
+    let mut tasks = Vec::new();
+    for i in 0..30 {
+        let address = balancebeam.address.clone();
+        tasks.push(tokio::spawn(async move {
+            let response = reqwest::get(format!("http://{}/item/{}", address, i))
+                .await
+                .expect("Error sending request to balancebeam");
+            assert_eq!(response.status().as_u16(), 200);
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    sleep(Duration::from_millis(500)).await;
+
+    let files = read_logs(&path, 5);
+    assert!(files.len() > 1, "The access log was never rotated");
+    let mut seen = Vec::new();
+    for lines in &files {
+        let size: usize = lines.iter().map(|line| line.len() + 1).sum();
+        assert!(size <= 1000, "A rotated file grew to {} bytes", size);
+        for line in lines {
+            let start = line.find("\"GET /item/").expect("Malformed access log line");
+            let rest = &line[start + "\"GET /item/".len()..];
+            let (number, rest) = rest.split_once(' ').unwrap();
+            assert!(
+                rest.starts_with("HTTP/1.1\" 200 "),
+                "Malformed access log line: {}",
+                line
+            );
+            assert!(line.contains(&format!("\"{}\"", upstream.address)));
+            seen.push(number.parse::<usize>().unwrap());
+        }
+    }
+    seen.sort();
+    assert_eq!(seen, (0..30).collect::<Vec<_>>());
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Time-based rotation should start a new file once the interval is up. How many times the timer
+/// fires in the meantime depends on scheduling, so only the split between the requests is checked.
+#[tokio::test]
+async fn test_access_log_time_rotation() {
+    init_logging();
+    let path = log_path();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-rotate-interval",
+            "2",
+        ],
+    )
+    .await;
+
+    balancebeam.get("/first").await.unwrap();
+    sleep(Duration::from_millis(2500)).await;
+    balancebeam.get("/second").await.unwrap();
+    sleep(Duration::from_millis(200)).await;
+
+    let files = read_logs(&path, 5);
+    let file_with = |request: &str| {
+        let line = format!("\"GET {} HTTP/1.1\" 200", request);
+        let found: Vec<usize> = (0..files.len())
+            .filter(|&i| files[i].iter().any(|l| l.contains(&line)))
+            .collect();
+        assert_eq!(found.len(), 1, "Expected {} to be logged exactly once", request);
+        found[0]
+    };
+    let first = file_with("/first");
+    let second = file_with("/second");
+    assert!(first < second, "Expected the log to be rotated between the requests");
+    assert_eq!(files.iter().map(Vec::len).sum::<usize>(), 2);
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
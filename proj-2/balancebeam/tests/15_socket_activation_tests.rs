@@ -0,0 +1,77 @@
+mod common;
+
+use common::{init_logging, EchoServer, Server};
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::sleep;
+
+fn target_bin_path() -> std::path::PathBuf {
+    let mut path = std::env::current_exe().expect("Could not get current test executable path");
+    path.pop();
+    path.pop();
+    path.push("balancebeam");
+    path
+}
+
+/// balancebeam should serve on a listening socket passed by systemd instead of binding --bind, and
+/// connections made before it starts should wait in the socket's queue rather than being refused.
+#[tokio::test]
+async fn test_socket_activation() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // What systemd would do: bind the socket, then start the service with it as fd 3
+    let socket = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+
+    let request = tokio::spawn({
+        let address = address.clone();
+        async move { reqwest::get(format!("http://{}/activated", address)).await }
+    });
+    sleep(Duration::from_millis(500)).await;
+    assert!(!request.is_finished(), "Nothing should be answering yet");
+
+    let fd = socket.as_raw_fd();
+    let mut cmd = Command::new("sh");
+    // LISTEN_PID must be balancebeam's own pid, which the shell's is once it execs
+    cmd.arg("-c")
+        .arg("export LISTEN_PID=$$ LISTEN_FDS=1 LISTEN_FDNAMES=http; exec \"$0\" \"$@\"")
+        .arg(target_bin_path())
+        .arg("--upstream")
+        .arg(&upstream.address)
+        // Would be used if the socket weren't passed; nothing should be listening here
+        .arg("--bind")
+        .arg("127.0.0.1:1");
+    unsafe {
+        cmd.pre_exec(move || {
+            if fd == 3 {
+                nix::fcntl::fcntl(
+                    3,
+                    nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()),
+                )?;
+            } else {
+                nix::unistd::dup2(fd, 3)?;
+            }
+            Ok(())
+        });
+    }
+    cmd.kill_on_drop(true);
+    cmd.stdout(std::process::Stdio::null());
+    cmd.stderr(std::process::Stdio::null());
+    let _child = cmd.spawn().expect("Could not execute balancebeam");
+
+    let response = request
+        .await
+        .unwrap()
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response.text().await.unwrap();
+    assert!(
+        body.starts_with("GET /activated"),
+        "Unexpected response: {}",
+        body
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// Sends `count` requests (with the given API key, if any) and returns their statuses.
+async fn send_requests(balancebeam: &BalanceBeam, count: usize, api_key: Option<&str>) -> Vec<u16> {
+    let client = reqwest::Client::new();
+    let mut statuses = Vec::new();
+    for i in 0..count {
+        let mut request = client.get(format!("http://{}/policy-{}", balancebeam.address, i));
+        if let Some(api_key) = api_key {
+            request = request.header("X-Api-Key", api_key);
+        }
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        statuses.push(response.status().as_u16());
+    }
+    statuses
+}
+
+/// Limits from the policy file should override the global limit, by CIDR and by API key, and
+/// changes to the file should apply without a restart.
+#[tokio::test]
+async fn test_rate_limit_policy() {
+    init_logging();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-rate-limits-{}.txt",
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::write(
+        &path,
+        "# Local clients get a little more than the global limit\n\
+         127.0.0.0/8 3\n\
+         key:partner 5\n",
+    )
+    .unwrap();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(1),
+        &[
+            "--rate-limit-policy",
+            path.to_str().unwrap(),
+            "--rate-limit-policy-reload-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    assert_eq!(
+        send_requests(&balancebeam, 4, None).await,
+        [200, 200, 200, 429],
+        "127.0.0.0/8 should get 3 requests per minute"
+    );
+    assert_eq!(
+        send_requests(&balancebeam, 6, Some("partner")).await,
+        [200, 200, 200, 200, 200, 429],
+        "The partner key should get its own 5 requests per minute"
+    );
+    assert_eq!(
+        send_requests(&balancebeam, 1, Some("unknown")).await,
+        [429],
+        "Unknown keys should count against the client's IP"
+    );
+
+    log::info!("Changing the policy");
+    std::fs::write(&path, "127.0.0.1 0\n").unwrap();
+    sleep(Duration::from_millis(2500)).await;
+    assert_eq!(
+        send_requests(&balancebeam, 5, None).await,
+        [200; 5],
+        "127.0.0.1 should now be unlimited"
+    );
+    assert_eq!(
+        send_requests(&balancebeam, 1, Some("partner")).await,
+        [200],
+        "The partner key no longer has a limit of its own"
+    );
+
+    let _ = std::fs::remove_file(&path);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
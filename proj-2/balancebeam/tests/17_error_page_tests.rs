@@ -0,0 +1,118 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::sleep;
+
+/// balancebeam's own errors should use the --error-pages templates, fill in the placeholders, and
+/// pick up changes to the templates without a restart.
+#[tokio::test]
+async fn test_error_pages() {
+    init_logging();
+    let dir = std::env::temp_dir().join(format!(
+        "balancebeam-error-pages-{}",
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(
+        dir.join("502.html"),
+        "<h1>{{status}} {{reason}}</h1><p>Request {{request_id}}</p>",
+    )
+    .unwrap();
+    std::fs::write(dir.join("error.html"), "<h1>Sorry ({{status}})</h1>").unwrap();
+
+    let args = [
+        "--error-pages",
+        dir.to_str().unwrap(),
+        "--error-pages-reload-interval",
+        "1",
+    ];
+    // Nothing listens on this upstream, so every request gets a 502 from balancebeam
+    let balancebeam = BalanceBeam::new_with_args(&["127.0.0.1:1"], None, None, &args).await;
+    let client = reqwest::Client::new();
+    let url = format!("http://{}/", balancebeam.address);
+
+    let response = client
+        .get(&url)
+        .header("X-Request-Id", "<abc>")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    assert_eq!(response.headers()["x-request-id"], "<abc>");
+    // balancebeam hangs up after this, so the client mustn't send anything else on the connection
+    assert_eq!(response.headers()["connection"], "close");
+    let body = response.text().await.unwrap();
+    assert_eq!(body, "<h1>502 Bad Gateway</h1><p>Request &lt;abc&gt;</p>");
+
+    // Without an X-Request-Id, balancebeam makes one up
+    let response = client.get(&url).send().await.unwrap();
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(!request_id.is_empty());
+    let body = response.text().await.unwrap();
+    assert!(body.contains(&request_id));
+
+    // Errors from forward auth and idempotency keys are balancebeam's own as well
+    let upstream = EchoServer::new().await;
+    let mut auth_args = args.to_vec();
+    auth_args.extend(["--forward-auth", "/private=127.0.0.1:1/check"]);
+    auth_args.extend(["--idempotency-key-ttl", "60"]);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &auth_args).await;
+    let response = client
+        .get(format!("http://{}/private", balancebeam.address))
+        .header("X-Request-Id", "auth")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "<h1>502 Bad Gateway</h1><p>Request auth</p>"
+    );
+    let post = |body: &'static str| {
+        client
+            .post(format!("http://{}/pay", balancebeam.address))
+            .header("Idempotency-Key", "payment-1")
+            .body(body)
+            .send()
+    };
+    assert_eq!(post("amount=10").await.unwrap().status().as_u16(), 200);
+    let response = post("amount=99").await.unwrap();
+    assert_eq!(response.status().as_u16(), 422);
+    assert_eq!(response.text().await.unwrap(), "<h1>Sorry (422)</h1>");
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+
+    // The second request is over the rate limit, and 429 has no page of its own
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], None, Some(1), &args).await;
+    let url = format!("http://{}/", balancebeam.address);
+    assert_eq!(
+        client.get(&url).send().await.unwrap().status().as_u16(),
+        200
+    );
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+    assert!(response.headers().contains_key("retry-after"));
+    assert_eq!(response.text().await.unwrap(), "<h1>Sorry (429)</h1>");
+
+    std::fs::write(dir.join("error.html"), "<h1>Slow down</h1>").unwrap();
+    sleep(Duration::from_secs(2)).await;
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "<h1>Slow down</h1>");
+
+    let _ = std::fs::remove_dir_all(&dir);
+    // Kill balancebeam first, so that the upstream isn't left waiting for its connection to close
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
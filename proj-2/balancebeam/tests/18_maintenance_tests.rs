@@ -0,0 +1,90 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+async fn get_as(balancebeam: &BalanceBeam, client_ip: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("X-Forwarded-For", client_ip)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+async fn admin_post(admin_address: &str, path: &str) {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}{}", admin_address, path))
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+/// In maintenance mode, clients should get the maintenance page and Retry-After, except for
+/// allow-listed ones, and the admin API should turn maintenance mode off and on.
+#[tokio::test]
+async fn test_maintenance_mode() {
+    init_logging();
+    let dir = std::env::temp_dir().join(format!(
+        "balancebeam-maintenance-{}",
+        rand::thread_rng().gen::<u32>()
+    ));
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("maintenance.html"), "<h1>Back soon</h1>").unwrap();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    // The test client is always 127.0.0.1, so trust it to say who it is in X-Forwarded-For
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--maintenance",
+            "--maintenance-allow",
+            "10.1.0.0/16",
+            "--maintenance-retry-after",
+            "120",
+            "--trusted-proxies",
+            "127.0.0.1",
+            "--error-pages",
+            dir.to_str().unwrap(),
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    let response = get_as(&balancebeam, "192.0.2.1").await;
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(response.headers()["retry-after"], "120");
+    assert_eq!(response.text().await.unwrap(), "<h1>Back soon</h1>");
+    assert_eq!(
+        get_as(&balancebeam, "10.1.2.3").await.status().as_u16(),
+        200
+    );
+
+    admin_post(&admin_address, "/maintenance/off").await;
+    assert_eq!(
+        get_as(&balancebeam, "192.0.2.1").await.status().as_u16(),
+        200
+    );
+
+    admin_post(&admin_address, "/maintenance/on").await;
+    assert_eq!(
+        get_as(&balancebeam, "192.0.2.1").await.status().as_u16(),
+        503
+    );
+    let mode = reqwest::get(format!("http://{}/maintenance", admin_address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(mode, "on\n");
+
+    let _ = std::fs::remove_dir_all(&dir);
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
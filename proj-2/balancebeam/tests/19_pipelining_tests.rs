@@ -0,0 +1,157 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Reads one response off the connection and returns its status line and body.
+async fn read_response(conn: &mut BufReader<TcpStream>) -> (String, String) {
+    let mut status_line = String::new();
+    conn.read_line(&mut status_line).await.unwrap();
+    let mut content_length = 0;
+    let mut line = String::new();
+    while conn.read_line(&mut line).await.unwrap() > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        line.clear();
+    }
+    let mut body = vec![0; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    (status_line, String::from_utf8(body).unwrap())
+}
+
+/// Requests sent back to back without waiting for responses (possibly all in one packet) should
+/// each be forwarded intact, and answered in order.
+#[tokio::test]
+async fn test_pipelined_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let requests = "GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n\
+        POST /second HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\r\nhello\
+        GET /third HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    conn.write_all(requests.as_bytes()).await.unwrap();
+    let mut conn = BufReader::new(conn);
+
+    let (status_line, body) = read_response(&mut conn).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert!(body.starts_with("GET /first "), "Unexpected echo: {}", body);
+    assert!(body.ends_with("\n\n"), "GET should have no body: {}", body);
+
+    let (status_line, body) = read_response(&mut conn).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert!(body.starts_with("POST /second "), "Unexpected echo: {}", body);
+    assert!(body.ends_with("\n\nhello"), "POST body was mangled: {}", body);
+
+    let (status_line, body) = read_response(&mut conn).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert!(body.starts_with("GET /third "), "Unexpected echo: {}", body);
+    drop(conn);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+
+    log::info!("All done :)");
+}
+
+/// With --max-requests-per-connection, the last request a connection may carry is answered with
+/// Connection: close, and the connection is closed after it, even if more requests were sent.
+#[tokio::test]
+async fn test_max_requests_per_connection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-requests-per-connection", "2"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let requests = "GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n\
+        GET /second HTTP/1.1\r\nHost: example.com\r\n\r\n\
+        GET /third HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    conn.write_all(requests.as_bytes()).await.unwrap();
+    let mut responses = String::new();
+    conn.read_to_string(&mut responses)
+        .await
+        .expect("balancebeam did not hang up");
+    let (first, second) = responses
+        .split_once("HTTP/1.1 200")
+        .and_then(|(_, rest)| rest.split_once("HTTP/1.1 200"))
+        .expect("Expected two responses");
+    assert!(
+        !first.to_ascii_lowercase().contains("connection: close"),
+        "Only the last response should close the connection: {}",
+        first
+    );
+    assert!(
+        second.to_ascii_lowercase().contains("connection: close"),
+        "The last response should close the connection: {}",
+        second
+    );
+    assert!(!responses.contains("GET /third"), "{}", responses);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// With --client-max-conn-age, the first request to arrive after the connection has been open that
+/// long is answered with Connection: close, and the connection is closed after it.
+#[tokio::test]
+async fn test_client_max_conn_age() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-max-conn-age", "1"],
+    )
+    .await;
+
+    let conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut conn = BufReader::new(conn);
+    let request = "GET /young HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    conn.get_mut().write_all(request.as_bytes()).await.unwrap();
+    let (status_line, body) = read_response(&mut conn).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert!(body.starts_with("GET /young"), "{}", body);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let request = "GET /old HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    conn.get_mut().write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response)
+        .await
+        .expect("balancebeam did not hang up");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(
+        response.to_ascii_lowercase().contains("connection: close"),
+        "The response to a request on an old connection should close it: {}",
+        response
+    );
+    assert!(response.contains("GET /old"), "{}", response);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
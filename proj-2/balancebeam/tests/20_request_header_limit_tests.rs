@@ -0,0 +1,116 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn get_with_headers(balancebeam: &BalanceBeam, headers: &[(String, String)]) -> u16 {
+    let mut request = reqwest::Client::new().get(format!("http://{}/", balancebeam.address));
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Requests with too much header data or too many headers should get a 431 without being
+/// forwarded, while requests within the limits go through as usual.
+#[tokio::test]
+async fn test_request_header_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--max-request-header-size",
+            "1024",
+            "--max-request-headers",
+            "10",
+        ],
+    )
+    .await;
+
+    let small: Vec<(String, String)> = (0..5)
+        .map(|i| (format!("x-header-{}", i), "value".to_string()))
+        .collect();
+    assert_eq!(get_with_headers(&balancebeam, &small).await, 200);
+
+    let big = vec![("x-big".to_string(), "a".repeat(2000))];
+    assert_eq!(get_with_headers(&balancebeam, &big).await, 431);
+
+    let many: Vec<(String, String)> = (0..20)
+        .map(|i| (format!("x-header-{}", i), "value".to_string()))
+        .collect();
+    assert_eq!(get_with_headers(&balancebeam, &many).await, 431);
+
+    assert_eq!(get_with_headers(&balancebeam, &[]).await, 200);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Requests over the header limits should not have been forwarded"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Requests with a request target or query string over the limits should get a 414 with a JSON
+/// body naming the limit, without being forwarded, and the connection should stay usable.
+#[tokio::test]
+async fn test_request_target_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--max-request-target-length",
+            "100",
+            "--max-query-length",
+            "20",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = |path: String| client.get(format!("http://{}{}", balancebeam.address, path)).send();
+
+    let response = get("/short?q=1".to_string()).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = get(format!("/{}", "a".repeat(200))).await.unwrap();
+    assert_eq!(response.status().as_u16(), 414);
+    assert_eq!(
+        response.headers()["x-balancebeam-error"],
+        "client-uri-too-long"
+    );
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"error\":\"client-uri-too-long\",\"status\":414,\"length\":201,\"limit\":100}\n"
+    );
+
+    let response = get(format!("/?q={}", "b".repeat(30))).await.unwrap();
+    assert_eq!(response.status().as_u16(), 414);
+    assert_eq!(
+        response.text().await.unwrap(),
+        "{\"error\":\"client-query-too-long\",\"status\":414,\"length\":32,\"limit\":20}\n"
+    );
+
+    // Still within both limits
+    let response = get(format!("/?q={}", "c".repeat(18))).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Requests over the target limits should not have been forwarded"
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Sends `request` over a new connection and returns the status line and body of the response,
+/// along with the connection.
+async fn send_raw(
+    balancebeam: &BalanceBeam,
+    request: &str,
+) -> (String, String, BufReader<TcpStream>) {
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut conn = BufReader::new(conn);
+    let mut status_line = String::new();
+    conn.read_line(&mut status_line).await.unwrap();
+    let mut content_length = 0;
+    let mut line = String::new();
+    while conn.read_line(&mut line).await.unwrap() > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        line.clear();
+    }
+    let mut body = vec![0; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    (status_line, String::from_utf8(body).unwrap(), conn)
+}
+
+/// Asserts that balancebeam rejected the request with `status` and hung up, without reading
+/// anything after it as a request of its own.
+async fn assert_rejected(balancebeam: &BalanceBeam, request: &str, status: u16) {
+    let (status_line, _, mut conn) = send_raw(balancebeam, request).await;
+    assert!(
+        status_line.starts_with(&format!("HTTP/1.1 {}", status)),
+        "Expected a {} for {:?}, got {}",
+        status,
+        request,
+        status_line
+    );
+    let mut rest = Vec::new();
+    conn.read_to_end(&mut rest).await.unwrap();
+    assert!(
+        rest.is_empty(),
+        "balancebeam answered more after rejecting {:?}: {}",
+        request,
+        String::from_utf8_lossy(&rest)
+    );
+}
+
+/// Requests whose body framing is ambiguous should be refused and the connection closed, so that
+/// a smuggled request hidden in the body never reaches the upstream.
+#[tokio::test]
+async fn test_ambiguous_framing_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let cl_te = format!(
+        "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+        Transfer-Encoding: chunked\r\n\r\n0\r\n\r\n{}",
+        smuggled
+    );
+    assert_rejected(&balancebeam, &cl_te, 400).await;
+
+    let te = format!(
+        "POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+        0\r\n\r\n{}",
+        smuggled
+    );
+    assert_rejected(&balancebeam, &te, 501).await;
+
+    let conflicting = format!(
+        "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\nContent-Length: {}\r\n\r\n{}",
+        smuggled.len(),
+        smuggled
+    );
+    assert_rejected(&balancebeam, &conflicting, 400).await;
+
+    let plus = format!(
+        "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: +{}\r\n\r\n{}",
+        smuggled.len(),
+        smuggled
+    );
+    assert_rejected(&balancebeam, &plus, 400).await;
+
+    let folded = "GET / HTTP/1.1\r\nHost: example.com\r\nX-Folded: a\r\n b\r\n\r\n";
+    let (status_line, _, _) = send_raw(&balancebeam, folded).await;
+    assert!(status_line.starts_with("HTTP/1.1 400"), "{}", status_line);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 0,
+        "Ambiguous requests should not have been forwarded"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Repeated Content-Length headers that agree are allowed, but only a single one is forwarded.
+#[tokio::test]
+async fn test_duplicate_content_length_normalized() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let request = "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 5\r\n\
+        Content-Length: 5, 005\r\n\r\nhello";
+    let (status_line, body, _) = send_raw(&balancebeam, request).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert_eq!(
+        body.matches("content-length: 5\n").count(),
+        1,
+        "Expected a single normalized Content-Length upstream: {}",
+        body
+    );
+    assert!(body.ends_with("\n\nhello"), "Body was mangled: {}", body);
+    drop(balancebeam);
+
+    Box::new(upstream).stop().await;
+
+    log::info!("All done :)");
+}
+
+/// A request that is refused before its body or the rest of its head has been read should end the
+/// connection. Otherwise the unread bytes would be read as the next request, which is how a client
+/// could get a request past us that we never saw the start of.
+#[tokio::test]
+async fn test_unread_request_not_reused() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-request-header-size", "1024"],
+    )
+    .await;
+
+    // The body we refused to read is sent once the 413 is in, as a client that doesn't wait for
+    // 100 Continue would
+    let smuggled = "GET /smuggled HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    let too_large = "POST /big HTTP/1.1\r\nHost: example.com\r\nContent-Length: 20000000\r\n\r\n";
+    let (status_line, _, mut conn) = send_raw(&balancebeam, too_large).await;
+    assert!(status_line.starts_with("HTTP/1.1 413"), "{}", status_line);
+    let _ = conn.get_mut().write_all(smuggled.as_bytes()).await;
+    let mut rest = Vec::new();
+    let _ = conn.read_to_end(&mut rest).await;
+    assert!(
+        rest.is_empty(),
+        "balancebeam answered more after a 413: {}",
+        String::from_utf8_lossy(&rest)
+    );
+
+    // The head is cut off at the limit, in the middle of a header that hides a request
+    let oversized_head = format!(
+        "GET / HTTP/1.1\r\nHost: example.com\r\nX-Padding: {}\r\n\r\n{}",
+        "a".repeat(1000),
+        smuggled
+    );
+    assert_rejected(&balancebeam, &oversized_head, 431).await;
+
+    let oversized_head = format!(
+        "GET / HTTP/1.1\r\nHost: example.com\r\nX-Padding: {}\r\n{}",
+        "a".repeat(1000),
+        smuggled
+    );
+    assert_rejected(&balancebeam, &oversized_head, 431).await;
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 0,
+        "Requests left unread after an error should not have been forwarded"
+    );
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+/// Reads everything balancebeam sends until it hangs up, failing if it doesn't hang up in time.
+async fn read_until_hangup<R: AsyncRead + Unpin>(conn: &mut R) -> String {
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not hang up on the slow client")
+        .unwrap();
+    String::from_utf8_lossy(&response).into_owned()
+}
+
+/// A client trickling in its request head should be answered with a 408 and hung up on once
+/// --client-header-timeout runs out, but a connection that is merely idle should not.
+#[tokio::test]
+async fn test_client_header_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-header-timeout", "1"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+    let (mut reader, mut writer) = conn.into_split();
+    let trickle = tokio::spawn(async move {
+        for i in 0..20 {
+            sleep(Duration::from_millis(200)).await;
+            let header = format!("X-Slow-{}: a\r\n", i);
+            if writer.write_all(header.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    });
+    let response = read_until_hangup(&mut reader).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    trickle.abort();
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    sleep(Duration::from_secs(2)).await;
+    conn.write_all(b"GET /idle HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0_u8; 12];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"HTTP/1.1 200");
+    drop(conn);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// A client that sends part of the HTTP/2 connection preface and stalls is held to
+/// --client-header-timeout too, rather than being waited on until it sends the rest.
+#[tokio::test]
+async fn test_partial_http2_preface_times_out() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-header-timeout", "1"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"PRI * HT").await.unwrap();
+    let response = read_until_hangup(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
+
+/// A client sending its request body slower than --min-body-rate should get a 408 and be hung up
+/// on, without the request being forwarded.
+#[tokio::test]
+async fn test_min_body_rate() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--min-body-rate", "1000"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let head = "POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 10000\r\n\r\n";
+    conn.write_all(head.as_bytes()).await.unwrap();
+    conn.write_all(&[b'a'; 100]).await.unwrap();
+    let response = read_until_hangup(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "{}", response);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
+
+/// A keep-alive connection that sits idle for longer than --client-idle-timeout should be hung up
+/// on quietly, while one that sends its next request in time should be served.
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-idle-timeout", "1"],
+    )
+    .await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    sleep(Duration::from_millis(500)).await;
+    conn.write_all(b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buf = [0_u8; 12];
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"HTTP/1.1 200");
+    // Then go quiet
+    let rest = read_until_hangup(&mut conn).await;
+    assert!(!rest.contains("HTTP/1.1 408"), "Idle clients get no 408: {}", rest);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
@@ -0,0 +1,74 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::time::{Duration, Instant};
+
+/// Posts a 30KB body to the echo server and returns how long it took to get it back.
+async fn time_echo(balancebeam: &BalanceBeam, path: &str) -> Duration {
+    let body = "a".repeat(30000);
+    let started = Instant::now();
+    let response = balancebeam
+        .post(path, &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.contains(&body), "Response did not echo the body");
+    started.elapsed()
+}
+
+/// With --client-bandwidth, a response body bigger than a second's worth should be paced out
+/// over several seconds.
+#[tokio::test]
+async fn test_client_bandwidth() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--client-bandwidth", "10000"],
+    )
+    .await;
+
+    // The bucket starts out with a second's worth, so the remaining ~20KB takes about 2 seconds
+    let elapsed = time_echo(&balancebeam, "/download").await;
+    assert!(
+        elapsed >= Duration::from_millis(1500),
+        "Response was not throttled (took {:?})",
+        elapsed
+    );
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+}
+
+/// --route-bandwidth should only throttle requests under its prefix.
+#[tokio::test]
+async fn test_route_bandwidth() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--route-bandwidth", "/slow=10000"],
+    )
+    .await;
+
+    let elapsed = time_echo(&balancebeam, "/fast").await;
+    assert!(
+        elapsed < Duration::from_millis(1000),
+        "Response outside the throttled route was slowed down (took {:?})",
+        elapsed
+    );
+    let elapsed = time_echo(&balancebeam, "/slow/file").await;
+    assert!(
+        elapsed >= Duration::from_millis(1500),
+        "Response under the throttled route was not throttled (took {:?})",
+        elapsed
+    );
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+
+async fn admin_request(method: reqwest::Method, admin_address: &str, path: &str) -> String {
+    let response = reqwest::Client::new()
+        .request(method, format!("http://{}{}", admin_address, path))
+        .send()
+        .await
+        .expect("Error sending request to the admin listener");
+    assert_eq!(response.status().as_u16(), 200);
+    response.text().await.unwrap()
+}
+
+/// Body capture should start off, be toggled through the admin API, and leave proxied requests
+/// untouched while it is on.
+#[tokio::test]
+async fn test_capture_toggle() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--capture-max-bytes", "16", "--admin-bind", &admin_address],
+    )
+    .await;
+
+    let get = reqwest::Method::GET;
+    let post = reqwest::Method::POST;
+    assert_eq!(
+        admin_request(get.clone(), &admin_address, "/capture").await,
+        "off\n"
+    );
+    admin_request(post.clone(), &admin_address, "/capture/on").await;
+    assert_eq!(
+        admin_request(get.clone(), &admin_address, "/capture").await,
+        "on\n"
+    );
+
+    let body = "secret stuff ".repeat(100);
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/captured", balancebeam.address))
+        .header("Authorization", "Bearer hunter2")
+        .body(body.clone())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let echoed = response.text().await.unwrap();
+    assert!(echoed.starts_with("POST /captured HTTP/1.1"), "{}", echoed);
+    assert!(echoed.contains("Bearer hunter2"), "{}", echoed);
+    assert!(echoed.ends_with(&body), "Body was not forwarded intact");
+
+    admin_request(post, &admin_address, "/capture/off").await;
+    assert_eq!(
+        admin_request(get, &admin_address, "/capture").await,
+        "off\n"
+    );
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+/// Reads from `conn` until a chunked message (head and body) is complete, and returns it.
+async fn read_chunked_message(conn: &mut TcpStream) -> String {
+    let mut message = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    loop {
+        let text = String::from_utf8_lossy(&message);
+        if let Some(body_start) = text.find("\r\n\r\n") {
+            if text[body_start..].contains("\r\n0\r\n") && text.ends_with("\r\n\r\n") {
+                return text.into_owned();
+            }
+        }
+        let bytes_read = conn.read(&mut buffer).await.unwrap();
+        assert!(bytes_read > 0, "Connection closed mid-message: {}", text);
+        message.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Reads one response with a Content-Length off the connection and returns its status line and
+/// body.
+async fn read_response(conn: &mut BufReader<TcpStream>) -> (String, String) {
+    let mut status_line = String::new();
+    conn.read_line(&mut status_line).await.unwrap();
+    let mut content_length = 0;
+    let mut line = String::new();
+    while conn.read_line(&mut line).await.unwrap() > 2 {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap();
+            }
+        }
+        line.clear();
+    }
+    let mut body = vec![0; content_length];
+    conn.read_exact(&mut body).await.unwrap();
+    (status_line, String::from_utf8(body).unwrap())
+}
+
+/// Starts an upstream that answers every request with a chunked response carrying a grpc-status
+/// trailer, and passes on each request exactly as it arrived.
+async fn start_trailer_server() -> (String, mpsc::UnboundedReceiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let request = read_chunked_message(&mut conn).await;
+                let _ = sender.send(request);
+                let response = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\
+                    Trailer: grpc-status\r\n\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n";
+                conn.write_all(response.as_bytes()).await.unwrap();
+            });
+        }
+    });
+    (address, receiver)
+}
+
+/// A chunked request's trailers should reach the upstream, and a chunked response's trailers
+/// should reach the client.
+#[tokio::test]
+async fn test_trailers_forwarded() {
+    init_logging();
+    let (upstream_address, mut requests) = start_trailer_server().await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request = "POST /upload HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\
+        Trailer: x-checksum\r\n\r\n5\r\nhello\r\n6;ext=1\r\n world\r\n0\r\nx-checksum: abc\r\n\r\n";
+    conn.write_all(request.as_bytes()).await.unwrap();
+
+    let response = read_chunked_message(&mut conn).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(
+        response.ends_with("\r\n5\r\nhello\r\n0\r\ngrpc-status: 0\r\n\r\n"),
+        "Response trailers were not forwarded: {:?}",
+        response
+    );
+
+    let forwarded = requests.recv().await.unwrap();
+    assert!(
+        !forwarded.to_ascii_lowercase().contains("content-length"),
+        "A chunked request shouldn't gain a Content-Length: {:?}",
+        forwarded
+    );
+    assert!(
+        forwarded.ends_with("\r\n\r\nb\r\nhello world\r\n0\r\nx-checksum: abc\r\n\r\n"),
+        "Request body or trailers were not forwarded: {:?}",
+        forwarded
+    );
+
+    log::info!("All done :)");
+}
+
+/// A chunked request followed by a pipelined request should be answered twice, with the body
+/// decoded correctly by the upstream, and the second request not mistaken for part of the first.
+#[tokio::test]
+async fn test_chunked_request_pipelined() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let requests =
+        "POST /first HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\n\
+        3\r\nabc\r\n0\r\nx-trailer: 1\r\n\r\n\
+        GET /second HTTP/1.1\r\nHost: example.com\r\n\r\n";
+    conn.write_all(requests.as_bytes()).await.unwrap();
+    let mut conn = BufReader::new(conn);
+
+    let (status_line, body) = read_response(&mut conn).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert!(
+        body.starts_with("POST /first "),
+        "Unexpected echo: {}",
+        body
+    );
+    assert!(body.ends_with("\n\nabc"), "Body was mangled: {}", body);
+
+    let (status_line, body) = read_response(&mut conn).await;
+    assert!(status_line.starts_with("HTTP/1.1 200"), "{}", status_line);
+    assert!(
+        body.starts_with("GET /second "),
+        "Unexpected echo: {}",
+        body
+    );
+    drop(conn);
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 2);
+
+    log::info!("All done :)");
+}
+
+/// Malformed chunked bodies, and codings other than chunked, are refused.
+#[tokio::test]
+async fn test_bad_chunked_request_rejected() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    for (request, status) in [
+        (
+            "POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            "HTTP/1.1 400",
+        ),
+        (
+            "POST / HTTP/1.1\r\nHost: example.com\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+            "HTTP/1.1 501",
+        ),
+    ] {
+        let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with(status), "{:?}: {}", request, response);
+    }
+    drop(balancebeam);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 0);
+
+    log::info!("All done :)");
+}
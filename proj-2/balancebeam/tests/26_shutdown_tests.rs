@@ -0,0 +1,106 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use nix::sys::signal::Signal;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+/// Starts an upstream that waits `delay` before answering each request with "slow".
+async fn start_slow_server(delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match conn.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                sleep(delay).await;
+                let _ = conn
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+/// SIGTERM should stop new connections, let an in-flight request finish (telling the client the
+/// connection is closing), and exit cleanly once the remaining connections are gone.
+#[tokio::test]
+async fn test_sigterm_drains() {
+    init_logging();
+    let upstream_address = start_slow_server(Duration::from_secs(1)).await;
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], None, None, &["--drain-timeout", "10"])
+            .await;
+
+    let idle = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.signal(Signal::SIGTERM);
+
+    let mut response = String::new();
+    timeout(Duration::from_secs(5), conn.read_to_string(&mut response))
+        .await
+        .expect("balancebeam did not close the connection after the in-flight response")
+        .unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(
+        response.to_ascii_lowercase().contains("connection: close"),
+        "{}",
+        response
+    );
+    assert!(response.ends_with("\r\n\r\nslow"), "{}", response);
+
+    // The idle connection keeps balancebeam around, but it shouldn't take new connections
+    assert!(
+        TcpStream::connect(&balancebeam.address).await.is_err(),
+        "balancebeam accepted a connection while draining"
+    );
+    drop(idle);
+    let status = timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam did not exit once drained");
+    assert_eq!(status.code(), Some(0));
+
+    log::info!("All done :)");
+}
+
+/// SIGQUIT should reset in-flight connections and exit straight away.
+#[tokio::test]
+async fn test_sigquit_resets() {
+    init_logging();
+    let upstream_address = start_slow_server(Duration::from_secs(10)).await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address).await.unwrap();
+    conn.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(300)).await;
+    balancebeam.signal(Signal::SIGQUIT);
+
+    let mut response = Vec::new();
+    let read = timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("balancebeam did not drop the connection");
+    let err = read.expect_err("The connection was closed instead of reset");
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+    let status = timeout(Duration::from_secs(5), balancebeam.wait())
+        .await
+        .expect("balancebeam did not exit");
+    assert_eq!(status.code(), Some(1));
+
+    log::info!("All done :)");
+}
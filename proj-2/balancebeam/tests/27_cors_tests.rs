@@ -0,0 +1,155 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+async fn preflight(balancebeam: &BalanceBeam, path: &str, origin: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .request(
+            reqwest::Method::OPTIONS,
+            format!("http://{}{}", balancebeam.address, path),
+        )
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "PUT")
+        .header("Access-Control-Request-Headers", "content-type, x-token")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+async fn get_from(balancebeam: &BalanceBeam, path: &str, origin: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("Origin", origin)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Preflights on a --cors route should be answered by balancebeam, with a 204 for allowed origins
+/// and a 403 for others, and never reach the upstream. Proxied responses should get
+/// Access-Control-Allow-Origin only on covered routes and for allowed origins.
+#[tokio::test]
+async fn test_cors() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--cors",
+            "/api=https://app.example.com,https://admin.example.com",
+            "--cors",
+            "/public=*",
+            "--cors-expose-headers",
+            "X-Total-Count",
+        ],
+    )
+    .await;
+
+    let response = preflight(&balancebeam, "/api/items", "https://app.example.com").await;
+    assert_eq!(response.status().as_u16(), 204);
+    let headers = response.headers();
+    assert_eq!(
+        headers["access-control-allow-origin"],
+        "https://app.example.com"
+    );
+    assert_eq!(
+        headers["access-control-allow-methods"],
+        "GET, HEAD, POST, PUT, PATCH, DELETE"
+    );
+    assert_eq!(
+        headers["access-control-allow-headers"],
+        "content-type, x-token"
+    );
+    assert_eq!(headers["access-control-max-age"], "600");
+    assert!(headers
+        .get_all("vary")
+        .iter()
+        .any(|value| value == "Origin"));
+
+    let response = preflight(&balancebeam, "/api/items", "https://evil.example.com").await;
+    assert_eq!(response.status().as_u16(), 403);
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+
+    let response = get_from(&balancebeam, "/api/items", "https://admin.example.com").await;
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://admin.example.com"
+    );
+    assert_eq!(
+        response.headers()["access-control-expose-headers"],
+        "X-Total-Count"
+    );
+
+    let response = get_from(&balancebeam, "/public/logo.png", "https://anyone.example").await;
+    assert_eq!(response.headers()["access-control-allow-origin"], "*");
+
+    // Not allowed, or not covered by a rule: proxied without CORS headers
+    for (path, origin) in [
+        ("/api/items", "https://evil.example.com"),
+        ("/private", "https://app.example.com"),
+    ] {
+        let response = get_from(&balancebeam, path, origin).await;
+        assert_eq!(response.status().as_u16(), 200);
+        assert!(response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none());
+    }
+
+    // Only the four GETs made it upstream
+    let requests_received = Box::new(upstream).stop().await;
+    assert_eq!(requests_received, 4);
+    log::info!("All done :)");
+}
+
+/// A rule for the request's host should win over a rule for any host, and credentials should make
+/// balancebeam echo the origin instead of sending *.
+#[tokio::test]
+async fn test_cors_host_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--cors",
+            "/=*",
+            "--cors",
+            "api.example.com/=https://app.example.com",
+            "--cors-allow-credentials",
+        ],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(format!("http://{}/", balancebeam.address))
+        .header("Host", "API.example.com:8080")
+        .header("Origin", "https://other.example.com")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response
+        .headers()
+        .get("access-control-allow-origin")
+        .is_none());
+
+    let response = get_from(&balancebeam, "/", "https://other.example.com").await;
+    assert_eq!(
+        response.headers()["access-control-allow-origin"],
+        "https://other.example.com"
+    );
+    assert_eq!(
+        response.headers()["access-control-allow-credentials"],
+        "true"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
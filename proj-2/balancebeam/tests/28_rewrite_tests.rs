@@ -0,0 +1,60 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+
+/// The echo server answers with the request it got, so the first line says which path the
+/// upstream saw.
+async fn upstream_request_line(balancebeam: &BalanceBeam, path: &str) -> String {
+    let response_text = balancebeam
+        .get(path)
+        .await
+        .expect("Error sending request to balancebeam");
+    response_text.lines().next().unwrap().to_string()
+}
+
+/// Prefix and regex rules should rewrite the path the upstream sees (keeping the query string),
+/// the first matching rule should win, and the original path should be logged.
+#[tokio::test]
+async fn test_rewrite_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--rewrite",
+            "/api/v1=/",
+            "--rewrite",
+            "/api=/legacy",
+            "--rewrite",
+            "~^/u/([0-9]+)$=/users?id=$1",
+        ],
+    )
+    .await;
+
+    for (path, expected) in [
+        ("/api/v1/users?page=2", "GET /users?page=2 HTTP/1.1"),
+        ("/api/v1", "GET / HTTP/1.1"),
+        // /api/v10 isn't under /api/v1, so the next rule gets it
+        ("/api/v10/users", "GET /legacy/v10/users HTTP/1.1"),
+        ("/u/42?sort=asc", "GET /users?id=42&sort=asc HTTP/1.1"),
+        ("/u/me", "GET /u/me HTTP/1.1"),
+        ("/other", "GET /other HTTP/1.1"),
+    ] {
+        assert_eq!(
+            upstream_request_line(&balancebeam, path).await,
+            expected,
+            "{}",
+            path
+        );
+    }
+
+    assert!(balancebeam
+        .output()
+        .iter()
+        .any(|line| line.contains("Rewrote /api/v1/users?page=2 to /users?page=2")));
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use common::{init_logging, BalanceBeam};
+use flate2::read::GzDecoder;
+use rand::Rng;
+use std::io::Read;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// A text body big enough to be worth compressing
+fn page() -> String {
+    "<p>balancebeam compresses this</p>\n".repeat(40)
+}
+
+/// Start a bare-bones upstream that answers /precompressed with a body it says is gzipped, and
+/// anything else with an uncompressed HTML page. Every response says in X-Saw-Accept-Encoding what
+/// Accept-Encoding the request came with.
+async fn start_upstream() -> String {
+    let address = format!("127.0.0.1:{}", rand::thread_rng().gen_range(1024..65535));
+    let listener = TcpListener::bind(&address)
+        .await
+        .expect("Could not bind upstream");
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = match listener.accept().await {
+                Ok(pair) => pair,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut buf = [0_u8; 4096];
+                while let Ok(n) = conn.read(&mut buf).await {
+                    if n == 0 {
+                        return;
+                    }
+                    let request = String::from_utf8_lossy(&buf[..n]).to_ascii_lowercase();
+                    let accept_encoding = request
+                        .lines()
+                        .find_map(|line| line.strip_prefix("accept-encoding: "))
+                        .unwrap_or("none")
+                        .to_string();
+                    let (encoding, body) = if request.starts_with("get /precompressed ") {
+                        ("Content-Encoding: gzip\r\n", "not really gzip".to_string())
+                    } else {
+                        ("", page())
+                    };
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n{}ETag: \"v1\"\r\n\
+                         X-Saw-Accept-Encoding: {}\r\nContent-Length: {}\r\n\r\n{}",
+                        encoding,
+                        accept_encoding,
+                        body.len(),
+                        body
+                    );
+                    let _ = conn.write_all(response.as_bytes()).await;
+                }
+            });
+        }
+    });
+    address
+}
+
+async fn get(balancebeam: &BalanceBeam, path: &str, accept_encoding: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .get(format!("http://{}{}", balancebeam.address, path))
+        .header("Accept-Encoding", accept_encoding)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// With --compression auto, uncompressed responses should be gzipped for clients that accept gzip
+/// (and only for them), and responses the upstream compressed should pass through untouched.
+#[tokio::test]
+async fn test_compression_auto() {
+    init_logging();
+    let upstream = start_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], None, None, &["--compression", "auto"]).await;
+
+    let response = get(&balancebeam, "/", "br;q=1.0, gzip;q=0.8").await;
+    let headers = response.headers().clone();
+    assert_eq!(headers["content-encoding"], "gzip");
+    assert_eq!(headers["x-saw-accept-encoding"], "br;q=1.0, gzip;q=0.8");
+    assert_eq!(headers["etag"], "W/\"v1\"");
+    assert!(headers
+        .get_all("vary")
+        .iter()
+        .any(|value| value == "Accept-Encoding"));
+    let compressed = response.bytes().await.unwrap();
+    assert_eq!(
+        headers["content-length"].to_str().unwrap(),
+        compressed.len().to_string()
+    );
+    let mut body = String::new();
+    GzDecoder::new(&compressed[..])
+        .read_to_string(&mut body)
+        .expect("Response isn't valid gzip");
+    assert_eq!(body, page());
+
+    // gzip refused explicitly, or not mentioned at all
+    for accept_encoding in ["gzip;q=0, deflate", "identity"] {
+        let response = get(&balancebeam, "/", accept_encoding).await;
+        assert!(response.headers().get("content-encoding").is_none());
+        assert_eq!(response.text().await.unwrap(), page());
+    }
+
+    let response = get(&balancebeam, "/precompressed", "gzip").await;
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+    assert_eq!(response.headers()["etag"], "\"v1\"");
+    assert_eq!(response.text().await.unwrap(), "not really gzip");
+
+    log::info!("All done :)");
+}
+
+/// With --compression proxy, upstreams shouldn't see Accept-Encoding, and the proxy should still
+/// compress for clients that accept gzip.
+#[tokio::test]
+async fn test_compression_proxy() {
+    init_logging();
+    let upstream = start_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], None, None, &["--compression", "proxy"]).await;
+
+    let response = get(&balancebeam, "/", "gzip, deflate").await;
+    assert_eq!(response.headers()["x-saw-accept-encoding"], "none");
+    assert_eq!(response.headers()["content-encoding"], "gzip");
+
+    log::info!("All done :)");
+}
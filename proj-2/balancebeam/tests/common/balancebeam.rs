@@ -1,6 +1,7 @@
 use rand::Rng;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::sleep;
 
@@ -8,6 +9,8 @@ pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Everything balancebeam has logged so far
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
@@ -23,6 +26,23 @@ impl BalanceBeam {
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
+    ) -> BalanceBeam {
+        BalanceBeam::new_with_args(
+            upstreams,
+            active_health_check_interval,
+            max_requests_per_minute,
+            &[],
+        )
+        .await
+    }
+
+    /// Like `new`, but passes `extra_args` through to the balancebeam command line. Used by tests
+    /// for options that the basic constructor doesn't know about.
+    pub async fn new_with_args(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
     ) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
@@ -39,13 +59,43 @@ impl BalanceBeam {
             cmd.arg("--max-requests-per-minute")
                 .arg(max_requests_per_minute.to_string());
         }
+        cmd.args(extra_args);
+        BalanceBeam::spawn(cmd, address, None).await
+    }
+
+    /// Starts balancebeam with `env` added to its environment and `stdin` (if any) written to its
+    /// stdin, without --upstream options, so that the upstreams can come from somewhere else.
+    #[allow(dead_code)]
+    pub async fn new_with_input(
+        extra_args: &[&str],
+        env: &[(&str, &str)],
+        stdin: Option<&str>,
+    ) -> BalanceBeam {
+        let mut rng = rand::thread_rng();
+        let address = format!("127.0.0.1:{}", rng.gen_range(1024..65535));
+        let mut cmd = Command::new(BalanceBeam::target_bin_path());
+        cmd.arg("--bind").arg(&address);
+        cmd.args(extra_args);
+        cmd.envs(env.iter().copied());
+        BalanceBeam::spawn(cmd, address, stdin).await
+    }
+
+    async fn spawn(mut cmd: Command, address: String, stdin: Option<&str>) -> BalanceBeam {
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
+        if stdin.is_some() {
+            cmd.stdin(std::process::Stdio::piped());
+        }
         let mut child = cmd.spawn().expect(&format!(
             "Could not execute balancebeam binary {}",
             BalanceBeam::target_bin_path().to_str().unwrap()
         ));
+        if let Some(input) = stdin {
+            // Dropping the pipe afterwards closes it, so that balancebeam sees the end of the input
+            let mut pipe = child.stdin.take().unwrap();
+            pipe.write_all(input.as_bytes()).await.unwrap();
+        }
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -68,6 +118,8 @@ impl BalanceBeam {
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let logged = Arc::clone(&output);
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,12 +128,36 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                logged.lock().unwrap().push(line);
             }
         });
 
         // Hack: wait for executable to start running
         sleep(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Returns the lines balancebeam has logged so far.
+    #[allow(dead_code)]
+    pub fn output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    /// Sends `signal` to the balancebeam process.
+    #[allow(dead_code)]
+    pub fn signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id().unwrap() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not signal balancebeam");
+    }
+
+    /// Waits for the balancebeam process to exit, and returns its exit status.
+    #[allow(dead_code)]
+    pub async fn wait(&mut self) -> std::process::ExitStatus {
+        self.child.wait().await.unwrap()
     }
 
     #[allow(dead_code)]
@@ -2,6 +2,7 @@ mod balancebeam;
 mod echo_server;
 mod error_server;
 mod server;
+mod tcp_echo_server;
 
 use std::sync;
 
@@ -9,6 +10,8 @@ pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
 pub use server::Server;
+#[allow(unused_imports)]
+pub use tcp_echo_server::start_tcp_echo_server;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
@@ -0,0 +1,18 @@
+use tokio::net::TcpListener;
+
+/// Starts a server that echoes back whatever bytes it receives, with no HTTP involved. Returns its
+/// address.
+#[allow(dead_code)]
+pub async fn start_tcp_echo_server() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut conn, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = conn.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    address
+}
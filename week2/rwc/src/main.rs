@@ -1,17 +1,291 @@
+use regex::Regex;
+use std::collections::HashSet;
 use std::env;
 use std::process;
 use std::fs::File; // For read_file_lines()
-use std::io::{self, BufRead}; // For read_file_lines()
+use std::io::{self, BufRead, Write}; // For read_file_lines()
 
-/// Reads the file at the supplied path, and returns a vector of strings.
-fn read_file_lines(filename: &String) -> Result<Vec<String>, io::Error> {
+/// Exit status when a count is over its --max-lines/--max-bytes limit. Within the limits, rwc
+/// exits with 0.
+const EXIT_OVER_LIMIT: i32 = 1;
+/// Exit status for bad arguments and unreadable files, so that scripts can tell them apart from a
+/// file that is merely too big
+const EXIT_ERROR: i32 = 2;
+
+/// Command-line options. The file to count is the only positional argument.
+struct Options {
+    filename: String,
+    /// Lines matching any of these patterns are left out of the counts
+    ignore_regexes: Vec<Regex>,
+    /// Skip blank lines and comments so that the counts reflect lines of actual code
+    code_mode: bool,
+    /// Where to write the line number -> byte offset index, if anywhere
+    index: Option<IndexOutput>,
+    /// Also report words per line and vocabulary statistics
+    stats: bool,
+    /// Print nothing; the exit status says whether the limits were met
+    quiet: bool,
+    /// Most lines (as counted, so after ignoring lines) the file may have
+    max_lines: Option<usize>,
+    /// Most bytes the file may have on disk
+    max_bytes: Option<u64>,
+    /// Patterns to report matching lines and words for, in the order given
+    match_regexes: Vec<Regex>,
+}
+
+/// How many of the counted lines, and of the words on them, match one --match pattern. A word
+/// matches if the pattern matches anywhere in it; anchor the pattern (^...$) to match whole words.
+struct MatchCounts<'a> {
+    regex: &'a Regex,
+    lines: usize,
+    words: usize,
+}
+
+/// Where and how to write the index of line start offsets
+struct IndexOutput {
+    path: String,
+    json: bool,
+}
+
+fn usage(program: &str) -> ! {
+    println!(
+        "Usage: {} [--code-mode] [--ignore-regex PATTERN]... \
+         [--index PATH | --index-json PATH] [--stats] [--match PATTERN]... [--max-lines N] \
+         [--max-bytes N] [--quiet] <file>",
+        program
+    );
+    process::exit(EXIT_ERROR);
+}
+
+/// Compiles the pattern given to the option at args[i - 1], or gives up with a usage error.
+fn parse_regex(args: &[String], i: usize) -> Regex {
+    let pattern = args.get(i).unwrap_or_else(|| usage(&args[0]));
+    Regex::new(pattern).unwrap_or_else(|err| {
+        println!("Invalid {} pattern {}: {}", args[i - 1], pattern, err);
+        process::exit(EXIT_ERROR);
+    })
+}
+
+/// Parses the value of a numeric option, or gives up with a usage error.
+fn parse_limit<T: std::str::FromStr>(args: &[String], i: usize) -> T {
+    let value = args.get(i).unwrap_or_else(|| usage(&args[0]));
+    value.parse().unwrap_or_else(|_| {
+        println!("Invalid {} value: {}", args[i - 1], value);
+        process::exit(EXIT_ERROR);
+    })
+}
+
+fn parse_args(args: &[String]) -> Options {
+    let mut filename = None;
+    let mut ignore_regexes = Vec::new();
+    let mut code_mode = false;
+    let mut index = None;
+    let mut stats = false;
+    let mut quiet = false;
+    let mut max_lines = None;
+    let mut max_bytes = None;
+    let mut match_regexes = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--code-mode" => code_mode = true,
+            "--stats" => stats = true,
+            "--quiet" => quiet = true,
+            "--max-lines" => {
+                i += 1;
+                max_lines = Some(parse_limit(args, i));
+            }
+            "--max-bytes" => {
+                i += 1;
+                max_bytes = Some(parse_limit(args, i));
+            }
+            "--ignore-regex" => {
+                i += 1;
+                ignore_regexes.push(parse_regex(args, i));
+            }
+            "--match" => {
+                i += 1;
+                match_regexes.push(parse_regex(args, i));
+            }
+            "--index" | "--index-json" => {
+                let json = args[i] == "--index-json";
+                i += 1;
+                let path = args.get(i).unwrap_or_else(|| usage(&args[0])).to_string();
+                index = Some(IndexOutput { path, json });
+            }
+            arg if arg.starts_with("--") => usage(&args[0]),
+            arg => filename = Some(arg.to_string()),
+        }
+        i += 1;
+    }
+    match filename {
+        Some(filename) => Options {
+            filename,
+            ignore_regexes,
+            code_mode,
+            index,
+            stats,
+            quiet,
+            max_lines,
+            max_bytes,
+            match_regexes,
+        },
+        None => {
+            println!("Too few arguments.");
+            process::exit(EXIT_ERROR);
+        }
+    }
+}
+
+/// Reads the file at the supplied path, and returns a vector of strings along with the byte offset
+/// at which each line starts. Line endings (\n or \r\n) are stripped from the strings but counted
+/// in the offsets, so the offsets can be used to seek straight to a line in the original file.
+fn read_file_lines(filename: &String) -> Result<(Vec<String>, Vec<u64>), io::Error> {
     let mut str_vec = Vec::new();
-    let file = File::open(filename)?;
-    for line in io::BufReader::new(file).lines() {
-        let line_str = line?;
+    let mut offsets = Vec::new();
+    let mut reader = io::BufReader::new(File::open(filename)?);
+    let mut offset = 0;
+    loop {
+        let mut line_str = String::new();
+        let len = reader.read_line(&mut line_str)?;
+        if len == 0 {
+            break;
+        }
+        offsets.push(offset);
+        offset += len as u64;
+        if line_str.ends_with('\n') {
+            line_str.pop();
+            if line_str.ends_with('\r') {
+                line_str.pop();
+            }
+        }
         str_vec.push(line_str);
     }
-    Ok(str_vec)
+    Ok((str_vec, offsets))
+}
+
+/// Writes the line number -> byte offset table, one "LINE OFFSET" pair per line (line numbers
+/// start at 1), or as a JSON object naming the file along with an array of
+/// {"line": LINE, "offset": OFFSET} objects.
+fn write_index(filename: &str, offsets: &[u64], output: &IndexOutput) -> Result<(), io::Error> {
+    let mut out = io::BufWriter::new(File::create(&output.path)?);
+    if output.json {
+        write!(out, "{{\"file\":{},\"lines\":[", json_string(filename))?;
+        for (i, offset) in offsets.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(out, "{}{{\"line\":{},\"offset\":{}}}", separator, i + 1, offset)?;
+        }
+        writeln!(out, "]}}")?;
+    } else {
+        for (i, offset) in offsets.iter().enumerate() {
+            writeln!(out, "{} {}", i + 1, offset)?;
+        }
+    }
+    out.flush()
+}
+
+/// Quotes `s` as a JSON string, escaping quotes, backslashes and control characters.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// How comments are written in the file being counted
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CommentSyntax {
+    /// `//` line comments and `/* */` block comments, as in Rust and C (the default)
+    Slashes,
+    /// `#` line comments, as in shell scripts, Python and Makefiles
+    Hash,
+}
+
+impl CommentSyntax {
+    /// Picks the syntax by the file's extension (or name, for Makefiles and the like).
+    fn for_file(filename: &str) -> CommentSyntax {
+        let name = filename.rsplit('/').next().unwrap_or(filename);
+        let extension = name.rsplit_once('.').map_or("", |(_, extension)| extension);
+        match (name, extension) {
+            ("Makefile" | "makefile" | "Dockerfile" | "CMakeLists.txt", _) => CommentSyntax::Hash,
+            (_, "sh" | "bash" | "zsh" | "py" | "rb" | "pl" | "toml" | "yaml" | "yml" | "cmake") => {
+                CommentSyntax::Hash
+            }
+            _ => CommentSyntax::Slashes,
+        }
+    }
+
+    fn line_comment(self) -> &'static str {
+        match self {
+            CommentSyntax::Slashes => "//",
+            CommentSyntax::Hash => "#",
+        }
+    }
+}
+
+/// Returns true if the line contains something other than whitespace and comments. `in_block` tracks
+/// whether we are inside a /* */ block comment, and is updated as the line is scanned. Comments may
+/// start anywhere on the line; markers inside "double-quoted strings" don't count.
+fn is_code_line(line: &str, syntax: CommentSyntax, in_block: &mut bool) -> bool {
+    let mut code = false;
+    let mut in_string = false;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let rest = &line[i..];
+        if *in_block {
+            if rest.starts_with("*/") {
+                *in_block = false;
+                chars.next();
+            }
+        } else if in_string {
+            match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if rest.starts_with(syntax.line_comment()) {
+            break;
+        } else if syntax == CommentSyntax::Slashes && rest.starts_with("/*") {
+            *in_block = true;
+            chars.next();
+        } else if !c.is_whitespace() {
+            code = true;
+            in_string = c == '"';
+        }
+    }
+    code
+}
+
+/// Drops the lines that shouldn't be counted according to the options, returning the remaining
+/// lines along with the number of lines that were dropped.
+fn filter_lines(lines: Vec<String>, options: &Options) -> (Vec<String>, usize) {
+    let total = lines.len();
+    let syntax = CommentSyntax::for_file(&options.filename);
+    let mut in_block = false;
+    // Every line goes through is_code_line, even one that an ignore pattern is about to drop, so
+    // that a /* or */ on that line still opens or closes a block comment
+    let kept: Vec<String> = lines
+        .into_iter()
+        .filter(|line| {
+            let code = !options.code_mode || is_code_line(line, syntax, &mut in_block);
+            code && !options.ignore_regexes.iter().any(|regex| regex.is_match(line))
+        })
+        .collect();
+    let ignored = total - kept.len();
+    (kept, ignored)
 }
 
 fn count_for_lines(file_vec: &Vec<String>) -> usize {
@@ -24,15 +298,380 @@ fn count_for_words(lines: &Vec<String>) -> usize {
          .sum()
 }
 
+/// Counts the lines and words matching each of `regexes`, all in one pass over the lines.
+fn count_matches<'a>(lines: &[String], regexes: &'a [Regex]) -> Vec<MatchCounts<'a>> {
+    let mut counts: Vec<MatchCounts> = regexes
+        .iter()
+        .map(|regex| MatchCounts {
+            regex,
+            lines: 0,
+            words: 0,
+        })
+        .collect();
+    for line in lines {
+        for count in counts.iter_mut() {
+            if count.regex.is_match(line) {
+                count.lines += 1;
+            }
+            // Checked word by word even on lines that don't match, since a pattern anchored to
+            // the start of a word needn't match at the start of the line
+            count.words += line
+                .split_whitespace()
+                .filter(|word| count.regex.is_match(word))
+                .count();
+        }
+    }
+    counts
+}
+
+/// Summary statistics, gathered one line at a time so that every figure comes out of a single pass
+/// over the lines.
+#[derive(Default)]
+struct Stats {
+    lines: usize,
+    words: usize,
+    max_words_per_line: usize,
+    /// Distinct words seen so far, lowercased so that "The" and "the" count once
+    vocabulary: HashSet<String>,
+}
+
+impl Stats {
+    fn add_line(&mut self, line: &str) {
+        let mut words = 0;
+        for word in line.split_whitespace() {
+            words += 1;
+            self.vocabulary.insert(word.to_lowercase());
+        }
+        self.lines += 1;
+        self.words += words;
+        self.max_words_per_line = self.max_words_per_line.max(words);
+    }
+
+    fn average_words_per_line(&self) -> f64 {
+        if self.lines == 0 {
+            0.0
+        } else {
+            self.words as f64 / self.lines as f64
+        }
+    }
+
+    /// Distinct words divided by total words: close to 1 for varied text, close to 0 for text that
+    /// keeps repeating itself.
+    fn type_token_ratio(&self) -> f64 {
+        if self.words == 0 {
+            0.0
+        } else {
+            self.vocabulary.len() as f64 / self.words as f64
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
         println!("Too few arguments.");
-        process::exit(1);
+        process::exit(EXIT_ERROR);
     }
-    let filename = &args[1];
+    let options = parse_args(&args);
     // Your code here :)
-    let file_vec: Vec<String> = read_file_lines(filename).expect("Invalid filename of args1");
-    println!("Count for lines: {}", count_for_lines(&file_vec));
+    let (file_vec, offsets) = match read_file_lines(&options.filename) {
+        Ok(read) => read,
+        Err(err) => {
+            println!("Could not read {}: {}", options.filename, err);
+            process::exit(EXIT_ERROR);
+        }
+    };
+    // The index describes the file as it is on disk, so it covers every line, ignored or not
+    if let Some(output) = &options.index {
+        if let Err(err) = write_index(&options.filename, &offsets, output) {
+            println!("Could not write index to {}: {}", output.path, err);
+            process::exit(EXIT_ERROR);
+        }
+    }
+    let (file_vec, ignored) = filter_lines(file_vec, &options);
+    let lines = count_for_lines(&file_vec);
+
+    // Limits are checked against the file as it is on disk for bytes, and against the counted
+    // lines for lines, so that --code-mode can enforce a limit on lines of code
+    let mut over_limit = Vec::new();
+    if let Some(max_lines) = options.max_lines {
+        if lines > max_lines {
+            over_limit.push(format!("Too many lines: {} (limit {})", lines, max_lines));
+        }
+    }
+    if let Some(max_bytes) = options.max_bytes {
+        let bytes = match std::fs::metadata(&options.filename) {
+            Ok(metadata) => metadata.len(),
+            Err(err) => {
+                println!("Could not read {}: {}", options.filename, err);
+                process::exit(EXIT_ERROR);
+            }
+        };
+        if bytes > max_bytes {
+            over_limit.push(format!("Too many bytes: {} (limit {})", bytes, max_bytes));
+        }
+    }
+    let status = if over_limit.is_empty() { 0 } else { EXIT_OVER_LIMIT };
+    if options.quiet {
+        process::exit(status);
+    }
+
+    println!("Count for lines: {}", lines);
     println!("Count for words: {}", count_for_words(&file_vec));
+    if options.code_mode || !options.ignore_regexes.is_empty() {
+        println!("Ignored lines: {}", ignored);
+    }
+    if options.stats {
+        let mut stats = Stats::default();
+        for line in &file_vec {
+            stats.add_line(line);
+        }
+        println!("Average words per line: {:.2}", stats.average_words_per_line());
+        println!("Max words per line: {}", stats.max_words_per_line);
+        println!("Unique words: {}", stats.vocabulary.len());
+        println!("Type-token ratio: {:.3}", stats.type_token_ratio());
+    }
+    for count in count_matches(&file_vec, &options.match_regexes) {
+        println!(
+            "Matches for {}: {} lines, {} words",
+            count.regex, count.lines, count.words
+        );
+    }
+    for message in &over_limit {
+        println!("{}", message);
+    }
+    process::exit(status);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Which of `lines` is_code_line counts as code, scanning them in order like filter_lines
+    fn code_lines(lines: &[&str], syntax: CommentSyntax) -> Vec<bool> {
+        let mut in_block = false;
+        lines
+            .iter()
+            .map(|line| is_code_line(line, syntax, &mut in_block))
+            .collect()
+    }
+
+    /// Options for counting `filename` with the given ignore patterns
+    fn options(filename: &str, code_mode: bool, ignore: &[&str]) -> Options {
+        Options {
+            filename: filename.to_string(),
+            ignore_regexes: ignore.iter().map(|pattern| Regex::new(pattern).unwrap()).collect(),
+            code_mode,
+            index: None,
+            stats: false,
+            quiet: false,
+            max_lines: None,
+            max_bytes: None,
+            match_regexes: Vec::new(),
+        }
+    }
+
+    fn to_lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ignored_line_still_opens_block_comment() {
+        let lines = to_lines(&["let a = 1;", "TODO /* later", "let b = 2;", "*/", "let c = 3;"]);
+        let (kept, ignored) = filter_lines(lines, &options("main.rs", true, &["TODO"]));
+        assert_eq!(kept, ["let a = 1;", "let c = 3;"]);
+        assert_eq!(ignored, 3);
+    }
+
+    /// A path in the temp directory for this test to write to
+    fn temp_path(name: &str) -> String {
+        let dir = env::temp_dir();
+        let path = dir.join(format!("rwc-test-{}-{}", process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_index_offsets() {
+        let path = temp_path("index-input");
+        std::fs::write(&path, "ab\r\nc\n\nlast").unwrap();
+        let (lines, offsets) = read_file_lines(&path).unwrap();
+        assert_eq!(lines, ["ab", "c", "", "last"]);
+        assert_eq!(offsets, [0, 4, 6, 7]);
+
+        let output = IndexOutput {
+            path: temp_path("index"),
+            json: false,
+        };
+        write_index(&path, &offsets, &output).unwrap();
+        assert_eq!(std::fs::read_to_string(&output.path).unwrap(), "1 0\n2 4\n3 6\n4 7\n");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&output.path).unwrap();
+    }
+
+    #[test]
+    fn test_json_index_escapes_file_name() {
+        let output = IndexOutput {
+            path: temp_path("index.json"),
+            json: true,
+        };
+        write_index("logs/\"odd\"\\name\t1.txt", &[0, 12], &output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&output.path).unwrap(),
+            "{\"file\":\"logs/\\\"odd\\\"\\\\name\\t1.txt\",\
+             \"lines\":[{\"line\":1,\"offset\":0},{\"line\":2,\"offset\":12}]}\n"
+        );
+        std::fs::remove_file(&output.path).unwrap();
+    }
+
+    #[test]
+    fn test_json_string_control_characters() {
+        assert_eq!(json_string("plain"), "\"plain\"");
+        assert_eq!(json_string("a\nb\u{1}"), "\"a\\nb\\u0001\"");
+        assert_eq!(json_string(""), "\"\"");
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = Stats::default();
+        for line in ["The cat sat", "", "the  CAT ran away", "sat"] {
+            stats.add_line(line);
+        }
+        assert_eq!(stats.lines, 4);
+        assert_eq!(stats.words, 8);
+        assert_eq!(stats.max_words_per_line, 4);
+        // the, cat, sat, ran, away
+        assert_eq!(stats.vocabulary.len(), 5);
+        assert_eq!(stats.average_words_per_line(), 2.0);
+        assert_eq!(stats.type_token_ratio(), 5.0 / 8.0);
+    }
+
+    #[test]
+    fn test_stats_of_nothing() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.average_words_per_line(), 0.0);
+        stats.add_line("   ");
+        assert_eq!(stats.max_words_per_line, 0);
+        assert_eq!(stats.average_words_per_line(), 0.0);
+        assert_eq!(stats.type_token_ratio(), 0.0);
+    }
+
+    /// (lines, words) counted for each of `patterns` over `lines`
+    fn matches(lines: &[&str], patterns: &[&str]) -> Vec<(usize, usize)> {
+        let regexes: Vec<Regex> = patterns.iter().map(|p| Regex::new(p).unwrap()).collect();
+        count_matches(&to_lines(lines), &regexes)
+            .iter()
+            .map(|count| (count.lines, count.words))
+            .collect()
+    }
+
+    #[test]
+    fn test_match_counts() {
+        let lines = ["error: disk full", "warning: error count 2", "ok", "errors errors errors"];
+        // A line or word counts once however many times the pattern matches in it
+        assert_eq!(matches(&lines, &["error"]), [(3, 5)]);
+        // Anchored to whole words
+        assert_eq!(matches(&lines, &["^errors?:?$"]), [(0, 5)]);
+        assert_eq!(matches(&lines, &[r"\d"]), [(1, 1)]);
+    }
+
+    #[test]
+    fn test_overlapping_matches() {
+        let lines = ["error: disk full", "warning: error count 2"];
+        // Each pattern counts every line and word it matches, even ones another pattern matched
+        assert_eq!(
+            matches(&lines, &["error", "err", ": "]),
+            [(2, 2), (2, 2), (2, 0)]
+        );
+    }
+
+    #[test]
+    fn test_no_matches() {
+        assert_eq!(matches(&["nothing to see", ""], &["xyz", "^$"]), [(0, 0), (1, 0)]);
+        assert_eq!(matches(&[], &["a"]), [(0, 0)]);
+        assert!(matches(&["a"], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_comment_syntax_by_file() {
+        assert_eq!(
+            CommentSyntax::for_file("src/main.rs"),
+            CommentSyntax::Slashes
+        );
+        assert_eq!(CommentSyntax::for_file("main.c"), CommentSyntax::Slashes);
+        assert_eq!(CommentSyntax::for_file("tools/gen.py"), CommentSyntax::Hash);
+        assert_eq!(
+            CommentSyntax::for_file("build/Makefile"),
+            CommentSyntax::Hash
+        );
+        assert_eq!(CommentSyntax::for_file("README"), CommentSyntax::Slashes);
+    }
+
+    #[test]
+    fn test_block_comment_after_code() {
+        let lines = [
+            "int x; /* start",
+            "   comment",
+            "   end */",
+            "int y;",
+            "// line",
+            "#include <stdio.h>",
+        ];
+        assert_eq!(
+            code_lines(&lines, CommentSyntax::Slashes),
+            [true, false, false, true, false, true]
+        );
+    }
+
+    #[test]
+    fn test_code_around_comments() {
+        let lines = [
+            "/* one */ let a = 1;",
+            "let b = 2; // trailing",
+            "/* open",
+            "close */ let c = 3;",
+            "  /* whole */  ",
+        ];
+        assert_eq!(
+            code_lines(&lines, CommentSyntax::Slashes),
+            [true, true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn test_attributes_are_code() {
+        let lines = ["#[derive(Debug)]", "#![allow(dead_code)]", "struct S;"];
+        assert_eq!(
+            code_lines(&lines, CommentSyntax::Slashes),
+            [true, true, true]
+        );
+    }
+
+    #[test]
+    fn test_markers_in_strings() {
+        let lines = [
+            "let glob = \"src/*.rs\";",
+            "let url = \"http://example.com\";",
+            "let quote = \"\\\" /* \";",
+            "next();",
+        ];
+        assert_eq!(
+            code_lines(&lines, CommentSyntax::Slashes),
+            [true, true, true, true]
+        );
+    }
+
+    #[test]
+    fn test_hash_comments() {
+        let lines = [
+            "# setup",
+            "x = 1  # trailing",
+            "   ",
+            "print(\"#1\")",
+            "a // b",
+        ];
+        assert_eq!(
+            code_lines(&lines, CommentSyntax::Hash),
+            [false, true, false, true, true]
+        );
+    }
 }
@@ -0,0 +1,76 @@
+//! Exit statuses of --max-lines/--max-bytes, and --quiet, checked against the built binary.
+
+use std::env;
+use std::fs;
+use std::process::{self, Command, Output};
+
+/// Writes `contents` to a file in the temp directory and returns its path.
+fn input_file(name: &str, contents: &str) -> String {
+    let path = env::temp_dir().join(format!("rwc-limits-{}-{}", process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+fn rwc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_rwc"))
+        .args(args)
+        .output()
+        .expect("could not run rwc")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// Three lines, 19 bytes
+const TEXT: &str = "one two\nthree\nfour\n";
+
+#[test]
+fn test_within_limits() {
+    let path = input_file("within", TEXT);
+    // Limits are inclusive
+    let output = rwc(&["--max-lines", "3", "--max-bytes", "19", &path]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "Count for lines: 3\nCount for words: 4\n");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_over_limits() {
+    let path = input_file("over", TEXT);
+    let output = rwc(&["--max-lines", "2", &path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Too many lines: 3 (limit 2)\n"));
+
+    let output = rwc(&["--max-bytes", "18", &path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains("Too many bytes: 19 (limit 18)\n"));
+
+    // Only the lines that are counted count towards --max-lines
+    let output = rwc(&["--max-lines", "2", "--ignore-regex", "^four$", &path]);
+    assert_eq!(output.status.code(), Some(0));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_quiet() {
+    let path = input_file("quiet", TEXT);
+    let output = rwc(&["--quiet", "--stats", "--max-lines", "3", &path]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+
+    let output = rwc(&["--quiet", "--max-lines", "3", "--max-bytes", "10", &path]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), "");
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_errors_are_not_over_limit() {
+    let missing = env::temp_dir().join("rwc-limits-no-such-file");
+    let output = rwc(&["--quiet", "--max-lines", "3", missing.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let output = rwc(&["--max-lines", "lots", "whatever.txt"]);
+    assert_eq!(output.status.code(), Some(2));
+}
@@ -0,0 +1,78 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::sync::Arc;
+use std::thread;
+
+// linked_list is a binary crate, so pull the modules in directly
+#[allow(dead_code)]
+#[path = "../src/linked_list.rs"]
+mod linked_list;
+#[allow(dead_code)]
+#[path = "../src/concurrent_stack.rs"]
+mod concurrent_stack;
+
+use concurrent_stack::MutexStack;
+#[cfg(feature = "treiber")]
+use concurrent_stack::TreiberStack;
+
+const OPS_PER_THREAD: usize = 10_000;
+
+trait Stack: Send + Sync + 'static {
+    fn push(&self, value: usize);
+    fn pop(&self) -> Option<usize>;
+}
+
+impl Stack for MutexStack<usize> {
+    fn push(&self, value: usize) {
+        MutexStack::push(self, value)
+    }
+    fn pop(&self) -> Option<usize> {
+        MutexStack::pop(self)
+    }
+}
+
+#[cfg(feature = "treiber")]
+impl Stack for TreiberStack<usize> {
+    fn push(&self, value: usize) {
+        TreiberStack::push(self, value)
+    }
+    fn pop(&self) -> Option<usize> {
+        TreiberStack::pop(self)
+    }
+}
+
+/// Every thread alternates pushes and pops, so all of them keep fighting over the top of the stack.
+fn contend<S: Stack>(stack: &Arc<S>, threads: usize) {
+    let handles: Vec<_> = (0..threads)
+        .map(|_| {
+            let stack = Arc::clone(stack);
+            thread::spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    stack.push(i);
+                    stack.pop();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn bench_contention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push_pop_under_contention");
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("mutex", threads), &threads, |b, &threads| {
+            let stack = Arc::new(MutexStack::new());
+            b.iter(|| contend(&stack, threads));
+        });
+        #[cfg(feature = "treiber")]
+        group.bench_with_input(BenchmarkId::new("treiber", threads), &threads, |b, &threads| {
+            let stack = Arc::new(TreiberStack::new());
+            b.iter(|| contend(&stack, threads));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_contention);
+criterion_main!(benches);
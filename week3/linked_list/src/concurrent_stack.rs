@@ -0,0 +1,134 @@
+use crate::linked_list::LinkedList;
+use std::sync::Mutex;
+
+/// A stack that can be shared between threads. With the `treiber` feature this is the lock-free
+/// TreiberStack; otherwise it is MutexStack.
+#[cfg(not(feature = "treiber"))]
+pub type ConcurrentStack<T> = MutexStack<T>;
+#[cfg(feature = "treiber")]
+pub type ConcurrentStack<T> = TreiberStack<T>;
+
+/// A LinkedList behind a Mutex. Simple, and it reuses the list's nodes as they are, but every push
+/// and pop takes the same lock.
+pub struct MutexStack<T> {
+    list: Mutex<LinkedList<T>>,
+}
+
+impl<T> MutexStack<T> {
+    pub fn new() -> MutexStack<T> {
+        MutexStack {
+            list: Mutex::new(LinkedList::new()),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        self.list.lock().unwrap().push_front(value);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        self.list.lock().unwrap().pop_front()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.lock().unwrap().is_empty()
+    }
+}
+
+impl<T> Default for MutexStack<T> {
+    fn default() -> Self {
+        MutexStack::new()
+    }
+}
+
+#[cfg(feature = "treiber")]
+pub use treiber::TreiberStack;
+
+#[cfg(feature = "treiber")]
+mod treiber {
+    use crossbeam_epoch::{self as epoch, Atomic, Owned};
+    use std::mem::ManuallyDrop;
+    use std::ptr;
+    use std::sync::atomic::Ordering;
+
+    /// Same shape as the LinkedList's node, except that `next` has to be an atomic pointer so that
+    /// threads can swing it with compare-and-swap.
+    struct Node<T> {
+        value: ManuallyDrop<T>,
+        next: Atomic<Node<T>>,
+    }
+
+    /// A lock-free stack: push and pop retry a compare-and-swap on the head until it succeeds.
+    /// Popped nodes are freed through crossbeam-epoch once no other thread can still be reading
+    /// them.
+    pub struct TreiberStack<T> {
+        head: Atomic<Node<T>>,
+    }
+
+    impl<T> TreiberStack<T> {
+        pub fn new() -> TreiberStack<T> {
+            TreiberStack {
+                head: Atomic::null(),
+            }
+        }
+
+        pub fn push(&self, value: T) {
+            let mut node = Owned::new(Node {
+                value: ManuallyDrop::new(value),
+                next: Atomic::null(),
+            });
+            let guard = epoch::pin();
+            loop {
+                let head = self.head.load(Ordering::Relaxed, &guard);
+                node.next.store(head, Ordering::Relaxed);
+                match self.head.compare_exchange(
+                    head,
+                    node,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                    &guard,
+                ) {
+                    Ok(_) => return,
+                    Err(err) => node = err.new,
+                }
+            }
+        }
+
+        pub fn pop(&self) -> Option<T> {
+            let guard = epoch::pin();
+            loop {
+                let head = self.head.load(Ordering::Acquire, &guard);
+                let node = unsafe { head.as_ref() }?;
+                let next = node.next.load(Ordering::Relaxed, &guard);
+                if self
+                    .head
+                    .compare_exchange(head, next, Ordering::Relaxed, Ordering::Relaxed, &guard)
+                    .is_ok()
+                {
+                    // We unlinked the node, so we're the only ones who will take its value. Other
+                    // threads may still be looking at the node itself, so freeing it is deferred.
+                    unsafe {
+                        guard.defer_destroy(head);
+                        return Some(ManuallyDrop::into_inner(ptr::read(&node.value)));
+                    }
+                }
+            }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            let guard = epoch::pin();
+            self.head.load(Ordering::Acquire, &guard).is_null()
+        }
+    }
+
+    impl<T> Default for TreiberStack<T> {
+        fn default() -> Self {
+            TreiberStack::new()
+        }
+    }
+
+    impl<T> Drop for TreiberStack<T> {
+        fn drop(&mut self) {
+            while self.pop().is_some() {}
+        }
+    }
+}
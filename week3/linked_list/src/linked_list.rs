@@ -1,106 +1,254 @@
+use std::cmp::Ordering;
 use std::fmt;
 use std::option::Option;
 
+/// A singly linked list whose nodes live in an arena (a Vec of slots) and point at each other by
+/// slot index instead of through Boxes. Knowing where the last node is then takes nothing more than
+/// its index, so push_back is O(1) like push_front, without a raw tail pointer into memory owned by
+/// a chain of Boxes. There is no unsafe code here at all: the borrow checker sees the whole list as
+/// one Vec, and a bad index panics instead of reading freed memory.
+///
+/// Slots freed by pop_front and remove are reused by later pushes, and the arena is emptied along
+/// with the list, so a list that keeps being drained and refilled doesn't keep growing.
 pub struct LinkedList<T> {
-    head: Option<Box<Node<T>>>,
+    /// Node storage. None marks a free slot.
+    nodes: Vec<Option<Node<T>>>,
+    /// Indices of the free slots in `nodes`
+    free: Vec<usize>,
+    head: Option<usize>,
+    tail: Option<usize>,
     size: usize,
 }
 
 struct Node<T> {
     value: T,
-    next: Option<Box<Node<T>>>,
+    /// Index of the next node in the arena
+    next: Option<usize>,
 }
 
 impl<T> Node<T> {
-    pub fn new(value: T, next: Option<Box<Node<T>>>) -> Node<T> {
-        Node {value: value, next: next}
+    pub fn new(value: T, next: Option<usize>) -> Node<T> {
+        Node { value, next }
     }
 }
 
 impl<T> LinkedList<T> {
     pub fn new() -> LinkedList<T> {
-        LinkedList::<T> {head: None, size: 0}
+        LinkedList::<T> {nodes: Vec::new(), free: Vec::new(), head: None, tail: None, size: 0}
     }
-    
+
     pub fn get_size(&self) -> usize {
         self.size
     }
-    
+
     pub fn is_empty(&self) -> bool {
         self.get_size() == 0
     }
-    
+
     pub fn push_front(&mut self, value: T) {
-        let new_node: Box<Node<T>> = Box::new(Node::<T>::new(value, self.head.take()));
-        self.head = Some(new_node);
+        let index = self.alloc(Node::<T>::new(value, self.head));
+        self.head = Some(index);
+        if self.tail.is_none() {
+            self.tail = Some(index);
+        }
+        self.size += 1;
+    }
+
+    /// Appends `value` to the end of the list. O(1).
+    pub fn push_back(&mut self, value: T) {
+        let index = self.alloc(Node::<T>::new(value, None));
+        match self.tail {
+            Some(tail) => self.node_mut(tail).next = Some(index),
+            None => self.head = Some(index),
+        }
+        self.tail = Some(index);
         self.size += 1;
     }
-    
+
     pub fn pop_front(&mut self) -> Option<T> {
-        let node: Box<Node<T>> = self.head.take()?;
+        let head = self.head?;
+        let node: Node<T> = self.release(head);
         self.head = node.next;
-        self.size -= 1;
+        if self.head.is_none() {
+            self.tail = None;
+        }
         Some(node.value)
     }
-}
 
+    /// Returns a reference to the value at `index`, or None if `index` is out of bounds. O(n).
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.index_of(index).map(|node| &self.node(node).value)
+    }
 
-impl<T: fmt::Display> fmt::Display for LinkedList<T> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut current: &Option<Box<Node<T>>> = &self.head;
-        let mut result = String::new();
-        loop {
-            match current {
-                Some(node) => {
-                    result = format!("{} {}", result, node.value);
-                    current = &node.next;
-                },
-                None => break,
+    /// Returns a mutable reference to the value at `index`, or None if `index` is out of bounds.
+    /// O(n).
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        let node = self.index_of(index)?;
+        Some(&mut self.node_mut(node).value)
+    }
+
+    /// Inserts `value` so that it ends up at `index`, shifting everything after it back by one.
+    /// `index` may be at most the size of the list (inserting at the size appends, in O(1)). O(n).
+    ///
+    /// Panics if `index` is out of bounds, like Vec::insert.
+    pub fn insert(&mut self, index: usize, value: T) {
+        assert!(
+            index <= self.size,
+            "insertion index (is {}) should be <= size (is {})",
+            index,
+            self.size
+        );
+        if index == 0 {
+            return self.push_front(value);
+        }
+        if index == self.size {
+            return self.push_back(value);
+        }
+        let prev = self.index_of(index - 1).unwrap();
+        let new_node = self.alloc(Node::<T>::new(value, self.node(prev).next));
+        self.node_mut(prev).next = Some(new_node);
+        self.size += 1;
+    }
+
+    /// Removes and returns the value at `index`, or None if `index` is out of bounds. O(n).
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.size {
+            return None;
+        }
+        if index == 0 {
+            return self.pop_front();
+        }
+        let prev = self.index_of(index - 1).unwrap();
+        let removed = self.node(prev).next.unwrap();
+        let node: Node<T> = self.release(removed);
+        self.node_mut(prev).next = node.next;
+        if self.tail == Some(removed) {
+            self.tail = Some(prev);
+        }
+        Some(node.value)
+    }
+
+    /// Returns a reference to the smallest value, or None if the list is empty. If several values
+    /// are equally small, returns the first of them, like Iterator::min. O(n).
+    pub fn min(&self) -> Option<&T>
+    where
+        T: Ord,
+    {
+        let mut min: Option<&T> = None;
+        for value in self.values() {
+            if min.is_none_or(|min| value < min) {
+                min = Some(value);
             }
         }
-        write!(f, "{}", result)
+        min
     }
-}
 
-impl<T> Drop for LinkedList<T> {
-    fn drop(&mut self) {
-        let mut current = self.head.take();
-        while let Some(mut node) = current {
-            current = node.next.take();
+    /// Returns a reference to the largest value, or None if the list is empty. If several values
+    /// are equally large, returns the last of them, like Iterator::max. O(n).
+    pub fn max(&self) -> Option<&T>
+    where
+        T: Ord,
+    {
+        let mut max: Option<&T> = None;
+        for value in self.values() {
+            if max.is_none_or(|max| value >= max) {
+                max = Some(value);
+            }
         }
+        max
+    }
+
+    /// Iterates over references to the values, front to back.
+    fn values(&self) -> Values<'_, T> {
+        Values { list: self, current: self.head }
+    }
+
+    /// Returns the arena index of the node at `index`, or None if `index` is out of bounds.
+    fn index_of(&self, index: usize) -> Option<usize> {
+        if index >= self.size {
+            return None;
+        }
+        // The last node is one step away, wherever it is
+        if index == self.size - 1 {
+            return self.tail;
+        }
+        let mut current = self.head;
+        for _ in 0..index {
+            current = self.node(current?).next;
+        }
+        current
+    }
+
+    fn node(&self, index: usize) -> &Node<T> {
+        self.nodes[index].as_ref().expect("link to a free slot")
+    }
+
+    fn node_mut(&mut self, index: usize) -> &mut Node<T> {
+        self.nodes[index].as_mut().expect("link to a free slot")
+    }
+
+    /// Stores `node` in a free slot (or a new one) and returns its index.
+    fn alloc(&mut self, node: Node<T>) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = Some(node);
+                index
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    /// Takes the node out of slot `index`, which the caller must unlink, and frees the slot.
+    fn release(&mut self, index: usize) -> Node<T> {
+        let node = self.nodes[index].take().expect("released a free slot");
+        self.size -= 1;
+        if self.size == 0 {
+            // Every slot is free now, so start the arena over rather than keep them around
+            self.nodes.clear();
+            self.free.clear();
+        } else {
+            self.free.push(index);
+        }
+        node
     }
 }
 
-impl<T: Clone> Clone for Node<T> {
-    fn clone(&self) -> Self {
-        Node::<T> {
-            value: self.value.clone(), 
-            next: self.next.clone()
+
+impl<T: fmt::Display> fmt::Display for LinkedList<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = String::new();
+        for value in self.values() {
+            result = format!("{} {}", result, value);
         }
+        write!(f, "{}", result)
     }
 }
 
+/// Clones into a fresh arena in list order, so the clone doesn't inherit the original's free
+/// slots.
 impl<T: Clone> Clone for LinkedList<T> {
     fn clone(&self) -> Self {
-        LinkedList::<T> {
-            head: self.head.clone(),
-            size: self.size.clone(),
+        let mut list = LinkedList::<T>::new();
+        for value in self.values() {
+            list.push_back(value.clone());
         }
+        list
     }
 }
 
 impl<T: PartialEq> PartialEq for LinkedList<T> {
     fn eq(&self, other: &Self) -> bool {
-        let mut a = &self.head;
-        let mut b = &other.head;
+        let mut a = self.values();
+        let mut b = other.values();
         loop {
-            match (a.as_ref(), b.as_ref()) {
-                (Some(a_node), Some(b_node)) => {
-                    if a_node.value != b_node.value {
+            match (a.next(), b.next()) {
+                (Some(a_value), Some(b_value)) => {
+                    if a_value != b_value {
                         return false;
                     }
-                    a = &a_node.next;
-                    b = &b_node.next;
                 }
                 (None, None) => return true,
                 _ => return false,
@@ -109,34 +257,63 @@ impl<T: PartialEq> PartialEq for LinkedList<T> {
     }
 }
 
+/// Lists are ordered lexicographically, the same way as Vecs and slices: by the first pair of
+/// values that differ, or if one list is a prefix of the other, the shorter one comes first.
+/// Compares one pair of nodes at a time in a loop, so long lists can't overflow the stack.
+impl<T: PartialOrd> PartialOrd for LinkedList<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        let mut a = self.values();
+        let mut b = other.values();
+        loop {
+            match (a.next(), b.next()) {
+                (Some(a_value), Some(b_value)) => match a_value.partial_cmp(b_value) {
+                    Some(Ordering::Equal) => {}
+                    non_eq => return non_eq,
+                },
+                (None, None) => return Some(Ordering::Equal),
+                (None, Some(_)) => return Some(Ordering::Less),
+                (Some(_), None) => return Some(Ordering::Greater),
+            }
+        }
+    }
+}
+
+/// References to the values of a list, front to back
+struct Values<'a, T> {
+    list: &'a LinkedList<T>,
+    current: Option<usize>,
+}
+
+impl<'a, T> Iterator for Values<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = self.list.node(self.current?);
+        self.current = node.next;
+        Some(&node.value)
+    }
+}
+
 pub struct LinkedListIterator<T> {
-    current: Option<Box<Node<T>>>,
+    list: LinkedList<T>,
 }
 
 pub struct LinkedListIter<'a, T> {
-    current: &'a Option<Box<Node<T>>>,
+    values: Values<'a, T>,
 }
 
 impl<T> Iterator for LinkedListIterator<T> {
     type Item = T;
 
     fn next(&mut self) -> Option<T> {
-        let mut node = self.current.take()?;
-        self.current = node.next.take();
-        Some(node.value)
+        self.list.pop_front()
     }
 }
 
 impl<T: Clone> Iterator for LinkedListIter<'_, T> {
     type Item = T;
     fn next(&mut self) -> Option<T> {
-        match self.current {
-            Some(node) => {
-                self.current = &node.next;
-                Some(node.value.clone())
-            },
-            None => None,
-        }
+        self.values.next().cloned()
     }
 }
 
@@ -144,9 +321,9 @@ impl<T> IntoIterator for LinkedList<T> {
     type Item = T;
     type IntoIter = LinkedListIterator<T>;
 
-    fn into_iter(mut self) -> LinkedListIterator<T> {
+    fn into_iter(self) -> LinkedListIterator<T> {
         LinkedListIterator::<T> {
-            current: self.head.take()
+            list: self
         }
     }
 }
@@ -156,6 +333,6 @@ impl<'a, T: Clone> IntoIterator for &'a LinkedList<T> {
     type IntoIter = LinkedListIter<'a, T>;
 
     fn into_iter(self) -> LinkedListIter<'a, T> {
-        LinkedListIter { current: &self.head }
+        LinkedListIter { values: self.values() }
     }
 }
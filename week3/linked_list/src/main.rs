@@ -1,4 +1,8 @@
+use concurrent_stack::ConcurrentStack;
 use linked_list::LinkedList;
+use std::sync::Arc;
+use std::thread;
+pub mod concurrent_stack;
 pub mod linked_list;
 
 fn main() {
@@ -21,6 +25,65 @@ fn main() {
     println!("{}", list.to_string()); // ToString impl for anything impl Display
     println!("{}", list0.eq(&list));
 
+    // Positional access
+    let mut numbers: LinkedList<u32> = LinkedList::new();
+    for i in (0..5).rev() {
+        numbers.push_front(i);
+    }
+    numbers.insert(2, 100);
+    numbers.insert(numbers.get_size(), 200);
+    if let Some(value) = numbers.get_mut(0) {
+        *value += 10;
+    }
+    println!("{}", numbers);
+    assert_eq!(numbers.get(2), Some(&100));
+    assert_eq!(numbers.remove(2), Some(100));
+    assert_eq!(numbers.remove(numbers.get_size()), None);
+    assert_eq!(numbers.get(numbers.get_size()), None);
+    println!("{} (size {})", numbers, numbers.get_size());
+
+    // push_back appends in O(1), and keeps working as nodes come and go around the tail
+    let mut queue: LinkedList<u32> = LinkedList::new();
+    for i in 0..5 {
+        queue.push_back(i);
+    }
+    queue.push_front(100);
+    assert_eq!(queue.pop_front(), Some(100));
+    assert_eq!(queue.remove(4), Some(4));
+    queue.push_back(5);
+    queue.insert(queue.get_size(), 6);
+    assert_eq!(queue.clone().into_iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 5, 6]);
+    while queue.pop_front().is_some() {}
+    queue.push_back(7);
+    assert_eq!((queue.get(0), queue.get_size()), (Some(&7), 1));
+    println!("{} (size {})", queue, queue.get_size());
+
+    // Ordering: min/max, and comparisons that agree with Vec's lexicographic ordering (checked
+    // against Vec in tests/operation_matrix.rs)
+    println!("min {:?}, max {:?}", numbers.min(), numbers.max());
+
+    // Several threads pushing onto one stack at once
+    let stack = Arc::new(ConcurrentStack::new());
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let stack = Arc::clone(&stack);
+            thread::spawn(move || {
+                for i in 0..100 {
+                    stack.push(t * 100 + i);
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let mut popped = 0;
+    while stack.pop().is_some() {
+        popped += 1;
+    }
+    println!("popped {} values from the concurrent stack", popped);
+    assert!(stack.is_empty());
+
     // If you implement iterator trait:
     //for val in &list {
     //    println!("{}", val);
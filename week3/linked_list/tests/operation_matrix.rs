@@ -0,0 +1,298 @@
+//! Runs every pair of list operations, starting from lists of a few sizes, against a VecDeque that
+//! says what the list should hold, with values that count how many of them are alive. Natively,
+//! this checks the list's behavior and that it neither leaks nor drops a value twice. Run under
+//! Miri or AddressSanitizer (see Cargo.toml), it also checks that no interleaving of clones, drops
+//! and iterators does anything undefined, which is what the list being free of unsafe code is
+//! supposed to guarantee, and what any unsafe variant of it has to keep guaranteeing.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+// linked_list is a binary crate, so pull the modules in directly
+#[allow(dead_code)]
+#[path = "../src/concurrent_stack.rs"]
+mod concurrent_stack;
+#[allow(dead_code)]
+#[path = "../src/linked_list.rs"]
+mod linked_list;
+
+use concurrent_stack::ConcurrentStack;
+use linked_list::LinkedList;
+
+/// A value that keeps count of how many Tracked values sharing its counter are alive
+#[derive(Debug)]
+struct Tracked {
+    value: u32,
+    alive: Arc<AtomicIsize>,
+}
+
+impl Tracked {
+    fn new(value: u32, alive: &Arc<AtomicIsize>) -> Tracked {
+        alive.fetch_add(1, Ordering::SeqCst);
+        Tracked {
+            value,
+            alive: Arc::clone(alive),
+        }
+    }
+}
+
+impl Clone for Tracked {
+    fn clone(&self) -> Tracked {
+        Tracked::new(self.value, &self.alive)
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.alive.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl PartialEq for Tracked {
+    fn eq(&self, other: &Tracked) -> bool {
+        self.value == other.value
+    }
+}
+
+impl PartialOrd for Tracked {
+    fn partial_cmp(&self, other: &Tracked) -> Option<std::cmp::Ordering> {
+        self.value.partial_cmp(&other.value)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    PushFront,
+    PushBack,
+    PopFront,
+    /// Insert in the middle
+    Insert,
+    /// Remove from the middle
+    Remove,
+    /// Change the last value in place
+    GetMut,
+    /// Clone the list, change the clone, and drop it
+    CloneAndDrop,
+    /// Iterate over (clones of) the values, stopping halfway
+    IterRef,
+    /// Consume the list, stopping halfway and dropping the rest, and start over with an empty one
+    IntoIter,
+    /// Compare the list with a clone of itself, and with a longer clone
+    Compare,
+}
+
+const OPS: [Op; 10] = [
+    Op::PushFront,
+    Op::PushBack,
+    Op::PopFront,
+    Op::Insert,
+    Op::Remove,
+    Op::GetMut,
+    Op::CloneAndDrop,
+    Op::IterRef,
+    Op::IntoIter,
+    Op::Compare,
+];
+
+fn values(list: &LinkedList<Tracked>) -> Vec<u32> {
+    list.into_iter().map(|tracked| tracked.value).collect()
+}
+
+fn apply(
+    op: Op,
+    list: &mut LinkedList<Tracked>,
+    model: &mut VecDeque<u32>,
+    alive: &Arc<AtomicIsize>,
+    next: &mut u32,
+) {
+    *next += 1;
+    let middle = list.get_size() / 2;
+    match op {
+        Op::PushFront => {
+            list.push_front(Tracked::new(*next, alive));
+            model.push_front(*next);
+        }
+        Op::PushBack => {
+            list.push_back(Tracked::new(*next, alive));
+            model.push_back(*next);
+        }
+        Op::PopFront => {
+            assert_eq!(list.pop_front().map(|t| t.value), model.pop_front());
+        }
+        Op::Insert => {
+            list.insert(middle, Tracked::new(*next, alive));
+            model.insert(middle, *next);
+        }
+        Op::Remove => {
+            assert_eq!(list.remove(middle).map(|t| t.value), model.remove(middle));
+        }
+        Op::GetMut => {
+            let last = list.get_size().wrapping_sub(1);
+            if let Some(tracked) = list.get_mut(last) {
+                tracked.value = *next;
+                model[last] = *next;
+            }
+        }
+        Op::CloneAndDrop => {
+            let mut clone = list.clone();
+            clone.push_back(Tracked::new(*next, alive));
+            clone.pop_front();
+            drop(clone);
+        }
+        Op::IterRef => {
+            let half: Vec<u32> = (&*list).into_iter().take(middle).map(|t| t.value).collect();
+            assert_eq!(half, model.iter().take(middle).copied().collect::<Vec<_>>());
+        }
+        Op::IntoIter => {
+            let mut iter = std::mem::replace(list, LinkedList::new()).into_iter();
+            for expected in model.iter().take(middle) {
+                assert_eq!(iter.next().map(|t| t.value), Some(*expected));
+            }
+            drop(iter);
+            model.clear();
+        }
+        Op::Compare => {
+            let clone = list.clone();
+            assert!(*list == clone);
+            let mut longer = list.clone();
+            longer.push_back(Tracked::new(*next, alive));
+            assert!(*list < longer);
+            assert!(*list != longer);
+        }
+    }
+    assert_eq!(values(list), model.iter().copied().collect::<Vec<_>>());
+    assert_eq!(list.get_size(), model.len());
+    assert_eq!(list.is_empty(), model.is_empty());
+}
+
+#[test]
+fn every_pair_of_operations() {
+    for size in 0..4 {
+        for first in OPS {
+            for second in OPS {
+                let alive = Arc::new(AtomicIsize::new(0));
+                let mut next = 0;
+                let mut list = LinkedList::new();
+                let mut model = VecDeque::new();
+                for _ in 0..size {
+                    apply(Op::PushBack, &mut list, &mut model, &alive, &mut next);
+                }
+                apply(first, &mut list, &mut model, &alive, &mut next);
+                apply(second, &mut list, &mut model, &alive, &mut next);
+                assert_eq!(
+                    alive.load(Ordering::SeqCst),
+                    model.len() as isize,
+                    "{} then {:?}, {:?}: values alive don't match the list",
+                    size,
+                    first,
+                    second
+                );
+                drop(list);
+                assert_eq!(
+                    alive.load(Ordering::SeqCst),
+                    0,
+                    "{} then {:?}, {:?}: values outlived the list",
+                    size,
+                    first,
+                    second
+                );
+            }
+        }
+    }
+}
+
+/// Lists that are drained and refilled reuse their slots; values must still come out in order and
+/// be dropped exactly once.
+#[test]
+fn drain_and_refill() {
+    let alive = Arc::new(AtomicIsize::new(0));
+    let mut list = LinkedList::new();
+    for round in 0..3 {
+        for i in 0..5 {
+            list.push_back(Tracked::new(round * 10 + i, &alive));
+        }
+        // The middle one of the five just pushed
+        let index = list.get_size() - 3;
+        assert_eq!(list.remove(index).map(|t| t.value), Some(round * 10 + 2));
+        while list.get_size() > 1 {
+            list.pop_front();
+        }
+    }
+    assert_eq!(values(&list), vec![24]);
+    drop(list);
+    assert_eq!(alive.load(Ordering::SeqCst), 0);
+}
+
+/// Comparisons between lists should agree with Vec's lexicographic ordering, and min/max with the
+/// values' own.
+#[test]
+fn ordering_matches_vec() {
+    let mut seed: u32 = 1;
+    let mut random_vec = || {
+        let mut next = || {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) % 4
+        };
+        let len = next() as usize;
+        (0..len).map(|_| next()).collect::<Vec<u32>>()
+    };
+    let to_list = |values: &Vec<u32>| {
+        let mut list = LinkedList::new();
+        for value in values.iter().rev() {
+            list.push_front(*value);
+        }
+        list
+    };
+    let rounds = if cfg!(miri) { 50 } else { 1000 };
+    for _ in 0..rounds {
+        let (a, b) = (random_vec(), random_vec());
+        let (list_a, list_b) = (to_list(&a), to_list(&b));
+        assert_eq!(list_a.partial_cmp(&list_b), a.partial_cmp(&b), "{:?} vs {:?}", a, b);
+        assert_eq!(list_a == list_b, a == b, "{:?} vs {:?}", a, b);
+        assert_eq!(list_a.min(), a.iter().min());
+        assert_eq!(list_a.max(), a.iter().max());
+    }
+}
+
+#[test]
+fn min_max() {
+    let mut list = LinkedList::new();
+    assert_eq!(list.min(), None);
+    assert_eq!(list.max(), None);
+    for value in [3, 1, 200, 1, 7] {
+        list.push_back(value);
+    }
+    assert_eq!(list.min(), Some(&1));
+    assert_eq!(list.max(), Some(&200));
+}
+
+/// Threads pushing and popping at once; every value pushed is either popped or dropped with the
+/// stack, exactly once.
+#[test]
+fn concurrent_stack_push_pop() {
+    let alive = Arc::new(AtomicIsize::new(0));
+    let stack = Arc::new(ConcurrentStack::new());
+    let per_thread = if cfg!(miri) { 10 } else { 1000 };
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let stack = Arc::clone(&stack);
+            let alive = Arc::clone(&alive);
+            thread::spawn(move || {
+                for i in 0..per_thread {
+                    stack.push(Tracked::new(t * per_thread + i, &alive));
+                    if i % 3 == 0 {
+                        stack.pop();
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(alive.load(Ordering::SeqCst) > 0);
+    drop(stack);
+    assert_eq!(alive.load(Ordering::SeqCst), 0);
+}
@@ -1,18 +1,79 @@
 use crossbeam_channel;
-use std::{sync::mpsc, thread, time};
+use std::cmp;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{env, fs, sync::mpsc, thread, time};
 
-fn parallel_map<T, U, F>(mut input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+/// One entry of a schedule trace: which worker processed which input index, and when (relative to
+/// the moment the map started).
+#[derive(Debug, Clone)]
+pub struct ScheduleEvent {
+    pub index: usize,
+    pub worker: usize,
+    pub start: time::Duration,
+    pub end: time::Duration,
+}
+
+/// Small xorshift generator used to shuffle the submission order. We only need something
+/// reproducible for a given seed, not anything statistically impressive.
+fn xorshift64(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// How many worker threads num_threads = 0 ("auto") means: one per CPU this process may run on,
+/// less one for each process the 1-minute load average says is already keeping a CPU busy, but
+/// never fewer than one. The load average is only read on systems with /proc/loadavg; elsewhere
+/// the pool just gets one thread per CPU. Any other num_threads is used as is.
+pub fn effective_threads(num_threads: usize) -> usize {
+    if num_threads > 0 {
+        return num_threads;
+    }
+    let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+    let busy = fs::read_to_string("/proc/loadavg")
+        .ok()
+        .and_then(|loadavg| loadavg.split_whitespace().next()?.parse::<f64>().ok())
+        .map_or(0, |load| load.round() as usize);
+    cpus.saturating_sub(busy).max(1)
+}
+
+/// Applies f to every item of input_vec on num_threads worker threads (0 picks a number to suit
+/// the machine; see effective_threads), returning the outputs in input order.
+fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
 where
     F: FnOnce(T) -> U + Send + Copy + 'static,
     T: Send + 'static,
     U: Send + 'static + Default,
 {
+    // No tracing here: that's only paid for by callers of parallel_map_instrumented
+    parallel_map_with(|| (), input_vec, num_threads, move |_: &mut (), val| f(val))
+}
+
+/// Like parallel_map, but each worker first calls init() once to build some state of its own (an
+/// RNG, a scratch buffer, a database handle, ...), and f gets that state along with each item. The
+/// state never leaves its worker thread, so it doesn't need to be Send, and anything expensive to
+/// set up is paid for once per worker instead of once per item.
+fn parallel_map_with<S, T, U, I, F>(
+    init: I,
+    mut input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> Vec<U>
+where
+    I: FnOnce() -> S + Send + Copy + 'static,
+    F: FnOnce(&mut S, T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let num_threads = effective_threads(num_threads);
     let len = input_vec.len();
     let mut output_vec: Vec<U> = Vec::with_capacity(len);
-    output_vec.resize_with(input_vec.len(), Default::default);
-    // Implement parallel map!
+    output_vec.resize_with(len, Default::default);
     let (s1, r1) = crossbeam_channel::unbounded::<(T, usize)>();
-    let (tx, rx) = mpsc::channel::<(U, usize)>();
+    let (tx, rx) = mpsc::channel::<(usize, U)>();
 
     let mut idx = len;
     while let Some(val) = input_vec.pop() {
@@ -20,25 +81,305 @@ where
         s1.send((val, idx)).unwrap();
     }
     drop(s1);
-    
+
     for _ in 0..num_threads {
         let r1 = r1.clone();
         let tx = tx.clone();
         thread::spawn(move || {
-            while let Ok((val, idx)) = r1.recv() {
-                tx.send((f(val), idx)).unwrap();
+            let mut state = init();
+            while let Ok((val, index)) = r1.recv() {
+                tx.send((index, f(&mut state, val))).unwrap();
             }
         });
     }
     drop(tx);
 
-    for (val, idx) in rx {
-        output_vec[idx] = val;
+    for (index, val) in rx {
+        output_vec[index] = val;
     }
+    output_vec
+}
 
+/// Same as parallel_map, but also returns a trace recording which worker processed each index and
+/// when. If a seed is supplied, items are submitted to the workers in a shuffled order that is
+/// identical across runs with the same seed, so scheduling experiments can be reproduced.
+/// Workers are numbered 0..num_threads and events are sorted by start time.
+fn parallel_map_instrumented<T, U, F>(
+    mut input_vec: Vec<T>,
+    num_threads: usize,
+    seed: Option<u64>,
+    f: F,
+) -> (Vec<U>, Vec<ScheduleEvent>)
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let num_threads = effective_threads(num_threads);
+    let len = input_vec.len();
+    let mut output_vec: Vec<U> = Vec::with_capacity(len);
+    output_vec.resize_with(input_vec.len(), Default::default);
+    let mut trace = Vec::with_capacity(len);
+    // Implement parallel map!
+    let (s1, r1) = crossbeam_channel::unbounded::<(T, usize)>();
+    let (tx, rx) = mpsc::channel::<(U, ScheduleEvent)>();
+
+    let mut items = Vec::with_capacity(len);
+    let mut idx = len;
+    while let Some(val) = input_vec.pop() {
+        idx -= 1;
+        items.push((val, idx));
+    }
+    if let Some(seed) = seed {
+        // Fisher-Yates shuffle. xorshift gets stuck at 0, so never let the state be 0 (without
+        // making any two seeds the same, as forcing the low bit on would).
+        let mut state = if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed };
+        for i in (1..items.len()).rev() {
+            let j = (xorshift64(&mut state) % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+    for item in items {
+        s1.send(item).unwrap();
+    }
+    drop(s1);
+
+    let started = time::Instant::now();
+    for worker in 0..num_threads {
+        let r1 = r1.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            while let Ok((val, index)) = r1.recv() {
+                let start = started.elapsed();
+                let result = f(val);
+                let end = started.elapsed();
+                tx.send((
+                    result,
+                    ScheduleEvent {
+                        index,
+                        worker,
+                        start,
+                        end,
+                    },
+                ))
+                .unwrap();
+            }
+        });
+    }
+    drop(tx);
+
+    for (val, event) in rx {
+        output_vec[event.index] = val;
+        trace.push(event);
+    }
+    trace.sort_by_key(|event| event.start);
+
+    (output_vec, trace)
+}
+
+/// What try_parallel_map does when the mapping function fails
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorMode {
+    /// Stop handing out work as soon as any item fails. Items already being processed are allowed
+    /// to finish, but their results are thrown away.
+    FailFast,
+    /// Process every item and report all of the failures.
+    CollectAll,
+}
+
+/// Like parallel_map, but for mapping functions that can fail. Returns the outputs in input order
+/// if every item succeeded; otherwise returns the failures as (input index, error) pairs sorted
+/// by index. With ErrorMode::FailFast that is usually a single error (more than one if several
+/// workers fail at about the same time). If f panics, the panic is passed on to the caller once
+/// the other workers are done.
+#[allow(dead_code)]
+fn try_parallel_map<T, U, E, F>(
+    mut input_vec: Vec<T>,
+    num_threads: usize,
+    mode: ErrorMode,
+    f: F,
+) -> Result<Vec<U>, Vec<(usize, E)>>
+where
+    F: FnOnce(T) -> Result<U, E> + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+    E: Send + 'static,
+{
+    let num_threads = effective_threads(num_threads);
+    let len = input_vec.len();
+    let mut output_vec: Vec<Option<U>> = Vec::with_capacity(len);
+    output_vec.resize_with(len, || None);
+    let mut errors = Vec::new();
+    let (s1, r1) = crossbeam_channel::unbounded::<(T, usize)>();
+    let (tx, rx) = mpsc::channel::<(usize, Result<U, E>)>();
+
+    let mut idx = len;
+    while let Some(val) = input_vec.pop() {
+        idx -= 1;
+        s1.send((val, idx)).unwrap();
+    }
+    drop(s1);
+
+    // Set on the first failure in fail-fast mode; workers check it before taking more work
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mut workers = Vec::with_capacity(num_threads);
+    for _ in 0..num_threads {
+        let r1 = r1.clone();
+        let tx = tx.clone();
+        let cancelled = Arc::clone(&cancelled);
+        workers.push(thread::spawn(move || {
+            while !cancelled.load(Ordering::Relaxed) {
+                let (val, index) = match r1.recv() {
+                    Ok(item) => item,
+                    Err(_) => break,
+                };
+                let result = f(val);
+                if result.is_err() && mode == ErrorMode::FailFast {
+                    cancelled.store(true, Ordering::Relaxed);
+                }
+                tx.send((index, result)).unwrap();
+            }
+        }));
+    }
+    drop(tx);
+
+    for (index, result) in rx {
+        match result {
+            Ok(val) => output_vec[index] = Some(val),
+            Err(err) => errors.push((index, err)),
+        }
+    }
+    // A worker that panicked took its item down with it, leaving that slot empty
+    for worker in workers {
+        if let Err(panic) = worker.join() {
+            std::panic::resume_unwind(panic);
+        }
+    }
+
+    if errors.is_empty() {
+        // No errors, no cancellation and no panics means every slot was filled
+        let missing = output_vec.iter().filter(|slot| slot.is_none()).count();
+        assert_eq!(missing, 0, "try_parallel_map lost {} of {} items", missing, len);
+        Ok(output_vec.into_iter().map(Option::unwrap).collect())
+    } else {
+        errors.sort_by_key(|(index, _)| *index);
+        Err(errors)
+    }
+}
+
+/// How urgently an item passed to prioritized_parallel_map should be processed. Workers always take
+/// the most urgent item that is still waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Batch,
+    Normal,
+    Interactive,
+}
+
+/// An item waiting in prioritized_parallel_map's queue. Ordered so that the heap's maximum is the
+/// most urgent item, with ties going to the one that came first in the input.
+#[allow(dead_code)]
+struct Job<T> {
+    priority: Priority,
+    index: usize,
+    val: T,
+}
+
+impl<T> PartialEq for Job<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority && self.index == other.index
+    }
+}
+
+impl<T> Eq for Job<T> {}
+
+impl<T> PartialOrd for Job<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Job<T> {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+/// Like parallel_map, but each input comes with a Priority. Whenever a worker is free it takes the
+/// highest-priority item still waiting (items of equal priority go in input order), so interactive
+/// work mixed in with a big batch isn't stuck behind it. Outputs are still in input order.
+#[allow(dead_code)]
+fn prioritized_parallel_map<T, U, F>(
+    input_vec: Vec<(T, Priority)>,
+    num_threads: usize,
+    f: F,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static + Default,
+{
+    let num_threads = effective_threads(num_threads);
+    let len = input_vec.len();
+    let mut output_vec: Vec<U> = Vec::with_capacity(len);
+    output_vec.resize_with(len, Default::default);
+    let queue: BinaryHeap<Job<T>> = input_vec
+        .into_iter()
+        .enumerate()
+        .map(|(index, (val, priority))| Job {
+            priority,
+            index,
+            val,
+        })
+        .collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let (tx, rx) = mpsc::channel::<(usize, U)>();
+
+    for _ in 0..num_threads {
+        let queue = Arc::clone(&queue);
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            // Take the lock only long enough to pop, so the others can dequeue while f runs
+            let job = queue.lock().unwrap().pop();
+            match job {
+                Some(job) => tx.send((job.index, f(job.val))).unwrap(),
+                None => break,
+            }
+        });
+    }
+    drop(tx);
+
+    for (index, val) in rx {
+        output_vec[index] = val;
+    }
     output_vec
 }
 
+/// Prints a schedule trace as CSV (one line per item), followed by a per-worker summary of how many
+/// items each worker handled and how long it spent busy.
+fn print_trace(trace: &[ScheduleEvent], num_threads: usize) {
+    eprintln!("index,worker,start_us,end_us");
+    for event in trace {
+        eprintln!(
+            "{},{},{},{}",
+            event.index,
+            event.worker,
+            event.start.as_micros(),
+            event.end.as_micros()
+        );
+    }
+    for worker in 0..num_threads {
+        let events = trace.iter().filter(|event| event.worker == worker);
+        let (count, busy) = events
+            .fold((0, time::Duration::from_secs(0)), |(count, busy), event| {
+                (count + 1, busy + (event.end - event.start))
+            });
+        eprintln!("# worker {}: {} items, busy {:?}", worker, count, busy);
+    }
+}
+
 // Implement a parallelized Mandelbrot Set generator.
 fn mandelbrot_escape(x: f64, y: f64, max_iter: usize) -> usize {
     let mut zx = 0.0;
@@ -65,7 +406,30 @@ fn main() {
             points.push((x, y));
         }
     }
-    let results = parallel_map(points, 12, move |(x, y)| mandelbrot_escape(x, y, max_iter));
+    // Pass --trace to dump the schedule trace to stderr, optionally with --seed N to shuffle the
+    // order in which points are handed to the workers.
+    let args: Vec<String> = env::args().collect();
+    let trace_enabled = args.iter().any(|arg| arg == "--trace");
+    let seed = args
+        .iter()
+        .position(|arg| arg == "--seed")
+        .and_then(|i| args.get(i + 1))
+        .map(|seed| seed.parse::<u64>().expect("--seed must be a number"));
+    // Let the pool size itself to the machine, and resolve the size up front so that the trace
+    // summary covers every worker
+    let num_threads = effective_threads(0);
+    let results = if trace_enabled {
+        let (results, trace) =
+            parallel_map_instrumented(points, num_threads, seed, move |(x, y)| {
+                mandelbrot_escape(x, y, max_iter)
+            });
+        print_trace(&trace, num_threads);
+        results
+    } else {
+        parallel_map(points, num_threads, move |(x, y)| {
+            mandelbrot_escape(x, y, max_iter)
+        })
+    };
     for j in 0..height {
         for i in 0..width {
             let idx = j * width + i;
@@ -83,4 +447,155 @@ fn main() {
         }
         println!("");
     }
+
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn parse(s: &str) -> Result<u32, String> {
+        s.parse::<u32>().map_err(|_| s.to_string())
+    }
+
+    #[test]
+    fn test_try_parallel_map_all_ok() {
+        let inputs: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        for mode in [ErrorMode::FailFast, ErrorMode::CollectAll] {
+            let outputs = try_parallel_map(inputs.clone(), 4, mode, |s: String| parse(&s));
+            assert_eq!(outputs, Ok((0..100).collect::<Vec<u32>>()));
+        }
+    }
+
+    #[test]
+    fn test_try_parallel_map_collect_all() {
+        let inputs = vec!["1", "x", "3", "y", "5", "z"];
+        let errors = try_parallel_map(inputs, 3, ErrorMode::CollectAll, parse).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![(1, "x".to_string()), (3, "y".to_string()), (5, "z".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_try_parallel_map_fail_fast() {
+        // With one worker, nothing is processed after the first failure, whichever one that is
+        static PROCESSED: AtomicUsize = AtomicUsize::new(0);
+        let inputs = vec!["1", "bad", "3", "4", "also bad", "6"];
+        let errors = try_parallel_map(inputs, 1, ErrorMode::FailFast, |s: &str| {
+            PROCESSED.fetch_add(1, Ordering::SeqCst);
+            parse(s)
+        })
+        .unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0] == (1, "bad".to_string()) || errors[0] == (4, "also bad".to_string()));
+        assert!(PROCESSED.load(Ordering::SeqCst) < 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "item 7 is cursed")]
+    fn test_try_parallel_map_passes_on_panics() {
+        let _ = try_parallel_map((0..20).collect(), 4, ErrorMode::CollectAll, |n: u32| {
+            if n == 7 {
+                panic!("item 7 is cursed");
+            }
+            Ok::<u32, ()>(n)
+        });
+    }
+
+    /// The order one worker processed the items in, i.e. the order they were submitted in
+    fn submission_order(seed: Option<u64>) -> Vec<usize> {
+        let (outputs, trace) =
+            parallel_map_instrumented((0..50).collect(), 1, seed, |n: u32| n * 2);
+        assert_eq!(outputs, (0..50).map(|n| n * 2).collect::<Vec<u32>>());
+        trace.iter().map(|event| event.index).collect()
+    }
+
+    #[test]
+    fn test_instrumented_seed_is_reproducible() {
+        let order = submission_order(Some(42));
+        assert_eq!(order, submission_order(Some(42)));
+        assert_ne!(order, submission_order(Some(43)));
+        assert_ne!(order, submission_order(None));
+        let mut sorted = order.clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..50).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_instrumented_keeps_input_order() {
+        let inputs: Vec<u64> = (0..500).collect();
+        let (outputs, trace) = parallel_map_instrumented(inputs, 4, Some(7), |n: u64| n * n);
+        assert_eq!(outputs, (0..500).map(|n| n * n).collect::<Vec<u64>>());
+        assert_eq!(trace.len(), 500);
+        assert!(trace.iter().all(|event| event.worker < 4 && event.start <= event.end));
+        assert!(trace.windows(2).all(|pair| pair[0].start <= pair[1].start));
+    }
+
+    #[test]
+    fn test_prioritized_runs_urgent_items_first() {
+        let jobs = vec![
+            (0, Priority::Batch),
+            (1, Priority::Normal),
+            (2, Priority::Interactive),
+            (3, Priority::Batch),
+            (4, Priority::Interactive),
+        ];
+        // One worker, so the order the items were taken in is the order they finished in
+        static TAKEN: AtomicUsize = AtomicUsize::new(0);
+        let outputs = prioritized_parallel_map(jobs, 1, |n: usize| {
+            (n, TAKEN.fetch_add(1, Ordering::SeqCst))
+        });
+        let inputs: Vec<usize> = outputs.iter().map(|(n, _)| *n).collect();
+        assert_eq!(inputs, vec![0, 1, 2, 3, 4]);
+        let mut by_turn = outputs.clone();
+        by_turn.sort_by_key(|(_, turn)| *turn);
+        let order: Vec<usize> = by_turn.iter().map(|(n, _)| *n).collect();
+        assert_eq!(order, vec![2, 4, 1, 0, 3]);
+    }
+
+    #[test]
+    fn test_prioritized_keeps_input_order() {
+        let priorities = [Priority::Batch, Priority::Normal, Priority::Interactive];
+        let jobs: Vec<(u32, Priority)> =
+            (0..300).map(|n| (n, priorities[n as usize % 3])).collect();
+        let outputs = prioritized_parallel_map(jobs, 4, |n: u32| n + 1);
+        assert_eq!(outputs, (1..=300).collect::<Vec<u32>>());
+    }
+
+    #[test]
+    fn test_parallel_map_with_reuses_worker_state() {
+        static INITS: AtomicUsize = AtomicUsize::new(0);
+        let outputs = parallel_map_with(
+            || {
+                INITS.fetch_add(1, Ordering::SeqCst);
+                Vec::new()
+            },
+            (0..200).collect(),
+            3,
+            |seen: &mut Vec<u32>, n: u32| {
+                seen.push(n);
+                (n * 10, seen.len())
+            },
+        );
+        let values: Vec<u32> = outputs.iter().map(|(value, _)| *value).collect();
+        assert_eq!(values, (0..200).map(|n| n * 10).collect::<Vec<u32>>());
+        let inits = INITS.load(Ordering::SeqCst);
+        assert_eq!(inits, 3, "init should run once per worker");
+        // Some worker handled at least its share of the items, and its state kept all of them
+        let most_seen: usize = outputs.iter().map(|(_, seen)| *seen).max().unwrap();
+        assert!(most_seen >= 200 / inits);
+    }
+
+    #[test]
+    fn test_effective_threads() {
+        assert_eq!(effective_threads(1), 1);
+        assert_eq!(effective_threads(12), 12);
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let auto = effective_threads(0);
+        assert!(auto >= 1 && auto <= cpus, "{} threads for {} CPUs", auto, cpus);
+        // parallel_map works with the automatic pool size
+        assert_eq!(parallel_map(vec![1, 2, 3], 0, |n: u32| n + 1), vec![2, 3, 4]);
+    }
 }